@@ -0,0 +1,489 @@
+use crate::*;
+use nix::{pty, sys::termios, unistd};
+use simulation::DeviceHandle;
+use std::{os::fd::OwnedFd, path::PathBuf, sync::Arc};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::mpsc,
+    time::sleep_until,
+};
+use tokio_stream::StreamExt;
+use virtual_device::DeviceObserver;
+
+/// What firmware sees from `sys get ver` and `AT+VER`
+const RN2903_VERSION: &str = "RN2903 1.0.5 virtual-lorawan-device";
+const LORA_E5_VERSION: &str = "4.0.11";
+/// How long a join is given before it's reported as denied
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long after an unconfirmed uplink its RX windows are taken to have
+/// closed without a downlink
+const UNCONFIRMED_WINDOWS: Duration = Duration::from_secs(3);
+/// How long an uplink is given to go on air and, if confirmed, to be acked,
+/// retransmissions and all
+const TX_TIMEOUT: Duration = Duration::from_secs(60);
+/// MAC parameters firmware commonly sets which the device's settings decide
+/// instead, so setting them is acknowledged and otherwise ignored
+const IGNORED_PARAMS: [&str; 9] = [
+    "adr", "ar", "dr", "pwridx", "retx", "rxdelay1", "linkchk", "class", "sync",
+];
+
+/// A virtual device behind a pseudo-terminal which speaks the AT commands of
+/// an RN2903 or a LoRa-E5 module, so host firmware written against a real
+/// module can drive it unmodified. The firmware decides when the device
+/// joins and sends, so the device's schedule is paused once it's attached.
+pub struct Modem {
+    label: String,
+    path: PathBuf,
+    device: watch::Sender<Option<DeviceHandle>>,
+    observed: mpsc::Sender<Observed>,
+}
+
+/// What the device's observer tells the modem
+enum Observed {
+    Downlink(Option<u8>, Vec<u8>),
+    SessionKeys([u8; 4]),
+}
+
+impl Modem {
+    /// Open a pseudo-terminal for the device, whose path is logged for the
+    /// firmware to be pointed at
+    pub fn open(label: &str, credentials: settings::Credentials) -> Result<Modem> {
+        let pty::OpenptyResult { master, slave } = pty::openpty(None, None)?;
+        // no echo or line editing, as on a module's UART
+        let mut attributes = termios::tcgetattr(&slave)?;
+        termios::cfmakeraw(&mut attributes);
+        termios::tcsetattr(&slave, termios::SetArg::TCSANOW, &attributes)?;
+        let path = unistd::ttyname(&slave)?;
+        info!("{} answers AT commands on {}", label, path.display());
+
+        let (device, device_receiver) = watch::channel(None);
+        let (observed, observed_receiver) = mpsc::channel(100);
+        let modem = Session {
+            label: label.to_string(),
+            credentials,
+            device: device_receiver,
+            observed: observed_receiver,
+            dev_addr: None,
+            lora_e5_port: 1,
+        };
+        tokio::spawn(async move {
+            let label = modem.label.clone();
+            if let Err(e) = modem.run(master, slave).await {
+                error!("AT modem of {} stopped: {:?}", label, e)
+            }
+        });
+        Ok(Modem {
+            label: label.to_string(),
+            path,
+            device,
+            observed,
+        })
+    }
+
+    /// The pseudo-terminal the firmware is to open
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Observer to register on the simulation before the device is started,
+    /// for the downlinks and DevAddr the firmware asks after
+    pub fn observer(&self) -> Arc<dyn DeviceObserver> {
+        Arc::new(ModemObserver {
+            label: self.label.clone(),
+            observed: self.observed.clone(),
+        })
+    }
+
+    /// Answer commands with this device from here on, called whenever the
+    /// device is (re)started. Its schedule is paused, leaving the uplinks to
+    /// the firmware.
+    pub async fn attach(&self, device: DeviceHandle) {
+        if device.pause().await.is_err() {
+            warn!("{} stopped before its AT modem attached", self.label);
+        }
+        let _ = self.device.send(Some(device));
+    }
+}
+
+struct ModemObserver {
+    label: String,
+    observed: mpsc::Sender<Observed>,
+}
+
+impl DeviceObserver for ModemObserver {
+    fn on_downlink(&self, device: &str, _fcnt: u32, fport: Option<u8>, payload: &[u8]) {
+        if device == self.label
+            && self
+                .observed
+                .try_send(Observed::Downlink(fport, payload.to_vec()))
+                .is_err()
+        {
+            warn!("AT modem of {} can't keep up, dropping a downlink", device);
+        }
+    }
+
+    fn on_session_keys(&self, device: &str, dev_addr: [u8; 4], _: &[u8; 16], _: &[u8; 16]) {
+        if device == self.label {
+            let _ = self.observed.try_send(Observed::SessionKeys(dev_addr));
+        }
+    }
+}
+
+/// How an uplink the firmware asked for went
+enum Outcome {
+    /// Its RX windows passed without a downlink
+    Sent,
+    /// A downlink arrived, acking the uplink if it was confirmed
+    Downlink(Option<u8>, Vec<u8>),
+    NoAck,
+    /// The device dropped the uplink rather than send it
+    Dropped,
+}
+
+struct Session {
+    label: String,
+    credentials: settings::Credentials,
+    device: watch::Receiver<Option<DeviceHandle>>,
+    observed: mpsc::Receiver<Observed>,
+    dev_addr: Option<[u8; 4]>,
+    /// FPort of AT+MSG and friends, set with AT+PORT
+    lora_e5_port: u8,
+}
+
+impl Session {
+    /// Answer each line the firmware writes, in turn
+    async fn run(mut self, master: OwnedFd, _slave: OwnedFd) -> Result {
+        // the slave is held open so that reads don't fail while the firmware
+        // has the terminal closed
+        let master = std::fs::File::from(master);
+        let mut writer = File::from_std(master.try_clone()?);
+        let mut lines = BufReader::new(File::from_std(master)).lines();
+        while let Some(line) = lines.next_line().await? {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            debug!("{} AT modem received {:?}", self.label, line);
+            if line
+                .get(..2)
+                .is_some_and(|at| at.eq_ignore_ascii_case("AT"))
+            {
+                self.lora_e5(line, &mut writer).await?;
+            } else {
+                self.rn2903(line, &mut writer).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn rn2903(&mut self, line: &str, writer: &mut (impl AsyncWrite + Unpin)) -> Result {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let handle = self.device.borrow().clone();
+        let answer = match (words.as_slice(), handle) {
+            (["sys", "get", "ver"] | ["sys", "reset"], _) => RN2903_VERSION.to_string(),
+            (["sys", "get", "hweui"] | ["mac", "get", "deveui"], _) => {
+                self.credentials.dev_eui.to_uppercase()
+            }
+            (["mac", "get", "appeui"], _) => self.credentials.app_eui.to_uppercase(),
+            (["mac", "get", "devaddr"], _) => hex::encode_upper(self.dev_addr()),
+            (["mac", "get", "upctr"], Some(handle)) => {
+                handle.state().fcnt_up.unwrap_or_default().to_string()
+            }
+            (["mac", "get", "dnctr"], Some(handle)) => handle
+                .state()
+                .next_fcnt_down
+                .unwrap_or_default()
+                .to_string(),
+            (["mac", "set", param, value], _) => self.set(param, value).to_string(),
+            (["mac", "save"], _) => "ok".to_string(),
+            (["mac", "join", "otaa"], Some(handle)) => {
+                respond(writer, "ok").await?;
+                if self.join(&handle).await {
+                    "accepted"
+                } else {
+                    "denied"
+                }
+                .to_string()
+            }
+            (["mac", "join", ..], None) => "busy".to_string(),
+            (["mac", "tx", kind @ ("cnf" | "uncnf"), fport, data], Some(handle)) => {
+                let fport = match fport.parse::<u8>() {
+                    Ok(fport) if (1..=223).contains(&fport) => fport,
+                    _ => return respond(writer, "invalid_param").await,
+                };
+                let data = match hex::decode(data) {
+                    Ok(data) => data,
+                    Err(_) => return respond(writer, "invalid_param").await,
+                };
+                if !handle.state().joined {
+                    return respond(writer, "not_joined").await;
+                }
+                respond(writer, "ok").await?;
+                match self.transmit(&handle, fport, data, *kind == "cnf").await {
+                    Outcome::Downlink(Some(fport), payload) => {
+                        format!("mac_rx {} {}", fport, hex::encode_upper(payload))
+                    }
+                    Outcome::Sent | Outcome::Downlink(None, _) => "mac_tx_ok".to_string(),
+                    Outcome::NoAck | Outcome::Dropped => "mac_err".to_string(),
+                }
+            }
+            (["mac", "tx", ..], None) => "busy".to_string(),
+            _ => "invalid_param".to_string(),
+        };
+        respond(writer, &answer).await
+    }
+
+    async fn lora_e5(&mut self, line: &str, writer: &mut (impl AsyncWrite + Unpin)) -> Result {
+        let (command, value) = match line[2..].split_once('=') {
+            Some((command, value)) => (command, Some(value.trim())),
+            None => (&line[2..], None),
+        };
+        let command = command.trim_start_matches('+').to_uppercase();
+        let name = if command.is_empty() { "AT" } else { &command };
+        let handle = self.device.borrow().clone();
+        let answers: Vec<String> = match (name, value, handle) {
+            ("AT", None, _) => vec!["OK".to_string()],
+            ("VER", None, _) => vec![LORA_E5_VERSION.to_string()],
+            ("ID", None, _) => {
+                vec![
+                    format!("DevAddr, {}", colons(&self.dev_addr())),
+                    format!("DevEui, {}", colons_hex(&self.credentials.dev_eui)),
+                    format!("AppEui, {}", colons_hex(&self.credentials.app_eui)),
+                ]
+            }
+            ("ID", Some(value), _) => match value.split_once(',') {
+                Some((param, value)) => vec![self.lora_e5_set(param, value)],
+                None => match value.to_lowercase().as_str() {
+                    "deveui" => vec![format!("DevEui, {}", colons_hex(&self.credentials.dev_eui))],
+                    "appeui" => vec![format!("AppEui, {}", colons_hex(&self.credentials.app_eui))],
+                    "devaddr" => vec![format!("DevAddr, {}", colons(&self.dev_addr()))],
+                    _ => vec!["ERROR(-1)".to_string()],
+                },
+            },
+            ("KEY", Some(value), _) => match value.split_once(',') {
+                Some((param, value)) => vec![self.lora_e5_set(param, value)],
+                None => vec!["ERROR(-1)".to_string()],
+            },
+            ("MODE", Some(mode), _) if mode.eq_ignore_ascii_case("LWOTAA") => {
+                vec!["LWOTAA".to_string()]
+            }
+            ("PORT", None, _) => vec![self.lora_e5_port.to_string()],
+            ("PORT", Some(fport), _) => match fport.parse::<u8>() {
+                Ok(fport) if (1..=223).contains(&fport) => {
+                    self.lora_e5_port = fport;
+                    vec![fport.to_string()]
+                }
+                _ => vec!["ERROR(-1)".to_string()],
+            },
+            ("DR" | "ADR" | "CLASS" | "POWER" | "RETRY" | "REPT", Some(value), _) => {
+                debug!("{} ignoring AT+{}={}", self.label, name, value);
+                vec![value.to_string()]
+            }
+            ("JOIN", _, Some(handle)) => {
+                respond(writer, "+JOIN: Start").await?;
+                respond(writer, "+JOIN: NORMAL").await?;
+                if self.join(&handle).await {
+                    vec![
+                        "Network joined".to_string(),
+                        format!("NetID 000000 DevAddr {}", colons(&self.dev_addr())),
+                        "Done".to_string(),
+                    ]
+                } else {
+                    vec!["Join failed".to_string(), "Done".to_string()]
+                }
+            }
+            ("MSG" | "CMSG" | "MSGHEX" | "CMSGHEX", Some(value), Some(handle)) => {
+                let confirmed = name.starts_with('C');
+                let value = value.trim_matches('"');
+                let data = if name.ends_with("HEX") {
+                    match hex::decode(value.replace(' ', "")) {
+                        Ok(data) => data,
+                        Err(_) => return respond(writer, &format!("+{}: ERROR(-1)", name)).await,
+                    }
+                } else {
+                    value.as_bytes().to_vec()
+                };
+                if !handle.state().joined {
+                    return respond(writer, &format!("+{}: Please join network first", name)).await;
+                }
+                respond(writer, &format!("+{}: Start", name)).await?;
+                if confirmed {
+                    respond(writer, &format!("+{}: Wait ACK", name)).await?;
+                }
+                let fport = self.lora_e5_port;
+                let mut answers = Vec::new();
+                match self.transmit(&handle, fport, data, confirmed).await {
+                    Outcome::Downlink(fport, payload) => {
+                        if confirmed {
+                            answers.push("ACK Received".to_string());
+                        }
+                        if let Some(fport) = fport {
+                            answers.push(format!(
+                                "PORT: {}; RX: \"{}\"",
+                                fport,
+                                hex::encode_upper(payload)
+                            ));
+                        }
+                    }
+                    Outcome::Sent | Outcome::NoAck => (),
+                    Outcome::Dropped => answers.push("Length error 0".to_string()),
+                }
+                answers.push("Done".to_string());
+                answers
+            }
+            ("JOIN" | "MSG" | "CMSG" | "MSGHEX" | "CMSGHEX", _, None) => {
+                vec!["BUSY".to_string()]
+            }
+            _ => vec!["ERROR(-1)".to_string()],
+        };
+        for answer in answers {
+            respond(writer, &format!("+{}: {}", name, answer)).await?;
+        }
+        Ok(())
+    }
+
+    /// `mac set`, answering as an RN2903 would
+    fn set(&self, param: &str, value: &str) -> &'static str {
+        match param {
+            "deveui" | "appeui" | "appkey" if self.configured(param, value) => "ok",
+            param if IGNORED_PARAMS.contains(&param) => {
+                debug!("{} ignoring mac set {} {}", self.label, param, value);
+                "ok"
+            }
+            _ => "invalid_param",
+        }
+    }
+
+    /// AT+ID=<param>,"<value>" and AT+KEY=APPKEY,"<value>", which can only
+    /// set the credentials the device already has
+    fn lora_e5_set(&self, param: &str, value: &str) -> String {
+        let param = param.trim().to_lowercase();
+        let value = value.trim().trim_matches('"').replace([':', ' '], "");
+        if self.configured(&param, &value) {
+            format!("{}, {}", param, colons_hex(&value))
+        } else {
+            "ERROR(-1)".to_string()
+        }
+    }
+
+    /// Whether the credential is the one in the device's settings, as the
+    /// device can't take on others while it runs
+    fn configured(&self, param: &str, value: &str) -> bool {
+        let configured = match param {
+            "deveui" => &self.credentials.dev_eui,
+            "appeui" => &self.credentials.app_eui,
+            "appkey" => &self.credentials.app_key,
+            _ => return false,
+        };
+        if !configured.eq_ignore_ascii_case(value) {
+            warn!(
+                "{} can't take on {} {}, its settings have {}",
+                self.label, param, value, configured
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Catch up on what the observer has seen, leaving out downlinks which
+    /// arrived while no uplink was waiting for them
+    fn catch_up(&mut self) {
+        while let Ok(observed) = self.observed.try_recv() {
+            if let Observed::SessionKeys(dev_addr) = observed {
+                self.dev_addr = Some(dev_addr)
+            }
+        }
+    }
+
+    /// DevAddr of the current session, zeros until the device has joined
+    fn dev_addr(&mut self) -> [u8; 4] {
+        self.catch_up();
+        self.dev_addr.unwrap_or_default()
+    }
+
+    /// Join again, reporting whether the device joined
+    async fn join(&mut self, handle: &DeviceHandle) -> bool {
+        let mut events = handle.events();
+        if handle.rejoin().await.is_err() {
+            return false;
+        }
+        let deadline = Instant::now() + JOIN_TIMEOUT;
+        loop {
+            match timeout_at(deadline, events.next()).await {
+                Ok(Some(event)) => match event["event"].as_str() {
+                    Some("join_success") => return true,
+                    Some("join_fail") => return false,
+                    _ => (),
+                },
+                Ok(None) | Err(_) => return false,
+            }
+        }
+    }
+
+    async fn transmit(
+        &mut self,
+        handle: &DeviceHandle,
+        fport: u8,
+        data: Vec<u8>,
+        confirmed: bool,
+    ) -> Outcome {
+        self.catch_up();
+        let mut events = handle.events();
+        if handle.send(fport, data, confirmed).await.is_err() {
+            return Outcome::Dropped;
+        }
+        let mut deadline = Instant::now() + TX_TIMEOUT;
+        loop {
+            tokio::select! {
+                event = events.next() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => return Outcome::Dropped,
+                    };
+                    match event["event"].as_str() {
+                        // the uplink is on air, its RX windows follow
+                        Some("uplink") if !confirmed => {
+                            deadline = Instant::now() + UNCONFIRMED_WINDOWS
+                        }
+                        Some("no_ack") => return Outcome::NoAck,
+                        Some("dwell_time_violation") => return Outcome::Dropped,
+                        _ => (),
+                    }
+                }
+                Some(observed) = self.observed.recv() => match observed {
+                    Observed::Downlink(fport, payload) => {
+                        return Outcome::Downlink(fport, payload)
+                    }
+                    Observed::SessionKeys(dev_addr) => self.dev_addr = Some(dev_addr),
+                },
+                _ = sleep_until(deadline) => {
+                    return if confirmed { Outcome::NoAck } else { Outcome::Sent };
+                }
+            }
+        }
+    }
+}
+
+async fn respond(writer: &mut (impl AsyncWrite + Unpin), line: &str) -> Result {
+    writer.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Bytes as a LoRa-E5 writes them, eg: 00:11:22:33
+fn colons(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn colons_hex(hex: &str) -> String {
+    colons(&hex::decode(hex).unwrap_or_default())
+}
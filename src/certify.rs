@@ -0,0 +1,280 @@
+use crate::*;
+use serde::Serialize;
+use serde_json::Value;
+use settings::NegativeJoin;
+use tokio::{sync::broadcast::error::RecvError, time::sleep_until};
+
+/// Downlinks a data case waits for before passing
+const DOWNLINKS: usize = 3;
+/// Unanswered join requests after which a device is taken not to get in
+const JOIN_ATTEMPTS: usize = 3;
+/// Seconds between uplinks during a case, to keep cases short
+const SECS_BETWEEN_TRANSMITS: u64 = 5;
+
+/// Where a case stands given the events seen so far
+pub enum Verdict {
+    Pending,
+    Pass(String),
+    Fail(String),
+}
+
+/// A scripted test mirroring a common certification test. Each case runs the
+/// device under test on its own, as a fresh device, until it passes, fails or
+/// times out.
+pub struct Case {
+    pub name: &'static str,
+    pub description: &'static str,
+    negative_join: Option<NegativeJoin>,
+    timeout: Duration,
+    judge: fn(&[Value]) -> Verdict,
+}
+
+pub const CASES: [Case; 7] = [
+    Case {
+        name: "join",
+        description: "the device joins",
+        negative_join: None,
+        timeout: Duration::from_secs(60),
+        judge: judge_join,
+    },
+    Case {
+        name: "join_accept_timing",
+        description: "the join accept is scheduled for RX1 or RX2",
+        negative_join: None,
+        timeout: Duration::from_secs(60),
+        judge: judge_join_accept_timing,
+    },
+    Case {
+        name: "confirmed_uplink",
+        description: "every confirmed uplink is acknowledged",
+        negative_join: None,
+        timeout: Duration::from_secs(120),
+        judge: judge_confirmed_uplink,
+    },
+    Case {
+        name: "downlink_sequence",
+        description: "downlinks are scheduled for RX1 or RX2 and FCntDown counts up by one",
+        negative_join: None,
+        timeout: Duration::from_secs(120),
+        judge: judge_downlink_sequence,
+    },
+    Case {
+        name: "wrong_app_key",
+        description: "a join signed with the wrong AppKey is rejected",
+        negative_join: Some(NegativeJoin::WrongAppKey),
+        timeout: Duration::from_secs(60),
+        judge: judge_negative_join,
+    },
+    Case {
+        name: "replayed_dev_nonce",
+        description: "a join reusing a DevNonce is rejected",
+        negative_join: Some(NegativeJoin::ReplayedDevNonce),
+        timeout: Duration::from_secs(90),
+        judge: judge_negative_join,
+    },
+    Case {
+        name: "unprovisioned",
+        description: "a join from an unknown DevEUI is rejected",
+        negative_join: Some(NegativeJoin::Unprovisioned),
+        timeout: Duration::from_secs(60),
+        judge: judge_negative_join,
+    },
+];
+
+#[derive(Serialize, Debug)]
+pub struct Outcome {
+    pub case: &'static str,
+    pub passed: bool,
+    pub detail: String,
+    pub elapsed_ms: u64,
+}
+
+/// Run the cases, or only those named, with the given device as the device
+/// under test. Its credentials must be registered with the network server.
+pub async fn run(
+    settings: &settings::Settings,
+    device: &str,
+    only: &[String],
+    options: simulation::Options,
+) -> Result<Vec<Outcome>> {
+    let base = settings
+        .device
+        .get(device)
+        .ok_or_else(|| Error::UnknownDevice(device.to_string()))?;
+    if let Some(name) = only
+        .iter()
+        .find(|name| !CASES.iter().any(|case| case.name == name.as_str()))
+    {
+        return Err(Error::UnknownCase(name.clone()));
+    }
+
+    let mut simulation = Simulation::new(settings, options)?;
+    let mut events = simulation.subscribe();
+    let mut outcomes = Vec::new();
+    for case in CASES
+        .iter()
+        .filter(|case| only.is_empty() || only.iter().any(|name| name == case.name))
+    {
+        info!("Running {}: {}", case.name, case.description);
+        let label = format!("{}-{}", device, case.name);
+        let mut device = base.clone();
+        device.negative_join = case.negative_join;
+        device.secs_between_transmits = SECS_BETWEEN_TRANSMITS;
+        let start = Instant::now();
+        simulation
+            .apply([(label.clone(), device)].into_iter().collect())
+            .await;
+
+        let mut seen = Vec::new();
+        let verdict = loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) if event["device"] == label.as_str() => {
+                        seen.push(event);
+                        match (case.judge)(&seen) {
+                            Verdict::Pending => (),
+                            verdict => break verdict,
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(RecvError::Lagged(n)) => {
+                        break Verdict::Fail(format!("{} events were dropped", n))
+                    }
+                    // the simulation holds the sender until it is stopped
+                    Err(RecvError::Closed) => unreachable!(),
+                },
+                _ = sleep_until(start + case.timeout) => {
+                    break Verdict::Fail(format!("timed out after {:?}", case.timeout))
+                }
+            }
+        };
+        simulation.stop_device(&label).await;
+
+        let (passed, detail) = match verdict {
+            Verdict::Pass(detail) => (true, detail),
+            Verdict::Fail(detail) => (false, detail),
+            Verdict::Pending => unreachable!(),
+        };
+        info!(
+            "{} {}: {}",
+            if passed { "PASS" } else { "FAIL" },
+            case.name,
+            detail
+        );
+        outcomes.push(Outcome {
+            case: case.name,
+            passed,
+            detail,
+            elapsed_ms: start.elapsed().as_millis() as u64,
+        });
+    }
+    simulation.stop().await;
+    Ok(outcomes)
+}
+
+fn named<'a>(events: &'a [Value], name: &'a str) -> impl Iterator<Item = &'a Value> {
+    events.iter().filter(move |event| event["event"] == name)
+}
+
+/// Events since the device last joined
+fn since_join(events: &[Value]) -> Option<&[Value]> {
+    let joined = events
+        .iter()
+        .rposition(|event| event["event"] == "join_success")?;
+    Some(&events[joined + 1..])
+}
+
+/// Fails once the device has given up on enough joins
+fn join_failed(events: &[Value]) -> Option<Verdict> {
+    let fails = named(events, "join_fail").count();
+    (fails >= JOIN_ATTEMPTS && since_join(events).is_none())
+        .then(|| Verdict::Fail(format!("no join accept to {} join requests", fails)))
+}
+
+fn judge_join(events: &[Value]) -> Verdict {
+    if since_join(events).is_some() {
+        Verdict::Pass("joined".to_string())
+    } else {
+        join_failed(events).unwrap_or(Verdict::Pending)
+    }
+}
+
+fn judge_join_accept_timing(events: &[Value]) -> Verdict {
+    if let Some(violation) = named(events, "rx_timing_violation").next() {
+        return Verdict::Fail(violation["reason"].as_str().unwrap_or_default().to_string());
+    }
+    match events.iter().find(|event| event["event"] == "join_success") {
+        Some(join) => Verdict::Pass(format!(
+            "join accept arrived with {} μs to spare",
+            join["time_remaining_us"]
+        )),
+        None => join_failed(events).unwrap_or(Verdict::Pending),
+    }
+}
+
+fn judge_confirmed_uplink(events: &[Value]) -> Verdict {
+    let events = match since_join(events) {
+        Some(events) => events,
+        None => return join_failed(events).unwrap_or(Verdict::Pending),
+    };
+    let mut confirmed = None;
+    let mut acknowledged = 0;
+    for event in events {
+        match event["event"].as_str() {
+            Some("uplink") if event["confirmed"] == true => confirmed = Some(&event["fcnt"]),
+            Some("downlink") => acknowledged += 1,
+            Some("no_ack") => {
+                return Verdict::Fail(format!(
+                    "confirmed uplink fcnt {} was not acknowledged",
+                    confirmed.unwrap_or(&Value::Null)
+                ))
+            }
+            _ => (),
+        }
+    }
+    if acknowledged >= DOWNLINKS {
+        Verdict::Pass(format!("{} confirmed uplinks acknowledged", acknowledged))
+    } else {
+        Verdict::Pending
+    }
+}
+
+fn judge_downlink_sequence(events: &[Value]) -> Verdict {
+    let events = match since_join(events) {
+        Some(events) => events,
+        None => return join_failed(events).unwrap_or(Verdict::Pending),
+    };
+    for event in events {
+        match event["event"].as_str() {
+            Some("rx_timing_violation") => {
+                return Verdict::Fail(event["reason"].as_str().unwrap_or_default().to_string())
+            }
+            Some("fcnt_down_discontinuity") => {
+                return Verdict::Fail(format!(
+                    "expected FCntDown {} but received {}",
+                    event["expected"], event["received"]
+                ))
+            }
+            Some("duplicate_downlink") => {
+                return Verdict::Fail("the same downlink was sent twice".to_string())
+            }
+            _ => (),
+        }
+    }
+    let downlinks = named(events, "downlink").count();
+    if downlinks >= DOWNLINKS {
+        Verdict::Pass(format!("{} downlinks in sequence and on time", downlinks))
+    } else {
+        Verdict::Pending
+    }
+}
+
+fn judge_negative_join(events: &[Value]) -> Verdict {
+    if named(events, "negative_join_accepted").next().is_some() {
+        Verdict::Fail("the join was accepted".to_string())
+    } else if named(events, "join_rejected").next().is_some() {
+        Verdict::Pass("the join was rejected".to_string())
+    } else {
+        Verdict::Pending
+    }
+}
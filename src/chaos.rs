@@ -0,0 +1,132 @@
+use crate::*;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng};
+use settings::FaultKind;
+use udp_runtime::Impairments;
+
+/// Least share of packets dropped during a packet loss burst
+const MIN_LOSS: f64 = 0.5;
+
+/// A fault injected by the chaos controller
+#[derive(Clone, Debug)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// Packet forwarder or device label
+    pub target: String,
+    pub duration: Duration,
+    pub loss: Option<f64>,
+    pub latency: Option<Duration>,
+}
+
+pub enum Step {
+    Inject(Fault),
+    Clear(Fault),
+}
+
+/// Decides which faults to inject and when, drawing from its own rng so that
+/// with a seed the same timeline is produced on every run
+pub struct Chaos {
+    settings: settings::Chaos,
+    rng: StdRng,
+    next_fault: Instant,
+    /// injected faults which have yet to end
+    active: Vec<(Instant, Fault)>,
+}
+
+impl Chaos {
+    pub fn new(settings: settings::Chaos, seed: Option<u64>) -> Chaos {
+        let mut rng = rng::device_rng(seed, "chaos");
+        let next_fault = Instant::now() + gap(&mut rng, &settings);
+        Chaos {
+            settings,
+            rng,
+            next_fault,
+            active: Vec::new(),
+        }
+    }
+
+    /// When the next fault is injected or an active one ends
+    pub fn deadline(&self) -> Instant {
+        self.active
+            .iter()
+            .map(|(until, _)| *until)
+            .fold(self.next_fault, std::cmp::min)
+    }
+
+    /// Clear the faults which have ended and inject the next fault if it's
+    /// due, picking its target from the given packet forwarders or devices
+    pub fn step(&mut self, packet_forwarders: &[String], devices: &[String]) -> Vec<Step> {
+        let now = Instant::now();
+        let mut steps = Vec::new();
+        let (ended, active) = self
+            .active
+            .drain(..)
+            .partition::<Vec<_>, _>(|(until, _)| *until <= now);
+        self.active = active;
+        steps.extend(ended.into_iter().map(|(_, fault)| Step::Clear(fault)));
+
+        if self.next_fault <= now {
+            self.next_fault = now + gap(&mut self.rng, &self.settings);
+            if let Some(fault) = self.draw(packet_forwarders, devices) {
+                // a reset is over as soon as it has happened
+                if fault.kind != FaultKind::DeviceReset {
+                    self.active.push((now + fault.duration, fault.clone()));
+                }
+                steps.push(Step::Inject(fault));
+            }
+        }
+        steps
+    }
+
+    fn draw(&mut self, packet_forwarders: &[String], devices: &[String]) -> Option<Fault> {
+        let kind = *self.settings.faults.choose(&mut self.rng)?;
+        let targets = match kind {
+            FaultKind::DeviceReset => devices,
+            _ => packet_forwarders,
+        };
+        let target = targets.choose(&mut self.rng)?.clone();
+        let duration = match kind {
+            FaultKind::DeviceReset => Duration::ZERO,
+            _ => Duration::from_secs(
+                self.rng
+                    .gen_range(1..=self.settings.max_duration_secs.max(1)),
+            ),
+        };
+        Some(Fault {
+            kind,
+            target,
+            duration,
+            loss: (kind == FaultKind::PacketLoss).then(|| self.rng.gen_range(MIN_LOSS..=1.0)),
+            latency: (kind == FaultKind::LatencySpike).then(|| {
+                Duration::from_millis(self.rng.gen_range(1..=self.settings.max_latency_ms.max(1)))
+            }),
+        })
+    }
+
+    /// The impairments a packet forwarder is under from the active faults.
+    /// Overlapping faults of a kind take the worst of them.
+    pub fn impairments(&self, packet_forwarder: &str) -> Impairments {
+        let mut impairments = Impairments::default();
+        for (_, fault) in self
+            .active
+            .iter()
+            .filter(|(_, fault)| fault.target == packet_forwarder)
+        {
+            match fault.kind {
+                FaultKind::PacketLoss => {
+                    impairments.loss = impairments.loss.max(fault.loss.unwrap_or_default())
+                }
+                FaultKind::GatewayFlap => impairments.offline = true,
+                FaultKind::LatencySpike => {
+                    impairments.latency = impairments.latency.max(fault.latency.unwrap_or_default())
+                }
+                FaultKind::DeviceReset => (),
+            }
+        }
+        impairments
+    }
+}
+
+/// Time until the next fault, averaging the configured interval
+fn gap(rng: &mut StdRng, settings: &settings::Chaos) -> Duration {
+    Duration::from_millis(rng.gen_range(0..=settings.interval_secs * 2000))
+}
@@ -0,0 +1,47 @@
+use crate::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use virtual_device::{DeviceState, Session};
+
+/// The state of the whole fleet, written every so often while running so a
+/// run can pick up after a crash or reboot
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Checkpoint {
+    /// Milliseconds since the epoch when the checkpoint was taken
+    pub timestamp_ms: u64,
+    pub devices: BTreeMap<String, DeviceState>,
+    /// DevAddr and keys of each joined device's session, by label
+    #[serde(default)]
+    pub sessions: BTreeMap<String, Session>,
+    /// Next DevNonce of each LoRaWAN 1.0.4 device, by DevEUI
+    pub dev_nonces: BTreeMap<String, u32>,
+}
+
+impl Checkpoint {
+    /// Take the device states, and the sessions of those which are joined
+    pub fn set_devices(&mut self, devices: BTreeMap<String, DeviceState>) {
+        self.sessions = devices
+            .iter()
+            .filter_map(|(label, state)| Some((label.clone(), state.session.clone()?)))
+            .collect();
+        self.devices = devices;
+    }
+
+    /// Read a checkpoint, or None if none has been written yet
+    pub fn read(path: &Path) -> Result<Option<Checkpoint>> {
+        match File::open(path) {
+            Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the checkpoint next to the path and move it into place, so a
+    /// crash while writing leaves the previous checkpoint whole
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let partial = path.with_extension("partial");
+        serde_json::to_writer_pretty(File::create(&partial)?, self)?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
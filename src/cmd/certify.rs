@@ -0,0 +1,71 @@
+use crate::*;
+use virtual_lorawan_device::certify;
+
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// Device under test, whose credentials are registered with the network
+    /// server. Defaults to the first device by label.
+    #[structopt(long)]
+    pub device: Option<String>,
+    /// Only run this case, may be given more than once
+    #[structopt(long = "case")]
+    pub cases: Vec<String>,
+    /// Write the outcome of every case to this file as JSON
+    #[structopt(long)]
+    pub report: Option<PathBuf>,
+    /// Write every device event as a JSON line to this file
+    #[structopt(long)]
+    pub event_log: Option<PathBuf>,
+    /// List the cases and exit
+    #[structopt(long)]
+    pub list: bool,
+}
+
+impl Cmd {
+    pub async fn run(self, settings: &Path, scenario: Option<&str>) -> Result<()> {
+        if self.list {
+            for case in &certify::CASES {
+                println!("{:20} {}", case.name, case.description);
+            }
+            return Ok(());
+        }
+        let settings = settings::Settings::new(settings, scenario)?;
+        let device = match self.device.clone() {
+            Some(device) => device,
+            None => settings
+                .device
+                .keys()
+                .min()
+                .cloned()
+                .ok_or_else(|| Error::UnknownDevice("(none configured)".to_string()))?,
+        };
+        let outcomes = certify::run(
+            &settings,
+            &device,
+            &self.cases,
+            simulation::Options {
+                event_log: self.event_log.clone(),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        println!();
+        for outcome in &outcomes {
+            println!(
+                "{} {:20} {:>6.1}s  {}",
+                if outcome.passed { "PASS" } else { "FAIL" },
+                outcome.case,
+                outcome.elapsed_ms as f64 / 1000.0,
+                outcome.detail
+            );
+        }
+        if let Some(report) = &self.report {
+            serde_json::to_writer_pretty(File::create(report)?, &outcomes)?;
+        }
+        match outcomes.iter().filter(|outcome| !outcome.passed).count() {
+            0 => Ok(()),
+            failed => Err(Error::CasesFailed(failed)),
+        }
+    }
+}
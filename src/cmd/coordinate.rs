@@ -0,0 +1,26 @@
+use crate::*;
+use std::{net::SocketAddr, num::NonZeroUsize};
+use virtual_lorawan_device::coordinator::Coordinator;
+
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// Address to serve workers and Prometheus service discovery on
+    #[structopt(long, default_value = "0.0.0.0:9899")]
+    pub listen: SocketAddr,
+    /// Number of workers to split the fleet between
+    #[structopt(long)]
+    pub workers: NonZeroUsize,
+    /// How long after the last worker registers to start the run, giving
+    /// every worker time to hear of it, eg: 10s
+    #[structopt(long, default_value = "10s", parse(try_from_str = super::run::parse_duration))]
+    pub start_delay: Duration,
+}
+
+impl Cmd {
+    /// Hand out shards until stopped
+    pub async fn run(self) -> Result<()> {
+        Coordinator::new(self.workers.get(), self.start_delay)
+            .serve(self.listen)
+            .await
+    }
+}
@@ -0,0 +1,38 @@
+use crate::*;
+use virtual_lorawan_device::fuzz;
+
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// Packet forwarder to send the frames through
+    #[structopt(long, default_value = "default")]
+    pub packet_forwarder: String,
+    /// Number of frames to send, cycling through every case
+    #[structopt(short, long, default_value = "120")]
+    pub count: usize,
+    /// Milliseconds between frames
+    #[structopt(long, default_value = "1000")]
+    pub interval_ms: u64,
+    /// Frequency in MHz the frames are reported on
+    #[structopt(long, default_value = "868.1")]
+    pub freq: f64,
+    /// Seed the generator to send the same frames every time
+    #[structopt(long)]
+    pub seed: Option<u64>,
+}
+
+impl Cmd {
+    pub async fn run(self, settings: &Path, scenario: Option<&str>) -> Result<()> {
+        let settings = settings::Settings::new(settings, scenario)?;
+        fuzz::run(
+            &settings,
+            fuzz::Options {
+                packet_forwarder: self.packet_forwarder,
+                count: self.count,
+                interval: Duration::from_millis(self.interval_ms),
+                freq: self.freq,
+                seed: self.seed,
+            },
+        )
+        .await
+    }
+}
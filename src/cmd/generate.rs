@@ -0,0 +1,47 @@
+use crate::*;
+use rand::RngCore;
+
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// Number of devices to generate
+    #[structopt(short, long, default_value = "1")]
+    pub count: usize,
+    /// Prefix for the generated device labels
+    #[structopt(long, default_value = "device")]
+    pub prefix: String,
+    /// Share this app_eui across all generated devices instead of generating one per device
+    #[structopt(long)]
+    pub app_eui: Option<String>,
+    /// Seed the generator to get the same credentials every time
+    #[structopt(long)]
+    pub seed: Option<u64>,
+}
+
+impl Cmd {
+    /// Print device sections with random credentials which can be pasted into settings.toml
+    pub fn run(self) -> Result<()> {
+        if let Some(app_eui) = &self.app_eui {
+            settings::mac_string_into_buf(app_eui)?;
+        }
+        let mut rng = rng::device_rng(self.seed, &self.prefix);
+        for n in 0..self.count {
+            let mut dev_eui = [0u8; 8];
+            let mut app_eui = [0u8; 8];
+            let mut app_key = [0u8; 16];
+            rng.fill_bytes(&mut dev_eui);
+            rng.fill_bytes(&mut app_eui);
+            rng.fill_bytes(&mut app_key);
+            let app_eui = self
+                .app_eui
+                .clone()
+                .unwrap_or_else(|| hex::encode_upper(app_eui));
+
+            println!("[device.{}-{}.credentials]", self.prefix, n);
+            println!("dev_eui = \"{}\"", hex::encode_upper(dev_eui));
+            println!("app_eui = \"{}\"", app_eui);
+            println!("app_key = \"{}\"", hex::encode_upper(app_key));
+            println!();
+        }
+        Ok(())
+    }
+}
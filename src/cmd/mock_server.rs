@@ -0,0 +1,31 @@
+use crate::*;
+use std::net::SocketAddr;
+use virtual_lorawan_device::mock_server::MockServer;
+
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// Address to receive packet forwarder traffic on
+    #[structopt(long, default_value = "127.0.0.1:1680")]
+    pub listen: SocketAddr,
+    /// Send every uplink's payload back to the device on the same port
+    #[structopt(long)]
+    pub echo: bool,
+    /// Hand join requests to the join server at this url, by LoRaWAN Backend
+    /// Interfaces JoinReq, instead of answering them here. Give as
+    /// <JoinEUI>=<url> to only hand it the joins with that JoinEUI, may be
+    /// given more than once
+    #[structopt(long)]
+    pub join_server: Vec<String>,
+}
+
+impl Cmd {
+    /// Serve joins for the configured devices until stopped
+    pub async fn run(self, settings: &Path, scenario: Option<&str>) -> Result<()> {
+        let settings = settings::Settings::new(settings, scenario)?;
+        let mut mock_server = MockServer::bind(self.listen, &settings.device, self.echo).await?;
+        for route in &self.join_server {
+            mock_server = mock_server.join_server(route)?;
+        }
+        mock_server.run().await
+    }
+}
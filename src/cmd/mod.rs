@@ -0,0 +1,57 @@
+use crate::*;
+
+pub mod certify;
+pub mod coordinate;
+pub mod fuzz;
+pub mod generate;
+pub mod mock_server;
+pub mod provision;
+pub mod replay;
+pub mod report;
+pub mod run;
+pub mod scenarios;
+pub mod stress;
+
+#[derive(Debug, StructOpt)]
+pub enum Cmd {
+    /// Run the configured virtual devices (the default)
+    Run(Box<run::Cmd>),
+    /// Run certification style test cases against the network server
+    Certify(certify::Cmd),
+    /// Generate device configuration with random credentials
+    Generate(generate::Cmd),
+    /// Export device credentials as CSV for registering them with a network server
+    Provision(provision::Cmd),
+    /// Summarize an event log written by a previous run
+    Report(report::Cmd),
+    /// List the scenarios in the settings path
+    Scenarios(scenarios::Cmd),
+    /// Run a minimal network server for the configured devices to join
+    MockServer(mock_server::Cmd),
+    /// Send invalid and boundary-case frames through a packet forwarder
+    Fuzz(fuzz::Cmd),
+    /// Send the uplinks recorded in a frame log through a packet forwarder again
+    Replay(replay::Cmd),
+    /// Split the fleet between run instances on several hosts, started together
+    Coordinate(coordinate::Cmd),
+    /// Run a built-in stress scenario against the network server
+    Stress(stress::Cmd),
+}
+
+impl Cmd {
+    pub async fn run(self, settings: &Path, scenario: Option<&str>) -> Result<()> {
+        match self {
+            Cmd::Run(cmd) => cmd.run(settings, scenario).await,
+            Cmd::Certify(cmd) => cmd.run(settings, scenario).await,
+            Cmd::Generate(cmd) => cmd.run(),
+            Cmd::Provision(cmd) => cmd.run(settings, scenario),
+            Cmd::Report(cmd) => cmd.run(),
+            Cmd::Scenarios(cmd) => cmd.run(settings),
+            Cmd::MockServer(cmd) => cmd.run(settings, scenario).await,
+            Cmd::Fuzz(cmd) => cmd.run(settings, scenario).await,
+            Cmd::Replay(cmd) => cmd.run(settings, scenario).await,
+            Cmd::Coordinate(cmd) => cmd.run().await,
+            Cmd::Stress(cmd) => cmd.run(settings, scenario).await,
+        }
+    }
+}
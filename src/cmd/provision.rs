@@ -0,0 +1,41 @@
+use crate::*;
+
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// Only export devices which use this server
+    #[structopt(long)]
+    pub server: Option<String>,
+}
+
+impl Cmd {
+    /// Print the configured device credentials as CSV so they can be imported
+    /// into a network server before running
+    pub fn run(self, settings: &Path, scenario: Option<&str>) -> Result<()> {
+        let settings = settings::Settings::new(settings, scenario)?;
+        let devices: BTreeMap<_, _> = settings.device.iter().collect();
+
+        println!("label,dev_eui,app_eui,app_key,region,server");
+        for (label, device) in devices {
+            let server = device.server.as_ref().unwrap_or(&settings.default_server);
+            if let Some(filter) = &self.server {
+                if filter != server {
+                    continue;
+                }
+            }
+            // validate before exporting anything we could not run with
+            device.credentials.deveui_cloned_into_buf()?;
+            device.credentials.appeui_cloned_into_buf()?;
+            device.credentials.appkey_cloned_into_buf()?;
+            println!(
+                "{},{},{},{},{:?},{}",
+                label,
+                device.credentials.dev_eui,
+                device.credentials.app_eui,
+                device.credentials.app_key,
+                device.region,
+                server
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use crate::*;
+use virtual_lorawan_device::replay;
+
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// Packet forwarder to send the frames through
+    #[structopt(long, default_value = "default")]
+    pub packet_forwarder: String,
+    /// SQLite database written by run --frame-log
+    #[structopt(long)]
+    pub frame_log: PathBuf,
+    /// Only replay the uplinks of this device
+    #[structopt(long)]
+    pub device: Option<String>,
+    /// Send the frames this many times faster than they were recorded
+    #[structopt(long, default_value = "1")]
+    pub speed: f64,
+}
+
+impl Cmd {
+    pub async fn run(self, settings: &Path, scenario: Option<&str>) -> Result<()> {
+        let settings = settings::Settings::new(settings, scenario)?;
+        replay::run(
+            &settings,
+            replay::Options {
+                packet_forwarder: self.packet_forwarder,
+                frame_log: self.frame_log,
+                device: self.device,
+                speed: self.speed,
+            },
+        )
+        .await
+    }
+}
@@ -0,0 +1,52 @@
+use crate::*;
+use serde_json::Value;
+use std::io::{BufRead, BufReader};
+
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// Event log written with `run --event-log`
+    pub event_log: PathBuf,
+}
+
+impl Cmd {
+    /// Print a per device count of every event type found in the event log
+    pub fn run(self) -> Result<()> {
+        let reader = BufReader::new(File::open(&self.event_log)?);
+        let mut devices: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+        let mut totals: BTreeMap<String, u64> = BTreeMap::new();
+
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Value = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Skipping line {} of event log: {}", n + 1, e);
+                    continue;
+                }
+            };
+            let device = record["device"].as_str().unwrap_or("unknown").to_string();
+            let event = record["event"].as_str().unwrap_or("unknown").to_string();
+            *devices
+                .entry(device)
+                .or_default()
+                .entry(event.clone())
+                .or_default() += 1;
+            *totals.entry(event).or_default() += 1;
+        }
+
+        for (device, events) in &devices {
+            println!("{}", device);
+            for (event, count) in events {
+                println!("  {:<24} {}", event, count);
+            }
+        }
+        println!("total ({} devices)", devices.len());
+        for (event, count) in &totals {
+            println!("  {:<24} {}", event, count);
+        }
+        Ok(())
+    }
+}
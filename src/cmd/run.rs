@@ -0,0 +1,566 @@
+use crate::*;
+use console::Command;
+use simulation::Simulation;
+use std::time::SystemTime;
+use tokio::{
+    io::AsyncBufReadExt,
+    sync::broadcast::error::RecvError,
+    time::{sleep, sleep_until},
+};
+use virtual_lorawan_device::at_modem;
+
+/// How often the settings directory is checked for changes with --watch
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the fleet is checkpointed with --checkpoint-file, unless given
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+/// How often packet forwarders still waiting for the network server are logged
+const READY_REMINDER: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default, StructOpt)]
+pub struct Cmd {
+    /// Limit number of devices to spawn
+    #[structopt(short, long)]
+    pub limit: Option<usize>,
+    /// Write every device event as a JSON line to this file
+    #[structopt(long)]
+    pub event_log: Option<PathBuf>,
+    /// Directory to write device state and the final metrics report to on shutdown
+    #[structopt(long)]
+    pub run_dir: Option<PathBuf>,
+    /// Also write each device's events to a file of its own under the run
+    /// directory's devices folder
+    #[structopt(long, requires = "run_dir")]
+    pub device_logs: bool,
+    /// Record every frame, raw and decrypted, into this SQLite database
+    #[structopt(long)]
+    pub frame_log: Option<PathBuf>,
+    /// Write the session keys of every join to this file, in the format of
+    /// Wireshark's LoRaWAN session keys table
+    #[structopt(long)]
+    pub key_log: Option<PathBuf>,
+    /// Seed all randomness so that a run can be reproduced
+    #[structopt(long)]
+    pub seed: Option<u64>,
+    /// Apply changes to the device settings while running. Changes to the
+    /// schedule keep the session, other device changes restart that device.
+    #[structopt(long)]
+    pub watch: bool,
+    /// Validate the settings and print them as they would be used, then exit
+    #[structopt(long)]
+    pub dry_run: bool,
+    /// Read commands from stdin to poke at devices while they run, try `help`
+    #[structopt(long)]
+    pub console: bool,
+    /// Answer RN2903 and LoRa-E5 AT commands for this device on a
+    /// pseudo-terminal, leaving its joins and uplinks to the firmware on the
+    /// other end. May be given more than once
+    #[structopt(long)]
+    pub at_pty: Vec<String>,
+    /// Stop after running for this long, eg: 90s, 30m or 2h
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub duration: Option<Duration>,
+    /// Stop each device once it has sent this many scheduled uplinks, and
+    /// stop the run once every device has
+    #[structopt(long)]
+    pub uplinks: Option<u32>,
+    /// Also run a minimal network server on this address, point a packet
+    /// forwarder's host at it to run without an external network server
+    #[structopt(long)]
+    pub mock_server: Option<std::net::SocketAddr>,
+    /// Have the mock server send every uplink's payload back on the same port
+    #[structopt(long)]
+    pub mock_echo: bool,
+    /// Have the mock server hand join requests to the join server at this
+    /// url, or with <JoinEUI>=<url> only those with that JoinEUI. May be
+    /// given more than once
+    #[structopt(long)]
+    pub mock_join_server: Vec<String>,
+    /// Checkpoint every device's state, session and DevNonce counter to this file
+    /// while running, and pick up from it when started again
+    #[structopt(long)]
+    pub checkpoint_file: Option<PathBuf>,
+    /// How often to write the checkpoint, eg: 30s or 5m. Defaults to a minute.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub checkpoint_interval: Option<Duration>,
+    /// Give up if the network server hasn't acked every packet forwarder
+    /// within this long, eg: 30s. Devices aren't started until it has.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub ready_timeout: Option<Duration>,
+    /// Run only the share of the fleet handed out by the coordinator at this
+    /// url, eg: http://10.0.0.1:9899, starting when it says
+    #[structopt(long)]
+    pub coordinator: Option<String>,
+    /// Name to register with the coordinator under, defaults to the address
+    /// of the metrics server
+    #[structopt(long)]
+    pub worker: Option<String>,
+}
+
+impl Cmd {
+    pub async fn run(self, settings_path: &Path, scenario: Option<&str>) -> Result<()> {
+        let settings = settings::Settings::new(settings_path, scenario)?;
+        if let (Some(name), Some(scenario)) = (scenario, &settings.scenario) {
+            info!(
+                "Running scenario {} version {}",
+                name,
+                scenario.version.as_deref().unwrap_or("unknown")
+            );
+        }
+        if self.dry_run {
+            return dry_run(&settings);
+        }
+        // devices with bad settings fail on their own, so the run goes ahead
+        for problem in settings.validate() {
+            warn!("{}", problem);
+        }
+        let mut expectations = expectations::Expectations::new(
+            settings
+                .scenario
+                .as_ref()
+                .map(|scenario| scenario.expect.clone())
+                .unwrap_or_default(),
+        );
+        if let Some(addr) = self.mock_server {
+            let mut mock_server =
+                mock_server::MockServer::bind(addr, &settings.device, self.mock_echo).await?;
+            for route in &self.mock_join_server {
+                mock_server = mock_server.join_server(route)?;
+            }
+            tokio::spawn(async move {
+                if let Err(e) = mock_server.run().await {
+                    error!("Mock server stopped: {:?}", e);
+                }
+            });
+        }
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+        let assignment = match &self.coordinator {
+            Some(url) => {
+                let registration = coordinator::Registration {
+                    name: self.worker.clone(),
+                    metrics_port: settings.metrics_port,
+                };
+                info!("Registering with coordinator {}", url);
+                tokio::select! {
+                    assignment = coordinator::register(url, &registration) => Some(assignment?),
+                    result = &mut shutdown => return result,
+                }
+            }
+            None => None,
+        };
+        if let Some(assignment) = &assignment {
+            info!(
+                "Running shard {} of {}",
+                assignment.shard.index, assignment.shard.count
+            );
+        }
+        let mut simulation = Simulation::new(
+            &settings,
+            simulation::Options {
+                limit: self.limit,
+                seed: self.seed,
+                uplink_limit: self.uplinks,
+                event_log: self.event_log.clone(),
+                device_log_dir: self
+                    .run_dir
+                    .as_ref()
+                    .filter(|_| self.device_logs)
+                    .map(|run_dir| run_dir.join("devices")),
+                frame_log: self.frame_log.clone(),
+                key_log: self.key_log.clone(),
+                shard: assignment.map(|assignment| assignment.shard),
+            },
+        )?;
+        let instant = simulation.instant();
+        let mut observed = simulation.subscribe();
+        let modems = self
+            .at_pty
+            .iter()
+            .map(|label| {
+                let device = settings
+                    .device
+                    .get(label)
+                    .ok_or_else(|| Error::UnknownDevice(label.clone()))?;
+                let modem = at_modem::Modem::open(label, device.credentials.clone())?;
+                simulation.observe(modem.observer());
+                Ok(modem)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(path) = &self.checkpoint_file {
+            if let Some(checkpoint) = checkpoint::Checkpoint::read(path)? {
+                restore(&mut simulation, &checkpoint);
+            }
+        }
+        tokio::select! {
+            result = async {
+                wait_for_network_server(&simulation, self.ready_timeout).await?;
+                // the other workers start at the same time
+                let start_in = assignment.map(|assignment| assignment.start_in());
+                if let Some(start_in) = start_in.filter(|start_in| !start_in.is_zero()) {
+                    info!("Starting with the other workers in {:?}", start_in);
+                    sleep(start_in).await;
+                }
+                Ok::<_, Error>(())
+            } => result?,
+            result = &mut shutdown => {
+                result?;
+                info!("Shutdown requested before the network server answered");
+                simulation.stop().await;
+                return Ok(());
+            }
+        }
+        simulation.apply(settings.device).await;
+        attach_modems(&simulation, &modems).await;
+
+        let mut last_modified = settings_modified(settings_path, scenario);
+        let mut watch_timer = tokio::time::interval(WATCH_INTERVAL);
+        let mut checkpoint_timer =
+            tokio::time::interval(self.checkpoint_interval.unwrap_or(CHECKPOINT_INTERVAL));
+        let mut console = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+        let mut console_open = self.console;
+        let run_for = async {
+            match self.duration {
+                Some(duration) => sleep(duration).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(run_for);
+        let start = instant;
+        loop {
+            let next_deadline = expectations.next_deadline();
+            let chaos_deadline = simulation.chaos_deadline();
+            let handover_deadline = simulation.handover_deadline();
+            tokio::select! {
+                result = &mut shutdown => {
+                    result?;
+                    info!("Shutdown requested, waiting for in-flight exchanges");
+                    break;
+                }
+                _ = &mut run_for => {
+                    info!("Ran for {:?}, waiting for in-flight exchanges", self.duration);
+                    break;
+                }
+                record = observed.recv(), if !expectations.is_empty() => match record {
+                    Ok(record) => {
+                        expectations.observe(&record);
+                        if expectations.all_met() {
+                            info!("Every expectation was met");
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("Expectations lagged, {} events dropped", n)
+                    }
+                    // the simulation holds the sender until it is stopped
+                    Err(RecvError::Closed) => unreachable!(),
+                },
+                _ = sleep_until(start + next_deadline.unwrap_or_default()),
+                    if next_deadline.is_some() =>
+                {
+                    if expectations.any_expired(instant.elapsed()) {
+                        info!("Expectation deadline passed, waiting for in-flight exchanges");
+                        break;
+                    }
+                }
+                _ = sleep_until(chaos_deadline.unwrap_or_else(tokio::time::Instant::now)),
+                    if chaos_deadline.is_some() =>
+                {
+                    simulation.chaos().await
+                }
+                _ = sleep_until(handover_deadline.unwrap_or_else(tokio::time::Instant::now)),
+                    if handover_deadline.is_some() =>
+                {
+                    simulation.handovers().await
+                }
+                _ = simulation.device_stopped() => {
+                    if self.uplinks.is_some() && simulation.all_stopped() {
+                        info!("Every device has sent its uplinks");
+                        break;
+                    }
+                }
+                line = console.next_line(), if console_open => match line? {
+                    Some(line) => console_command(&simulation, &line).await,
+                    None => console_open = false,
+                },
+                _ = checkpoint_timer.tick(), if self.checkpoint_file.is_some() => {
+                    if let Some(path) = &self.checkpoint_file {
+                        if let Err(e) = simulation.checkpoint().write(path) {
+                            warn!("Unable to write checkpoint: {:?}", e);
+                        }
+                    }
+                }
+                _ = watch_timer.tick(), if self.watch => {
+                    let modified = settings_modified(settings_path, scenario);
+                    if modified == last_modified {
+                        continue;
+                    }
+                    last_modified = modified;
+                    match settings::Settings::new(settings_path, scenario) {
+                        Ok(settings) => {
+                            info!("Settings changed, applying device changes");
+                            if settings.default_server != simulation.default_server() {
+                                warn!("Changing default_server requires a restart");
+                            }
+                            simulation.apply(settings.device).await;
+                            attach_modems(&simulation, &modems).await;
+                        }
+                        Err(e) => warn!("Ignoring settings change: {:?}", e),
+                    }
+                }
+            }
+        }
+
+        let mut checkpoint = simulation.checkpoint();
+        let latencies = simulation.latencies();
+        let device_states = simulation.stop().await;
+        info!("{}", summary(device_states.values().cloned()));
+        for percentiles in latencies.percentiles() {
+            info!("{}", percentiles);
+        }
+
+        if let Some(path) = &self.checkpoint_file {
+            checkpoint.set_devices(device_states.clone());
+            checkpoint.write(path)?;
+        }
+
+        if let Some(run_dir) = &self.run_dir {
+            std::fs::create_dir_all(run_dir)?;
+            write_device_states(&run_dir.join("state.json"), &device_states)?;
+            latencies.refresh();
+            Metrics::write_report(&run_dir.join("metrics.txt"))?;
+            info!("Wrote device state and metrics to {}", run_dir.display());
+        }
+
+        match expectations.report_unmet() {
+            0 => Ok(()),
+            unmet => Err(Error::ExpectationsFailed(unmet)),
+        }
+    }
+}
+
+/// Hold off starting devices until the network server has acked every packet
+/// forwarder, so that no load is started against a dead target
+async fn wait_for_network_server(
+    simulation: &Simulation,
+    ready_timeout: Option<Duration>,
+) -> Result<()> {
+    let health = simulation.health();
+    let deadline = ready_timeout.map(|ready_timeout| tokio::time::Instant::now() + ready_timeout);
+    let mut reminder = tokio::time::interval(READY_REMINDER);
+    loop {
+        tokio::select! {
+            _ = health.all_connected() => return Ok(()),
+            _ = reminder.tick() => {
+                let disconnected = health.disconnected();
+                if !disconnected.is_empty() {
+                    info!("Waiting for the network server to ack {}", disconnected.join(", "));
+                }
+            }
+            _ = sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)),
+                if deadline.is_some() =>
+            {
+                return Err(Error::NetworkServerUnreachable(health.disconnected().join(", ")))
+            }
+        }
+    }
+}
+
+/// Pick up the DevNonce counters and sessions from a checkpoint. The stack
+/// takes a session up as an ABP one, whose FCntUp it can't set, so devices
+/// which were joined carry on from FCntUp 0.
+fn restore(simulation: &mut Simulation, checkpoint: &checkpoint::Checkpoint) {
+    let taken = SystemTime::UNIX_EPOCH + Duration::from_millis(checkpoint.timestamp_ms);
+    info!(
+        "Restoring {} devices from a checkpoint taken {} ago",
+        checkpoint.devices.len(),
+        humantime::format_duration(Duration::from_secs(
+            taken.elapsed().map(|age| age.as_secs()).unwrap_or_default()
+        ))
+    );
+    for (label, state) in &checkpoint.devices {
+        if let (false, true, Some(fcnt_up)) = (
+            checkpoint.sessions.contains_key(label),
+            state.joined,
+            state.fcnt_up,
+        ) {
+            info!(
+                "{} was joined at FCnt {}, it will join again",
+                label, fcnt_up
+            );
+        }
+    }
+    if !checkpoint.sessions.is_empty() {
+        warn!(
+            "{} sessions carry on from FCntUp 0, the network server has to allow their \
+             frame counters to reset",
+            checkpoint.sessions.len()
+        );
+    }
+    simulation.restore(checkpoint);
+}
+
+/// Hand each AT modem its device, as it's started or restarted
+async fn attach_modems(simulation: &Simulation, modems: &[at_modem::Modem]) {
+    for modem in modems {
+        match simulation.device(modem.label()) {
+            Some(device) => modem.attach(device).await,
+            None => warn!("{} isn't running, its AT modem can't answer", modem.label()),
+        }
+    }
+}
+
+/// Handle a line typed into the console
+async fn console_command(simulation: &Simulation, line: &str) {
+    if line.trim().is_empty() {
+        return;
+    }
+    let command = match line.parse::<Command>() {
+        Ok(command) => command,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let (device, result) = match command {
+        Command::Help => return println!("{}", console::HELP),
+        Command::List => {
+            for device in simulation.devices() {
+                let state = device.state();
+                println!(
+                    "{:16} {} joined = {} fcnt_up = {:?} next_fcnt_down = {:?}{}",
+                    device.label(),
+                    state.dev_eui,
+                    state.joined,
+                    state.fcnt_up,
+                    state.next_fcnt_down,
+                    if state.paused { " paused" } else { "" }
+                );
+            }
+            return;
+        }
+        Command::Stats => {
+            return println!(
+                "{}",
+                summary(simulation.devices().map(|device| device.state()))
+            )
+        }
+        Command::Send {
+            device,
+            fport,
+            data,
+            confirmed,
+        } => match simulation.device(&device) {
+            Some(handle) => (device, handle.send(fport, data, confirmed).await),
+            None => return println!("no device named {}", device),
+        },
+        Command::SendNow { device } => match simulation.device(&device) {
+            Some(handle) => (device, handle.send_now().await),
+            None => return println!("no device named {}", device),
+        },
+        Command::Rejoin { device } => match simulation.device(&device) {
+            Some(handle) => (device, handle.rejoin().await),
+            None => return println!("no device named {}", device),
+        },
+        Command::Pause { device } => match simulation.device(&device) {
+            Some(handle) => (device, handle.pause().await),
+            None => return println!("no device named {}", device),
+        },
+        Command::Resume { device } => match simulation.device(&device) {
+            Some(handle) => (device, handle.resume().await),
+            None => return println!("no device named {}", device),
+        },
+        Command::Handover {
+            device,
+            packet_forwarder,
+        } => match simulation.hand_over(&device, &packet_forwarder).await {
+            Ok(()) => return,
+            Err(e) => return println!("unable to hand {} over: {}", device, e),
+        },
+    };
+    if result.is_err() {
+        println!("{} is not running", device)
+    }
+}
+
+/// Print the effective settings and any problems found in them
+fn dry_run(settings: &settings::Settings) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(settings)?);
+    let problems = settings.validate();
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    if problems.is_empty() {
+        info!("Settings are valid");
+        Ok(())
+    } else {
+        Err(Error::InvalidSettings(problems.len()))
+    }
+}
+
+fn summary(states: impl Iterator<Item = virtual_device::DeviceState>) -> String {
+    let (mut devices, mut joined, mut uplinks, mut violations) = (0, 0, 0u64, 0u64);
+    for state in states {
+        devices += 1;
+        if state.joined {
+            joined += 1;
+        }
+        uplinks += state.fcnt_up.map(u64::from).unwrap_or(0);
+        violations += u64::from(state.rule_violations);
+    }
+    format!(
+        "{} devices, {} joined, {} uplinks in current sessions, {} downlink rule violations",
+        devices, joined, uplinks, violations
+    )
+}
+
+/// Parse durations like 90s, 30m or 2h. A bare number is in seconds.
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {}", s))?;
+    let secs = match unit {
+        "s" => Some(number),
+        "m" => number.checked_mul(60),
+        "h" => number.checked_mul(60 * 60),
+        _ => return Err(format!("invalid duration unit {}, use s, m or h", unit)),
+    };
+    secs.map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration {}", s))
+}
+
+/// Latest modification time of the settings files, used to notice edits
+fn settings_modified(path: &Path, scenario: Option<&str>) -> Option<SystemTime> {
+    let mut files = settings::files(path, scenario);
+    files.push(settings::credentials_file(path));
+    files
+        .iter()
+        .filter_map(|file| std::fs::metadata(file).ok()?.modified().ok())
+        .max()
+}
+
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = sigterm.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+fn write_device_states(
+    path: &Path,
+    device_states: &BTreeMap<String, virtual_device::DeviceState>,
+) -> Result<()> {
+    serde_json::to_writer_pretty(File::create(path)?, device_states)?;
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use crate::*;
+
+#[derive(Debug, StructOpt)]
+pub struct Cmd {}
+
+impl Cmd {
+    pub fn run(self, settings: &Path) -> Result<()> {
+        for (name, scenario) in settings::Settings::scenarios(settings)? {
+            println!(
+                "{:24} {:10} {}",
+                name,
+                scenario.version.as_deref().unwrap_or("-"),
+                scenario.description.as_deref().unwrap_or("")
+            );
+        }
+        Ok(())
+    }
+}
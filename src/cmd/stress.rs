@@ -0,0 +1,292 @@
+use crate::*;
+use virtual_lorawan_device::stress;
+
+#[derive(Debug, StructOpt)]
+pub enum Cmd {
+    /// Have the devices all try to join within a short window, reporting the
+    /// joins each second and why any failed
+    JoinStorm(JoinStorm),
+    /// Follow many downlinks queued for each device as they arrive, checking
+    /// FPending, their order and the payload limits
+    DownlinkFlood(DownlinkFlood),
+    /// Start the devices at the slowest data rate with a good link and
+    /// follow ADR as it brings them to SF7
+    AdrConvergence(AdrConvergence),
+}
+
+impl Cmd {
+    pub async fn run(self, settings: &Path, scenario: Option<&str>) -> Result<()> {
+        let settings = settings::Settings::new(settings, scenario)?;
+        match self {
+            Cmd::JoinStorm(cmd) => cmd.run(&settings).await,
+            Cmd::DownlinkFlood(cmd) => cmd.run(&settings).await,
+            Cmd::AdrConvergence(cmd) => cmd.run(&settings).await,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct JoinStorm {
+    /// Only storm with the first devices, sorted by label
+    #[structopt(long)]
+    pub devices: Option<usize>,
+    /// Window the devices start within, eg: 10s
+    #[structopt(long, default_value = "10s", parse(try_from_str = super::run::parse_duration))]
+    pub window: Duration,
+    /// Share of the devices, from 0 to 1, which join a second time with the
+    /// DevNonce of their first join
+    #[structopt(long, default_value = "0")]
+    pub replayed_dev_nonces: f64,
+    /// Give up on devices which haven't joined within this long
+    #[structopt(long, default_value = "2m", parse(try_from_str = super::run::parse_duration))]
+    pub timeout: Duration,
+    /// Seed the start times so that a storm can be repeated
+    #[structopt(long)]
+    pub seed: Option<u64>,
+    /// Write the report to this file as JSON
+    #[structopt(long)]
+    pub report: Option<PathBuf>,
+    /// Write every device event as a JSON line to this file
+    #[structopt(long)]
+    pub event_log: Option<PathBuf>,
+}
+
+impl JoinStorm {
+    async fn run(self, settings: &settings::Settings) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.replayed_dev_nonces) {
+            return Err(Error::InvalidSetting(
+                "--replayed-dev-nonces".to_string(),
+                format!("must be between 0 and 1, got {}", self.replayed_dev_nonces),
+            ));
+        }
+        let report = stress::join_storm(
+            settings,
+            stress::JoinStormOptions {
+                devices: self.devices,
+                window: self.window,
+                replayed_dev_nonces: self.replayed_dev_nonces,
+                timeout: self.timeout,
+                seed: self.seed,
+            },
+            simulation::Options {
+                seed: self.seed,
+                event_log: self.event_log.clone(),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        println!();
+        println!("second  requests  accepts  failures");
+        for second in &report.seconds {
+            println!(
+                "{:>6}  {:>8}  {:>7}  {:>8}",
+                second.second, second.requests, second.accepts, second.failures
+            );
+        }
+        println!();
+        println!(
+            "{} of {} devices joined, at most {} a second",
+            report.joined,
+            report.devices,
+            report.peak_accepts_per_sec()
+        );
+        if let Some(all_joined_ms) = report.all_joined_ms {
+            println!(
+                "every device joined in {:.1}s",
+                all_joined_ms as f64 / 1000.0
+            );
+        }
+        if let (Some(p50), Some(p95), Some(max)) = (
+            report.join_latency_p50_ms,
+            report.join_latency_p95_ms,
+            report.join_latency_max_ms,
+        ) {
+            println!(
+                "join latency p50 {} ms, p95 {} ms, max {} ms",
+                p50, p95, max
+            );
+        }
+        if self.replayed_dev_nonces > 0.0 {
+            println!("{} replayed DevNonces rejected", report.replays_rejected);
+        }
+        for (cause, count) in &report.failures {
+            println!("{:>8}  {}", count, cause);
+        }
+        if let Some(path) = &self.report {
+            serde_json::to_writer_pretty(File::create(path)?, &report)?;
+        }
+        match report.devices - report.joined {
+            0 => Ok(()),
+            missing => Err(Error::NotJoined(missing)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DownlinkFlood {
+    /// Only flood the first devices, sorted by label
+    #[structopt(long)]
+    pub devices: Option<usize>,
+    /// Downlinks the application queues for each device
+    #[structopt(long, default_value = "10")]
+    pub downlinks: usize,
+    /// Bytes at the start of each downlink's payload numbering it, big endian
+    /// from 0, to check order, drops and duplicates by
+    #[structopt(long, default_value = "0")]
+    pub sequence_bytes: usize,
+    /// Seconds between the devices' uplinks
+    #[structopt(long, default_value = "5")]
+    pub secs_between_transmits: u64,
+    /// Give up on devices which haven't had every downlink within this long
+    #[structopt(long, default_value = "10m", parse(try_from_str = super::run::parse_duration))]
+    pub timeout: Duration,
+    /// Write the report to this file as JSON
+    #[structopt(long)]
+    pub report: Option<PathBuf>,
+    /// Write every device event as a JSON line to this file
+    #[structopt(long)]
+    pub event_log: Option<PathBuf>,
+}
+
+impl DownlinkFlood {
+    async fn run(self, settings: &settings::Settings) -> Result<()> {
+        if self.sequence_bytes > 8 {
+            return Err(Error::InvalidSetting(
+                "--sequence-bytes".to_string(),
+                format!("must be at most 8, got {}", self.sequence_bytes),
+            ));
+        }
+        let report = stress::downlink_flood(
+            settings,
+            stress::DownlinkFloodOptions {
+                devices: self.devices,
+                downlinks: self.downlinks,
+                sequence_bytes: self.sequence_bytes,
+                secs_between_transmits: self.secs_between_transmits,
+                timeout: self.timeout,
+            },
+            simulation::Options {
+                event_log: self.event_log.clone(),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        println!();
+        println!(
+            "{:16} received  fpending  chain  missing  order  dups  over limit",
+            "device"
+        );
+        for (label, device) in &report.devices {
+            println!(
+                "{:16} {:>8}  {:>8}  {:>5}  {:>7}  {:>5}  {:>4}  {:>10}",
+                label,
+                device.received,
+                device.f_pending,
+                device.longest_chain,
+                device.missing,
+                device.out_of_order,
+                device.duplicates,
+                device.over_limit
+            );
+            if device.f_pending_missing > 0 {
+                println!(
+                    "{:16} FPending unset on {} downlinks which more followed",
+                    "", device.f_pending_missing
+                );
+            }
+            if device.f_pending_left_set {
+                println!("{:16} FPending set on the last downlink", "");
+            }
+            if device.fcnt_gaps > 0 {
+                println!("{:16} {} FCntDowns skipped", "", device.fcnt_gaps);
+            }
+        }
+        if let Some(path) = &self.report {
+            serde_json::to_writer_pretty(File::create(path)?, &report)?;
+        }
+        match report.failed() {
+            0 => Ok(()),
+            failed => Err(Error::DownlinkProblems(failed)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AdrConvergence {
+    /// Only run the first devices, sorted by label
+    #[structopt(long)]
+    pub devices: Option<usize>,
+    /// SNR the devices' uplinks are received with
+    #[structopt(long, default_value = "10")]
+    pub uplink_snr_db: f32,
+    /// Seconds between the devices' uplinks
+    #[structopt(long, default_value = "10")]
+    pub secs_between_transmits: u64,
+    /// Give up on devices which haven't reached SF7 within this long
+    #[structopt(long, default_value = "30m", parse(try_from_str = super::run::parse_duration))]
+    pub timeout: Duration,
+    /// Write the report to this file as JSON
+    #[structopt(long)]
+    pub report: Option<PathBuf>,
+    /// Write every device event as a JSON line to this file
+    #[structopt(long)]
+    pub event_log: Option<PathBuf>,
+}
+
+impl AdrConvergence {
+    async fn run(self, settings: &settings::Settings) -> Result<()> {
+        let report = stress::adr_convergence(
+            settings,
+            stress::AdrConvergenceOptions {
+                devices: self.devices,
+                uplink_snr_db: self.uplink_snr_db,
+                secs_between_transmits: self.secs_between_transmits,
+                timeout: self.timeout,
+            },
+            simulation::Options {
+                event_log: self.event_log.clone(),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        println!();
+        println!(
+            "{:16} uplinks  minutes  LinkADRReq  NbTrans  data rates",
+            "device"
+        );
+        for (label, device) in &report.devices {
+            let data_rates: Vec<String> = device
+                .data_rates
+                .iter()
+                .map(|data_rate| format!("DR{}", data_rate))
+                .collect();
+            println!(
+                "{:16} {:>7}  {:>7}  {:>10}  {:>7}  {}",
+                label,
+                or_dash(device.converged_uplinks.map(|uplinks| uplinks.to_string())),
+                or_dash(
+                    device
+                        .converged_secs
+                        .map(|secs| format!("{:.1}", secs as f64 / 60.0))
+                ),
+                device.link_adr_reqs,
+                or_dash(device.nb_trans.map(|nb_trans| nb_trans.to_string())),
+                data_rates.join(" ")
+            );
+            for problem in &device.problems {
+                println!("{:16} {}", "", problem);
+            }
+        }
+        if let Some(path) = &self.report {
+            serde_json::to_writer_pretty(File::create(path)?, &report)?;
+        }
+        match report.failed() {
+            0 => Ok(()),
+            failed => Err(Error::AdrNotConverged(failed)),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::str::FromStr;
+
+pub const HELP: &str = "\
+commands:
+  list                                 list devices and their session state
+  send <device> <fport> <hex> [confirmed]
+                                       send an uplink from a device
+  send-now <device>                    send the next scheduled uplink now
+  rejoin <device>                      drop the session and join again
+  pause <device>                       hold the device's scheduled uplinks
+  resume <device>                      carry on with the schedule
+  handover <device> <packet_forwarder> move a device to another packet forwarder
+  stats                                summarize the fleet
+  help                                 show this message";
+
+/// A command typed into the interactive console
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    List,
+    Send {
+        device: String,
+        fport: u8,
+        data: Vec<u8>,
+        confirmed: bool,
+    },
+    SendNow {
+        device: String,
+    },
+    Rejoin {
+        device: String,
+    },
+    Pause {
+        device: String,
+    },
+    Resume {
+        device: String,
+    },
+    Handover {
+        device: String,
+        packet_forwarder: String,
+    },
+    Stats,
+    Help,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> std::result::Result<Command, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["list"] => Ok(Command::List),
+            ["stats"] => Ok(Command::Stats),
+            ["help"] | ["?"] => Ok(Command::Help),
+            ["send-now", device] => Ok(Command::SendNow {
+                device: device.to_string(),
+            }),
+            ["rejoin", device] => Ok(Command::Rejoin {
+                device: device.to_string(),
+            }),
+            ["pause", device] => Ok(Command::Pause {
+                device: device.to_string(),
+            }),
+            ["resume", device] => Ok(Command::Resume {
+                device: device.to_string(),
+            }),
+            ["handover", device, packet_forwarder] => Ok(Command::Handover {
+                device: device.to_string(),
+                packet_forwarder: packet_forwarder.to_string(),
+            }),
+            ["send", device, fport, data, rest @ ..] => {
+                let confirmed = match rest {
+                    [] => false,
+                    ["confirmed"] => true,
+                    _ => return Err(format!("unexpected arguments: {}", rest.join(" "))),
+                };
+                let fport = match fport.parse::<u8>() {
+                    Ok(fport) if (1..=223).contains(&fport) => fport,
+                    _ => return Err(format!("fport must be between 1 and 223, got {}", fport)),
+                };
+                let data = hex::decode(data).map_err(|e| format!("invalid hex payload: {}", e))?;
+                Ok(Command::Send {
+                    device: device.to_string(),
+                    fport,
+                    data,
+                    confirmed,
+                })
+            }
+            [] => Err("empty command".to_string()),
+            _ => Err(format!("unknown command: {}, try help", line.trim())),
+        }
+    }
+}
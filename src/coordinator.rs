@@ -0,0 +1,222 @@
+use crate::*;
+use hyper::{
+    header::CONTENT_TYPE,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Client, Method, Request, Response, Server, StatusCode, Uri,
+};
+use serde::{Deserialize, Serialize};
+use simulation::Shard;
+use std::{
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Splits the fleet between simulator instances on several hosts. Each
+/// worker registers and is handed a shard, and once every worker has
+/// registered they are all told when to start, by the wall clock, so that
+/// their scenario timelines line up. Prometheus finds the workers' metrics
+/// servers through /targets, to aggregate across the fleet.
+pub struct Coordinator {
+    workers: usize,
+    start_delay: Duration,
+    registered: Mutex<Vec<Worker>>,
+    start_sender: watch::Sender<Option<u64>>,
+    start: watch::Receiver<Option<u64>>,
+}
+
+struct Worker {
+    name: String,
+    /// where its metrics are served
+    target: SocketAddr,
+}
+
+/// What a worker registers with
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Registration {
+    /// Defaults to the address its metrics are served on
+    pub name: Option<String>,
+    pub metrics_port: u16,
+}
+
+/// The worker's share of the fleet and when to start it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Assignment {
+    pub shard: Shard,
+    pub start_at_ms: u64,
+}
+
+impl Assignment {
+    /// Time left until the run starts, zero once it has
+    pub fn start_in(&self) -> Duration {
+        Duration::from_millis(self.start_at_ms.saturating_sub(now_ms()))
+    }
+}
+
+/// An entry of Prometheus HTTP service discovery
+#[derive(Serialize)]
+struct Targets {
+    targets: Vec<String>,
+    labels: BTreeMap<String, String>,
+}
+
+impl Coordinator {
+    /// Coordinate this many workers, starting them this long after the last
+    /// one registers
+    pub fn new(workers: usize, start_delay: Duration) -> Arc<Coordinator> {
+        let (start_sender, start) = watch::channel(None);
+        Arc::new(Coordinator {
+            workers,
+            start_delay,
+            registered: Mutex::new(Vec::new()),
+            start_sender,
+            start,
+        })
+    }
+
+    pub async fn serve(self: Arc<Self>, addr: SocketAddr) -> Result<()> {
+        info!(
+            "Coordinator listening on http://{} for {} workers",
+            addr, self.workers
+        );
+        Server::bind(&addr)
+            .serve(make_service_fn(move |conn: &AddrStream| {
+                let (coordinator, remote) = (self.clone(), conn.remote_addr());
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| {
+                        coordinator.clone().route(remote, req)
+                    }))
+                }
+            }))
+            .await?;
+        Ok(())
+    }
+
+    async fn route(
+        self: Arc<Self>,
+        remote: SocketAddr,
+        req: Request<Body>,
+    ) -> Result<Response<Body>> {
+        match (req.method(), req.uri().path()) {
+            (&Method::POST, "/register") => {
+                let body = hyper::body::to_bytes(req.into_body()).await?;
+                let registration = match serde_json::from_slice(&body) {
+                    Ok(registration) => registration,
+                    Err(e) => {
+                        return Ok(Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::from(e.to_string()))?)
+                    }
+                };
+                match self.register(remote, registration).await {
+                    Some(assignment) => json(&assignment),
+                    None => Ok(Response::builder()
+                        .status(StatusCode::CONFLICT)
+                        .body(Body::from("every shard is taken"))?),
+                }
+            }
+            (&Method::GET, "/targets") => json(&self.targets()),
+            _ => Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())?),
+        }
+    }
+
+    /// Hand the worker its shard, once every worker has registered, or None
+    /// if every shard is already taken
+    async fn register(&self, remote: SocketAddr, registration: Registration) -> Option<Assignment> {
+        let target = SocketAddr::new(remote.ip(), registration.metrics_port);
+        let name = registration.name.unwrap_or_else(|| target.to_string());
+        let index = {
+            let mut registered = self.registered.lock().expect("registered lock");
+            // a worker which registers again, eg: after a restart, keeps its
+            // shard and joins a run which has already started
+            let index = match registered.iter().position(|worker| worker.name == name) {
+                Some(index) => {
+                    registered[index].target = target;
+                    index
+                }
+                None if registered.len() < self.workers => {
+                    registered.push(Worker {
+                        name: name.clone(),
+                        target,
+                    });
+                    registered.len() - 1
+                }
+                None => return None,
+            };
+            if registered.len() == self.workers && self.start.borrow().is_none() {
+                info!(
+                    "Every worker has registered, starting in {:?}",
+                    self.start_delay
+                );
+                let start_at_ms = now_ms() + self.start_delay.as_millis() as u64;
+                let _ = self.start_sender.send(Some(start_at_ms));
+            }
+            index
+        };
+        info!("Worker {} has shard {} of {}", name, index, self.workers);
+
+        let mut start = self.start.clone();
+        loop {
+            if let Some(start_at_ms) = *start.borrow() {
+                return Some(Assignment {
+                    shard: Shard {
+                        index,
+                        count: self.workers,
+                    },
+                    start_at_ms,
+                });
+            }
+            // we hold a sender ourselves so this never fails
+            let _ = start.changed().await;
+        }
+    }
+
+    /// The workers' metrics servers, labelled with their shard
+    fn targets(&self) -> Vec<Targets> {
+        self.registered
+            .lock()
+            .expect("registered lock")
+            .iter()
+            .enumerate()
+            .map(|(index, worker)| Targets {
+                targets: vec![worker.target.to_string()],
+                labels: [
+                    ("shard".to_string(), index.to_string()),
+                    ("worker".to_string(), worker.name.clone()),
+                ]
+                .into_iter()
+                .collect(),
+            })
+            .collect()
+    }
+}
+
+/// Register with the coordinator at this url, waiting until every worker has
+/// so that they all learn when to start
+pub async fn register(url: &str, registration: &Registration) -> Result<Assignment> {
+    let uri: Uri = format!("{}/register", url.trim_end_matches('/')).parse()?;
+    let request = Request::post(uri)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(registration)?))?;
+    let response = Client::new().request(request).await?;
+    if response.status() == StatusCode::CONFLICT {
+        return Err(Error::CoordinatorFull);
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+fn json<T: Serialize>(value: &T) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(value)?))?)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -15,10 +15,80 @@ pub enum Error {
     InvalidHex(#[from] hex::FromHexError),
     #[error("io error")]
     IoError(#[from] std::io::Error),
+    #[error("json error")]
+    Json(#[from] serde_json::Error),
+    #[error("prometheus error")]
+    Prometheus(#[from] prometheus::Error),
     #[error("metrics channel error")]
     MetricsChannel,
-    #[error("semtech_udp client_runtime error")]
-    SemtechUdpClientRuntime(#[from] semtech_udp::client_runtime::Error),
+    #[error("event log channel error")]
+    EventLogChannel,
+    #[error("semtech_udp packet error")]
+    SemtechUdp(#[from] semtech_udp::Error),
+    #[error("semtech_udp client_runtime closed")]
+    UdpRuntimeClosed,
     #[error("invalid region string")]
     InvalidRegionString(String),
+    #[error("invalid packet forwarder {0}")]
+    InvalidPacketForwarder(String),
+    #[error("no scenario named {0}")]
+    UnknownScenario(String),
+    #[error("{0} scenario expectations were not met")]
+    ExpectationsFailed(usize),
+    #[error("no device named {0}")]
+    UnknownDevice(String),
+    #[error("no test case named {0}")]
+    UnknownCase(String),
+    #[error("{0} test cases failed")]
+    CasesFailed(usize),
+    #[error("{0} devices did not join")]
+    NotJoined(usize),
+    #[error("{0} devices had downlink problems")]
+    DownlinkProblems(usize),
+    #[error("ADR didn't converge sensibly for {0} devices")]
+    AdrNotConverged(usize),
+    #[error("invalid downlink rule pattern: {0}")]
+    Regex(#[from] regex::Error),
+    #[error("{0}: {1}")]
+    InvalidSetting(String, String),
+    #[error("{0} problems found in settings")]
+    InvalidSettings(usize),
+    #[error("udp radio error")]
+    Radio(#[from] virtual_device::RadioError),
+    #[error("device event channel closed")]
+    DeviceChannelClosed,
+    #[error("device builder is missing {0}")]
+    IncompleteDevice(&'static str),
+    #[error("invalid uri")]
+    InvalidUri(#[from] hyper::http::uri::InvalidUri),
+    #[error("http error")]
+    Http(#[from] hyper::http::Error),
+    #[error("http client error")]
+    HttpClient(#[from] hyper::Error),
+    #[error("join server rejected the join: {0}")]
+    JoinServerRejected(String),
+    #[error("unable to decrypt credentials")]
+    Decrypt(#[from] age::DecryptError),
+    #[error("credentials file is encrypted but {0} is not set")]
+    CredentialsLocked(&'static str),
+    #[error("{0} resolved to no addresses")]
+    UnresolvedHost(String),
+    #[error("{0} no longer resolves to {1}")]
+    HostMoved(String, SocketAddr),
+    #[error("{0} stopped acking, failed over to the backup")]
+    FailedOver(String),
+    #[error("network server never acked packet forwarders {0}")]
+    NetworkServerUnreachable(String),
+    #[error("every shard of the coordinator is taken")]
+    CoordinatorFull,
+    #[error("invalid payload source: {0}")]
+    InvalidPayload(String),
+    #[error("csv error")]
+    Csv(#[from] csv::Error),
+    #[error("frame log database error")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("replay speed must be above zero, not {0}")]
+    InvalidReplaySpeed(f64),
+    #[error("pseudo-terminal error")]
+    Pty(#[from] nix::Error),
 }
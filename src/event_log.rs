@@ -0,0 +1,315 @@
+use super::*;
+use error::{Error, Result};
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::{LineWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{broadcast, mpsc};
+
+#[derive(Clone)]
+pub struct Sender {
+    device: String,
+    dev_eui: String,
+    time: Instant,
+    sender: mpsc::Sender<Record>,
+}
+
+impl Sender {
+    pub async fn send(&self, event: Event) -> Result<()> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        self.sender
+            .send(Record {
+                timestamp_ms,
+                elapsed_us: self.time.elapsed().as_micros() as u64,
+                device: self.device.clone(),
+                dev_eui: self.dev_eui.clone(),
+                event,
+            })
+            .await
+            .map_err(|_| Error::EventLogChannel)
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    JoinRequest,
+    JoinSuccess {
+        time_remaining_us: i64,
+    },
+    JoinFail,
+    /// A negative join went unanswered, as it should
+    JoinRejected {
+        mode: settings::NegativeJoin,
+    },
+    /// A negative join was accepted
+    NegativeJoinAccepted {
+        mode: settings::NegativeJoin,
+    },
+    Uplink {
+        fcnt: u32,
+        fport: u8,
+        confirmed: bool,
+    },
+    Downlink {
+        fcnt: u32,
+        fport: Option<u8>,
+        time_remaining_us: Option<i64>,
+        unscheduled: bool,
+        /// The network server has more downlinks queued
+        f_pending: bool,
+        /// TX parameters of the txpk it came in
+        tx: Option<virtual_device::TxParams>,
+    },
+    FCntDownDiscontinuity {
+        expected: u32,
+        received: u32,
+    },
+    /// A downlink was dropped as its FCntDown was MAX_FCNT_GAP or more past
+    /// the one expected
+    FCntDownRejected {
+        expected: u32,
+        received: u32,
+    },
+    DuplicateDownlink,
+    /// A downlink's TX parameters would keep it from reaching the device or
+    /// break the region's limits
+    TxParamViolation {
+        reason: String,
+        tx: virtual_device::TxParams,
+    },
+    RfMismatch {
+        expected_freq: f64,
+        freq: f64,
+        expected_datr: String,
+        datr: String,
+    },
+    OversizedDownlink {
+        size: usize,
+    },
+    DownlinkRuleViolation {
+        fcnt: u32,
+        reason: String,
+    },
+    /// A downlink scheduled outside the RX windows the spec mandates
+    RxTimingViolation {
+        offset_us: i64,
+        reason: String,
+    },
+    NoAck,
+    /// The chaos controller injected a fault into a packet forwarder or device
+    FaultInjected {
+        fault: settings::FaultKind,
+        target: String,
+        duration_ms: u64,
+        loss: Option<f64>,
+        latency_ms: Option<u64>,
+    },
+    /// An injected fault ended
+    FaultCleared {
+        fault: settings::FaultKind,
+        target: String,
+    },
+    MissedRxWindow {
+        late_by_us: u32,
+    },
+    /// A confirmed uplink which wasn't acknowledged, or an unconfirmed one
+    /// from a 1.0.4 device, was sent again with the same FCnt, for the given
+    /// time under NbTrans
+    Retransmission {
+        fcnt: u32,
+        transmission: u8,
+    },
+    /// An uplink was dropped as it would have been on air for longer than
+    /// the region allows
+    DwellTimeViolation {
+        fport: u8,
+        size: usize,
+        airtime_ms: u64,
+    },
+    /// An uplink was too weak for any gateway to demodulate
+    UplinkUnheard,
+    /// No downlink arrived in this many uplinks, so the device went back to
+    /// full power or, already there, down to the given data rate
+    AdrBackoff {
+        uplinks: u32,
+        data_rate: Option<u8>,
+    },
+    /// A LinkADRReq arrived, with the data rate it moved the device to and
+    /// the channel mask in hex
+    LinkAdrReq {
+        data_rate: Option<u8>,
+        ch_mask: String,
+        ch_mask_cntl: u8,
+        nb_trans: Option<u8>,
+    },
+    /// A LinkADRReq set the device's TX power this far below its maximum
+    TxPower {
+        reduction_db: u8,
+    },
+    /// The device answered a DevStatusReq, with its battery level out of 254
+    /// or 255 if it has no battery
+    DevStatusAns {
+        battery: u8,
+        margin: i8,
+    },
+    /// MAC command answers were sent in the FOpts of an uplink, given in hex
+    Piggybacked {
+        fcnt: u32,
+        fopts: String,
+    },
+    /// MAC command answers were sent as the FRMPayload of an uplink on
+    /// FPort 0, given in hex
+    MacOnlyUplink {
+        fcnt: u32,
+        frm_payload: String,
+    },
+    /// The device moved to another packet forwarder, keeping its session
+    Handover {
+        packet_forwarder: String,
+    },
+    /// A relay wrapped an end device's uplink in a ForwardUplinkReq
+    RelayUplink {
+        freq: f64,
+        datr: String,
+        size: usize,
+    },
+    /// A relay played a ForwardDownlinkReq to its end devices
+    RelayDownlink {
+        size: usize,
+    },
+    Timeout,
+    SessionExpired,
+    /// The session was discarded for a fresh join under rejoin_frames or
+    /// rejoin_secs
+    SessionRotated {
+        fcnt_up: u32,
+        session_age_secs: u64,
+    },
+    /// The device was reset under reboot_secs and is joining afresh
+    Reboot {
+        uptime_secs: u64,
+        mac_answers_dropped: usize,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Serialize, Debug)]
+struct Record {
+    timestamp_ms: u64,
+    elapsed_us: u64,
+    device: String,
+    dev_eui: String,
+    #[serde(flatten)]
+    event: Event,
+}
+
+pub struct EventLog {
+    time: Instant,
+    sender: mpsc::Sender<Record>,
+}
+
+impl EventLog {
+    /// Start the event log writer. Every event is also broadcast to the
+    /// observer as the JSON value written to the log. With a device
+    /// directory, each device's events are also appended to a file of its
+    /// own in there, named after its label.
+    pub fn run(
+        path: Option<&Path>,
+        device_dir: Option<&Path>,
+        time: Instant,
+        observer: broadcast::Sender<serde_json::Value>,
+    ) -> Result<EventLog> {
+        let mut writer = if let Some(path) = path {
+            info!("Writing event log to {}", path.display());
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Some(LineWriter::new(file))
+        } else {
+            None
+        };
+        if let Some(device_dir) = device_dir {
+            info!("Writing device event logs to {}", device_dir.display());
+            std::fs::create_dir_all(device_dir)?;
+        }
+        let device_dir = device_dir.map(Path::to_path_buf);
+        let (sender, mut rx) = mpsc::channel::<Record>(1024);
+
+        tokio::spawn(async move {
+            while let Some(record) = rx.recv().await {
+                if writer.is_some() || device_dir.is_some() {
+                    match serde_json::to_string(&record) {
+                        Ok(line) => {
+                            if let Some(writer) = &mut writer {
+                                if let Err(e) = writeln!(writer, "{}", line) {
+                                    error!("unable to write event log: {:?}", e)
+                                }
+                            }
+                            if let Some(device_dir) = &device_dir {
+                                if let Err(e) =
+                                    append_device_line(device_dir, &record.device, &line)
+                                {
+                                    error!("unable to write {} event log: {:?}", record.device, e)
+                                }
+                            }
+                        }
+                        Err(e) => error!("unable to serialize event: {:?}", e),
+                    }
+                }
+                // skip the work when nobody is subscribed
+                if observer.receiver_count() > 0 {
+                    match serde_json::to_value(&record) {
+                        Ok(value) => {
+                            let _ = observer.send(value);
+                        }
+                        Err(e) => error!("unable to serialize event: {:?}", e),
+                    }
+                }
+            }
+            warn!("Event log receive channel closed");
+        });
+
+        Ok(EventLog { time, sender })
+    }
+
+    /// Sender for the chaos controller, whose faults are recorded under the
+    /// device name "chaos" so they appear in the timeline with the rest
+    pub fn get_chaos_sender(&self) -> Sender {
+        self.get_device_sender("chaos", "")
+    }
+
+    pub fn get_device_sender(&self, device: &str, dev_eui: &str) -> Sender {
+        Sender {
+            device: device.to_string(),
+            dev_eui: dev_eui.to_string(),
+            time: self.time,
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+/// Append a line to a device's own event log. The file is opened for each
+/// line rather than kept open, as a fleet of thousands of devices would run
+/// out of file descriptors.
+fn append_device_line(device_dir: &Path, device: &str, line: &str) -> std::io::Result<()> {
+    // labels are free form, so keep them to a plain file name
+    let name: String = device
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(device_dir.join(format!("{}.jsonl", name)))?;
+    writeln!(file, "{}", line)
+}
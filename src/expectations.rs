@@ -0,0 +1,103 @@
+use crate::settings::Expectation;
+use log::{error, info};
+use serde_json::Value;
+use std::{fmt, time::Duration};
+
+/// Tracks scenario expectations against the events devices report
+pub struct Expectations {
+    expectations: Vec<Expectation>,
+    met: Vec<bool>,
+}
+
+impl Expectations {
+    pub fn new(expectations: Vec<Expectation>) -> Expectations {
+        let met = vec![false; expectations.len()];
+        Expectations { expectations, met }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.expectations.is_empty()
+    }
+
+    /// Mark off every expectation this event log record satisfies
+    pub fn observe(&mut self, record: &Value) {
+        let elapsed_us = record["elapsed_us"].as_u64().unwrap_or(u64::MAX);
+        for (expectation, met) in self.expectations.iter().zip(self.met.iter_mut()) {
+            if !*met && expectation.matches(record) && elapsed_us <= expectation.deadline_us() {
+                info!("Met {}", expectation);
+                *met = true;
+            }
+        }
+    }
+
+    pub fn all_met(&self) -> bool {
+        self.met.iter().all(|met| *met)
+    }
+
+    /// Time since start at which the next unmet expectation expires
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.unmet()
+            .map(|expectation| Duration::from_secs(expectation.within_secs))
+            .min()
+    }
+
+    /// Whether any unmet expectation's deadline has passed
+    pub fn any_expired(&self, elapsed: Duration) -> bool {
+        self.unmet()
+            .any(|expectation| elapsed >= Duration::from_secs(expectation.within_secs))
+    }
+
+    /// Log every unmet expectation, returning how many there are
+    pub fn report_unmet(&self) -> usize {
+        let mut unmet = 0;
+        for expectation in self.unmet() {
+            error!("Failed {}", expectation);
+            unmet += 1;
+        }
+        unmet
+    }
+
+    pub fn unmet(&self) -> impl Iterator<Item = &Expectation> {
+        self.expectations
+            .iter()
+            .zip(self.met.iter())
+            .filter(|(_, met)| !**met)
+            .map(|(expectation, _)| expectation)
+    }
+}
+
+impl Expectation {
+    fn matches(&self, record: &Value) -> bool {
+        record["event"].as_str() == Some(self.event.as_str())
+            && self
+                .device
+                .as_ref()
+                .is_none_or(|device| record["device"].as_str() == Some(device.as_str()))
+            && self
+                .fport
+                .is_none_or(|fport| record["fport"].as_u64() == Some(fport.into()))
+            && self
+                .fcnt
+                .is_none_or(|fcnt| record["fcnt"].as_u64() == Some(fcnt.into()))
+    }
+
+    fn deadline_us(&self) -> u64 {
+        self.within_secs.saturating_mul(1_000_000)
+    }
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expectation: {}", self.event)?;
+        if let Some(device) = &self.device {
+            write!(f, " from {}", device)?;
+        }
+        if let Some(fport) = self.fport {
+            write!(f, " on fport {}", fport)?;
+        }
+        if let Some(fcnt) = self.fcnt {
+            write!(f, " with fcnt {}", fcnt)?;
+        }
+        write!(f, " within {}s", self.within_secs)
+    }
+}
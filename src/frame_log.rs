@@ -0,0 +1,118 @@
+use crate::*;
+use rusqlite::{params, Connection};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use virtual_device::{DeviceObserver, Frame};
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS frames (
+    id INTEGER PRIMARY KEY,
+    timestamp_ms INTEGER NOT NULL,
+    device TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    fcnt INTEGER,
+    fport INTEGER,
+    phy_payload BLOB NOT NULL,
+    payload BLOB NOT NULL,
+    freq REAL NOT NULL,
+    datr TEXT,
+    tmst INTEGER
+)";
+
+/// A frame waiting to be written, owned so it can cross to the writer
+struct Row {
+    timestamp_ms: u64,
+    device: String,
+    direction: &'static str,
+    fcnt: Option<u32>,
+    fport: Option<u8>,
+    phy_payload: Vec<u8>,
+    payload: Vec<u8>,
+    freq: f64,
+    datr: Option<String>,
+    tmst: Option<u32>,
+}
+
+/// Records every frame the devices send and accept into the frames table of
+/// a SQLite database, for analysis after the run. Rows are written from a
+/// thread of their own, so devices never wait on the disk.
+pub struct FrameLog {
+    sender: mpsc::Sender<Row>,
+}
+
+impl FrameLog {
+    /// Open the database, creating it and the frames table if need be. Frames
+    /// are added to those of earlier runs.
+    pub fn open(path: &Path) -> Result<FrameLog> {
+        info!("Recording frames to {}", path.display());
+        let connection = Connection::open(path)?;
+        connection.execute(SCHEMA, [])?;
+        let (sender, receiver) = mpsc::channel(1024);
+        std::thread::spawn(move || write(connection, receiver));
+        Ok(FrameLog { sender })
+    }
+}
+
+impl DeviceObserver for FrameLog {
+    fn on_frame(&self, device: &str, frame: &Frame) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let row = Row {
+            timestamp_ms,
+            device: device.to_string(),
+            direction: frame.direction.as_str(),
+            fcnt: frame.fcnt,
+            fport: frame.fport,
+            phy_payload: frame.phy_payload.to_vec(),
+            payload: frame.payload.to_vec(),
+            freq: frame.freq,
+            datr: frame.datr.map(str::to_string),
+            tmst: frame.tmst,
+        };
+        match self.sender.try_send(row) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => warn!("frame log can't keep up, dropping a frame"),
+            Err(TrySendError::Closed(_)) => (),
+        }
+    }
+}
+
+/// Write rows as they come until every device is done with the log, all
+/// those already waiting in one transaction
+fn write(mut connection: Connection, mut receiver: mpsc::Receiver<Row>) {
+    while let Some(row) = receiver.blocking_recv() {
+        let mut rows = vec![row];
+        while let Ok(row) = receiver.try_recv() {
+            rows.push(row);
+        }
+        if let Err(e) = insert(&mut connection, &rows) {
+            error!("unable to record {} frames: {:?}", rows.len(), e)
+        }
+    }
+}
+
+fn insert(connection: &mut Connection, rows: &[Row]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(
+            "INSERT INTO frames (timestamp_ms, device, direction, fcnt, fport, phy_payload, \
+             payload, freq, datr, tmst) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        )?;
+        for row in rows {
+            statement.execute(params![
+                row.timestamp_ms as i64,
+                row.device,
+                row.direction,
+                row.fcnt,
+                row.fport,
+                row.phy_payload,
+                row.payload,
+                row.freq,
+                row.datr,
+                row.tmst,
+            ])?;
+        }
+    }
+    transaction.commit()
+}
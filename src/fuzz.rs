@@ -0,0 +1,230 @@
+use crate::*;
+use rand::{rngs::StdRng, Rng, RngCore};
+use semtech_udp::{
+    push_data::{self, RxPk, RxPkV1},
+    Bandwidth, CodingRate, DataRate, SpreadingFactor,
+};
+use std::fmt;
+
+/// Largest PHYPayload at the lowest spreading factors
+const MAX_PHY_PAYLOAD: usize = 255;
+/// FOptsLen is a 4 bit field
+const MAX_FOPTS: usize = 15;
+
+/// A kind of invalid or boundary-case PHYPayload
+#[derive(Clone, Copy, Debug)]
+pub enum Case {
+    Empty,
+    /// A data uplink cut short somewhere
+    Truncated,
+    /// MType 111, whose content is up to the network
+    Proprietary,
+    /// MType 110, reserved in LoRaWAN 1.0
+    RfuMType,
+    /// Major version bits other than LoRaWAN R1
+    RfuMajor,
+    /// Downlink MTypes arriving as uplinks
+    DownlinkMType,
+    JoinAcceptAsUplink,
+    /// Join requests one byte short or long
+    JoinRequestLength,
+    /// Fifteen bytes of FOpts, the most FOptsLen can announce
+    MaxFOpts,
+    /// FOptsLen announcing more bytes than the frame holds
+    FOptsOverrun,
+    /// FOpts and an FRMPayload on port 0 at once
+    FOptsWithPortZero,
+    /// A data uplink of the largest size any region allows
+    MaxLength,
+}
+
+impl Case {
+    pub const ALL: [Case; 12] = [
+        Case::Empty,
+        Case::Truncated,
+        Case::Proprietary,
+        Case::RfuMType,
+        Case::RfuMajor,
+        Case::DownlinkMType,
+        Case::JoinAcceptAsUplink,
+        Case::JoinRequestLength,
+        Case::MaxFOpts,
+        Case::FOptsOverrun,
+        Case::FOptsWithPortZero,
+        Case::MaxLength,
+    ];
+
+    /// Build a frame for this case. Addresses, counters, payloads and MICs are
+    /// random, so frames are structurally interesting but never authentic.
+    pub fn frame(&self, rng: &mut StdRng) -> Vec<u8> {
+        match self {
+            Case::Empty => Vec::new(),
+            Case::Truncated => {
+                let fport = rng.gen();
+                let mut frame = data_uplink(rng, 0, Some(fport), 8);
+                frame.truncate(rng.gen_range(1..frame.len()));
+                frame
+            }
+            Case::Proprietary => {
+                let len = rng.gen_range(0..32);
+                let mut frame = vec![0xE0];
+                frame.extend(random_bytes(rng, len));
+                frame
+            }
+            Case::RfuMType => {
+                let mut frame = data_uplink(rng, 0, Some(1), 8);
+                frame[0] = 0xC0;
+                frame
+            }
+            Case::RfuMajor => {
+                let mut frame = data_uplink(rng, 0, Some(1), 8);
+                frame[0] |= rng.gen_range(1..4);
+                frame
+            }
+            Case::DownlinkMType => {
+                let mut frame = data_uplink(rng, 0, Some(1), 8);
+                frame[0] = if rng.gen() { 0x60 } else { 0xA0 };
+                frame
+            }
+            Case::JoinAcceptAsUplink => {
+                let len = if rng.gen() { 16 } else { 32 };
+                let mut frame = vec![0x20];
+                frame.extend(random_bytes(rng, len));
+                frame
+            }
+            Case::JoinRequestLength => {
+                let len = if rng.gen() { 21 } else { 23 };
+                let mut frame = vec![0x00];
+                frame.extend(random_bytes(rng, len));
+                frame
+            }
+            Case::MaxFOpts => {
+                let fport = rng.gen_range(1..224);
+                data_uplink(rng, MAX_FOPTS, Some(fport), 8)
+            }
+            Case::FOptsOverrun => {
+                let mut frame = data_uplink(rng, 0, None, 0);
+                frame[5] |= MAX_FOPTS as u8;
+                frame
+            }
+            Case::FOptsWithPortZero => data_uplink(rng, 4, Some(0), 4),
+            Case::MaxLength => {
+                let fport = rng.gen_range(1..224);
+                let mut frame = data_uplink(rng, 0, Some(fport), 0);
+                frame.truncate(frame.len() - 4);
+                let payload = MAX_PHY_PAYLOAD - frame.len() - 4;
+                frame.extend(random_bytes(rng, payload + 4));
+                frame
+            }
+        }
+    }
+}
+
+impl fmt::Display for Case {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// An unconfirmed data uplink: MHDR, DevAddr, FCtrl, FCnt, FOpts, FPort,
+/// FRMPayload and MIC
+fn data_uplink(rng: &mut StdRng, fopts: usize, fport: Option<u8>, payload: usize) -> Vec<u8> {
+    let mut frame = vec![0x40];
+    frame.extend(random_bytes(rng, 4));
+    frame.push(fopts as u8);
+    frame.extend(random_bytes(rng, 2));
+    frame.extend(random_bytes(rng, fopts));
+    if let Some(fport) = fport {
+        frame.push(fport);
+        frame.extend(random_bytes(rng, payload));
+    }
+    frame.extend(random_bytes(rng, 4));
+    frame
+}
+
+fn random_bytes(rng: &mut StdRng, len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    rng.fill_bytes(&mut bytes);
+    bytes
+}
+
+pub struct Options {
+    /// Packet forwarder the frames are sent through
+    pub packet_forwarder: String,
+    /// Number of frames to send, cycling through every case
+    pub count: usize,
+    pub interval: Duration,
+    /// Frequency in MHz the frames are reported on
+    pub freq: f64,
+    pub seed: Option<u64>,
+}
+
+/// Send invalid frames wrapped in valid rxpk JSON through a packet forwarder,
+/// to fuzz the network server from the gateway interface inward
+pub async fn run(settings: &settings::Settings, options: Options) -> Result<()> {
+    let packet_forwarder = settings
+        .packet_forwarder
+        .get(&options.packet_forwarder)
+        .ok_or_else(|| Error::InvalidPacketForwarder(options.packet_forwarder.clone()))?;
+    let instant = Instant::now();
+    let metrics_server: IpAddr = settings.metrics_server.parse()?;
+    let metrics = Metrics::run(
+        (metrics_server, settings.metrics_port).into(),
+        settings.get_servers(),
+        settings.metric_labels,
+        settings.metric_top_k,
+    );
+    let clock = gateway_clock::GatewayClock::new(instant, packet_forwarder.drift_ppm);
+    let runtime = udp_runtime::Runtime::new(
+        options.packet_forwarder.clone(),
+        packet_forwarder.mac_cloned_into_buf()?,
+        udp_runtime::Upstream {
+            host: settings
+                .host(&options.packet_forwarder)
+                .unwrap_or_default()
+                .to_string(),
+            failover: None,
+            mirror: None,
+        },
+        settings
+            .local_port(&options.packet_forwarder)
+            .unwrap_or_default(),
+        clock,
+        packet_forwarder.into(),
+        metrics.get_packet_forwarder_sender(&options.packet_forwarder),
+    );
+    let sender = runtime.handle().publish_to();
+    tokio::spawn(runtime.run());
+
+    let mut rng = rng::device_rng(options.seed, "fuzz");
+    let mut interval = tokio::time::interval(options.interval);
+    for (n, case) in Case::ALL.iter().cycle().take(options.count).enumerate() {
+        interval.tick().await;
+        let data = case.frame(&mut rng);
+        info!("Fuzz frame {} {}: {}", n, case, hex::encode_upper(&data));
+        let rxpk = RxPkV1 {
+            chan: 0,
+            codr: CodingRate::_4_5,
+            size: data.len() as u64,
+            data,
+            datr: DataRate::new(SpreadingFactor::SF7, Bandwidth::BW125),
+            freq: options.freq,
+            lsnr: 5.5,
+            modu: semtech_udp::Modulation::LORA,
+            rfch: 0,
+            rssi: -112,
+            rssis: None,
+            stat: push_data::CRC::OK,
+            tmst: clock.tmst(),
+            time: None,
+        };
+        sender
+            .send(push_data::Packet::from_rxpk(RxPk::V1(rxpk)).into())
+            .await
+            .map_err(|_| Error::UdpRuntimeClosed)?;
+    }
+    info!("Sent {} fuzz frames", options.count);
+    // give the last frame time to leave the packet forwarder
+    tokio::time::sleep(options.interval.min(Duration::from_secs(1))).await;
+    Ok(())
+}
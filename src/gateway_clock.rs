@@ -0,0 +1,72 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Emulates the free running microsecond counter a concentrator reports as
+/// tmst. Real gateway clocks drift from true time, so the counter may run
+/// fast or slow by a configurable rate.
+#[derive(Clone, Copy, Debug)]
+pub struct GatewayClock {
+    start: Instant,
+    drift_ppm: f64,
+}
+
+impl GatewayClock {
+    pub fn new(start: Instant, drift_ppm: f64) -> GatewayClock {
+        GatewayClock { start, drift_ppm }
+    }
+
+    fn rate(&self) -> f64 {
+        1.0 + self.drift_ppm / 1_000_000.0
+    }
+
+    /// Current value of the gateway's tmst counter. Like a real concentrator's
+    /// counter it is 32 bits wide, so it wraps roughly every 71 minutes.
+    pub fn tmst(&self) -> u32 {
+        (self.start.elapsed().as_micros() as f64 * self.rate()) as u64 as u32
+    }
+
+    /// Signed distance in μs from one tmst to another, correct across the
+    /// counter wrapping as long as they are within ~35 minutes of each other
+    pub fn offset(from: u32, to: u32) -> i32 {
+        to.wrapping_sub(from) as i32
+    }
+
+    /// Convert a span measured by the gateway's counter into real time
+    pub fn to_real(&self, gateway_micros: u32) -> Duration {
+        Duration::from_micros((gateway_micros as f64 / self.rate()) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_across_the_counter_wrapping() {
+        assert_eq!(GatewayClock::offset(1_000, 6_000), 5_000);
+        assert_eq!(GatewayClock::offset(6_000, 1_000), -5_000);
+        assert_eq!(GatewayClock::offset(u32::MAX - 5, 10), 16);
+        assert_eq!(GatewayClock::offset(10, u32::MAX - 5), -16);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tmst_wraps_at_32_bits() {
+        let clock = GatewayClock::new(Instant::now(), 0.0);
+        tokio::time::advance(Duration::from_micros(u64::from(u32::MAX) - 9)).await;
+        assert_eq!(clock.tmst(), u32::MAX - 9);
+        tokio::time::advance(Duration::from_micros(510)).await;
+        assert_eq!(clock.tmst(), 500);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drift() {
+        let fast = GatewayClock::new(Instant::now(), 100.0);
+        let slow = GatewayClock::new(Instant::now(), -100.0);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(fast.tmst(), 10_001_000);
+        assert_eq!(slow.tmst(), 9_999_000);
+        // spans the gateway counted come back to real time within a μs
+        let real = fast.to_real(10_001_000).as_micros();
+        assert!((9_999_999..=10_000_000).contains(&real), "{}", real);
+    }
+}
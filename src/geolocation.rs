@@ -0,0 +1,327 @@
+use crate::*;
+use gateway_clock::GatewayClock;
+use rand::{rngs::StdRng, Rng};
+use semtech_udp::{pull_resp, push_data, StringOrNum};
+use settings::{Location, Mobility};
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::sync::mpsc;
+use virtual_device::{Delivery, VirtualTransport};
+
+/// Power devices transmit at when the network server hasn't turned them
+/// down, in dBm
+const TX_POWER_DBM: f64 = 14.0;
+/// Log-distance path loss exponent, between free space (2) and dense urban
+const PATH_LOSS_EXPONENT: f64 = 2.7;
+/// Noise figure of the gateway's receiver
+const NOISE_FIGURE_DB: f64 = 6.0;
+const SPEED_OF_LIGHT_M_PER_S: f64 = 299_792_458.0;
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+/// Downlinks remembered for acking, the device acks one soon after it arrives
+const RECENT_DOWNLINKS: usize = 64;
+
+/// What a gateway at a known location measures of an uplink
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reception {
+    pub rssi: i32,
+    pub lsnr: f32,
+    pub distance_m: f64,
+    /// Time of flight from the device to the gateway
+    pub delay_ns: u64,
+}
+
+/// Straight line distance between two locations, over the earth's surface
+/// and the difference in altitude
+pub fn distance_m(a: &Location, b: &Location) -> f64 {
+    let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.longitude - a.longitude).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    let ground = 2.0 * EARTH_RADIUS_M * h.sqrt().asin();
+    ground.hypot(b.altitude_m - a.altitude_m)
+}
+
+/// The reception of an uplink sent from the device's location at the given
+/// TX power, or None if it is too weak for the gateway to demodulate.
+/// Everything is derived from the geometry alone, so the measurements of
+/// several gateways agree.
+pub fn receive(
+    device: &Location,
+    gateway: &Location,
+    tx_power_dbm: f64,
+    freq_mhz: f64,
+    datr: &str,
+) -> Option<Reception> {
+    let (spreading_factor, bandwidth_khz) = parse_datr(datr)?;
+    // a metre apart at the least, inside of which the model doesn't hold
+    let distance_m = distance_m(device, gateway).max(1.0);
+    let path_loss =
+        20.0 * freq_mhz.log10() - 27.55 + 10.0 * PATH_LOSS_EXPONENT * distance_m.log10();
+    let rssi = tx_power_dbm - path_loss;
+    let noise_floor = -174.0 + 10.0 * (bandwidth_khz * 1000.0).log10() + NOISE_FIGURE_DB;
+    let snr = rssi - noise_floor;
+    if snr < demodulation_floor_db(spreading_factor) {
+        return None;
+    }
+    Some(Reception {
+        rssi: rssi.round() as i32,
+        // the concentrator's SNR estimate saturates
+        lsnr: (snr.min(13.5) * 4.0).round() as f32 / 4.0,
+        distance_m,
+        delay_ns: (distance_m / SPEED_OF_LIGHT_M_PER_S * 1e9).round() as u64,
+    })
+}
+
+/// The location a distance away from another, along a bearing in radians
+/// clockwise from north. Good over the short distances devices move.
+fn offset(from: &Location, distance_m: f64, bearing: f64) -> Location {
+    let latitude = from.latitude + (distance_m * bearing.cos() / EARTH_RADIUS_M).to_degrees();
+    let longitude = from.longitude
+        + (distance_m * bearing.sin() / (EARTH_RADIUS_M * from.latitude.to_radians().cos()))
+            .to_degrees();
+    Location {
+        latitude,
+        longitude,
+        altitude_m: from.altitude_m,
+    }
+}
+
+/// The location a share of the way from one location to another
+fn between(from: &Location, to: &Location, share: f64) -> Location {
+    Location {
+        latitude: from.latitude + (to.latitude - from.latitude) * share,
+        longitude: from.longitude + (to.longitude - from.longitude) * share,
+        altitude_m: from.altitude_m + (to.altitude_m - from.altitude_m) * share,
+    }
+}
+
+/// Where a device is over time. It moves in straight legs at a steady
+/// speed, round its waypoints or, without any, to one random point after
+/// another within a radius of where it started.
+pub struct Track {
+    started: Instant,
+    start: Location,
+    mobility: Option<Mobility>,
+    rng: StdRng,
+    /// the current leg, and how far along the track it begins
+    from: Location,
+    to: Location,
+    leg_start_m: f64,
+    next_waypoint: usize,
+}
+
+impl Track {
+    pub fn new(start: Location, mobility: Option<Mobility>, rng: StdRng) -> Track {
+        let mut track = Track {
+            started: Instant::now(),
+            start,
+            mobility,
+            rng,
+            from: start,
+            to: start,
+            leg_start_m: 0.0,
+            next_waypoint: 0,
+        };
+        track.to = track.next_stop();
+        track
+    }
+
+    /// Where the device is now
+    pub fn location(&mut self) -> Location {
+        let speed_mps = match &self.mobility {
+            Some(mobility) => mobility.speed_mps,
+            None => return self.start,
+        };
+        let travelled_m = speed_mps * self.started.elapsed().as_secs_f64();
+        loop {
+            let leg_m = distance_m(&self.from, &self.to);
+            let along_m = travelled_m - self.leg_start_m;
+            if along_m < leg_m {
+                return between(&self.from, &self.to, along_m / leg_m);
+            }
+            self.leg_start_m += leg_m;
+            self.from = self.to;
+            self.to = self.next_stop();
+        }
+    }
+
+    fn next_stop(&mut self) -> Location {
+        let mobility = match &self.mobility {
+            Some(mobility) => mobility,
+            None => return self.start,
+        };
+        if mobility.waypoints.is_empty() {
+            // uniform over the disc around the start
+            let distance = mobility.radius_m * self.rng.gen::<f64>().sqrt();
+            let bearing = self.rng.gen_range(0.0..std::f64::consts::TAU);
+            return offset(&self.start, distance, bearing);
+        }
+        // round the waypoints and back to the start
+        let stop = mobility
+            .waypoints
+            .get(self.next_waypoint)
+            .copied()
+            .unwrap_or(self.start);
+        self.next_waypoint = (self.next_waypoint + 1) % (mobility.waypoints.len() + 1);
+        stop
+    }
+}
+
+/// Lowest SNR an uplink can be demodulated at with this spreading factor,
+/// dropping 2.5 dB with each step up from SF7's -7.5 dB
+pub fn demodulation_floor_db(spreading_factor: u8) -> f64 {
+    -5.0 - 2.5 * f64::from(spreading_factor.saturating_sub(6))
+}
+
+/// Whether an uplink with this SNR can be demodulated at its datr, eg:
+/// "SF7BW125". Datrs which aren't LoRa are taken to be heard.
+pub fn demodulates(snr: f64, datr: &str) -> bool {
+    parse_datr(datr)
+        .is_none_or(|(spreading_factor, _)| snr >= demodulation_floor_db(spreading_factor))
+}
+
+/// A datr as written in an rxpk, eg: "SF7BW125", formatted on the stack as
+/// it's needed for every uplink
+pub fn datr_name(datr: &semtech_udp::DataRate) -> heapless::String<16> {
+    let mut name = heapless::String::new();
+    // the variants are named as in a datr, and DataRate's to_string allocates;
+    // the longest, SF12BW500, fits with room to spare
+    let _ = write!(name, "{:?}{:?}", datr.spreading_factor(), datr.bandwidth());
+    name
+}
+
+/// Spreading factor and bandwidth in kHz of a datr, eg: "SF7BW125"
+fn parse_datr(datr: &str) -> Option<(u8, f64)> {
+    let (spreading_factor, bandwidth) = datr.strip_prefix("SF")?.split_once("BW")?;
+    Some((spreading_factor.parse().ok()?, bandwidth.parse().ok()?))
+}
+
+/// A packet forwarder a device may be heard by
+#[derive(Clone)]
+pub struct Gateway {
+    pub label: String,
+    pub transport: udp_runtime::Handle,
+    pub location: Location,
+}
+
+/// A transport for a device at a location, whose uplinks are heard by every
+/// gateway in range with the RSSI, SNR and arrival time its distance gives.
+/// Arrival times are carried in `time`, as a GPS synchronised gateway would.
+/// The location is taken from the device's track as each uplink is sent, so
+/// a moving device comes into and goes out of range of gateways.
+///
+/// The device keeps time by its home gateway. Downlinks sent through the
+/// other gateways have their tmst moved onto the home gateway's clock.
+pub struct GeoTransport {
+    home: GatewayClock,
+    track: Mutex<Track>,
+    gateways: Vec<Gateway>,
+    /// which gateway recent downlinks came through, by token, to ack them there
+    downlink_gateways: Arc<Mutex<VecDeque<(u16, usize)>>>,
+}
+
+impl GeoTransport {
+    pub fn new(home: GatewayClock, track: Track, gateways: Vec<Gateway>) -> GeoTransport {
+        GeoTransport {
+            home,
+            track: Mutex::new(track),
+            gateways,
+            downlink_gateways: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+}
+
+impl VirtualTransport for GeoTransport {
+    fn clock(&self) -> GatewayClock {
+        self.home
+    }
+
+    fn uplink(&self, rxpk: push_data::RxPkV1) -> bool {
+        self.uplink_at_reduced_power(rxpk, 0) != Delivery::Full
+    }
+
+    fn uplink_at_reduced_power(&self, rxpk: push_data::RxPkV1, reduction_db: u8) -> Delivery {
+        let sent = SystemTime::now();
+        let tx_power_dbm = TX_POWER_DBM - f64::from(reduction_db);
+        let datr = datr_name(&rxpk.datr);
+        let location = self.track.lock().expect("track lock").location();
+        debug!(
+            "uplink sent from {:.6}, {:.6}",
+            location.latitude, location.longitude
+        );
+        let mut delivery = Delivery::Unheard;
+        for gateway in &self.gateways {
+            let reception =
+                match receive(&location, &gateway.location, tx_power_dbm, rxpk.freq, &datr) {
+                    Some(reception) => reception,
+                    None => continue,
+                };
+            let delay = Duration::from_nanos(reception.delay_ns);
+            let mut heard = rxpk.clone();
+            heard.rssi = reception.rssi;
+            heard.lsnr = reception.lsnr;
+            // the gateway stamps the frame by its own counter
+            heard.tmst = gateway
+                .transport
+                .clock()
+                .tmst()
+                .wrapping_add(delay.as_micros() as u32);
+            heard.time = Some(humantime::format_rfc3339_nanos(sent + delay).to_string());
+            match (delivery, gateway.transport.uplink(heard)) {
+                (Delivery::Full, _) => (),
+                (_, true) => delivery = Delivery::Delivered,
+                (_, false) => delivery = Delivery::Full,
+            }
+        }
+        delivery
+    }
+
+    fn downlinks(&self) -> mpsc::Receiver<Box<pull_resp::Packet>> {
+        let (sender, downlinks) = mpsc::channel(100);
+        for (index, gateway) in self.gateways.iter().enumerate() {
+            let mut gateway_downlinks = gateway.transport.downlinks();
+            let (sender, clock) = (sender.clone(), gateway.transport.clock());
+            let (home, downlink_gateways) = (self.home, self.downlink_gateways.clone());
+            tokio::spawn(async move {
+                while let Some(mut downlink) = gateway_downlinks.recv().await {
+                    if let StringOrNum::N(tmst) = downlink.data.txpk.tmst {
+                        let offset = GatewayClock::offset(clock.tmst(), tmst);
+                        downlink.data.txpk.tmst =
+                            StringOrNum::N(home.tmst().wrapping_add(offset as u32));
+                    }
+                    {
+                        let mut downlink_gateways =
+                            downlink_gateways.lock().expect("downlink gateways lock");
+                        if downlink_gateways.len() == RECENT_DOWNLINKS {
+                            downlink_gateways.pop_front();
+                        }
+                        downlink_gateways.push_back((downlink.random_token, index));
+                    }
+                    // the device has stopped so there's nobody left to deliver to
+                    if sender.send(downlink).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        downlinks
+    }
+
+    fn ack(&self, downlink: Box<pull_resp::Packet>) {
+        let index = self
+            .downlink_gateways
+            .lock()
+            .expect("downlink gateways lock")
+            .iter()
+            .rev()
+            .find(|(token, _)| *token == downlink.random_token)
+            .map(|(_, index)| *index);
+        if let Some(gateway) = index.and_then(|index| self.gateways.get(index)) {
+            gateway.transport.ack(downlink);
+        }
+    }
+}
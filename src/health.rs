@@ -0,0 +1,112 @@
+use crate::*;
+use hyper::{header::CONTENT_TYPE, Body, Response};
+use serde::Serialize;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use tokio::sync::Notify;
+
+/// What the metrics server's /healthz and /readyz report: whether each
+/// packet forwarder is connected, that is acked by the network server, and
+/// how many devices are running or have failed
+#[derive(Default)]
+pub struct Health {
+    packet_forwarders: Mutex<BTreeMap<String, bool>>,
+    /// notified whenever a packet forwarder connects or disconnects
+    changed: Notify,
+    devices_running: AtomicUsize,
+    devices_failed: AtomicUsize,
+}
+
+#[derive(Serialize)]
+struct Report {
+    healthy: bool,
+    ready: bool,
+    packet_forwarders: BTreeMap<String, bool>,
+    devices_running: usize,
+    devices_failed: usize,
+}
+
+/// Counts a device as running for as long as it is held, so that a device
+/// task which is aborted stops counting too
+pub struct RunningDevice(Arc<Health>);
+
+impl Drop for RunningDevice {
+    fn drop(&mut self) {
+        self.0.devices_running.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Health {
+    pub fn packet_forwarder_connected(&self, label: &str, connected: bool) {
+        self.packet_forwarders
+            .lock()
+            .expect("packet forwarders lock")
+            .insert(label.to_string(), connected);
+        self.changed.notify_waiters();
+    }
+
+    /// Packet forwarders which aren't connected
+    pub fn disconnected(&self) -> Vec<String> {
+        self.packet_forwarders
+            .lock()
+            .expect("packet forwarders lock")
+            .iter()
+            .filter(|(_, connected)| !**connected)
+            .map(|(label, _)| label.clone())
+            .collect()
+    }
+
+    /// Wait until every packet forwarder is connected
+    pub async fn all_connected(&self) {
+        loop {
+            // registered before checking so that no change is missed
+            let changed = self.changed.notified();
+            if self.disconnected().is_empty() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Count a device as running until the returned guard is dropped
+    pub fn device_running(self: &Arc<Self>) -> RunningDevice {
+        self.devices_running.fetch_add(1, Ordering::Relaxed);
+        RunningDevice(self.clone())
+    }
+
+    pub fn device_failed(&self) {
+        self.devices_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> Report {
+        let packet_forwarders = self
+            .packet_forwarders
+            .lock()
+            .expect("packet forwarders lock")
+            .clone();
+        let devices_running = self.devices_running.load(Ordering::Relaxed);
+        let devices_failed = self.devices_failed.load(Ordering::Relaxed);
+        Report {
+            // failed devices only matter once none are left running
+            healthy: devices_running > 0 || devices_failed == 0,
+            ready: devices_running > 0 && packet_forwarders.values().all(|connected| *connected),
+            packet_forwarders,
+            devices_running,
+            devices_failed,
+        }
+    }
+
+    /// The answer to /healthz or /readyz, 503 if the simulator isn't healthy
+    /// or ready respectively, with the report as JSON
+    pub fn response(&self, ready: bool) -> Response<Body> {
+        let report = self.report();
+        let ok = if ready { report.ready } else { report.healthy };
+        Response::builder()
+            .status(if ok { 200 } else { 503 })
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&report).unwrap_or_default()))
+            .unwrap()
+    }
+}
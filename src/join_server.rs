@@ -0,0 +1,159 @@
+use crate::*;
+use hyper::{client::HttpConnector, header::CONTENT_TYPE, Body, Client, Request, Uri};
+use lorawan::keys::AES128;
+use serde::{Deserialize, Serialize};
+
+/// LoRaWAN Backend Interfaces version spoken to the join server
+const PROTOCOL_VERSION: &str = "1.0";
+const MAC_VERSION: &str = "1.0.3";
+
+/// Client for an external join server, which takes over join processing
+/// through the LoRaWAN Backend Interfaces JoinReq and JoinAns messages
+#[derive(Clone)]
+pub struct JoinServer {
+    client: Client<HttpConnector>,
+    uri: Uri,
+    /// NetID of the network server, sent as the SenderID
+    net_id: String,
+}
+
+/// What the network server is handed back for an accepted join
+pub struct Accepted {
+    /// The join accept, encrypted by the join server
+    pub phy_payload: Vec<u8>,
+    pub nwk_skey: AES128,
+    /// None when the join server keeps the AppSKey for the application server
+    pub app_skey: Option<AES128>,
+}
+
+/// The parameters of a join the network server has already settled
+pub struct Join<'a> {
+    pub transaction_id: u32,
+    pub phy_payload: &'a [u8],
+    /// As sent on air, least significant byte first
+    pub join_eui: [u8; 8],
+    pub dev_eui: [u8; 8],
+    pub dev_addr: [u8; 4],
+    pub dl_settings: u8,
+    pub rx_delay: u8,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct JoinReq<'a> {
+    protocol_version: &'a str,
+    #[serde(rename = "SenderID")]
+    sender_id: &'a str,
+    #[serde(rename = "ReceiverID")]
+    receiver_id: String,
+    #[serde(rename = "TransactionID")]
+    transaction_id: u32,
+    message_type: &'a str,
+    #[serde(rename = "MACVersion")]
+    mac_version: &'a str,
+    #[serde(rename = "PHYPayload")]
+    phy_payload: String,
+    #[serde(rename = "DevEUI")]
+    dev_eui: String,
+    dev_addr: String,
+    #[serde(rename = "DLSettings")]
+    dl_settings: String,
+    rx_delay: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JoinAns {
+    result: AnsResult,
+    #[serde(rename = "PHYPayload")]
+    phy_payload: Option<String>,
+    #[serde(rename = "NwkSKey")]
+    nwk_skey: Option<KeyEnvelope>,
+    #[serde(rename = "AppSKey")]
+    app_skey: Option<KeyEnvelope>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AnsResult {
+    result_code: String,
+    #[serde(default)]
+    description: String,
+}
+
+#[derive(Deserialize)]
+struct KeyEnvelope {
+    #[serde(rename = "KEKLabel", default)]
+    kek_label: String,
+    #[serde(rename = "AESKey")]
+    aes_key: String,
+}
+
+impl JoinServer {
+    pub fn new(uri: &str, net_id: [u8; 3]) -> Result<JoinServer> {
+        Ok(JoinServer {
+            client: Client::new(),
+            uri: uri.parse()?,
+            net_id: hex::encode_upper(net_id),
+        })
+    }
+
+    /// Send a JoinReq and wait for its JoinAns
+    pub async fn join(&self, join: Join<'_>) -> Result<Accepted> {
+        let join_req = JoinReq {
+            protocol_version: PROTOCOL_VERSION,
+            sender_id: &self.net_id,
+            receiver_id: hex_be(&join.join_eui),
+            transaction_id: join.transaction_id,
+            message_type: "JoinReq",
+            mac_version: MAC_VERSION,
+            phy_payload: hex::encode_upper(join.phy_payload),
+            dev_eui: hex_be(&join.dev_eui),
+            dev_addr: hex_be(&join.dev_addr),
+            dl_settings: hex::encode_upper([join.dl_settings]),
+            rx_delay: join.rx_delay,
+        };
+        let request = Request::post(self.uri.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&join_req)?))?;
+        let response = self.client.request(request).await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let join_ans: JoinAns = serde_json::from_slice(&body)?;
+
+        if join_ans.result.result_code != "Success" {
+            return Err(Error::JoinServerRejected(format!(
+                "{} {}",
+                join_ans.result.result_code, join_ans.result.description
+            )));
+        }
+        let phy_payload = join_ans
+            .phy_payload
+            .ok_or_else(|| Error::JoinServerRejected("JoinAns without a PHYPayload".to_string()))?;
+        let nwk_skey = join_ans
+            .nwk_skey
+            .ok_or_else(|| Error::JoinServerRejected("JoinAns without a NwkSKey".to_string()))?;
+        Ok(Accepted {
+            phy_payload: hex::decode(phy_payload)?,
+            nwk_skey: key(&nwk_skey)?,
+            app_skey: join_ans.app_skey.as_ref().map(key).transpose()?,
+        })
+    }
+}
+
+/// Keys wrapped with a KEK can't be opened, as the mock server has no KEKs
+fn key(envelope: &KeyEnvelope) -> Result<AES128> {
+    if !envelope.kek_label.is_empty() {
+        return Err(Error::JoinServerRejected(format!(
+            "key wrapped with KEK {}",
+            envelope.kek_label
+        )));
+    }
+    let mut key = [0; 16];
+    hex::decode_to_slice(&envelope.aes_key, &mut key)?;
+    Ok(AES128(key))
+}
+
+/// Hex of an EUI or address sent least significant byte first, as written
+fn hex_be(bytes: &[u8]) -> String {
+    hex::encode_upper(bytes.iter().rev().copied().collect::<Vec<u8>>())
+}
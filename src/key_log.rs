@@ -0,0 +1,50 @@
+use crate::*;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time::SystemTime,
+};
+use virtual_device::DeviceObserver;
+
+/// Writes the session keys of every join to a file in the format of
+/// Wireshark's LoRaWAN session keys table, so captures of the run can be
+/// decrypted. Each entry follows a comment naming the device it's for.
+pub struct KeyLog {
+    file: Mutex<File>,
+}
+
+impl KeyLog {
+    /// Open the key log, adding to the sessions of earlier runs
+    pub fn open(path: &Path) -> Result<KeyLog> {
+        info!("Writing session keys to {}", path.display());
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(KeyLog {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl DeviceObserver for KeyLog {
+    fn on_session_keys(
+        &self,
+        device: &str,
+        dev_addr: [u8; 4],
+        nwk_skey: &[u8; 16],
+        app_skey: &[u8; 16],
+    ) {
+        let entry = format!(
+            "# {} joined {}\n\"{}\",\"{}\",\"{}\"\n",
+            device,
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            hex::encode_upper(dev_addr),
+            hex::encode_upper(nwk_skey),
+            hex::encode_upper(app_skey)
+        );
+        // a whole entry at a time, so devices joining together don't interleave
+        let mut file = self.file.lock().expect("key log lock");
+        if let Err(e) = file.write_all(entry.as_bytes()) {
+            error!("unable to write session keys of {}: {:?}", device, e)
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::*;
+use hdrhistogram::Histogram;
+use prometheus::{register_gauge_vec, GaugeVec};
+use serde::Serialize;
+use std::{fmt, sync::Mutex};
+
+/// Quantiles each latency is reported at
+const QUANTILES: [(f64, &str); 4] = [(0.5, "0.5"), (0.9, "0.9"), (0.99, "0.99"), (0.999, "0.999")];
+
+/// Latencies reported as percentiles rather than averages, which hide the
+/// tail that makes devices miss their windows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Latency {
+    /// From a join request being sent until its join accept arrived
+    Join,
+    /// Time which was left in the RX window as a downlink arrived
+    DownlinkMargin,
+    /// From a confirmed uplink being sent until the downlink acking it
+    /// arrived
+    AckRoundTrip,
+}
+
+impl Latency {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Latency::Join => "join",
+            Latency::DownlinkMargin => "downlink_margin",
+            Latency::AckRoundTrip => "ack_round_trip",
+        }
+    }
+}
+
+/// Percentiles of one latency of the devices of one server, in microseconds
+#[derive(Debug, Serialize)]
+pub struct Percentiles {
+    pub server: String,
+    pub latency: Latency,
+    pub count: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub p999_us: u64,
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |us: u64| us as f64 / 1000.0;
+        write!(
+            f,
+            "{} {}: p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, p99.9 {:.1} ms over {}",
+            self.server,
+            self.latency.as_str(),
+            ms(self.p50_us),
+            ms(self.p90_us),
+            ms(self.p99_us),
+            ms(self.p999_us),
+            self.count
+        )
+    }
+}
+
+/// A histogram of each latency of each server's devices, from which the
+/// latency_seconds gauges are set as they are scraped and the end of run
+/// report is drawn
+pub struct Latencies {
+    histograms: Mutex<BTreeMap<(String, Latency), Histogram<u64>>>,
+    gauge: GaugeVec,
+}
+
+impl Latencies {
+    pub(crate) fn new() -> Latencies {
+        Latencies {
+            histograms: Mutex::default(),
+            gauge: register_gauge_vec!(
+                "latency_seconds",
+                "latency percentiles",
+                &["server", "latency", "quantile"]
+            )
+            .unwrap(),
+        }
+    }
+
+    pub(crate) fn record(&self, server: &str, latency: Latency, elapsed: Duration) {
+        let mut histograms = self.histograms.lock().expect("latencies lock");
+        histograms
+            .entry((server.to_string(), latency))
+            // 3 significant figures, growing to fit whatever is recorded
+            .or_insert_with(|| Histogram::new(3).expect("histogram"))
+            .saturating_record(elapsed.as_micros().min(u64::MAX as u128) as u64);
+    }
+
+    /// Set the latency_seconds gauges to the percentiles so far
+    pub fn refresh(&self) {
+        let histograms = self.histograms.lock().expect("latencies lock");
+        for ((server, latency), histogram) in histograms.iter() {
+            for (quantile, label) in QUANTILES {
+                self.gauge
+                    .with_label_values(&[server, latency.as_str(), label])
+                    .set(histogram.value_at_quantile(quantile) as f64 / 1e6);
+            }
+        }
+    }
+
+    /// The percentiles of every latency recorded so far
+    pub fn percentiles(&self) -> Vec<Percentiles> {
+        let histograms = self.histograms.lock().expect("latencies lock");
+        histograms
+            .iter()
+            .map(|((server, latency), histogram)| Percentiles {
+                server: server.clone(),
+                latency: *latency,
+                count: histogram.len(),
+                p50_us: histogram.value_at_quantile(0.5),
+                p90_us: histogram.value_at_quantile(0.9),
+                p99_us: histogram.value_at_quantile(0.99),
+                p999_us: histogram.value_at_quantile(0.999),
+            })
+            .collect()
+    }
+}
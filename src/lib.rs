@@ -0,0 +1,58 @@
+//! Virtual LoRaWAN devices which talk to a network server through simulated
+//! Semtech UDP packet forwarders.
+//!
+//! The `virtual-lorawan-device` binary is a thin wrapper around this library.
+//! Integration tests for a network server can embed devices directly: load
+//! [`settings::Settings`], create a [`Simulation`], subscribe to its events
+//! and poke at devices through their [`DeviceHandle`].
+//!
+//! Everything is timed by tokio's clock, so tests can run a simulation in
+//! virtual time with `tokio::time::pause` rather than sleeping through RX
+//! windows and uplink intervals. With a seed in the [`Options`], every
+//! device's randomness is drawn from its own seeded rng too.
+
+use log::{debug, error, info, warn};
+use metrics::Metrics;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+use tokio::{
+    sync::watch,
+    time::{timeout_at, Duration, Instant},
+};
+
+pub mod at_modem;
+pub mod certify;
+mod chaos;
+pub mod checkpoint;
+pub mod coordinator;
+pub mod error;
+pub mod event_log;
+pub mod expectations;
+pub mod frame_log;
+pub mod fuzz;
+pub mod gateway_clock;
+pub mod geolocation;
+pub mod health;
+mod join_server;
+pub mod key_log;
+pub mod latency;
+pub mod logging;
+pub mod metrics;
+pub mod mock_server;
+pub mod payload;
+pub mod rate_limit;
+mod relay;
+pub mod replay;
+pub mod rng;
+pub mod settings;
+pub mod simulation;
+pub mod stress;
+pub mod udp_runtime;
+pub mod virtual_device;
+
+pub use error::{Error, Result};
+pub use settings::{mac_string_into_buf, Credentials};
+pub use simulation::{DeviceHandle, Options, Simulation};
@@ -0,0 +1,95 @@
+use env_logger::fmt::{Color, Formatter};
+use log::{Log, Metadata, Record};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::Write,
+};
+
+/// Device labels are padded to this width so that messages line up
+const LABEL_WIDTH: usize = 8;
+const LABEL_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Red,
+];
+const DEVICE_TARGET: &str = "device::";
+const STEADY_STATE_SUFFIX: &str = "::steady";
+
+/// Set up logging from the environment:
+/// * RUST_LOG sets the level, defaulting to INFO
+/// * RUST_LOG_STYLE=always|never overrides whether colors are used
+/// * VDEVICE_LOG_TIMESTAMP=false omits timestamps
+/// * VDEVICE_LOG_STEADY_STATE=false hides routine per-frame device messages
+pub fn init() {
+    let mut builder = env_logger::Builder::from_env(
+        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "INFO"),
+    );
+    let timestamps = env_flag("VDEVICE_LOG_TIMESTAMP");
+    builder.format(move |buf, record| format(buf, record, timestamps));
+
+    let logger = Logger {
+        inner: builder.build(),
+        steady_state: env_flag("VDEVICE_LOG_STEADY_STATE"),
+    };
+    log::set_max_level(logger.inner.filter());
+    log::set_boxed_logger(Box::new(logger)).expect("logger already set");
+}
+
+/// Flags are on unless set to anything other than "true"
+fn env_flag(name: &str) -> bool {
+    std::env::var(name).map(|v| v == "true").unwrap_or(true)
+}
+
+struct Logger {
+    inner: env_logger::Logger,
+    steady_state: bool,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+            && (self.steady_state || !metadata.target().ends_with(STEADY_STATE_SUFFIX))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+fn format(buf: &mut Formatter, record: &Record, timestamps: bool) -> std::io::Result<()> {
+    if timestamps {
+        write!(buf, "{} ", buf.timestamp())?;
+    }
+    write!(buf, "{:<5} ", buf.default_styled_level(record.level()))?;
+    match device_label(record.target()) {
+        Some(label) => {
+            let mut style = buf.style();
+            style.set_color(label_color(label));
+            let label = format!("{:width$}", label, width = LABEL_WIDTH);
+            writeln!(buf, "{} {}", style.value(label), record.args())
+        }
+        None => writeln!(buf, "{:width$} {}", "", record.args(), width = LABEL_WIDTH),
+    }
+}
+
+fn device_label(target: &str) -> Option<&str> {
+    let label = target.strip_prefix(DEVICE_TARGET)?;
+    Some(label.strip_suffix(STEADY_STATE_SUFFIX).unwrap_or(label))
+}
+
+/// A device keeps its color from run to run
+fn label_color(label: &str) -> Color {
+    let mut hasher = DefaultHasher::new();
+    label.hash(&mut hasher);
+    LABEL_COLORS[hasher.finish() as usize % LABEL_COLORS.len()].clone()
+}
@@ -1,21 +1,18 @@
-use log::{debug, error, info, warn};
-use metrics::Metrics;
-use semtech_udp::client_runtime::UdpRuntime;
+use log::{error, info, warn};
 use std::{
-    collections::HashMap,
-    net::{IpAddr, SocketAddr},
-    path::PathBuf,
-    time::Instant,
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
 };
 use structopt::StructOpt;
+use tokio::time::Duration;
+use virtual_lorawan_device::{
+    checkpoint, coordinator, expectations, logging, metrics::Metrics, mock_server, rng, settings,
+    simulation, virtual_device, Error, Result,
+};
 
-mod error;
-mod metrics;
-mod settings;
-mod virtual_device;
-
-pub use error::{Error, Result};
-pub use settings::{mac_string_into_buf, Credentials};
+mod cmd;
+mod console;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "virtual-lorawan-device", about = "LoRaWAN test device utility")]
@@ -23,114 +20,32 @@ pub struct Opt {
     /// Path to settings subdirectory
     #[structopt(short, long, default_value = "./settings")]
     pub settings: PathBuf,
-    /// Limit number of devices to spawn
-    #[structopt(short, long)]
-    pub limit: Option<usize>,
+    /// Merge this scenario from the settings scenarios folder over the settings
+    #[structopt(long)]
+    pub scenario: Option<String>,
+    #[structopt(subcommand)]
+    pub cmd: Option<cmd::Cmd>,
 }
 
-const DEFAULT_PF: &str = "default";
-
 #[tokio::main]
-async fn main() -> Result<()> {
-    // Default log level to INFO unless environment override
-    let mut log_builder = env_logger::Builder::from_env(
-        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "INFO"),
-    );
-
-    // Allow timestamps to be disabled
-    let timestamps = std::env::var("VDEVICE_LOG_TIMESTAMP").unwrap_or_else(|_| "true".to_string());
-    if timestamps != "true" {
-        log_builder.format_timestamp(None).init();
-    } else {
-        log_builder.init();
-    }
+async fn main() {
+    logging::init();
 
     let cli = Opt::from_args();
-    let instant = Instant::now();
-    let settings = settings::Settings::new(&cli.settings)?;
-    let metrics_server: IpAddr = settings.metrics_server.parse()?;
-    let metrics = Metrics::run(
-        (metrics_server, settings.metrics_port).into(),
-        settings.get_servers(),
-    );
-    let device_limit = if let Some(limit) = cli.limit {
-        limit
-    } else {
-        usize::MAX
-    };
-
-    let pf_map = setup_packet_forwarders(settings.packet_forwarder).await?;
-
-    for (label, device) in settings.device.into_iter().take(device_limit) {
-        let packet_forwarder = if let Some(pf) = &device.packet_forwarder {
-            pf
-        } else {
-            DEFAULT_PF
-        };
-
-        let metrics_sender = metrics.get_server_sender(if let Some(server) = &device.server {
-            server
-        } else {
-            &settings.default_server
-        });
-
-        let lorawan_app = virtual_device::VirtualDevice::new(
-            label.clone(),
-            instant,
-            if let Some(pf) = pf_map.get(packet_forwarder) {
-                pf
-            } else {
-                panic!("{} is invalid packet forwarder", packet_forwarder)
-            },
-            device.credentials,
-            metrics_sender,
-            device.rejoin_frames,
-            device.secs_between_transmits,
-            device.region,
-        )
-        .await?;
-
-        tokio::spawn(async move {
-            if let Err(e) = lorawan_app.run().await {
-                error!("{} device threw error: {:?}", label, e)
-            }
-        });
+    let result = cli
+        .cmd
+        .unwrap_or_else(|| cmd::Cmd::Run(Box::default()))
+        .run(&cli.settings, cli.scenario.as_deref())
+        .await;
+    if let Err(e) = result {
+        // each cause in turn, eg: "io error: No such file or directory"
+        let mut message = e.to_string();
+        let mut source = std::error::Error::source(&e);
+        while let Some(cause) = source {
+            message.push_str(&format!(": {}", cause));
+            source = cause.source();
+        }
+        error!("{}", message);
+        std::process::exit(1);
     }
-
-    for (_, runtime) in pf_map {
-        tokio::spawn(runtime.run());
-    }
-
-    tokio::signal::ctrl_c().await?;
-    info!("User exit via ctrl C");
-    Ok(())
-}
-
-async fn setup_packet_forwarders(
-    mut packet_forwarder: HashMap<String, settings::PacketForwarder>,
-) -> Result<HashMap<String, UdpRuntime>> {
-    // prune the deafult packet forwarder if we have more than one
-    if packet_forwarder.len() != 1 && packet_forwarder.contains_key("default") {
-        packet_forwarder.remove("default");
-    }
-
-    let mut pf_map = HashMap::new();
-    for (label, packet_forwarder) in packet_forwarder {
-        let outbound = SocketAddr::from(([0, 0, 0, 0], 0));
-        info!(
-            "Creating packet forwarder {} connecting to {} from {}",
-            label,
-            packet_forwarder.host,
-            outbound.to_string()
-        );
-        let udp_runtime = UdpRuntime::new(
-            packet_forwarder.mac_cloned_into_buf().unwrap(),
-            outbound,
-            packet_forwarder.host,
-        )
-        .await?;
-        pf_map.insert(label, udp_runtime);
-    }
-
-    Ok(pf_map)
 }
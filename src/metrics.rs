@@ -1,37 +1,120 @@
 use super::*;
 use error::{Error, Result};
+use health::Health;
 use hyper::{
     header::CONTENT_TYPE,
     service::{make_service_fn, service_fn},
     Body, Request, Response, Server,
 };
+use latency::{Latencies, Latency};
 use log::{debug, warn};
-use prometheus::{register_counter_vec, register_histogram_vec};
-use prometheus::{CounterVec, HistogramVec};
+use prometheus::{register_counter_vec, register_gauge_vec, register_histogram_vec};
+use prometheus::{CounterVec, GaugeVec, HistogramVec};
 use prometheus::{Encoder, TextEncoder};
+use settings::MetricLabels;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 
 pub struct Sender {
-    server: String,
-    sender: mpsc::Sender<InternalMessage>,
+    // server label for device senders, packet forwarder label otherwise
+    label: String,
+    sender: Option<mpsc::Sender<InternalMessage>>,
 }
 
 impl Sender {
+    /// A sender which records nothing, for devices run without metrics
+    pub fn disabled() -> Sender {
+        Sender {
+            label: String::new(),
+            sender: None,
+        }
+    }
+
     pub async fn send(&mut self, message: Message) -> Result<()> {
-        let server = self.server.clone();
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return Ok(()),
+        };
+        let server = self.label.clone();
         match message {
-            Message::JoinSuccess(t) => {
-                self.sender
-                    .send(InternalMessage::JoinSuccess(server, t))
+            Message::JoinSuccess(t) => sender.send(InternalMessage::JoinSuccess(server, t)).await,
+            Message::JoinFail => sender.send(InternalMessage::JoinFail(server)).await,
+            Message::DataSuccess(t) => sender.send(InternalMessage::DataSuccess(server, t)).await,
+            Message::DataFail => sender.send(InternalMessage::DataFail(server)).await,
+            Message::FCntDownGap(skipped) => {
+                sender
+                    .send(InternalMessage::FCntDownGap(server, skipped))
+                    .await
+            }
+            Message::FCntDownRepeat => sender.send(InternalMessage::FCntDownRepeat(server)).await,
+            Message::DuplicateDownlink => {
+                sender
+                    .send(InternalMessage::DuplicateDownlink(server))
+                    .await
+            }
+            Message::RfMismatch => sender.send(InternalMessage::RfMismatch(server)).await,
+            Message::OversizedDownlink => {
+                sender
+                    .send(InternalMessage::OversizedDownlink(server))
+                    .await
+            }
+            Message::LateTimer => sender.send(InternalMessage::LateTimer(server)).await,
+            Message::MissedRxWindow => sender.send(InternalMessage::MissedRxWindow(server)).await,
+            Message::RfChainRejected => sender.send(InternalMessage::RfChainRejected(server)).await,
+            Message::TxParamViolation => {
+                sender.send(InternalMessage::TxParamViolation(server)).await
+            }
+            Message::FCntDownRejected => {
+                sender.send(InternalMessage::FCntDownRejected(server)).await
+            }
+            Message::DwellTimeViolation => {
+                sender
+                    .send(InternalMessage::DwellTimeViolation(server))
+                    .await
+            }
+            Message::UplinkUnheard => sender.send(InternalMessage::UplinkUnheard(server)).await,
+            Message::RxTimingViolation => {
+                sender
+                    .send(InternalMessage::RxTimingViolation(server))
+                    .await
+            }
+            Message::NegativeJoinAccepted => {
+                sender
+                    .send(InternalMessage::NegativeJoinAccepted(server))
+                    .await
+            }
+            Message::DownlinkRuleViolation => {
+                sender
+                    .send(InternalMessage::DownlinkRuleViolation(server))
+                    .await
+            }
+            Message::MalformedDownlink => {
+                sender
+                    .send(InternalMessage::MalformedDownlink(server))
+                    .await
+            }
+            Message::Latency(latency, elapsed) => {
+                sender
+                    .send(InternalMessage::Latency(server, latency, elapsed))
+                    .await
+            }
+            Message::UdpReconnect => sender.send(InternalMessage::UdpReconnect(server)).await,
+            Message::Failover => sender.send(InternalMessage::Failover(server)).await,
+            Message::Connected(connected) => {
+                sender
+                    .send(InternalMessage::Connected(server, connected))
                     .await
             }
-            Message::JoinFail => self.sender.send(InternalMessage::JoinFail(server)).await,
-            Message::DataSuccess(t) => {
-                self.sender
-                    .send(InternalMessage::DataSuccess(server, t))
+            Message::DownlinkQueueDepth(device, depth) => {
+                sender
+                    .send(InternalMessage::DownlinkQueueDepth(server, device, depth))
+                    .await
+            }
+            Message::BatteryLevel(device, level) => {
+                sender
+                    .send(InternalMessage::BatteryLevel(server, device, level))
                     .await
             }
-            Message::DataFail => self.sender.send(InternalMessage::DataFail(server)).await,
         }
         .map_err(|_| Error::MetricsChannel)
     }
@@ -43,10 +126,57 @@ pub enum Message {
     JoinFail,
     DataSuccess(i64),
     DataFail,
+    /// FCntDown skipped ahead by the given number of frames
+    FCntDownGap(u32),
+    /// FCntDown repeated or went backwards
+    FCntDownRepeat,
+    DuplicateDownlink,
+    /// Downlink frequency or datarate didn't match the RX window
+    RfMismatch,
+    OversizedDownlink,
+    LateTimer,
+    MissedRxWindow,
+    /// A downlink named an RF chain which can't transmit
+    RfChainRejected,
+    TxParamViolation,
+    /// A downlink was dropped, its FCntDown too far past the one expected
+    FCntDownRejected,
+    /// Uplink dropped as it would be on air for longer than the region allows
+    DwellTimeViolation,
+    /// Uplink too weak for any gateway to demodulate at its spreading factor
+    UplinkUnheard,
+    /// Downlink scheduled outside the spec's RX1 and RX2 delays
+    RxTimingViolation,
+    /// Join accepted which the network server should have rejected
+    NegativeJoinAccepted,
+    DownlinkRuleViolation,
+    /// Downlinks in a row the given device received with FPending set, 0
+    /// once one arrives without it
+    DownlinkQueueDepth(String, u32),
+    /// Battery level of the given device, in percent
+    BatteryLevel(String, f64),
+    /// Sent by packet forwarders rather than devices
+    UdpReconnect,
+    /// Sent by packet forwarders as they switch to their backup server, or
+    /// back
+    Failover,
+    /// Sent by packet forwarders as they connect to and lose the network
+    /// server
+    Connected(bool),
+    /// Sent by packet forwarders rather than devices
+    MalformedDownlink,
+    /// A join or confirmed uplink took the given time to be answered
+    Latency(Latency, Duration),
 }
 
+/// How often the fleet gauges are worked out from the last value of each
+/// device
+const FLEET_INTERVAL: Duration = Duration::from_secs(5);
+
 pub struct Metrics {
     sender: mpsc::Sender<InternalMessage>,
+    health: Arc<Health>,
+    latencies: Arc<Latencies>,
 }
 
 #[derive(Debug)]
@@ -55,6 +185,28 @@ enum InternalMessage {
     JoinFail(String),
     DataSuccess(String, i64),
     DataFail(String),
+    FCntDownGap(String, u32),
+    FCntDownRepeat(String),
+    DuplicateDownlink(String),
+    RfMismatch(String),
+    OversizedDownlink(String),
+    LateTimer(String),
+    MissedRxWindow(String),
+    RfChainRejected(String),
+    TxParamViolation(String),
+    FCntDownRejected(String),
+    DwellTimeViolation(String),
+    UplinkUnheard(String),
+    RxTimingViolation(String),
+    NegativeJoinAccepted(String),
+    DownlinkRuleViolation(String),
+    DownlinkQueueDepth(String, String, u32),
+    BatteryLevel(String, String, f64),
+    UdpReconnect(String),
+    Failover(String),
+    Connected(String, bool),
+    MalformedDownlink(String),
+    Latency(String, Latency, Duration),
 }
 
 struct InternalMetrics {
@@ -62,16 +214,52 @@ struct InternalMetrics {
     join_fail_counter: CounterVec,
     data_success_counter: CounterVec,
     data_fail_counter: CounterVec,
+    fcnt_down_gap_counter: CounterVec,
+    fcnt_down_repeat_counter: CounterVec,
+    duplicate_downlink_counter: CounterVec,
+    rf_mismatch_counter: CounterVec,
+    oversized_downlink_counter: CounterVec,
+    late_timer_counter: CounterVec,
+    missed_rx_window_counter: CounterVec,
+    rf_chain_rejected_counter: CounterVec,
+    tx_param_violation_counter: CounterVec,
+    fcnt_down_rejected_counter: CounterVec,
+    dwell_time_violation_counter: CounterVec,
+    uplink_unheard_counter: CounterVec,
+    rx_timing_violation_counter: CounterVec,
+    negative_join_accepted_counter: CounterVec,
+    downlink_rule_violation_counter: CounterVec,
+    udp_reconnect_counter: CounterVec,
+    failover_counter: CounterVec,
+    malformed_downlink_counter: CounterVec,
+    downlink_queue_depth: GaugeVec,
+    battery_level: GaugeVec,
+    packet_forwarder_connected: GaugeVec,
     join_latency: HistogramVec,
     data_latency: HistogramVec,
 }
 
 impl Metrics {
-    pub fn run(addr: std::net::SocketAddr, servers: Vec<&String>) -> Metrics {
-        // Start Prom Metrics Endpoint
+    pub fn run(
+        addr: std::net::SocketAddr,
+        servers: Vec<&String>,
+        labels: MetricLabels,
+        top_k: usize,
+    ) -> Metrics {
+        // Start Prom Metrics Endpoint, which answers health checks too
         info!("Prometheus Server listening on http://{}", addr);
-        let serve_future = Server::bind(&addr).serve(make_service_fn(|_| async {
-            Ok::<_, hyper::Error>(service_fn(Metrics::serve_req))
+        let health = Arc::new(Health::default());
+        let latencies = Arc::new(Latencies::new());
+        let serve_health = health.clone();
+        let serve_latencies = latencies.clone();
+        let serve_future = Server::bind(&addr).serve(make_service_fn(move |_| {
+            let health = serve_health.clone();
+            let latencies = serve_latencies.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    Metrics::route(health.clone(), latencies.clone(), req)
+                }))
+            }
         }));
 
         tokio::spawn(async move {
@@ -99,6 +287,132 @@ impl Metrics {
             .unwrap(),
             data_fail_counter: register_counter_vec!("data_fail", "data fail counter", &["server"])
                 .unwrap(),
+            fcnt_down_gap_counter: register_counter_vec!(
+                "fcnt_down_gap",
+                "downlink frames skipped by the network server",
+                &["server"]
+            )
+            .unwrap(),
+            fcnt_down_repeat_counter: register_counter_vec!(
+                "fcnt_down_repeat",
+                "downlinks with a repeated or backwards FCntDown",
+                &["server"]
+            )
+            .unwrap(),
+            duplicate_downlink_counter: register_counter_vec!(
+                "duplicate_downlink",
+                "downlinks received more than once",
+                &["server"]
+            )
+            .unwrap(),
+            rf_mismatch_counter: register_counter_vec!(
+                "rf_mismatch",
+                "downlinks sent outside the RX window frequency or datarate",
+                &["server"]
+            )
+            .unwrap(),
+            oversized_downlink_counter: register_counter_vec!(
+                "oversized_downlink",
+                "downlinks dropped for exceeding the rx buffer",
+                &["server"]
+            )
+            .unwrap(),
+            late_timer_counter: register_counter_vec!(
+                "late_timer",
+                "timers requested after their deadline had passed",
+                &["server"]
+            )
+            .unwrap(),
+            missed_rx_window_counter: register_counter_vec!(
+                "missed_rx_window",
+                "downlinks received after their scheduled tmst",
+                &["server"]
+            )
+            .unwrap(),
+            rf_chain_rejected_counter: register_counter_vec!(
+                "rf_chain_rejected",
+                "downlinks dropped as their rfch can't transmit",
+                &["server"]
+            )
+            .unwrap(),
+            tx_param_violation_counter: register_counter_vec!(
+                "tx_param_violation",
+                "downlinks whose powe, ipol, codr or rfch break the region's limits or LoRaWAN conventions",
+                &["server"]
+            )
+            .unwrap(),
+            fcnt_down_rejected_counter: register_counter_vec!(
+                "fcnt_down_rejected",
+                "downlinks dropped for an FCntDown past MAX_FCNT_GAP",
+                &["server"]
+            )
+            .unwrap(),
+            dwell_time_violation_counter: register_counter_vec!(
+                "dwell_time_violation",
+                "uplinks dropped for exceeding the dwell time limit",
+                &["server"]
+            )
+            .unwrap(),
+            uplink_unheard_counter: register_counter_vec!(
+                "uplink_unheard",
+                "uplinks too weak for any gateway to demodulate",
+                &["server"]
+            )
+            .unwrap(),
+            rx_timing_violation_counter: register_counter_vec!(
+                "rx_timing_violation",
+                "downlinks scheduled outside the spec's RX1 and RX2 delays",
+                &["server"]
+            )
+            .unwrap(),
+            negative_join_accepted_counter: register_counter_vec!(
+                "negative_join_accepted",
+                "joins accepted which the network server should have rejected",
+                &["server"]
+            )
+            .unwrap(),
+            downlink_rule_violation_counter: register_counter_vec!(
+                "downlink_rule_violation",
+                "downlinks which broke a downlink rule",
+                &["server"]
+            )
+            .unwrap(),
+            udp_reconnect_counter: register_counter_vec!(
+                "udp_reconnect",
+                "packet forwarder reconnections",
+                &["packet_forwarder"]
+            )
+            .unwrap(),
+            failover_counter: register_counter_vec!(
+                "packet_forwarder_failover",
+                "packet forwarder switches between primary and backup server",
+                &["packet_forwarder"]
+            )
+            .unwrap(),
+            malformed_downlink_counter: register_counter_vec!(
+                "malformed_downlinks_total",
+                "downlinks dropped for being malformed",
+                &["packet_forwarder"]
+            )
+            .unwrap(),
+            downlink_queue_depth: register_gauge_vec!(
+                "downlink_queue_depth",
+                "downlinks in a row received with FPending set",
+                &["server", "device"]
+            )
+            .unwrap(),
+            battery_level: register_gauge_vec!(
+                "battery_level",
+                "battery level in percent",
+                &["server", "device"]
+            )
+            .unwrap(),
+            packet_forwarder_connected: register_gauge_vec!(
+                "packet_forwarder_connected",
+                "1 while the packet forwarder is connected",
+                &["packet_forwarder"]
+            )
+            .unwrap(),
             join_latency: register_histogram_vec!(
                 "join_latency",
                 "join latency histogram",
@@ -133,17 +447,94 @@ impl Metrics {
                 .data_fail_counter
                 .with_label_values(&[server])
                 .reset();
+            metrics
+                .fcnt_down_gap_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .fcnt_down_repeat_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .duplicate_downlink_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .rf_mismatch_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .oversized_downlink_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .late_timer_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .missed_rx_window_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .rf_chain_rejected_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .tx_param_violation_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .fcnt_down_rejected_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .dwell_time_violation_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .uplink_unheard_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .rx_timing_violation_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .negative_join_accepted_counter
+                .with_label_values(&[server])
+                .reset();
+            metrics
+                .downlink_rule_violation_counter
+                .with_label_values(&[server])
+                .reset();
         }
 
+        let mut fleet = match labels {
+            MetricLabels::PerDevice => None,
+            MetricLabels::Fleet => Some(Fleet::new(top_k)),
+        };
+        let task_health = health.clone();
+        let task_latencies = latencies.clone();
         tokio::spawn(async move {
+            let mut fleet_timer = tokio::time::interval(FLEET_INTERVAL);
             loop {
-                match rx.recv().await {
+                let message = tokio::select! {
+                    message = rx.recv() => message,
+                    _ = fleet_timer.tick() => {
+                        if let Some(fleet) = &fleet {
+                            fleet.refresh(&metrics.downlink_queue_depth, &metrics.battery_level);
+                        }
+                        continue;
+                    }
+                };
+                match message {
                     Some(InternalMessage::JoinSuccess(label, t)) => {
                         let in_secs = (t as f64) / 1000000.0;
                         metrics
                             .join_latency
                             .with_label_values(&[&label])
                             .observe(in_secs);
+                        task_latencies.record(&label, Latency::DownlinkMargin, margin(t));
                         metrics
                             .join_success_counter
                             .with_label_values(&[&label])
@@ -159,6 +550,7 @@ impl Metrics {
                             .data_latency
                             .with_label_values(&[&label])
                             .observe(in_secs);
+                        task_latencies.record(&label, Latency::DownlinkMargin, margin(t));
                         metrics
                             .data_success_counter
                             .with_label_values(&[&label])
@@ -167,20 +559,174 @@ impl Metrics {
                     Some(InternalMessage::DataFail(label)) => {
                         metrics.data_fail_counter.with_label_values(&[&label]).inc()
                     }
+                    Some(InternalMessage::FCntDownGap(label, skipped)) => metrics
+                        .fcnt_down_gap_counter
+                        .with_label_values(&[&label])
+                        .inc_by(skipped as f64),
+                    Some(InternalMessage::FCntDownRepeat(label)) => metrics
+                        .fcnt_down_repeat_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::DuplicateDownlink(label)) => metrics
+                        .duplicate_downlink_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::RfMismatch(label)) => metrics
+                        .rf_mismatch_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::OversizedDownlink(label)) => metrics
+                        .oversized_downlink_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::LateTimer(label)) => metrics
+                        .late_timer_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::MissedRxWindow(label)) => metrics
+                        .missed_rx_window_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::RfChainRejected(label)) => metrics
+                        .rf_chain_rejected_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::TxParamViolation(label)) => metrics
+                        .tx_param_violation_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::FCntDownRejected(label)) => metrics
+                        .fcnt_down_rejected_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::DwellTimeViolation(label)) => metrics
+                        .dwell_time_violation_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::UplinkUnheard(label)) => metrics
+                        .uplink_unheard_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::RxTimingViolation(label)) => metrics
+                        .rx_timing_violation_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::NegativeJoinAccepted(label)) => metrics
+                        .negative_join_accepted_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::DownlinkRuleViolation(label)) => metrics
+                        .downlink_rule_violation_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::DownlinkQueueDepth(label, device, depth)) => {
+                        match &mut fleet {
+                            Some(fleet) => {
+                                fleet
+                                    .queue_depths
+                                    .entry(label)
+                                    .or_default()
+                                    .insert(device, depth);
+                            }
+                            None => metrics
+                                .downlink_queue_depth
+                                .with_label_values(&[&label, &device])
+                                .set(depth as f64),
+                        }
+                    }
+                    Some(InternalMessage::BatteryLevel(label, device, level)) => match &mut fleet {
+                        Some(fleet) => {
+                            fleet
+                                .battery_levels
+                                .entry(label)
+                                .or_default()
+                                .insert(device, level);
+                        }
+                        None => metrics
+                            .battery_level
+                            .with_label_values(&[&label, &device])
+                            .set(level),
+                    },
+                    Some(InternalMessage::UdpReconnect(label)) => metrics
+                        .udp_reconnect_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::Failover(label)) => {
+                        metrics.failover_counter.with_label_values(&[&label]).inc()
+                    }
+                    Some(InternalMessage::MalformedDownlink(label)) => metrics
+                        .malformed_downlink_counter
+                        .with_label_values(&[&label])
+                        .inc(),
+                    Some(InternalMessage::Connected(label, connected)) => {
+                        metrics
+                            .packet_forwarder_connected
+                            .with_label_values(&[&label])
+                            .set(if connected { 1.0 } else { 0.0 });
+                        task_health.packet_forwarder_connected(&label, connected);
+                    }
+                    Some(InternalMessage::Latency(label, latency, elapsed)) => {
+                        task_latencies.record(&label, latency, elapsed)
+                    }
                     None => warn!("Metrics receive channel returned None. Is closed?"),
                 }
             }
         });
-        Metrics { sender }
+        Metrics {
+            sender,
+            health,
+            latencies,
+        }
+    }
+
+    /// What is reported by /healthz and /readyz, for devices to be counted in
+    pub fn health(&self) -> Arc<Health> {
+        self.health.clone()
+    }
+
+    /// Percentiles of the latencies the devices have reported
+    pub fn latencies(&self) -> Arc<Latencies> {
+        self.latencies.clone()
+    }
+
+    async fn route(
+        health: Arc<Health>,
+        latencies: Arc<Latencies>,
+        req: Request<Body>,
+    ) -> Result<Response<Body>> {
+        match req.uri().path() {
+            "/healthz" => Ok(health.response(false)),
+            "/readyz" => Ok(health.response(true)),
+            _ => {
+                latencies.refresh();
+                Metrics::serve_req(req).await
+            }
+        }
     }
 
     pub fn get_server_sender(&self, server: &str) -> Sender {
         Sender {
-            server: server.to_string(),
-            sender: self.sender.clone(),
+            label: server.to_string(),
+            sender: Some(self.sender.clone()),
         }
     }
 
+    pub fn get_packet_forwarder_sender(&self, packet_forwarder: &str) -> Sender {
+        Sender {
+            label: packet_forwarder.to_string(),
+            sender: Some(self.sender.clone()),
+        }
+    }
+
+    /// Write the current value of every metric to a file
+    pub fn write_report(path: &std::path::Path) -> Result<()> {
+        let encoder = TextEncoder::new();
+        let mut buffer = vec![];
+        encoder.encode(&prometheus::gather(), &mut buffer)?;
+        std::fs::write(path, buffer)?;
+        Ok(())
+    }
+
     pub async fn serve_req(_req: Request<Body>) -> Result<Response<Body>> {
         let encoder = TextEncoder::new();
 
@@ -202,3 +748,85 @@ impl Metrics {
         Ok(response)
     }
 }
+
+/// The time left in the RX window, from the microseconds reported, as none
+/// once the window has passed
+fn margin(time_remaining: i64) -> Duration {
+    Duration::from_micros(time_remaining.max(0) as u64)
+}
+
+/// The gauges of each device for a fleet too big to label every device. The
+/// last value of each device is kept here and summed up per server, with
+/// only the top_k worst devices exported under their own labels.
+struct Fleet {
+    top_k: usize,
+    queue_depths: BTreeMap<String, BTreeMap<String, u32>>,
+    battery_levels: BTreeMap<String, BTreeMap<String, f64>>,
+    queue_depth_max: GaugeVec,
+    battery_level_min: GaugeVec,
+    battery_level_mean: GaugeVec,
+}
+
+impl Fleet {
+    fn new(top_k: usize) -> Fleet {
+        Fleet {
+            top_k,
+            queue_depths: BTreeMap::new(),
+            battery_levels: BTreeMap::new(),
+            queue_depth_max: register_gauge_vec!(
+                "fleet_downlink_queue_depth_max",
+                "deepest downlink queue of any device",
+                &["server"]
+            )
+            .unwrap(),
+            battery_level_min: register_gauge_vec!(
+                "fleet_battery_level_min",
+                "lowest battery level of any device in percent",
+                &["server"]
+            )
+            .unwrap(),
+            battery_level_mean: register_gauge_vec!(
+                "fleet_battery_level_mean",
+                "mean battery level of the devices in percent",
+                &["server"]
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Set the fleet gauges, and the device gauges to those of the worst
+    /// devices alone
+    fn refresh(&self, queue_depth: &GaugeVec, battery_level: &GaugeVec) {
+        queue_depth.reset();
+        battery_level.reset();
+        for (server, depths) in &self.queue_depths {
+            let max = depths.values().copied().max().unwrap_or_default();
+            self.queue_depth_max
+                .with_label_values(&[server])
+                .set(f64::from(max));
+            let mut worst: Vec<(&String, &u32)> =
+                depths.iter().filter(|(_, depth)| **depth > 0).collect();
+            worst.sort_by(|a, b| b.1.cmp(a.1));
+            for (device, depth) in worst.into_iter().take(self.top_k) {
+                queue_depth
+                    .with_label_values(&[server, device])
+                    .set(f64::from(*depth));
+            }
+        }
+        for (server, levels) in &self.battery_levels {
+            let min = levels.values().copied().fold(f64::INFINITY, f64::min);
+            let mean = levels.values().sum::<f64>() / levels.len() as f64;
+            self.battery_level_min.with_label_values(&[server]).set(min);
+            self.battery_level_mean
+                .with_label_values(&[server])
+                .set(mean);
+            let mut worst: Vec<(&String, &f64)> = levels.iter().collect();
+            worst.sort_by(|a, b| a.1.total_cmp(b.1));
+            for (device, level) in worst.into_iter().take(self.top_k) {
+                battery_level
+                    .with_label_values(&[server, device])
+                    .set(*level);
+            }
+        }
+    }
+}
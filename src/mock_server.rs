@@ -0,0 +1,510 @@
+use crate::*;
+use join_server::JoinServer;
+use lorawan::{
+    creator::{DataPayloadCreator, JoinAcceptCreator},
+    default_crypto::DefaultFactory,
+    keys::AES128,
+    parser::{
+        parse, DataHeader, DataPayload, FCtrl, FRMPayload, JoinAcceptPayload, MHDRAble, MType,
+        PhyPayload,
+    },
+};
+use semtech_udp::{pull_resp, push_data, Modulation, StringOrNum};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tokio::{net::UdpSocket, sync::mpsc};
+
+const PROTOCOL_VERSION: u8 = 2;
+const PUSH_DATA: u8 = 0;
+const PUSH_ACK: u8 = 1;
+const PULL_DATA: u8 = 2;
+const PULL_RESP: u8 = 3;
+const PULL_ACK: u8 = 4;
+const TX_ACK: u8 = 5;
+/// JOIN_ACCEPT_DELAY1 and the RX1 delay handed out in join accepts
+const JOIN_ACCEPT_DELAY_US: u32 = 5_000_000;
+const RX1_DELAY_US: u32 = 1_000_000;
+const RX1_DELAY_SECS: u8 = 1;
+/// FCtrl ACK bit of a downlink
+const FCTRL_ACK: u8 = 0x20;
+/// MHDR, AppNonce, NetID, DevAddr, DLSettings, RxDelay and MIC of a join
+/// accept without a CFList
+const JOIN_ACCEPT_LEN: usize = 17;
+/// Device addresses are handed out counting up from here
+const DEV_ADDR_BASE: u32 = 0x2600_0000;
+const NET_ID: [u8; 3] = [0, 0, 0];
+/// DLSettings handed out in join accepts: no RX1 offset, RX2 at the default
+const DL_SETTINGS: u8 = 0;
+
+/// A minimal LoRaWAN network server speaking the Semtech UDP protocol, so
+/// devices can be run without an external network server. It accepts joins
+/// from the configured devices, unless they reuse a DevNonce, ACKs confirmed uplinks and, with echo, sends
+/// every uplink's payload back on the same port. Downlinks go out in RX1 on
+/// the uplink's frequency and data rate, as in EU868.
+///
+/// With a join server, join requests are handed to it instead, so that an
+/// operator's join server can be load tested without their network server.
+pub struct MockServer {
+    socket: UdpSocket,
+    echo: bool,
+    /// JoinEUIs and app keys of the configured devices keyed by DevEUI, as
+    /// sent on air
+    devices: HashMap<[u8; 8], ([u8; 8], [u8; 16])>,
+    /// DevNonces each device has joined with, which may not be used again
+    dev_nonces: HashMap<[u8; 8], HashSet<[u8; 2]>>,
+    sessions: HashMap<[u8; 4], Session>,
+    next_dev_addr: u32,
+    /// where each gateway pulls downlinks from, keyed by gateway mac
+    gateways: HashMap<[u8; 8], SocketAddr>,
+    /// join servers for devices with a given JoinEUI, as sent on air, and
+    /// the one for the rest
+    join_servers: HashMap<[u8; 8], JoinServer>,
+    join_server: Option<JoinServer>,
+    transaction_id: u32,
+    /// joins accepted by the join server come back here
+    joined_sender: mpsc::Sender<Joined>,
+    joined: mpsc::Receiver<Joined>,
+}
+
+struct Session {
+    nwk_skey: AES128,
+    /// None when the join server keeps it from the network server
+    app_skey: Option<AES128>,
+    fcnt_up: Option<u32>,
+    fcnt_down: u32,
+}
+
+/// A join accepted by the join server, to be answered
+struct Joined {
+    mac: [u8; 8],
+    rxpk: push_data::RxPkV1,
+    dev_addr: [u8; 4],
+    accepted: join_server::Accepted,
+}
+
+#[derive(Deserialize)]
+struct PushData {
+    #[serde(default)]
+    rxpk: Vec<push_data::RxPk>,
+}
+
+#[derive(Serialize)]
+struct PullResp {
+    txpk: pull_resp::TxPk,
+}
+
+impl MockServer {
+    pub async fn bind(
+        addr: SocketAddr,
+        devices: &HashMap<String, settings::Device>,
+        echo: bool,
+    ) -> Result<MockServer> {
+        let mut keys = HashMap::new();
+        for (label, device) in devices {
+            match (
+                device.credentials.deveui_cloned_into_buf(),
+                device.credentials.appeui_cloned_into_buf(),
+                device.credentials.appkey_cloned_into_buf(),
+            ) {
+                (Ok(dev_eui), Ok(join_eui), Ok(app_key)) => {
+                    keys.insert(dev_eui, (join_eui, app_key));
+                }
+                _ => warn!("Mock server ignoring {} with invalid credentials", label),
+            }
+        }
+        let socket = UdpSocket::bind(addr).await?;
+        let (joined_sender, joined) = mpsc::channel(100);
+        info!(
+            "Mock server listening on {} for {} devices",
+            socket.local_addr()?,
+            keys.len()
+        );
+        Ok(MockServer {
+            socket,
+            echo,
+            devices: keys,
+            dev_nonces: HashMap::new(),
+            sessions: HashMap::new(),
+            next_dev_addr: DEV_ADDR_BASE,
+            gateways: HashMap::new(),
+            join_servers: HashMap::new(),
+            join_server: None,
+            transaction_id: 0,
+            joined_sender,
+            joined,
+        })
+    }
+
+    /// Hand join requests to the join server at this url, by LoRaWAN Backend
+    /// Interfaces JoinReq, rather than answering them here. Given as
+    /// `<JoinEUI>=<url>` it only takes the joins of devices with that
+    /// JoinEUI, leaving the rest to the join server given without one.
+    pub fn join_server(mut self, route: &str) -> Result<MockServer> {
+        let join_eui = route.split_once('=').filter(|(join_eui, _)| {
+            join_eui.len() == 16 && join_eui.bytes().all(|b| b.is_ascii_hexdigit())
+        });
+        match join_eui {
+            Some((join_eui, url)) => {
+                let mut eui = settings::mac_string_into_buf(join_eui)?;
+                eui.reverse();
+                self.join_servers.insert(eui, JoinServer::new(url, NET_ID)?);
+                info!(
+                    "Mock server delegating joins for JoinEUI {} to {}",
+                    join_eui, url
+                );
+            }
+            None => {
+                self.join_server = Some(JoinServer::new(route, NET_ID)?);
+                info!("Mock server delegating joins to {}", route);
+            }
+        }
+        Ok(self)
+    }
+
+    /// The join server joins with this JoinEUI, as sent on air, are handed to
+    fn join_server_for(&self, join_eui: &[u8]) -> Option<&JoinServer> {
+        self.join_servers
+            .get(join_eui)
+            .or(self.join_server.as_ref())
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let mut buf = [0; 65535];
+        loop {
+            let (n, from) = tokio::select! {
+                received = self.socket.recv_from(&mut buf) => received?,
+                // we hold a sender ourselves so this channel never closes
+                Some(joined) = self.joined.recv() => {
+                    let txpk = self.joined(joined.dev_addr, joined.accepted, joined.rxpk);
+                    self.downlink(joined.mac, txpk).await?;
+                    continue;
+                }
+            };
+            if n < 4 || buf[0] != PROTOCOL_VERSION {
+                debug!("Mock server ignoring {} bytes from {}", n, from);
+                continue;
+            }
+            let token = [buf[1], buf[2]];
+            match buf[3] {
+                PULL_DATA if n >= 12 => {
+                    let mut mac = [0; 8];
+                    mac.copy_from_slice(&buf[4..12]);
+                    self.gateways.insert(mac, from);
+                    self.reply(from, token, PULL_ACK).await?;
+                }
+                PUSH_DATA if n >= 12 => {
+                    self.reply(from, token, PUSH_ACK).await?;
+                    let mut mac = [0; 8];
+                    mac.copy_from_slice(&buf[4..12]);
+                    let push_data: PushData = match serde_json::from_slice(&buf[12..n]) {
+                        Ok(push_data) => push_data,
+                        Err(e) => {
+                            warn!("Mock server unable to parse PUSH_DATA: {:?}", e);
+                            continue;
+                        }
+                    };
+                    for rxpk in push_data.rxpk {
+                        if let Some(txpk) = self.uplink(mac, rxpk_v1(rxpk)) {
+                            self.downlink(mac, txpk).await?;
+                        }
+                    }
+                }
+                TX_ACK => (),
+                id => debug!("Mock server ignoring packet id {} from {}", id, from),
+            }
+        }
+    }
+
+    async fn reply(&self, to: SocketAddr, token: [u8; 2], id: u8) -> Result<()> {
+        self.socket
+            .send_to(&[PROTOCOL_VERSION, token[0], token[1], id], to)
+            .await?;
+        Ok(())
+    }
+
+    async fn downlink(&self, mac: [u8; 8], txpk: pull_resp::TxPk) -> Result<()> {
+        let to = match self.gateways.get(&mac) {
+            Some(to) => *to,
+            None => {
+                warn!("Mock server has no PULL_DATA from {}", hex::encode(mac));
+                return Ok(());
+            }
+        };
+        let token: [u8; 2] = rand::random();
+        let mut packet = vec![PROTOCOL_VERSION, token[0], token[1], PULL_RESP];
+        packet.extend(serde_json::to_vec(&PullResp { txpk })?);
+        self.socket.send_to(&packet, to).await?;
+        Ok(())
+    }
+
+    /// Handle one uplink, returning the downlink to answer it with, if any
+    fn uplink(&mut self, mac: [u8; 8], rxpk: push_data::RxPkV1) -> Option<pull_resp::TxPk> {
+        let (data, delay) = match parse(rxpk.data.clone()) {
+            Ok(PhyPayload::JoinRequest(join_request))
+                if self
+                    .join_server_for(join_request.app_eui().as_ref())
+                    .is_some() =>
+            {
+                let mut join_eui = [0; 8];
+                join_eui.copy_from_slice(join_request.app_eui().as_ref());
+                let mut dev_eui = [0; 8];
+                dev_eui.copy_from_slice(join_request.dev_eui().as_ref());
+                self.delegate_join(mac, rxpk, join_eui, dev_eui);
+                return None;
+            }
+            Ok(PhyPayload::JoinRequest(join_request)) => {
+                let mut dev_eui = [0; 8];
+                dev_eui.copy_from_slice(join_request.dev_eui().as_ref());
+                let mut join_eui = [0; 8];
+                join_eui.copy_from_slice(join_request.app_eui().as_ref());
+                let (configured, app_key) = self.devices.get(&dev_eui)?;
+                // the device may be known, but not in the application it asked for
+                if join_eui != *configured {
+                    join_eui.reverse();
+                    warn!(
+                        "Mock server join request for JoinEUI {} not configured for the device",
+                        hex::encode_upper(join_eui)
+                    );
+                    return None;
+                }
+                let app_key = AES128(*app_key);
+                if !join_request.validate_mic(&app_key) {
+                    warn!("Mock server join request MIC invalid");
+                    return None;
+                }
+                let dev_nonce = join_request.dev_nonce();
+                let mut nonce = [0; 2];
+                nonce.copy_from_slice(dev_nonce.as_ref());
+                if !self.dev_nonces.entry(dev_eui).or_default().insert(nonce) {
+                    warn!(
+                        "Mock server join request replayed DevNonce {}",
+                        hex::encode(nonce)
+                    );
+                    return None;
+                }
+
+                let dev_addr = self.next_dev_addr();
+                let app_nonce: [u8; 3] = rand::random();
+                let mut creator = JoinAcceptCreator::with_options([0; 33], DefaultFactory).ok()?;
+                creator
+                    .set_app_nonce(&app_nonce)
+                    .set_net_id(&NET_ID)
+                    .set_dev_addr(&dev_addr)
+                    .set_dl_settings(DL_SETTINGS)
+                    .set_rx_delay(RX1_DELAY_SECS);
+                // the creator hands back its whole buffer, room for a CFList included
+                let mut join_accept = creator.build(&app_key).ok()?.to_vec();
+                join_accept.truncate(JOIN_ACCEPT_LEN);
+
+                // the session keys are derived from the accept as devices do
+                let decrypted = match parse(join_accept.clone()) {
+                    Ok(PhyPayload::JoinAccept(JoinAcceptPayload::Encrypted(encrypted))) => {
+                        encrypted.decrypt(&app_key)
+                    }
+                    _ => return None,
+                };
+                self.sessions.insert(
+                    dev_addr,
+                    Session {
+                        nwk_skey: decrypted.derive_newskey(&dev_nonce, &app_key),
+                        app_skey: Some(decrypted.derive_appskey(&dev_nonce, &app_key)),
+                        fcnt_up: None,
+                        fcnt_down: 0,
+                    },
+                );
+                info!(
+                    "Mock server accepted join from {}, dev_addr {:08X}",
+                    hex::encode_upper(dev_eui.iter().rev().copied().collect::<Vec<u8>>()),
+                    u32::from_le_bytes(dev_addr)
+                );
+                (join_accept, JOIN_ACCEPT_DELAY_US)
+            }
+            Ok(PhyPayload::Data(DataPayload::Encrypted(uplink))) if uplink.is_uplink() => {
+                let mut dev_addr = [0; 4];
+                dev_addr.copy_from_slice(uplink.fhdr().dev_addr().as_ref());
+                let echo = self.echo;
+                let session = self.sessions.get_mut(&dev_addr)?;
+                let fcnt = full_fcnt(session.fcnt_up, uplink.fhdr().fcnt());
+                if !uplink.validate_mic(&session.nwk_skey, fcnt) {
+                    warn!("Mock server uplink MIC invalid");
+                    return None;
+                }
+                session.fcnt_up = Some(fcnt);
+                let confirmed = uplink.mhdr().mtype() == MType::ConfirmedDataUp;
+                let fport = uplink.f_port();
+                // without the AppSKey application payloads can't be echoed
+                let app_skey = session.app_skey.as_ref();
+                let payload = app_skey
+                    .and_then(|app_skey| {
+                        uplink
+                            .decrypt(Some(&session.nwk_skey), Some(app_skey), fcnt)
+                            .ok()
+                    })
+                    .and_then(|decrypted| match decrypted.frm_payload() {
+                        Ok(FRMPayload::Data(data)) => Some(data.to_vec()),
+                        _ => None,
+                    })
+                    .unwrap_or_default();
+                let echo_port = fport.filter(|fport| echo && *fport > 0 && app_skey.is_some());
+                if !confirmed && echo_port.is_none() {
+                    return None;
+                }
+
+                let mut creator = DataPayloadCreator::new();
+                creator
+                    .set_confirmed(false)
+                    .set_uplink(false)
+                    .set_dev_addr(&dev_addr)
+                    .set_fcnt(session.fcnt_down)
+                    .set_fctrl(&FCtrl::new(if confirmed { FCTRL_ACK } else { 0 }, false));
+                if let Some(fport) = echo_port {
+                    creator.set_f_port(fport);
+                }
+                let payload: &[u8] = if echo_port.is_some() { &payload } else { &[] };
+                // the AppSKey only encrypts FRMPayloads, which are never sent without it
+                let app_skey = session.app_skey.as_ref().unwrap_or(&session.nwk_skey);
+                let downlink = creator
+                    .build(payload, &[], &session.nwk_skey, app_skey)
+                    .ok()?
+                    .to_vec();
+                session.fcnt_down += 1;
+                (downlink, RX1_DELAY_US)
+            }
+            _ => return None,
+        };
+        Some(txpk(rxpk, data, delay))
+    }
+
+    fn next_dev_addr(&mut self) -> [u8; 4] {
+        let dev_addr = self.next_dev_addr.to_le_bytes();
+        self.next_dev_addr += 1;
+        dev_addr
+    }
+
+    /// Send a join request on to the join server. Its answer comes back
+    /// through the joined channel, so other traffic is served meanwhile.
+    fn delegate_join(
+        &mut self,
+        mac: [u8; 8],
+        rxpk: push_data::RxPkV1,
+        join_eui: [u8; 8],
+        dev_eui: [u8; 8],
+    ) {
+        let join_server = match self.join_server_for(&join_eui) {
+            Some(join_server) => join_server.clone(),
+            None => return,
+        };
+        let dev_addr = self.next_dev_addr();
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        let transaction_id = self.transaction_id;
+        let joined = self.joined_sender.clone();
+        tokio::spawn(async move {
+            let join = join_server::Join {
+                transaction_id,
+                phy_payload: &rxpk.data,
+                join_eui,
+                dev_eui,
+                dev_addr,
+                dl_settings: DL_SETTINGS,
+                rx_delay: RX1_DELAY_SECS,
+            };
+            match join_server.join(join).await {
+                Ok(accepted) => {
+                    let _ = joined
+                        .send(Joined {
+                            mac,
+                            rxpk,
+                            dev_addr,
+                            accepted,
+                        })
+                        .await;
+                }
+                Err(e) => warn!(
+                    "Mock server join for {} not accepted: {}",
+                    hex::encode_upper(dev_eui.iter().rev().copied().collect::<Vec<u8>>()),
+                    e
+                ),
+            }
+        });
+    }
+
+    /// Start the session of a join the join server accepted and answer it
+    fn joined(
+        &mut self,
+        dev_addr: [u8; 4],
+        accepted: join_server::Accepted,
+        rxpk: push_data::RxPkV1,
+    ) -> pull_resp::TxPk {
+        self.sessions.insert(
+            dev_addr,
+            Session {
+                nwk_skey: accepted.nwk_skey,
+                app_skey: accepted.app_skey,
+                fcnt_up: None,
+                fcnt_down: 0,
+            },
+        );
+        info!(
+            "Join server accepted join, dev_addr {:08X}",
+            u32::from_le_bytes(dev_addr)
+        );
+        txpk(rxpk, accepted.phy_payload, JOIN_ACCEPT_DELAY_US)
+    }
+}
+
+/// The first version of an rxpk, the second's first antenna for its signal
+fn rxpk_v1(rxpk: push_data::RxPk) -> push_data::RxPkV1 {
+    let rxpk = match rxpk {
+        push_data::RxPk::V1(rxpk) => return rxpk,
+        push_data::RxPk::V2(rxpk) => rxpk,
+    };
+    let rsig = rxpk.rsig.first();
+    push_data::RxPkV1 {
+        chan: rsig.map_or(0, |rsig| rsig.chan),
+        codr: rxpk.codr,
+        data: rxpk.data,
+        datr: rxpk.datr,
+        freq: rxpk.freq,
+        lsnr: rsig.map_or(0.0, |rsig| rsig.lsnr),
+        modu: Modulation::LORA,
+        rfch: rsig.map_or(0, |rsig| rsig.ant as u64),
+        rssi: rsig.map_or(0, |rsig| rsig.rssic),
+        rssis: rsig.and_then(|rsig| rsig.rssis),
+        size: rxpk.size,
+        stat: rxpk.stat,
+        tmst: rxpk.tmst,
+        time: rxpk.time,
+    }
+}
+
+/// A downlink in RX1 of the uplink, on the same frequency and data rate
+fn txpk(rxpk: push_data::RxPkV1, data: Vec<u8>, delay: u32) -> pull_resp::TxPk {
+    pull_resp::TxPk {
+        imme: false,
+        tmst: StringOrNum::N(rxpk.tmst.wrapping_add(delay)),
+        tmms: None,
+        freq: rxpk.freq,
+        rfch: 0,
+        powe: 14,
+        modu: Modulation::LORA,
+        datr: rxpk.datr,
+        codr: rxpk.codr,
+        fdev: None,
+        ipol: true,
+        prea: None,
+        size: data.len() as u64,
+        data,
+        ncrc: None,
+    }
+}
+
+/// Recover the full 32 bit frame counter from the 16 bits sent on air
+fn full_fcnt(last: Option<u32>, fcnt: u16) -> u32 {
+    let last = last.unwrap_or(0);
+    let full = (last & 0xFFFF_0000) | u32::from(fcnt);
+    if full < last {
+        full.wrapping_add(0x1_0000)
+    } else {
+        full
+    }
+}
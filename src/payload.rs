@@ -0,0 +1,328 @@
+use crate::*;
+use prost::Message;
+use prost_reflect::DynamicMessage;
+use regex::Regex;
+use serde_json::{Map, Value};
+use settings::{CsvCodec, GpsCodec, Location};
+use std::time::UNIX_EPOCH;
+use virtual_device::TimedPayloadSource;
+
+/// Cayenne LPP type of an analog input, in hundredths
+const LPP_ANALOG_INPUT: u8 = 0x02;
+
+/// The payload source a device's settings ask for
+pub fn source(payload: &settings::Payload) -> Result<TimedPayloadSource> {
+    match payload {
+        settings::Payload::GpsTrack { path, codec, fport } => {
+            let positions = track(&std::fs::read_to_string(path)?)?;
+            if positions.is_empty() {
+                return Err(Error::InvalidPayload(format!(
+                    "no positions in {}",
+                    path.display()
+                )));
+            }
+            let (codec, fport) = (*codec, *fport);
+            let mut next = 0;
+            Ok(Box::new(move || {
+                let position = positions[next];
+                next = (next + 1) % positions.len();
+                (None, fport, encode(codec, &position))
+            }))
+        }
+        settings::Payload::Csv {
+            path,
+            timestamp_column,
+            columns,
+            speed,
+            codec,
+            fport,
+        } => {
+            if !(speed.is_finite() && *speed > 0.0) {
+                return Err(Error::InvalidPayload(format!(
+                    "speed {} is not positive",
+                    speed
+                )));
+            }
+            let rows = csv_rows(path, timestamp_column, columns, *codec)?;
+            let (speed, fport) = (*speed, *fport);
+            let mut next = 0;
+            Ok(Box::new(move || {
+                let (timestamp, data) = &rows[next];
+                // the first row, and the first again after the last, are sent
+                // on the schedule
+                let delay = next.checked_sub(1).map(|previous| {
+                    Duration::from_secs_f64(((timestamp - rows[previous].0) / speed).max(0.0))
+                });
+                next = (next + 1) % rows.len();
+                (delay, fport, data.clone())
+            }))
+        }
+        settings::Payload::Protobuf {
+            schema,
+            message,
+            values,
+            fport,
+        } => {
+            let messages = protobuf_messages(schema, message, values)?;
+            let fport = *fport;
+            let mut next = 0;
+            Ok(Box::new(move || {
+                let data = messages[next].clone();
+                next = (next + 1) % messages.len();
+                (None, fport, data)
+            }))
+        }
+    }
+}
+
+/// The values encoded as messages of the schema, which is compiled as the
+/// device is created
+fn protobuf_messages(
+    schema: &Path,
+    message: &str,
+    values: &[serde_json::Value],
+) -> Result<Vec<Vec<u8>>> {
+    let invalid = |e: protox::Error| Error::InvalidPayload(format!("{}: {}", schema.display(), e));
+    let include = schema
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let mut compiler = protox::Compiler::new([include]).map_err(invalid)?;
+    compiler.include_imports(true);
+    compiler.open_file(schema).map_err(invalid)?;
+    let descriptor = compiler
+        .descriptor_pool()
+        .get_message_by_name(message)
+        .ok_or_else(|| {
+            Error::InvalidPayload(format!("no message {} in {}", message, schema.display()))
+        })?;
+    values
+        .iter()
+        .map(|value| {
+            let message = DynamicMessage::deserialize(descriptor.clone(), value.clone())?;
+            Ok(message.encode_to_vec())
+        })
+        .collect()
+}
+
+/// The timestamp, in seconds since the epoch, and payload of each row
+fn csv_rows(
+    path: &Path,
+    timestamp_column: &str,
+    columns: &[String],
+    codec: CsvCodec,
+) -> Result<Vec<(f64, Vec<u8>)>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let index = |column: &str| {
+        headers
+            .iter()
+            .position(|header| header.trim() == column)
+            .ok_or_else(|| {
+                Error::InvalidPayload(format!("no {} column in {}", column, path.display()))
+            })
+    };
+    let timestamp = index(timestamp_column)?;
+    let columns: Vec<usize> = if columns.is_empty() {
+        (0..headers.len()).filter(|i| *i != timestamp).collect()
+    } else {
+        columns
+            .iter()
+            .map(|column| index(column))
+            .collect::<Result<_>>()?
+    };
+
+    let mut rows = Vec::new();
+    for (line, record) in reader.records().enumerate() {
+        let record = record?;
+        let cell = |i: usize| record.get(i).unwrap_or_default().trim();
+        let seconds = match parse_timestamp(cell(timestamp)) {
+            Some(seconds) => seconds,
+            None => {
+                warn!(
+                    "skipping row {} of {}, its timestamp {:?} can't be read",
+                    line + 1,
+                    path.display(),
+                    cell(timestamp)
+                );
+                continue;
+            }
+        };
+        let data = match codec {
+            CsvCodec::CayenneLpp => {
+                let mut data = Vec::new();
+                for (channel, i) in columns.iter().enumerate() {
+                    // the channel is kept for a column whose cell is empty
+                    if let Ok(value) = cell(*i).parse::<f64>() {
+                        data.push(channel as u8 + 1);
+                        data.push(LPP_ANALOG_INPUT);
+                        data.extend_from_slice(&((value * 100.0).round() as i16).to_be_bytes());
+                    }
+                }
+                data
+            }
+            CsvCodec::Json => {
+                let object: Map<String, Value> = columns
+                    .iter()
+                    .map(|i| (headers[*i].trim().to_string(), json_value(cell(*i))))
+                    .collect();
+                serde_json::to_vec(&object)?
+            }
+        };
+        rows.push((seconds, data));
+    }
+    if rows.is_empty() {
+        return Err(Error::InvalidPayload(format!(
+            "no rows in {}",
+            path.display()
+        )));
+    }
+    Ok(rows)
+}
+
+/// Seconds since the epoch of a timestamp given as such or in RFC 3339
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    if let Ok(seconds) = timestamp.parse::<f64>() {
+        return seconds.is_finite().then_some(seconds);
+    }
+    let time = humantime::parse_rfc3339_weak(timestamp).ok()?;
+    Some(time.duration_since(UNIX_EPOCH).ok()?.as_secs_f64())
+}
+
+fn json_value(cell: &str) -> Value {
+    if let Ok(integer) = cell.parse::<i64>() {
+        Value::from(integer)
+    } else if let Some(number) = cell
+        .parse::<f64>()
+        .ok()
+        .and_then(serde_json::Number::from_f64)
+    {
+        Value::Number(number)
+    } else {
+        Value::String(cell.to_string())
+    }
+}
+
+/// The positions of a GPX file's track, route or waypoints, or of the fixes
+/// in NMEA sentences
+fn track(contents: &str) -> Result<Vec<Location>> {
+    if contents.contains("<gpx") {
+        gpx(contents)
+    } else {
+        Ok(nmea(contents))
+    }
+}
+
+fn gpx(contents: &str) -> Result<Vec<Location>> {
+    let point =
+        Regex::new(r"(?s)<(?:trkpt|rtept|wpt)\b([^>]*?)(?:/>|>(.*?)</(?:trkpt|rtept|wpt)>)")?;
+    let lat = Regex::new(r#"\blat\s*=\s*["']([^"']+)["']"#)?;
+    let lon = Regex::new(r#"\blon\s*=\s*["']([^"']+)["']"#)?;
+    let ele = Regex::new(r"<ele>\s*([^<\s]+)\s*</ele>")?;
+    let attribute = |regex: &Regex, text: &str| -> Option<f64> {
+        regex.captures(text)?.get(1)?.as_str().parse().ok()
+    };
+
+    let mut positions = Vec::new();
+    for captures in point.captures_iter(contents) {
+        let attributes = captures.get(1).map_or("", |m| m.as_str());
+        let body = captures.get(2).map_or("", |m| m.as_str());
+        let (latitude, longitude) = match (attribute(&lat, attributes), attribute(&lon, attributes))
+        {
+            (Some(latitude), Some(longitude)) => (latitude, longitude),
+            _ => {
+                warn!(
+                    "skipping GPX point without lat and lon: {}",
+                    attributes.trim()
+                );
+                continue;
+            }
+        };
+        positions.push(Location {
+            latitude,
+            longitude,
+            altitude_m: attribute(&ele, body).unwrap_or_default(),
+        });
+    }
+    Ok(positions)
+}
+
+/// Positions from the GGA sentences, or the RMC sentences if there are no
+/// GGA, leaving out those without a fix
+fn nmea(contents: &str) -> Vec<Location> {
+    let sentences: Vec<Vec<&str>> = contents
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix('$'))
+        .map(|line| line.split_once('*').map_or(line, |(sentence, _)| sentence))
+        .map(|sentence| sentence.split(',').collect())
+        .collect();
+    // the talker, eg: GP or GN, comes before the sentence type
+    let of_type = |kind: &'static str| {
+        sentences
+            .iter()
+            .filter(move |fields| fields[0].len() == 5 && fields[0].ends_with(kind))
+    };
+
+    let gga: Vec<Location> = of_type("GGA")
+        .filter(|fields| {
+            fields
+                .get(6)
+                .is_some_and(|fix| !fix.is_empty() && *fix != "0")
+        })
+        .filter_map(|fields| {
+            Some(Location {
+                latitude: nmea_degrees(fields.get(2)?, fields.get(3)?)?,
+                longitude: nmea_degrees(fields.get(4)?, fields.get(5)?)?,
+                altitude_m: fields.get(9)?.parse().unwrap_or_default(),
+            })
+        })
+        .collect();
+    if !gga.is_empty() {
+        return gga;
+    }
+    of_type("RMC")
+        .filter(|fields| fields.get(2) == Some(&"A"))
+        .filter_map(|fields| {
+            Some(Location {
+                latitude: nmea_degrees(fields.get(3)?, fields.get(4)?)?,
+                longitude: nmea_degrees(fields.get(5)?, fields.get(6)?)?,
+                altitude_m: 0.0,
+            })
+        })
+        .collect()
+}
+
+/// Degrees from NMEA's (d)ddmm.mmmm and hemisphere
+fn nmea_degrees(value: &str, hemisphere: &str) -> Option<f64> {
+    let value: f64 = value.parse().ok()?;
+    let degrees = (value / 100.0).trunc();
+    let degrees = degrees + (value - degrees * 100.0) / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(degrees),
+        "S" | "W" => Some(-degrees),
+        _ => None,
+    }
+}
+
+fn encode(codec: GpsCodec, position: &Location) -> Vec<u8> {
+    match codec {
+        GpsCodec::CayenneLpp => {
+            let mut payload = vec![0x01, 0x88];
+            for value in [
+                position.latitude * 1e4,
+                position.longitude * 1e4,
+                position.altitude_m * 100.0,
+            ] {
+                payload.extend_from_slice(&(value.round() as i32).to_be_bytes()[1..]);
+            }
+            payload
+        }
+        GpsCodec::Compact => {
+            let mut payload = Vec::with_capacity(10);
+            payload.extend_from_slice(&((position.latitude * 1e7).round() as i32).to_be_bytes());
+            payload.extend_from_slice(&((position.longitude * 1e7).round() as i32).to_be_bytes());
+            payload.extend_from_slice(&(position.altitude_m.round() as i16).to_be_bytes());
+            payload
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::*;
+use std::sync::Mutex;
+use tokio::time::sleep;
+
+/// Fleet-wide token bucket which scheduled uplinks wait on, so the total
+/// uplink rate stays within the configured limit however many devices run
+/// and whatever their schedules. The limit can step up or down over the run.
+pub struct RateLimiter {
+    started: Instant,
+    settings: settings::RateLimit,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// negative once uplinks are waiting for tokens yet to be refilled
+    tokens: f64,
+    refilled: Instant,
+    /// the rate in force when last refilled, to log steps as they happen
+    rate: f64,
+}
+
+impl RateLimiter {
+    pub fn new(settings: settings::RateLimit) -> RateLimiter {
+        let now = Instant::now();
+        let rate = settings.uplinks_per_sec;
+        info!("Limiting the fleet to {} uplinks/s", rate);
+        RateLimiter {
+            started: now,
+            bucket: Mutex::new(Bucket {
+                tokens: settings.burst(),
+                refilled: now,
+                rate,
+            }),
+            settings,
+        }
+    }
+
+    /// Wait for the uplink's turn. Each caller reserves a token, possibly
+    /// one yet to be refilled, so uplinks go out in the order they asked.
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("bucket lock");
+            let now = Instant::now();
+            // a rate which isn't positive all but stops uplinks
+            let rate = self
+                .settings
+                .rate_after(now - self.started)
+                .max(f64::EPSILON);
+            if rate != bucket.rate {
+                info!("Limiting the fleet to {} uplinks/s", rate);
+                bucket.rate = rate;
+            }
+            let refill = (now - bucket.refilled).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(self.settings.burst());
+            bucket.refilled = now;
+            bucket.tokens -= 1.0;
+            Duration::from_secs_f64((-bucket.tokens).max(0.0) / rate)
+        };
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn limiter(uplinks_per_sec: f64, burst: Option<f64>, step: Vec<(u64, f64)>) -> RateLimiter {
+        RateLimiter::new(settings::RateLimit {
+            uplinks_per_sec,
+            burst,
+            step: step
+                .into_iter()
+                .map(|(after_secs, uplinks_per_sec)| settings::RateStep {
+                    after_secs,
+                    uplinks_per_sec,
+                })
+                .collect(),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_then_rate() {
+        let limiter = limiter(10.0, Some(5.0), Vec::new());
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_up_to_the_burst() {
+        let limiter = limiter(10.0, Some(2.0), Vec::new());
+        limiter.acquire().await;
+        limiter.acquire().await;
+        tokio::time::advance(Duration::from_secs(60)).await;
+        let start = Instant::now();
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn steps() {
+        let limiter = limiter(10.0, Some(1.0), vec![(10, 1.0)]);
+        limiter.acquire().await;
+        tokio::time::advance(Duration::from_secs(10)).await;
+        limiter.acquire().await;
+        let start = Instant::now();
+        limiter.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_uplinks_go_in_turn() {
+        let limiter = Arc::new(limiter(4.0, Some(1.0), Vec::new()));
+        let start = Instant::now();
+        let waits: Vec<_> = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    limiter.acquire().await;
+                    start.elapsed()
+                })
+            })
+            .collect();
+        let mut elapsed = Vec::new();
+        for wait in waits {
+            elapsed.push(wait.await.unwrap());
+        }
+        elapsed.sort();
+        assert_eq!(
+            elapsed,
+            [0, 250, 500, 750].map(Duration::from_millis).to_vec()
+        );
+    }
+}
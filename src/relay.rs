@@ -0,0 +1,189 @@
+use crate::*;
+use semtech_udp::{
+    pull_resp::TxPk, push_data::RxPkV1, Bandwidth, CodingRate, DataRate, Modulation,
+    SpreadingFactor, StringOrNum,
+};
+use simulation::DeviceHandle;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use virtual_device::{DeviceObserver, Loopback};
+
+/// FPort reserved by TS011 for relay to network server traffic
+pub const FPORT: u8 = 226;
+/// Delays from the end of an end device's uplink to its RX2 window
+const RX2_DELAY_US: u32 = 2_000_000;
+const JOIN_ACCEPT_RX2_DELAY_US: u32 = 6_000_000;
+/// MType of a join accept, in the top bits of the MHDR
+const JOIN_ACCEPT: u8 = 0b001;
+
+/// A TS011 relay in front of simulated end devices. End devices reach it
+/// through a Loopback transport as if it were their gateway. Whatever it
+/// hears is wrapped in a ForwardUplinkReq and sent as an uplink of the relay
+/// device, and the ForwardDownlinkReq it receives are played to the end
+/// devices in their RX2 window.
+pub struct Relay {
+    transport: Loopback,
+    device: watch::Sender<Option<DeviceHandle>>,
+    downlinks: mpsc::Sender<Vec<u8>>,
+}
+
+impl Relay {
+    pub fn new(
+        instant: Instant,
+        region: settings::Region,
+        event_sender: event_log::Sender,
+    ) -> Relay {
+        let (transport, uplinks) = Loopback::new(gateway_clock::GatewayClock::new(instant, 0.0));
+        let (device, device_receiver) = watch::channel(None);
+        let (downlinks, downlink_receiver) = mpsc::channel(100);
+        tokio::spawn(run(
+            transport.clone(),
+            region,
+            event_sender,
+            device_receiver,
+            uplinks,
+            downlink_receiver,
+        ));
+        Relay {
+            transport,
+            device,
+            downlinks,
+        }
+    }
+
+    /// The transport end devices behind the relay use
+    pub fn transport(&self) -> Loopback {
+        self.transport.clone()
+    }
+
+    /// Forward through this device from here on, called whenever the relay
+    /// device is (re)started
+    pub fn attach(&self, device: DeviceHandle) {
+        let _ = self.device.send(Some(device));
+    }
+
+    /// Observer to register on the relay device, picking out the
+    /// ForwardDownlinkReq among its downlinks
+    pub fn observer(&self) -> Arc<dyn DeviceObserver> {
+        Arc::new(Downlinks(self.downlinks.clone()))
+    }
+}
+
+struct Downlinks(mpsc::Sender<Vec<u8>>);
+
+impl DeviceObserver for Downlinks {
+    fn on_downlink(&self, _device: &str, _fcnt: u32, fport: Option<u8>, payload: &[u8]) {
+        if fport == Some(FPORT) && self.0.try_send(payload.to_vec()).is_err() {
+            warn!("relay downlink queue full, dropping ForwardDownlinkReq");
+        }
+    }
+}
+
+async fn run(
+    transport: Loopback,
+    region: settings::Region,
+    event_sender: event_log::Sender,
+    device: watch::Receiver<Option<DeviceHandle>>,
+    mut uplinks: mpsc::Receiver<RxPkV1>,
+    mut downlinks: mpsc::Receiver<Vec<u8>>,
+) -> Result {
+    // downlinks are timed from the last uplink forwarded, as the relay
+    // doesn't know which end device a downlink is for
+    let mut last_tmst = None;
+    loop {
+        tokio::select! {
+            Some(rxpk) = uplinks.recv() => {
+                let handle = device.borrow().clone();
+                let handle = match handle {
+                    Some(handle) => handle,
+                    None => {
+                        warn!("relay device isn't running, dropping uplink");
+                        continue;
+                    }
+                };
+                let payload = match forward_uplink_req(&region, &rxpk) {
+                    Some(payload) => payload,
+                    None => {
+                        warn!(
+                            "relay can't describe uplink on {}, dropping it",
+                            rxpk.datr.to_string()
+                        );
+                        continue;
+                    }
+                };
+                last_tmst = Some(rxpk.tmst);
+                // like a real relay, one which is busy with its own exchange
+                // misses the uplink
+                if let Err(e) = handle.send(FPORT, payload, false).await {
+                    warn!("relay unable to forward uplink: {:?}", e);
+                    continue;
+                }
+                event_sender
+                    .send(event_log::Event::RelayUplink {
+                        freq: rxpk.freq,
+                        datr: rxpk.datr.to_string(),
+                        size: rxpk.data.len(),
+                    })
+                    .await?;
+            }
+            Some(phy_payload) = downlinks.recv() => {
+                let tmst = match last_tmst {
+                    Some(tmst) => tmst,
+                    None => continue,
+                };
+                let delay = match phy_payload.first() {
+                    Some(mhdr) if mhdr >> 5 == JOIN_ACCEPT => JOIN_ACCEPT_RX2_DELAY_US,
+                    _ => RX2_DELAY_US,
+                };
+                event_sender
+                    .send(event_log::Event::RelayDownlink {
+                        size: phy_payload.len(),
+                    })
+                    .await?;
+                transport.downlink(rx2_txpk(&region, tmst.wrapping_add(delay), phy_payload));
+            }
+            else => return Ok(()),
+        }
+    }
+}
+
+/// The FRMPayload of a ForwardUplinkReq: the uplink metadata, its frequency
+/// and the end device's PHYPayload
+fn forward_uplink_req(region: &settings::Region, rxpk: &RxPkV1) -> Option<Vec<u8>> {
+    let dr = region.data_rate(&geolocation::datr_name(&rxpk.datr))?;
+    let snr = (rxpk.lsnr.round() as i32).clamp(-20, 11);
+    let rssi = rxpk.rssi.clamp(-142, -15);
+    // WOR channel 0, the default relay channel
+    let metadata = u32::from(dr) | (((snr + 20) as u32) << 4) | (((-rssi - 15) as u32) << 9);
+    let frequency = (rxpk.freq * 10_000.0).round() as u32;
+
+    let mut payload = Vec::with_capacity(6 + rxpk.data.len());
+    payload.extend_from_slice(&metadata.to_le_bytes()[..3]);
+    payload.extend_from_slice(&frequency.to_le_bytes()[..3]);
+    payload.extend_from_slice(&rxpk.data);
+    Some(payload)
+}
+
+fn rx2_txpk(region: &settings::Region, tmst: u32, data: Vec<u8>) -> TxPk {
+    let (freq, bandwidth) = match region {
+        settings::Region::EU868 => (869.525, Bandwidth::BW125),
+        settings::Region::US915 => (923.3, Bandwidth::BW500),
+    };
+    TxPk {
+        imme: false,
+        tmst: StringOrNum::N(tmst),
+        tmms: None,
+        freq,
+        rfch: 0,
+        powe: 14,
+        modu: Modulation::LORA,
+        datr: DataRate::new(SpreadingFactor::SF12, bandwidth),
+        codr: CodingRate::_4_5,
+        fdev: None,
+        ipol: true,
+        prea: None,
+        size: data.len() as u64,
+        data,
+        ncrc: None,
+    }
+}
@@ -0,0 +1,138 @@
+use crate::*;
+use rusqlite::{params, Connection};
+use semtech_udp::{
+    push_data::{self, RxPk, RxPkV1},
+    Bandwidth, CodingRate, DataRate, SpreadingFactor,
+};
+use std::{path::PathBuf, str::FromStr};
+
+pub struct Options {
+    /// Packet forwarder the frames are sent through
+    pub packet_forwarder: String,
+    /// SQLite database written by run --frame-log
+    pub frame_log: PathBuf,
+    /// Only the uplinks of this device, rather than every device's
+    pub device: Option<String>,
+    /// How many times faster than recorded the frames are sent
+    pub speed: f64,
+}
+
+/// An uplink as recorded in the frame log
+struct Recorded {
+    timestamp_ms: u64,
+    device: String,
+    phy_payload: Vec<u8>,
+    freq: f64,
+    datr: Option<String>,
+}
+
+/// Send the uplinks recorded by an earlier run through a packet forwarder
+/// again, as they were and spaced as they were, eg: to check that the
+/// network server rejects them as replays, or to reproduce a run's traffic
+/// without its devices
+pub async fn run(settings: &settings::Settings, options: Options) -> Result<()> {
+    let packet_forwarder = settings
+        .packet_forwarder
+        .get(&options.packet_forwarder)
+        .ok_or_else(|| Error::InvalidPacketForwarder(options.packet_forwarder.clone()))?;
+    if options.speed.is_nan() || options.speed <= 0.0 {
+        return Err(Error::InvalidReplaySpeed(options.speed));
+    }
+    let uplinks = read(&options.frame_log, options.device.as_deref())?;
+    info!(
+        "Replaying {} uplinks from {}",
+        uplinks.len(),
+        options.frame_log.display()
+    );
+
+    let instant = Instant::now();
+    let metrics_server: IpAddr = settings.metrics_server.parse()?;
+    let metrics = Metrics::run(
+        (metrics_server, settings.metrics_port).into(),
+        settings.get_servers(),
+        settings.metric_labels,
+        settings.metric_top_k,
+    );
+    let clock = gateway_clock::GatewayClock::new(instant, packet_forwarder.drift_ppm);
+    let runtime = udp_runtime::Runtime::new(
+        options.packet_forwarder.clone(),
+        packet_forwarder.mac_cloned_into_buf()?,
+        udp_runtime::Upstream {
+            host: settings
+                .host(&options.packet_forwarder)
+                .unwrap_or_default()
+                .to_string(),
+            failover: None,
+            mirror: None,
+        },
+        settings
+            .local_port(&options.packet_forwarder)
+            .unwrap_or_default(),
+        clock,
+        packet_forwarder.into(),
+        metrics.get_packet_forwarder_sender(&options.packet_forwarder),
+    );
+    let sender = runtime.handle().publish_to();
+    tokio::spawn(runtime.run());
+
+    let first_ms = uplinks.first().map_or(0, |uplink| uplink.timestamp_ms);
+    for uplink in &uplinks {
+        let recorded_after = Duration::from_millis(uplink.timestamp_ms.saturating_sub(first_ms));
+        tokio::time::sleep_until(instant + recorded_after.div_f64(options.speed)).await;
+        info!(
+            "Replaying {} uplink: {}",
+            uplink.device,
+            hex::encode_upper(&uplink.phy_payload)
+        );
+        let datr = uplink
+            .datr
+            .as_deref()
+            .and_then(|datr| DataRate::from_str(datr).ok())
+            .unwrap_or_else(|| DataRate::new(SpreadingFactor::SF7, Bandwidth::BW125));
+        let rxpk = RxPkV1 {
+            chan: 0,
+            codr: CodingRate::_4_5,
+            size: uplink.phy_payload.len() as u64,
+            data: uplink.phy_payload.clone(),
+            datr,
+            freq: uplink.freq,
+            lsnr: 5.5,
+            modu: semtech_udp::Modulation::LORA,
+            rfch: 0,
+            rssi: -112,
+            rssis: None,
+            stat: push_data::CRC::OK,
+            tmst: clock.tmst(),
+            time: None,
+        };
+        sender
+            .send(push_data::Packet::from_rxpk(RxPk::V1(rxpk)).into())
+            .await
+            .map_err(|_| Error::UdpRuntimeClosed)?;
+    }
+    info!("Replayed {} uplinks", uplinks.len());
+    // give the last frame time to leave the packet forwarder
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    Ok(())
+}
+
+/// The recorded uplinks, joins included, in the order they were recorded
+fn read(frame_log: &Path, device: Option<&str>) -> Result<Vec<Recorded>> {
+    let connection = Connection::open(frame_log)?;
+    let mut statement = connection.prepare(
+        "SELECT timestamp_ms, device, phy_payload, freq, datr FROM frames
+         WHERE direction = 'uplink' AND (?1 IS NULL OR device = ?1) ORDER BY id",
+    )?;
+    let uplinks = statement
+        .query_map(params![device], |row| {
+            Ok(Recorded {
+                timestamp_ms: row.get(0)?,
+                device: row.get(1)?,
+                phy_payload: row.get(2)?,
+                freq: row.get(3)?,
+                datr: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(uplinks)
+}
@@ -0,0 +1,98 @@
+use rand::{
+    distributions::{Distribution, Standard},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, VecDeque},
+    future::Future,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+tokio::task_local! {
+    static DEVICE_RNG: RefCell<StdRng>;
+    static JOIN_DRAWS: RefCell<JoinDraws>;
+    static DEV_NONCES: Arc<AtomicU32>;
+}
+
+#[derive(Default)]
+struct JoinDraws {
+    recorded: Vec<u32>,
+    replay: VecDeque<u32>,
+}
+
+/// Create the rng for a device. With a seed, each device gets its own stream
+/// derived from the seed and its label so that runs can be reproduced.
+pub fn device_rng(seed: Option<u64>, label: &str) -> StdRng {
+    match seed {
+        Some(seed) => {
+            let mut hasher = DefaultHasher::new();
+            label.hash(&mut hasher);
+            StdRng::seed_from_u64(seed ^ hasher.finish())
+        }
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// Run a device's task with its rng. The LoRaWAN stack only accepts a plain
+/// function for DevNonces, so the rng is task local rather than passed around.
+pub async fn scope<F: Future>(rng: StdRng, f: F) -> F::Output {
+    let f = JOIN_DRAWS.scope(RefCell::new(JoinDraws::default()), f);
+    DEVICE_RNG.scope(RefCell::new(rng), f).await
+}
+
+/// Have the stack's draws within a device's task count up from the given
+/// counter instead of being random, so that its DevNonces count up as
+/// LoRaWAN 1.0.4 requires. The counter outlives the task, as a real device
+/// keeps its DevNonce counter across resets.
+pub async fn count_dev_nonces<F: Future>(counter: Arc<AtomicU32>, f: F) -> F::Output {
+    DEV_NONCES.scope(counter, f).await
+}
+
+/// Random values for the LoRaWAN stack, which draws DevNonces from them.
+/// Values drawn since the latest start_join are recorded so that replay_join
+/// can have the next join draw exactly the same again.
+pub fn stack_random() -> u32 {
+    let draw = || {
+        DEV_NONCES
+            .try_with(|counter| counter.fetch_add(1, Ordering::Relaxed))
+            .unwrap_or_else(|_| random())
+    };
+    JOIN_DRAWS
+        .try_with(|draws| {
+            let mut draws = draws.borrow_mut();
+            let value = draws.replay.pop_front().unwrap_or_else(draw);
+            draws.recorded.push(value);
+            value
+        })
+        .unwrap_or_else(|_| draw())
+}
+
+/// Called as the current device starts a join
+pub fn start_join() {
+    let _ = JOIN_DRAWS.try_with(|draws| draws.borrow_mut().recorded.clear());
+}
+
+/// Have the current device's next join reuse the DevNonce of its last one
+pub fn replay_join() {
+    let _ = JOIN_DRAWS.try_with(|draws| {
+        let mut draws = draws.borrow_mut();
+        draws.replay = draws.recorded.iter().copied().collect();
+    });
+}
+
+/// Draw from the current device's rng, falling back to the thread rng
+/// outside of a device task
+pub fn random<T>() -> T
+where
+    Standard: Distribution<T>,
+{
+    DEVICE_RNG
+        .try_with(|rng| rng.borrow_mut().gen())
+        .unwrap_or_else(|_| rand::random())
+}
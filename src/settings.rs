@@ -1,35 +1,666 @@
 use super::Result;
-use config::{Config, File};
+use config::{Config, ConfigError, Environment, File, FileFormat};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-#[derive(Deserialize, Debug)]
+const SCENARIOS_DIR: &str = "scenarios";
+const CREDENTIALS_FILE: &str = "credentials.toml.age";
+/// Environment variables holding what unlocks the credentials file
+const CREDENTIALS_IDENTITY_VAR: &str = "VLD_CREDENTIALS_IDENTITY";
+const CREDENTIALS_PASSPHRASE_VAR: &str = "VLD_CREDENTIALS_PASSPHRASE";
+const RX_SPREADING_FACTORS: [&str; 6] = ["SF7", "SF8", "SF9", "SF10", "SF11", "SF12"];
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Settings {
     pub default_server: String,
     pub device: HashMap<String, Device>,
     pub packet_forwarder: HashMap<String, PacketForwarder>,
+    /// Network servers packet forwarders can name in place of a host, so
+    /// groups of gateways in one fleet can be pointed at different servers
+    #[serde(default)]
+    pub server: HashMap<String, Server>,
     pub metrics_server: String,
     pub metrics_port: u16,
+    #[serde(default)]
+    pub scenario: Option<Scenario>,
+    #[serde(default)]
+    pub downlink_rule: Vec<DownlinkRule>,
+    /// How far a downlink's tmst may be from the RX1 or RX2 delay after the
+    /// uplink before it is flagged as not conformant
+    #[serde(default = "default_rx_timing_tolerance_us")]
+    pub rx_timing_tolerance_us: u32,
+    /// Inject faults at random while running, when given
+    #[serde(default)]
+    pub chaos: Option<Chaos>,
+    #[serde(default)]
+    pub handover: Vec<Handover>,
+    /// Cap the total rate of scheduled uplinks across the fleet, when given
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Whether gauges of each device are labelled with every device or
+    /// summed up for the fleet
+    #[serde(default)]
+    pub metric_labels: MetricLabels,
+    /// With fleet metric labels, how many of the worst devices are still
+    /// labelled with their own gauges
+    #[serde(default)]
+    pub metric_top_k: usize,
+    /// Local ports handed out, in order of label, to the packet forwarders
+    /// without a local_port of their own
+    #[serde(default)]
+    pub local_port_range: Option<PortRange>,
+}
+
+/// A network server's Semtech UDP endpoint
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Server {
+    pub host: String,
+}
+
+/// UDP ports from start to end, both included
+#[derive(Clone, Copy, Deserialize, Serialize, Debug)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+/// How the gauges of each device, downlink_queue_depth and battery_level,
+/// are exported
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricLabels {
+    /// A label set for every device, for runs small enough to want the detail
+    #[default]
+    PerDevice,
+    /// Gauges per server summing up its devices, as thousands of label sets
+    /// overwhelm Prometheus
+    Fleet,
+}
+
+/// A fleet-wide limit on scheduled uplinks, which can be stepped up or down
+/// as the run goes on to ramp the load on the network server
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct RateLimit {
+    pub uplinks_per_sec: f64,
+    /// Uplinks which may go out at once after a quiet spell, a second's
+    /// worth by default
+    #[serde(default)]
+    pub burst: Option<f64>,
+    #[serde(default)]
+    pub step: Vec<RateStep>,
+}
+
+/// A new uplink rate, from some time into the run
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct RateStep {
+    pub after_secs: u64,
+    pub uplinks_per_sec: f64,
+}
+
+impl RateLimit {
+    pub fn burst(&self) -> f64 {
+        self.burst.unwrap_or(self.uplinks_per_sec).max(1.0)
+    }
+
+    /// The rate in force this long into the run, from the latest step which
+    /// has been reached
+    pub fn rate_after(&self, elapsed: Duration) -> f64 {
+        self.step
+            .iter()
+            .filter(|step| Duration::from_secs(step.after_secs) <= elapsed)
+            .max_by_key(|step| step.after_secs)
+            .map_or(self.uplinks_per_sec, |step| step.uplinks_per_sec)
+    }
+}
+
+/// Move devices to another packet forwarder partway through a run, keeping
+/// their sessions. With the packet forwarder connected to a visited network
+/// server this simulates a device roaming away from its home network.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Handover {
+    /// Time since the start of the run
+    pub after_secs: u64,
+    pub packet_forwarder: String,
+    /// Devices to move, every device when empty
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+/// Settings for the chaos controller, which injects faults at random times
+/// for random durations. With a seed the timeline can be reproduced.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Chaos {
+    /// Mean time between faults
+    #[serde(default = "default_chaos_interval_secs")]
+    pub interval_secs: u64,
+    /// Longest a fault lasts, each lasts between one second and this
+    #[serde(default = "default_chaos_max_duration_secs")]
+    pub max_duration_secs: u64,
+    /// Most extra delay a latency spike adds to uplinks
+    #[serde(default = "default_chaos_max_latency_ms")]
+    pub max_latency_ms: u64,
+    /// Kinds of fault to inject, all of them when not given
+    #[serde(default = "default_chaos_faults")]
+    pub faults: Vec<FaultKind>,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind {
+    /// A share of the packets through a packet forwarder are dropped, both ways
+    PacketLoss,
+    /// A packet forwarder disconnects from the network server
+    GatewayFlap,
+    /// Uplinks through a packet forwarder are delayed
+    LatencySpike,
+    /// A device is power cycled, losing its session
+    DeviceReset,
+}
+
+/// Checked against every downlink accepted by a device. Every field is
+/// optional; a rule with no device or fport applies to all of them.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct DownlinkRule {
+    pub device: Option<String>,
+    pub fport: Option<u8>,
+    /// regex which the hex encoded (uppercase) FRMPayload must match
+    pub payload: Option<String>,
+    /// longest allowed time from the end of the uplink to the downlink
+    /// reaching the gateway
+    pub max_latency_ms: Option<u32>,
+    /// frequency in MHz the downlink must be sent on
+    pub freq: Option<f64>,
+}
+
+/// A scenario is a settings file in the scenarios folder which is merged over
+/// the regular settings when selected by name, eg: scenarios/join-storm.toml.
+/// It may describe itself in a [scenario] table.
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
+pub struct Scenario {
+    pub version: Option<String>,
+    pub description: Option<String>,
+    /// The run fails unless every expectation is met
+    #[serde(default)]
+    pub expect: Vec<Expectation>,
+}
+
+/// An event which must show up in the event log within some time of the run
+/// starting, eg: a downlink on port 1 within 10s
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Expectation {
+    /// Event name as written to the event log, eg: "join_success" or "downlink"
+    pub event: String,
+    /// Device label; any device will do when not given
+    pub device: Option<String>,
+    pub fport: Option<u8>,
+    pub fcnt: Option<u32>,
+    pub within_secs: u64,
+}
+
+#[derive(Deserialize)]
+struct ScenarioFile {
+    #[serde(default)]
+    scenario: Scenario,
 }
 
 impl Settings {
     /// Load Settings from a given path. Settings are loaded from a default.toml
     /// file in the given path, followed by merging in an optional settings.toml
-    /// in the same folder.
-    pub fn new(path: &Path) -> Result<Settings> {
+    /// and settings.yaml in the same folder, the selected scenario, if any, and
+    /// an optional age encrypted credentials.toml.age. Finally, any value can be
+    /// overridden with a `VLD_` environment variable, using `__` to separate
+    /// nested keys, eg: `VLD_PACKET_FORWARDER__DEFAULT__HOST`. The files a
+    /// plain file lists under include are merged right after it. Whatever is
+    /// under device_defaults is then merged under every device, which keeps
+    /// any setting of its own.
+    pub fn new(path: &Path, scenario: Option<&str>) -> Result<Settings> {
+        if let Some(scenario) = scenario {
+            if !scenario_file(path, scenario).exists() {
+                return Err(Error::UnknownScenario(scenario.to_string()));
+            }
+        }
         let mut c = Config::new();
-        let default_file = path.join("default.toml");
         // Load default config and merge in overrides
-        c.merge(File::with_name(default_file.to_str().expect("file name")))?;
-        let settings_file = path.join("settings.toml");
-        if settings_file.exists() {
-            c.merge(File::with_name(settings_file.to_str().expect("file name")))?;
+        let mut included = Vec::new();
+        for (i, file) in files(path, scenario).iter().enumerate() {
+            // only default.toml is required
+            if i == 0 || file.exists() {
+                merge_with_includes(&mut c, file, &mut included)?;
+            }
+        }
+        let credentials = credentials_file(path);
+        if credentials.exists() {
+            c.merge(File::from_str(
+                &decrypt_credentials(&credentials)?,
+                FileFormat::Toml,
+            ))?;
+        }
+        c.merge(Environment::with_prefix("VLD").separator("__"))?;
+        apply_device_defaults(&mut c)?;
+        // serde's own messages don't say where in the settings they are
+        let mut settings: Settings = serde_path_to_error::deserialize(c)
+            .map_err(|e| Error::InvalidSetting(e.path().to_string(), e.into_inner().to_string()))?;
+        // prune the default packet forwarder if we have more than one
+        if settings.packet_forwarder.len() != 1 {
+            settings.packet_forwarder.remove("default");
+        }
+        Ok(settings)
+    }
+
+    /// List the scenarios found in the given settings path
+    pub fn scenarios(path: &Path) -> Result<BTreeMap<String, Scenario>> {
+        let mut scenarios = BTreeMap::new();
+        let dir = path.join(SCENARIOS_DIR);
+        if !dir.exists() {
+            return Ok(scenarios);
+        }
+        for entry in std::fs::read_dir(dir)? {
+            let file = entry?.path();
+            if file.extension().and_then(|e| e.to_str()) != Some("toml") {
+                continue;
+            }
+            let name = match file.file_stem().and_then(|s| s.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let mut c = Config::new();
+            c.merge(File::with_name(file.to_str().expect("file name")))?;
+            let scenario_file: ScenarioFile = c.try_into()?;
+            scenarios.insert(name, scenario_file.scenario);
+        }
+        Ok(scenarios)
+    }
+
+    /// Check for mistakes which would otherwise only show up once devices
+    /// start, returning a description of each problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Err(e) = self.metrics_server.parse::<std::net::IpAddr>() {
+            problems.push(format!("metrics_server {}: {}", self.metrics_server, e));
+        }
+
+        let servers: BTreeMap<_, _> = self.server.iter().collect();
+        for (label, server) in servers {
+            if let Some(problem) = host_problem(&server.host) {
+                problems.push(format!("server.{}.host: {}", label, problem));
+            }
+        }
+
+        let packet_forwarders: BTreeMap<_, _> = self.packet_forwarder.iter().collect();
+        for (label, pf) in packet_forwarders {
+            if let Some(problem) = hex_problem(&pf.mac, 8) {
+                problems.push(format!("packet_forwarder.{}.mac {}", label, problem));
+            }
+            if pf.host.is_none() && pf.server.is_none() {
+                problems.push(format!(
+                    "packet_forwarder.{} needs either a host or a server",
+                    label
+                ));
+            }
+            for (prefix, host, server) in [
+                ("", &pf.host, &pf.server),
+                ("backup_", &pf.backup_host, &pf.backup_server),
+                ("mirror_", &pf.mirror_host, &pf.mirror_server),
+            ] {
+                if let Some(problem) = self.endpoint_problem(prefix, host, server) {
+                    problems.push(format!("packet_forwarder.{}{}", label, problem));
+                }
+            }
+            let mirror = self.mirror_host(label);
+            if mirror.is_some() && mirror == self.host(label) {
+                problems.push(format!(
+                    "packet_forwarder.{}: the mirror is the server it already sends to",
+                    label
+                ));
+            }
+            if pf.failover_missed_acks == 0 {
+                problems.push(format!(
+                    "packet_forwarder.{}.failover_missed_acks is 0",
+                    label
+                ));
+            }
+            if !pf.drift_ppm.is_finite() {
+                problems.push(format!(
+                    "packet_forwarder.{}.drift_ppm is not finite",
+                    label
+                ));
+            }
+            if let Some(problem) = pf.location.as_ref().and_then(Location::problem) {
+                problems.push(format!("packet_forwarder.{}.location: {}", label, problem));
+            }
+            for (i, rf_chain) in pf.rf_chain.iter().enumerate() {
+                if !positive(rf_chain.freq_mhz) || !rf_chain.antenna_gain_dbi.is_finite() {
+                    problems.push(format!(
+                        "packet_forwarder.{}.rf_chain[{}]: freq_mhz or antenna_gain_dbi is invalid",
+                        label, i
+                    ));
+                }
+            }
+            if !pf.rf_chain.is_empty() && !pf.rf_chain.iter().any(|rf_chain| rf_chain.tx_enable) {
+                problems.push(format!(
+                    "packet_forwarder.{}.rf_chain: no chain has tx_enable, so no downlink can be sent",
+                    label
+                ));
+            }
+        }
+
+        for (i, rule) in self.downlink_rule.iter().enumerate() {
+            if let Some(Err(e)) = rule.payload.as_deref().map(regex::Regex::new) {
+                problems.push(format!("downlink_rule[{}].payload: {}", i, e));
+            }
+            if let Some(device) = &rule.device {
+                if !self.device.contains_key(device) {
+                    problems.push(format!(
+                        "downlink_rule[{}].device: {} is not defined",
+                        i, device
+                    ));
+                }
+            }
+        }
+
+        if let Some(chaos) = &self.chaos {
+            if chaos.interval_secs == 0 {
+                problems.push("chaos.interval_secs is 0".to_string());
+            }
+            if chaos.max_duration_secs == 0 {
+                problems.push("chaos.max_duration_secs is 0".to_string());
+            }
+            if chaos.faults.is_empty() {
+                problems.push("chaos.faults is empty".to_string());
+            }
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            if !positive(rate_limit.uplinks_per_sec) {
+                problems.push("rate_limit.uplinks_per_sec is not positive".to_string());
+            }
+            for (i, step) in rate_limit.step.iter().enumerate() {
+                if !positive(step.uplinks_per_sec) {
+                    problems.push(format!(
+                        "rate_limit.step[{}].uplinks_per_sec is not positive",
+                        i
+                    ));
+                }
+            }
+        }
+
+        let mut local_ports: BTreeMap<u16, Vec<&String>> = BTreeMap::new();
+        for label in self.packet_forwarder.keys() {
+            match self.local_port(label) {
+                Some(0) | None => (),
+                Some(port) => local_ports.entry(port).or_default().push(label),
+            }
+        }
+        for (port, mut labels) in local_ports {
+            if labels.len() > 1 {
+                labels.sort();
+                problems.push(format!(
+                    "packet forwarders {:?} all send from local port {}",
+                    labels, port
+                ));
+            }
+        }
+        if let Some(range) = &self.local_port_range {
+            let unassigned = self
+                .packet_forwarder
+                .values()
+                .filter(|pf| pf.local_port.is_none())
+                .count();
+            if range.start > range.end || usize::from(range.end - range.start) + 1 < unassigned {
+                problems.push(format!(
+                    "local_port_range {}-{} doesn't have a port for each of the {} packet \
+                     forwarders without a local_port",
+                    range.start, range.end, unassigned
+                ));
+            }
+        }
+
+        if self.metric_top_k > 0 && self.metric_labels == MetricLabels::PerDevice {
+            problems.push(
+                "metric_top_k is only used with metric_labels = \"fleet\", every device is \
+                 labelled already"
+                    .to_string(),
+            );
+        }
+
+        for (i, handover) in self.handover.iter().enumerate() {
+            if !self
+                .packet_forwarder
+                .contains_key(&handover.packet_forwarder)
+            {
+                problems.push(format!(
+                    "handover[{}].packet_forwarder: {} is not defined",
+                    i, handover.packet_forwarder
+                ));
+            }
+            for device in &handover.devices {
+                if !self.device.contains_key(device) {
+                    problems.push(format!(
+                        "handover[{}].devices: {} is not defined",
+                        i, device
+                    ));
+                }
+            }
+        }
+
+        let devices: BTreeMap<_, _> = self.device.iter().collect();
+        for (label, device) in devices {
+            let credentials = &device.credentials;
+            for (field, value, bytes) in [
+                ("dev_eui", &credentials.dev_eui, 8),
+                ("app_eui", &credentials.app_eui, 8),
+                ("app_key", &credentials.app_key, 16),
+            ] {
+                if let Some(problem) = hex_problem(value, bytes) {
+                    problems.push(format!(
+                        "device.{}.credentials.{} {}",
+                        label, field, problem
+                    ));
+                }
+            }
+
+            let pf = device.packet_forwarder.as_deref().unwrap_or("default");
+            if device.relay.is_none() && !self.packet_forwarder.contains_key(pf) {
+                problems.push(format!(
+                    "device.{}.packet_forwarder: {} is not defined",
+                    label, pf
+                ));
+            }
+            if let Some(relay) = &device.relay {
+                match self.device.get(relay) {
+                    None => {
+                        problems.push(format!("device.{}.relay: {} is not defined", label, relay))
+                    }
+                    Some(relay_device) if relay_device.relay.is_some() => problems.push(format!(
+                        "device.{}.relay: {} is itself behind a relay",
+                        label, relay
+                    )),
+                    Some(relay_device) if relay_device.region != device.region => problems.push(
+                        format!("device.{}.relay: {} is in a different region", label, relay),
+                    ),
+                    Some(_) => (),
+                }
+            }
+
+            if let Some(problem) = device.location.as_ref().and_then(Location::problem) {
+                problems.push(format!("device.{}.location: {}", label, problem));
+            }
+            if let Some(mobility) = &device.mobility {
+                match device.location {
+                    None => problems.push(format!(
+                        "device.{}.mobility: the device has no location to start from",
+                        label
+                    )),
+                    Some(location) => {
+                        if let Some(problem) = mobility.problem(&location) {
+                            problems.push(format!("device.{}.mobility: {}", label, problem));
+                        }
+                    }
+                }
+            }
+
+            if let Some(problem) = device.payload.as_ref().and_then(Payload::problem) {
+                problems.push(format!("device.{}.payload: {}", label, problem));
+            }
+
+            if device.adr_ack_delay == 0 {
+                problems.push(format!("device.{}.adr_ack_delay is 0", label));
+            }
+            if device.reboot_secs == Some(0) {
+                problems.push(format!("device.{}.reboot_secs is 0", label));
+            }
+            if let Some(data_rate) = device.data_rate {
+                if device.region.datr(data_rate).is_none() {
+                    problems.push(format!(
+                        "device.{}.data_rate {} is not an uplink data rate of {:?}",
+                        label, data_rate, device.region
+                    ));
+                }
+            }
+            if !device.uplink_snr_db.is_finite() {
+                problems.push(format!(
+                    "device.{}.uplink_snr_db {} is not an SNR",
+                    label, device.uplink_snr_db
+                ));
+            }
+            if let Some(quality) = &device.uplink_quality {
+                if device.location.is_some() {
+                    problems.push(format!(
+                        "device.{}.uplink_quality: the device's location decides what gateways \
+                         report instead",
+                        label
+                    ));
+                }
+                for (name, measurement) in
+                    [("rssi_dbm", &quality.rssi_dbm), ("snr_db", &quality.snr_db)]
+                {
+                    if let Some(problem) = measurement.as_ref().and_then(Measurement::problem) {
+                        problems.push(format!(
+                            "device.{}.uplink_quality.{}: {}",
+                            label, name, problem
+                        ));
+                    }
+                }
+            }
+            if let Some(battery) = &device.battery {
+                if !(battery.capacity_mah > 0.0 && battery.capacity_mah.is_finite()) {
+                    problems.push(format!(
+                        "device.{}.battery.capacity_mah {} is not a capacity",
+                        label, battery.capacity_mah
+                    ));
+                }
+                if !(0.0..=100.0).contains(&battery.level_percent) {
+                    problems.push(format!(
+                        "device.{}.battery.level_percent {} is out of range",
+                        label, battery.level_percent
+                    ));
+                }
+            }
+
+            if device.rx_window.timing.duration_ms == 0 {
+                problems.push(format!("device.{}.rx_window.duration_ms is 0", label));
+            }
+            for (sf, timing) in &device.rx_window.spreading_factor {
+                // both regions receive on SF7 to SF12
+                if !RX_SPREADING_FACTORS.contains(&sf.to_uppercase().as_str()) {
+                    problems.push(format!(
+                        "device.{}.rx_window.spreading_factor.{}: not a spreading factor used by {:?}",
+                        label, sf, device.region
+                    ));
+                }
+                if timing.duration_ms == 0 {
+                    problems.push(format!(
+                        "device.{}.rx_window.spreading_factor.{}.duration_ms is 0",
+                        label, sf
+                    ));
+                }
+            }
         }
-        c.try_into().map_err(|e| e.into())
+        problems
+    }
+
+    /// The host:port a packet forwarder sends to, its own or its server's
+    pub fn host(&self, label: &str) -> Option<&str> {
+        let pf = self.packet_forwarder.get(label)?;
+        self.endpoint(&pf.host, &pf.server)
+    }
+
+    /// The host:port a packet forwarder fails over to, if it has a backup
+    pub fn backup_host(&self, label: &str) -> Option<&str> {
+        let pf = self.packet_forwarder.get(label)?;
+        self.endpoint(&pf.backup_host, &pf.backup_server)
+    }
+
+    /// The host:port a packet forwarder copies its uplinks to, if mirrored
+    pub fn mirror_host(&self, label: &str) -> Option<&str> {
+        let pf = self.packet_forwarder.get(label)?;
+        self.endpoint(&pf.mirror_host, &pf.mirror_server)
+    }
+
+    /// A network server given as host:port or by the label of a server
+    fn endpoint<'a>(
+        &'a self,
+        host: &'a Option<String>,
+        server: &Option<String>,
+    ) -> Option<&'a str> {
+        match (host, server) {
+            (Some(host), _) => Some(host),
+            (None, Some(server)) => Some(&self.server.get(server)?.host),
+            (None, None) => None,
+        }
+    }
+
+    /// What is wrong with a network server given as <prefix>host or
+    /// <prefix>server, but not whether one is given at all
+    fn endpoint_problem(
+        &self,
+        prefix: &str,
+        host: &Option<String>,
+        server: &Option<String>,
+    ) -> Option<String> {
+        match (host, server) {
+            (Some(_), Some(_)) => {
+                Some(format!(" has both a {}host and a {}server", prefix, prefix))
+            }
+            (Some(host), None) => {
+                host_problem(host).map(|problem| format!(".{}host: {}", prefix, problem))
+            }
+            (None, Some(server)) if !self.server.contains_key(server) => {
+                Some(format!(".{}server: no server named {}", prefix, server))
+            }
+            _ => None,
+        }
+    }
+
+    /// The local port a packet forwarder sends from, 0 for any free port.
+    /// None when local_port_range runs out before reaching it.
+    pub fn local_port(&self, label: &str) -> Option<u16> {
+        let pf = self.packet_forwarder.get(label)?;
+        if let Some(port) = pf.local_port {
+            return Some(port);
+        }
+        let range = match &self.local_port_range {
+            Some(range) => range,
+            None => return Some(0),
+        };
+        let mut unassigned: Vec<&String> = self
+            .packet_forwarder
+            .iter()
+            .filter(|(_, pf)| pf.local_port.is_none())
+            .map(|(label, _)| label)
+            .collect();
+        unassigned.sort();
+        let index = unassigned.iter().position(|other| *other == label)?;
+        let port = u32::from(range.start) + index as u32;
+        (port <= u32::from(range.end)).then_some(port as u16)
     }
 
     pub fn get_servers(&self) -> Vec<&String> {
-        let mut servers = Vec::new();
+        let mut servers: Vec<&String> = self.server.keys().collect();
         for device in self.device.values() {
             if let Some(server) = &device.server {
                 if !servers.contains(&server) {
@@ -41,37 +672,463 @@ impl Settings {
     }
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct Device {
     pub credentials: Credentials,
     #[serde(default = "default_rejoin_frames")]
     pub rejoin_frames: u32,
+    /// Rejoin once a session is this old, as well as after rejoin_frames
+    pub rejoin_secs: Option<u64>,
+    /// Reset the device this long after it starts and after each reset,
+    /// as a watchdog would, losing its session and pending MAC answers
+    pub reboot_secs: Option<u64>,
     #[serde(default = "default_secs_between_transmits")]
     pub secs_between_transmits: u64,
+    /// Uplinks without a downlink after which ADR backs off, and between
+    /// each step after that
+    #[serde(default = "default_adr_ack_limit")]
+    pub adr_ack_limit: u32,
+    #[serde(default = "default_adr_ack_delay")]
+    pub adr_ack_delay: u32,
     #[serde(default = "default_region")]
     pub region: Region,
     pub server: Option<String>,
     pub packet_forwarder: Option<String>,
+    /// Reach the network server through this device, acting as a TS011
+    /// relay, instead of a packet forwarder
+    pub relay: Option<String>,
+    /// Where the device is. It is then heard by every packet forwarder with
+    /// a location in range, with measurements following from the distance.
+    pub location: Option<Location>,
+    /// How the device moves from its location
+    pub mobility: Option<Mobility>,
+    /// What the device runs on, reported in DevStatusAns
+    pub battery: Option<Battery>,
+    /// SNR gateways receive the device's uplinks with at full power, lower
+    /// for a device far away. The RSSI follows it.
+    #[serde(default = "default_uplink_snr_db")]
+    pub uplink_snr_db: f32,
+    /// RSSI and SNR gateways report the device's uplinks with at full
+    /// power, each fixed or drawn for every uplink, in place of those
+    /// uplink_snr_db gives
+    pub uplink_quality: Option<UplinkQuality>,
+    #[serde(default)]
+    pub rx_window: RxWindow,
+    /// Join in a way the network server must reject, to check that it does
+    pub negative_join: Option<NegativeJoin>,
+    /// Data rate index every session starts at, the LoRaWAN stack's default
+    /// if unset. LinkADRReq moves it from there.
+    pub data_rate: Option<u8>,
+    #[serde(default)]
+    pub lorawan_version: LorawanVersion,
+    /// Where the device's payloads come from, four random bytes on a random
+    /// FPort by default
+    pub payload: Option<Payload>,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+/// A source of payloads for a device's scheduled uplinks
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Payload {
+    /// The positions of a GPX or NMEA file, one per uplink and back to the
+    /// first after the last
+    GpsTrack {
+        path: PathBuf,
+        #[serde(default)]
+        codec: GpsCodec,
+        #[serde(default = "default_payload_fport")]
+        fport: u8,
+    },
+    /// The rows of a CSV file of sensor readings, each sent as long after
+    /// the one before as their timestamps are apart, and back to the first
+    /// row after the last
+    Csv {
+        path: PathBuf,
+        /// Column of the timestamps, RFC 3339 or seconds since the epoch
+        #[serde(default = "default_csv_timestamp_column")]
+        timestamp_column: String,
+        /// Columns sent, in order, every column but the timestamp's if empty
+        #[serde(default)]
+        columns: Vec<String>,
+        /// How many times faster than recorded rows are replayed
+        #[serde(default = "default_csv_speed")]
+        speed: f64,
+        #[serde(default)]
+        codec: CsvCodec,
+        #[serde(default = "default_payload_fport")]
+        fport: u8,
+    },
+    /// Messages of a protobuf schema, one per uplink in turn
+    Protobuf {
+        /// The .proto file, whose imports are looked for alongside it
+        schema: PathBuf,
+        /// Full name of the message type, eg: "sensor.v1.Reading"
+        message: String,
+        /// The messages, in the protobuf JSON mapping
+        values: Vec<serde_json::Value>,
+        #[serde(default = "default_payload_fport")]
+        fport: u8,
+    },
+}
+
+impl Payload {
+    fn problem(&self) -> Option<String> {
+        match self {
+            Payload::Csv { speed, .. } if !positive(*speed) => {
+                Some(format!("speed {} is not positive", speed))
+            }
+            Payload::Protobuf { values, .. } if values.is_empty() => {
+                Some("values is empty".to_string())
+            }
+            Payload::GpsTrack { path, fport, .. }
+            | Payload::Csv { path, fport, .. }
+            | Payload::Protobuf {
+                schema: path,
+                fport,
+                ..
+            } => {
+                if *fport == 0 {
+                    Some("fport 0 is reserved for MAC commands".to_string())
+                } else if !path.exists() {
+                    Some(format!("{} doesn't exist", path.display()))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// How a position is put in a payload
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GpsCodec {
+    /// A Cayenne LPP GPS location on channel 1
+    #[default]
+    CayenneLpp,
+    /// Latitude and longitude as signed 32 bit integers of 1e-7 degrees and
+    /// altitude as a signed 16 bit integer of metres, big endian
+    Compact,
+}
+
+/// How a row of sensor readings is put in a payload
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CsvCodec {
+    /// Each column a Cayenne LPP analog input, on channels counting up from 1
+    #[default]
+    CayenneLpp,
+    /// A JSON object of the columns, numbers where they parse as one
+    Json,
+}
+
+/// A battery drained by the device's uplinks
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Battery {
+    #[serde(default = "default_battery_capacity_mah")]
+    pub capacity_mah: f64,
+    /// How full the battery is when the device starts
+    #[serde(default = "default_battery_level_percent")]
+    pub level_percent: f64,
+}
+
+/// What gateways report of a device's uplinks. Either may be left out, the
+/// SNR then follows uplink_snr_db and the RSSI the SNR as it does without.
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq)]
+pub struct UplinkQuality {
+    pub rssi_dbm: Option<Measurement>,
+    pub snr_db: Option<Measurement>,
+}
+
+/// A value that is the same for every uplink or drawn for each
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Measurement {
+    Fixed { value: f32 },
+    Uniform { min: f32, max: f32 },
+    Normal { mean: f32, std_dev: f32 },
+}
+
+impl Measurement {
+    fn problem(&self) -> Option<String> {
+        match *self {
+            Measurement::Fixed { value } if !value.is_finite() => {
+                Some(format!("value {} is not finite", value))
+            }
+            Measurement::Uniform { min, max } if !(min.is_finite() && max.is_finite()) => {
+                Some(format!("min {} and max {} are not both finite", min, max))
+            }
+            Measurement::Uniform { min, max } if min > max => {
+                Some(format!("min {} is above max {}", min, max))
+            }
+            Measurement::Normal { mean, std_dev } if !(mean.is_finite() && std_dev >= 0.0) => {
+                Some(format!(
+                    "mean {} and std_dev {} are not a distribution",
+                    mean, std_dev
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// LoRaWAN versions a device can follow
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq)]
+pub enum LorawanVersion {
+    #[serde(rename = "1.0.3")]
+    #[default]
+    V1_0_3,
+    /// DevNonces count up and RX windows must open within 20 μs of their
+    /// delay
+    #[serde(rename = "1.0.4")]
+    V1_0_4,
+}
+
+/// Ways of joining which a network server must reject
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NegativeJoin {
+    /// Join requests are signed with a different AppKey than the one configured
+    WrongAppKey,
+    /// After joining once, join again reusing the same DevNonce
+    ReplayedDevNonce,
+    /// Join with a DevEUI derived from the configured one, which the network
+    /// server doesn't know
+    Unprovisioned,
+}
+
+/// How early the device opens an RX window relative to its nominal start and
+/// how long it keeps listening. Overrides may be given per spreading factor
+/// since slow data rates need longer preambles.
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct RxWindow {
+    #[serde(flatten)]
+    pub timing: RxWindowTiming,
+    /// keyed by spreading factor, eg: "SF12"
+    #[serde(default)]
+    pub spreading_factor: HashMap<String, RxWindowTiming>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct RxWindowTiming {
+    #[serde(default = "default_rx_window_offset_ms")]
+    pub offset_ms: i32,
+    #[serde(default = "default_rx_window_duration_ms")]
+    pub duration_ms: u32,
+}
+
+impl Default for RxWindow {
+    fn default() -> RxWindow {
+        RxWindow {
+            timing: RxWindowTiming {
+                offset_ms: default_rx_window_offset_ms(),
+                duration_ms: default_rx_window_duration_ms(),
+            },
+            spreading_factor: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub enum Region {
     US915,
     EU868,
 }
 
+impl Region {
+    /// Longest an uplink may be on air for, in regions which limit dwell
+    /// time. US915 keeps to the FCC's 400 ms.
+    pub fn dwell_time_limit(&self) -> Option<Duration> {
+        match self {
+            Region::US915 => Some(Duration::from_millis(400)),
+            Region::EU868 => None,
+        }
+    }
+
+    /// Highest TX power a gateway may send a downlink on this frequency at,
+    /// in dBm. EU868 allows 27 dBm in the 869.4 to 869.65 MHz sub-band RX2
+    /// uses and 16 dBm elsewhere, US915 allows 30 dBm.
+    pub fn max_downlink_power_dbm(&self, freq_mhz: f64) -> u64 {
+        match self {
+            Region::EU868 if (869.4..=869.65).contains(&freq_mhz) => 27,
+            Region::EU868 => 16,
+            Region::US915 => 30,
+        }
+    }
+
+    /// Largest MACPayload a downlink sent at this datr may carry, after the
+    /// regional parameters' M
+    pub fn max_downlink_mac_payload(&self, datr: &str) -> Option<usize> {
+        let size = match (self, datr) {
+            (Region::EU868, "SF12BW125" | "SF11BW125" | "SF10BW125") => 59,
+            (Region::EU868, "SF9BW125") => 123,
+            (Region::EU868, "SF8BW125" | "SF7BW125" | "SF7BW250") => 250,
+            (Region::US915, "SF12BW500") => 61,
+            (Region::US915, "SF11BW500") => 137,
+            (Region::US915, "SF10BW500" | "SF9BW500" | "SF8BW500" | "SF7BW500") => 250,
+            _ => return None,
+        };
+        Some(size)
+    }
+
+    /// The data rate index of an uplink's datr, eg: "SF7BW125"
+    pub fn data_rate(&self, datr: &str) -> Option<u8> {
+        self.uplink_datrs()
+            .iter()
+            .position(|data_rate| *data_rate == datr)
+            .map(|dr| dr as u8)
+    }
+
+    /// The datr of an uplink data rate index
+    pub fn datr(&self, data_rate: u8) -> Option<&'static str> {
+        self.uplink_datrs().get(usize::from(data_rate)).copied()
+    }
+
+    fn uplink_datrs(&self) -> &'static [&'static str] {
+        match self {
+            Region::EU868 => &[
+                "SF12BW125",
+                "SF11BW125",
+                "SF10BW125",
+                "SF9BW125",
+                "SF8BW125",
+                "SF7BW125",
+                "SF7BW250",
+            ],
+            Region::US915 => &["SF10BW125", "SF9BW125", "SF8BW125", "SF7BW125", "SF8BW500"],
+        }
+    }
+}
+
+fn default_rx_timing_tolerance_us() -> u32 {
+    20
+}
+
+fn default_rxpk_time() -> TimeField {
+    TimeField {
+        enabled: true,
+        error_ms: 0,
+    }
+}
+fn default_rxpk_tmms() -> TimeField {
+    TimeField {
+        enabled: false,
+        error_ms: 0,
+    }
+}
+fn default_failover_missed_acks() -> u32 {
+    3
+}
+fn default_time_field_enabled() -> bool {
+    true
+}
+
+/// What keeps a host from being connected to
+fn host_problem(host: &str) -> Option<String> {
+    match host.rsplit_once(':') {
+        Some((address, port)) if port.parse::<u16>().is_ok() => {
+            // the port of a bare IPv6 address can't be told from its last group
+            (address.contains(':') && !address.starts_with('[')).then(|| {
+                format!(
+                    "{} has an IPv6 address outside brackets, eg: [2001:db8::1]:1700",
+                    host
+                )
+            })
+        }
+        _ => Some(format!("{} is not of the form host:port", host)),
+    }
+}
+
+fn positive(value: f64) -> bool {
+    value.is_finite() && value > 0.0
+}
+
+/// What's wrong with a hex field which should hold this many bytes, eg:
+/// "must be 32 hex characters, got 30"
+fn hex_problem(value: &str, bytes: usize) -> Option<String> {
+    if let Some((i, c)) = value.char_indices().find(|(_, c)| !c.is_ascii_hexdigit()) {
+        Some(format!(
+            "must be hex, but has {:?} at character {}",
+            c,
+            i + 1
+        ))
+    } else if value.len() != bytes * 2 {
+        Some(format!(
+            "must be {} hex characters, got {}",
+            bytes * 2,
+            value.len()
+        ))
+    } else {
+        None
+    }
+}
+
+fn default_chaos_interval_secs() -> u64 {
+    60
+}
+fn default_chaos_max_duration_secs() -> u64 {
+    30
+}
+fn default_chaos_max_latency_ms() -> u64 {
+    3000
+}
+fn default_chaos_faults() -> Vec<FaultKind> {
+    vec![
+        FaultKind::PacketLoss,
+        FaultKind::GatewayFlap,
+        FaultKind::LatencySpike,
+        FaultKind::DeviceReset,
+    ]
+}
+
 fn default_secs_between_transmits() -> u64 {
     0
 }
+fn default_adr_ack_limit() -> u32 {
+    64
+}
+fn default_adr_ack_delay() -> u32 {
+    32
+}
 fn default_rejoin_frames() -> u32 {
     0xFFFF
 }
 fn default_region() -> Region {
     Region::US915
 }
+fn default_rx_window_offset_ms() -> i32 {
+    20
+}
+fn default_rx_window_duration_ms() -> u32 {
+    100
+}
+fn default_mobility_radius_m() -> f64 {
+    1000.0
+}
+fn default_uplink_snr_db() -> f32 {
+    5.5
+}
+fn default_payload_fport() -> u8 {
+    1
+}
+fn default_csv_timestamp_column() -> String {
+    "timestamp".to_string()
+}
+fn default_csv_speed() -> f64 {
+    1.0
+}
+fn default_battery_capacity_mah() -> f64 {
+    2400.0
+}
+fn default_battery_level_percent() -> f64 {
+    100.0
+}
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct Credentials {
+    /// JoinEUI, named AppEUI before LoRaWAN 1.0.4, which may be given as
+    /// join_eui too
+    #[serde(alias = "join_eui")]
     pub app_eui: String,
     pub app_key: String,
     pub dev_eui: String,
@@ -79,31 +1136,141 @@ pub struct Credentials {
 
 impl Credentials {
     pub fn appeui_cloned_into_buf(&self) -> Result<[u8; 8]> {
-        let vec = hex::decode(&self.app_eui)?;
-        Ok([
-            vec[7], vec[6], vec[5], vec[4], vec[3], vec[2], vec[1], vec[0],
-        ])
+        let mut buf = [0; 8];
+        hex::decode_to_slice(&self.app_eui, &mut buf)?;
+        buf.reverse();
+        Ok(buf)
     }
 
     pub fn deveui_cloned_into_buf(&self) -> Result<[u8; 8]> {
-        let vec = hex::decode(&self.dev_eui)?;
-        Ok([
-            vec[7], vec[6], vec[5], vec[4], vec[3], vec[2], vec[1], vec[0],
-        ])
+        let mut buf = [0; 8];
+        hex::decode_to_slice(&self.dev_eui, &mut buf)?;
+        buf.reverse();
+        Ok(buf)
     }
     pub fn appkey_cloned_into_buf(&self) -> Result<[u8; 16]> {
-        let vec = hex::decode(&self.app_key)?;
-        Ok([
-            vec[0], vec[1], vec[2], vec[3], vec[4], vec[5], vec[6], vec[7], vec[8], vec[9],
-            vec[10], vec[11], vec[12], vec[13], vec[14], vec[15],
-        ])
+        let mut buf = [0; 16];
+        hex::decode_to_slice(&self.app_key, &mut buf)?;
+        Ok(buf)
     }
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct PacketForwarder {
     mac: String,
-    pub host: String,
+    /// Network server sent to, as host:port
+    pub host: Option<String>,
+    /// Label of the network server sent to, in place of a host. Devices
+    /// reporting through the packet forwarder have their metrics labelled
+    /// with it unless they name a server of their own.
+    pub server: Option<String>,
+    /// Rate at which the reported tmst runs fast (positive) or slow (negative)
+    #[serde(default)]
+    pub drift_ppm: f64,
+    /// Where the gateway stands, which lets it hear devices with a location
+    pub location: Option<Location>,
+    /// The concentrator's RF chains, indexed by rfch. When not given every
+    /// uplink is heard on rfch 0 and downlinks are sent whichever they name.
+    #[serde(default)]
+    pub rf_chain: Vec<RfChain>,
+    /// The UTC time uplinks are stamped with in their rxpk
+    #[serde(default = "default_rxpk_time")]
+    pub time: TimeField,
+    /// The GPS time uplinks are stamped with, which only the second version
+    /// of the rxpk has room for, so it is sent once this is enabled
+    #[serde(default = "default_rxpk_tmms")]
+    pub tmms: TimeField,
+    /// Local UDP port to send from. Network servers which key gateway
+    /// sessions on the source address then see each packet forwarder at one
+    /// of its own, however often it reconnects.
+    #[serde(default)]
+    pub local_port: Option<u16>,
+    /// Network server to switch to once the one in use stops acking, as
+    /// host:port or by the label of a server, as a packet forwarder does
+    /// with an alternate serv_port_up
+    pub backup_host: Option<String>,
+    pub backup_server: Option<String>,
+    /// Ack windows in a row without a PUSH_ACK or PULL_ACK after which the
+    /// packet forwarder switches to its backup, and back again
+    #[serde(default = "default_failover_missed_acks")]
+    pub failover_missed_acks: u32,
+    /// Network server sent a copy of every uplink, as host:port or by the
+    /// label of a server, to shadow test it. Its downlinks are dropped.
+    pub mirror_host: Option<String>,
+    pub mirror_server: Option<String>,
+}
+
+/// A time field of the rxpk, and how far off the gateway's clock for it is
+#[derive(Clone, Copy, Deserialize, Serialize, Debug)]
+pub struct TimeField {
+    #[serde(default = "default_time_field_enabled")]
+    pub enabled: bool,
+    /// Positive when the gateway's clock is ahead of true time
+    #[serde(default)]
+    pub error_ms: i64,
+}
+
+/// One of a concentrator's radios and the antenna on it. Uplinks are heard
+/// on the chain whose centre frequency is nearest.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct RfChain {
+    pub freq_mhz: f64,
+    /// Added to the RSSI and SNR of uplinks heard on this chain
+    #[serde(default)]
+    pub antenna_gain_dbi: f64,
+    /// Whether downlinks may be sent on this chain, on a real concentrator
+    /// usually only rfch 0
+    #[serde(default)]
+    pub tx_enable: bool,
+}
+
+/// A point on the earth, in WGS84 degrees
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default)]
+    pub altitude_m: f64,
+}
+
+impl Location {
+    fn problem(&self) -> Option<String> {
+        if !(-90.0..=90.0).contains(&self.latitude) {
+            Some(format!("latitude {} is out of range", self.latitude))
+        } else if !(-180.0..=180.0).contains(&self.longitude) {
+            Some(format!("longitude {} is out of range", self.longitude))
+        } else {
+            None
+        }
+    }
+}
+
+/// Movement of a device from its location, round the waypoints and back
+/// again or, without waypoints, from one random point to the next within
+/// radius_m of the location
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Mobility {
+    pub speed_mps: f64,
+    #[serde(default)]
+    pub waypoints: Vec<Location>,
+    #[serde(default = "default_mobility_radius_m")]
+    pub radius_m: f64,
+}
+
+impl Mobility {
+    fn problem(&self, start: &Location) -> Option<String> {
+        if !(self.speed_mps > 0.0 && self.speed_mps.is_finite()) {
+            Some(format!("speed_mps {} is not a speed", self.speed_mps))
+        } else if let Some(problem) = self.waypoints.iter().find_map(Location::problem) {
+            Some(format!("waypoints: {}", problem))
+        } else if !self.waypoints.is_empty() && self.waypoints.iter().all(|w| w == start) {
+            Some("every waypoint is the device's location".to_string())
+        } else if self.waypoints.is_empty() && !(self.radius_m > 0.0 && self.radius_m.is_finite()) {
+            Some(format!("radius_m {} is not a distance", self.radius_m))
+        } else {
+            None
+        }
+    }
 }
 
 impl PacketForwarder {
@@ -112,11 +1279,133 @@ impl PacketForwarder {
     }
 }
 
+/// The plain files settings are loaded from, in the order they are merged
+pub fn files(path: &Path, scenario: Option<&str>) -> Vec<PathBuf> {
+    let mut files = vec![
+        path.join("default.toml"),
+        path.join("settings.toml"),
+        path.join("settings.yaml"),
+    ];
+    if let Some(scenario) = scenario {
+        files.push(scenario_file(path, scenario));
+    }
+    files
+}
+
+/// Merge a file, then the files and directories it includes, relative to
+/// it. A directory stands for every toml and yaml file in it, in order of
+/// name. A file is only merged the first time it is included.
+fn merge_with_includes(c: &mut Config, file: &Path, included: &mut Vec<PathBuf>) -> Result<()> {
+    let name = file.to_str().expect("file name");
+    c.merge(File::with_name(name))?;
+    let mut own = Config::new();
+    own.merge(File::with_name(name))?;
+    let includes: Vec<String> = match own.get("include") {
+        Ok(includes) => includes,
+        Err(ConfigError::NotFound(_)) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let dir = file.parent().unwrap_or_else(|| Path::new("."));
+    for include in includes {
+        let include = dir.join(include);
+        let files = if include.is_dir() {
+            let mut files = Vec::new();
+            for entry in std::fs::read_dir(&include)? {
+                let file = entry?.path();
+                if matches!(
+                    file.extension().and_then(|e| e.to_str()),
+                    Some("toml" | "yaml" | "yml")
+                ) {
+                    files.push(file);
+                }
+            }
+            files.sort();
+            files
+        } else if include.exists() {
+            vec![include]
+        } else {
+            return Err(Error::InvalidSetting(
+                format!("{}: include", file.display()),
+                format!("{} doesn't exist", include.display()),
+            ));
+        };
+        for file in files {
+            if included.contains(&file) {
+                continue;
+            }
+            included.push(file.clone());
+            merge_with_includes(c, &file, included)?;
+        }
+    }
+    Ok(())
+}
+
+/// Set every value under device_defaults as a default of each device, so
+/// tables are merged key by key and a device's own values win
+fn apply_device_defaults(c: &mut Config) -> Result<()> {
+    let defaults = match c.get_table("device_defaults") {
+        Ok(defaults) => defaults,
+        Err(ConfigError::NotFound(_)) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let labels: Vec<String> = match c.get_table("device") {
+        Ok(devices) => devices.into_keys().collect(),
+        Err(ConfigError::NotFound(_)) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for label in &labels {
+        for (key, value) in &defaults {
+            c.set_default(&format!("device.{}.{}", label, key), value.clone())?;
+        }
+    }
+    Ok(())
+}
+
+/// The encrypted credentials file, merged after the plain files
+pub fn credentials_file(path: &Path) -> PathBuf {
+    path.join(CREDENTIALS_FILE)
+}
+
+/// Decrypt an age encrypted credentials file, with the identity file named by
+/// VLD_CREDENTIALS_IDENTITY or else the passphrase in VLD_CREDENTIALS_PASSPHRASE
+fn decrypt_credentials(file: &Path) -> Result<String> {
+    use std::io::Read;
+    let encrypted = std::fs::read(file)?;
+    let mut reader = match age::Decryptor::new(&encrypted[..])? {
+        age::Decryptor::Recipients(decryptor) => {
+            let identity = std::env::var(CREDENTIALS_IDENTITY_VAR)
+                .map_err(|_| Error::CredentialsLocked(CREDENTIALS_IDENTITY_VAR))?;
+            // only native x25519 identities, age's plugin support isn't enabled
+            let identities: Vec<age::x25519::Identity> = age::IdentityFile::from_file(identity)?
+                .into_identities()
+                .into_iter()
+                .map(|age::IdentityFileEntry::Native(identity)| identity)
+                .collect();
+            decryptor.decrypt(
+                identities
+                    .iter()
+                    .map(|identity| identity as &dyn age::Identity),
+            )?
+        }
+        age::Decryptor::Passphrase(decryptor) => {
+            let passphrase = std::env::var(CREDENTIALS_PASSPHRASE_VAR)
+                .map_err(|_| Error::CredentialsLocked(CREDENTIALS_PASSPHRASE_VAR))?;
+            decryptor.decrypt(&age::secrecy::Secret::new(passphrase), None)?
+        }
+    };
+    let mut credentials = String::new();
+    reader.read_to_string(&mut credentials)?;
+    Ok(credentials)
+}
+
+fn scenario_file(path: &Path, scenario: &str) -> PathBuf {
+    path.join(SCENARIOS_DIR).join(format!("{}.toml", scenario))
+}
+
 pub fn mac_string_into_buf(s: &str) -> Result<[u8; 8]> {
-    let vec = hex::decode(s)?;
-    Ok([
-        vec[0], vec[1], vec[2], vec[3], vec[4], vec[5], vec[6], vec[7],
-    ])
+    let mut buf = [0; 8];
+    hex::decode_to_slice(s, &mut buf)?;
+    Ok(buf)
 }
 
 use super::error::Error;
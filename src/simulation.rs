@@ -0,0 +1,830 @@
+use crate::*;
+use chaos::{Chaos, Step};
+use event_log::EventLog;
+use rate_limit::RateLimiter;
+use relay::Relay;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+use virtual_device::{
+    DeviceObserver, DeviceState, IntermediateEvent, Schedule, Session, VirtualDevice,
+};
+
+const DEFAULT_PF: &str = "default";
+/// How long a device is given to finish its in-flight exchange when stopped.
+/// This covers a join accept arriving in the second RX window.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(8);
+/// How many events a subscriber may fall behind before it misses some
+const EVENT_CAPACITY: usize = 1024;
+
+/// Options for a simulation which aren't part of the settings files
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Only run the first devices, sorted by label
+    pub limit: Option<usize>,
+    /// Seed all randomness so that a run can be reproduced
+    pub seed: Option<u64>,
+    /// Stop each device once it has sent this many scheduled uplinks
+    pub uplink_limit: Option<u32>,
+    /// Also write every event as a JSON line to this file
+    pub event_log: Option<PathBuf>,
+    /// Also write each device's events to a file of its own in this directory
+    pub device_log_dir: Option<PathBuf>,
+    /// Record every frame into this SQLite database
+    pub frame_log: Option<PathBuf>,
+    /// Write the session keys of every join to this file, for Wireshark
+    pub key_log: Option<PathBuf>,
+    /// Only run this instance's share of the fleet
+    pub shard: Option<Shard>,
+}
+
+/// One of several simulator instances the fleet is split between. Devices
+/// are assigned by a hash of their label, so a device stays in its shard as
+/// others are added or removed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Shard {
+    pub fn contains(&self, label: &str) -> bool {
+        let mut hasher = DefaultHasher::new();
+        label.hash(&mut hasher);
+        hasher.finish() % self.count as u64 == self.index as u64
+    }
+}
+
+/// A set of virtual devices and the packet forwarders they talk through.
+///
+/// Creating a simulation starts the metrics server and the packet forwarders,
+/// devices are started by applying device settings. Subscribe before applying
+/// to see every event. Only one simulation can be created per process, as the
+/// metrics are registered globally.
+///
+/// ```no_run
+/// # async fn example() -> virtual_lorawan_device::Result<()> {
+/// use virtual_lorawan_device::{settings::Settings, Options, Simulation};
+///
+/// let settings = Settings::new("./settings".as_ref(), None)?;
+/// let mut simulation = Simulation::new(&settings, Options::default())?;
+/// let mut events = simulation.subscribe();
+/// simulation.apply(settings.device).await;
+/// while let Ok(event) = events.recv().await {
+///     if event["event"] == "join_success" {
+///         let device = simulation.device(event["device"].as_str().unwrap()).unwrap();
+///         device.send(1, vec![0xAB, 0xCD], true).await?;
+///         break;
+///     }
+/// }
+/// let states = simulation.stop().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Simulation {
+    instant: Instant,
+    seed: Option<u64>,
+    limit: usize,
+    shard: Option<Shard>,
+    default_server: String,
+    metrics: Metrics,
+    event_log: EventLog,
+    events: broadcast::Sender<Value>,
+    chaos: Option<Chaos>,
+    chaos_log: event_log::Sender,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// handovers yet to happen, soonest last
+    handovers: Vec<settings::Handover>,
+    packet_forwarders: HashMap<String, udp_runtime::Handle>,
+    /// the server named by each packet forwarder which names one, which
+    /// labels the metrics of its devices
+    servers: HashMap<String, String>,
+    /// packet forwarders with a location, sorted by label
+    gateways: Vec<geolocation::Gateway>,
+    /// keyed by the label of the relay device
+    relays: HashMap<String, Relay>,
+    downlink_rules: Vec<settings::DownlinkRule>,
+    /// told about every device started from here on
+    observers: Vec<Arc<dyn DeviceObserver>>,
+    rx_timing_tolerance_us: u32,
+    uplink_limit: Option<u32>,
+    /// DevNonce counters of 1.0.4 devices by DevEUI, kept across restarts
+    dev_nonces: HashMap<String, Arc<AtomicU32>>,
+    /// sessions from a checkpoint and their next FCntDown, by DevEUI, taken
+    /// up by the first start of each device
+    resumes: HashMap<String, (Session, Option<u32>)>,
+    next_id: u64,
+    /// devices report their id here when their task ends
+    finished_sender: mpsc::UnboundedSender<u64>,
+    finished: mpsc::UnboundedReceiver<u64>,
+    devices: BTreeMap<String, Running>,
+}
+
+/// A spawned device and the handles needed to reconfigure or stop it
+struct Running {
+    id: u64,
+    finished: bool,
+    device: settings::Device,
+    handle: DeviceHandle,
+    schedule: watch::Sender<Schedule>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+/// Handle to a running device, for reading its state or poking at it
+#[derive(Clone)]
+pub struct DeviceHandle {
+    label: String,
+    control: virtual_device::Sender<IntermediateEvent>,
+    state: watch::Receiver<DeviceState>,
+    events: broadcast::Sender<Value>,
+}
+
+impl DeviceHandle {
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// The device state as of its last event
+    pub fn state(&self) -> DeviceState {
+        self.state.borrow().clone()
+    }
+
+    /// This device's events from here on, as the JSON values written to the
+    /// event log
+    pub fn events(&self) -> impl Stream<Item = Value> + Unpin {
+        let label = self.label.clone();
+        event_stream(self.events.subscribe()).filter(move |event| event["device"] == label.as_str())
+    }
+
+    /// Send an uplink now, in addition to the device's schedule
+    pub async fn send(&self, fport: u8, data: Vec<u8>, confirmed: bool) -> Result {
+        self.control(IntermediateEvent::ManualPacket(data, fport, confirmed))
+            .await
+    }
+
+    /// Drop the current session and join again
+    pub async fn rejoin(&self) -> Result {
+        self.control(IntermediateEvent::NewSession).await
+    }
+
+    /// Send the next scheduled payload now, or the uplink held while paused
+    pub async fn send_now(&self) -> Result {
+        self.control(IntermediateEvent::SendNow).await
+    }
+
+    /// Hold the device's scheduled uplinks until resumed
+    pub async fn pause(&self) -> Result {
+        self.control(IntermediateEvent::Pause).await
+    }
+
+    pub async fn resume(&self) -> Result {
+        self.control(IntermediateEvent::Resume).await
+    }
+
+    async fn control(&self, event: IntermediateEvent) -> Result {
+        self.control
+            .send(event)
+            .await
+            .map_err(|_| Error::DeviceChannelClosed)
+    }
+}
+
+impl Simulation {
+    /// Start the metrics server, the event log and the packet forwarders from
+    /// the settings. No devices run until device settings are applied.
+    pub fn new(settings: &settings::Settings, options: Options) -> Result<Simulation> {
+        let instant = Instant::now();
+        let metrics_server: IpAddr = settings.metrics_server.parse()?;
+        let metrics = Metrics::run(
+            (metrics_server, settings.metrics_port).into(),
+            settings.get_servers(),
+            settings.metric_labels,
+            settings.metric_top_k,
+        );
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let event_log = EventLog::run(
+            options.event_log.as_deref(),
+            options.device_log_dir.as_deref(),
+            instant,
+            events.clone(),
+        )?;
+        let chaos_log = event_log.get_chaos_sender();
+        let mut observers: Vec<Arc<dyn DeviceObserver>> = Vec::new();
+        if let Some(path) = &options.frame_log {
+            observers.push(Arc::new(frame_log::FrameLog::open(path)?));
+        }
+        if let Some(path) = &options.key_log {
+            observers.push(Arc::new(key_log::KeyLog::open(path)?));
+        }
+
+        let mut packet_forwarders = HashMap::new();
+        let mut servers = HashMap::new();
+        let mut gateways = Vec::new();
+        for (label, packet_forwarder) in &settings.packet_forwarder {
+            let runtime = udp_runtime::Runtime::new(
+                label.clone(),
+                packet_forwarder.mac_cloned_into_buf()?,
+                udp_runtime::Upstream {
+                    host: settings.host(label).unwrap_or_default().to_string(),
+                    failover: settings
+                        .backup_host(label)
+                        .map(|host| udp_runtime::Failover {
+                            host: host.to_string(),
+                            missed_acks: packet_forwarder.failover_missed_acks,
+                        }),
+                    mirror: settings.mirror_host(label).map(str::to_string),
+                },
+                settings.local_port(label).unwrap_or_default(),
+                gateway_clock::GatewayClock::new(instant, packet_forwarder.drift_ppm),
+                packet_forwarder.into(),
+                metrics.get_packet_forwarder_sender(label),
+            )
+            .seed(options.seed);
+            // not ready until it has connected
+            metrics.health().packet_forwarder_connected(label, false);
+            packet_forwarders.insert(label.clone(), runtime.handle());
+            if let Some(server) = &packet_forwarder.server {
+                servers.insert(label.clone(), server.clone());
+            }
+            if let Some(location) = packet_forwarder.location {
+                gateways.push(geolocation::Gateway {
+                    label: label.clone(),
+                    transport: runtime.handle(),
+                    location,
+                });
+            }
+            tokio::spawn(runtime.run());
+        }
+
+        gateways.sort_by(|a, b| a.label.cmp(&b.label));
+
+        let (finished_sender, finished) = mpsc::unbounded_channel();
+        Ok(Simulation {
+            instant,
+            seed: options.seed,
+            limit: options.limit.unwrap_or(usize::MAX),
+            shard: options.shard,
+            default_server: settings.default_server.clone(),
+            metrics,
+            event_log,
+            events,
+            chaos: settings
+                .chaos
+                .clone()
+                .map(|chaos| Chaos::new(chaos, options.seed)),
+            chaos_log,
+            rate_limiter: settings
+                .rate_limit
+                .clone()
+                .map(|rate_limit| Arc::new(RateLimiter::new(rate_limit))),
+            handovers: {
+                let mut handovers = settings.handover.clone();
+                handovers.sort_by_key(|handover| std::cmp::Reverse(handover.after_secs));
+                handovers
+            },
+            packet_forwarders,
+            servers,
+            gateways,
+            relays: HashMap::new(),
+            downlink_rules: settings.downlink_rule.clone(),
+            observers,
+            rx_timing_tolerance_us: settings.rx_timing_tolerance_us,
+            uplink_limit: options.uplink_limit,
+            dev_nonces: HashMap::new(),
+            resumes: HashMap::new(),
+            next_id: 0,
+            finished_sender,
+            finished,
+            devices: BTreeMap::new(),
+        })
+    }
+
+    /// What is reported by the metrics server's /healthz and /readyz
+    pub fn health(&self) -> Arc<health::Health> {
+        self.metrics.health()
+    }
+
+    /// Percentiles of the devices' join latency, downlink margin and ack
+    /// round trip
+    pub fn latencies(&self) -> Arc<latency::Latencies> {
+        self.metrics.latencies()
+    }
+
+    /// When the simulation started, event times are relative to this
+    pub fn instant(&self) -> Instant {
+        self.instant
+    }
+
+    pub fn default_server(&self) -> &str {
+        &self.default_server
+    }
+
+    /// Receive every event from here on, as the JSON value written to the
+    /// event log
+    pub fn subscribe(&self) -> broadcast::Receiver<Value> {
+        self.events.subscribe()
+    }
+
+    /// Every device's events from here on as one stream, in the order they
+    /// happened. Like subscribe, but events missed by falling too far behind
+    /// are skipped with a warning.
+    pub fn events(&self) -> impl Stream<Item = Value> + Unpin {
+        event_stream(self.events.subscribe())
+    }
+
+    /// Tell an observer about the lifecycle of every device started from
+    /// here on, so register observers before applying device settings
+    pub fn observe(&mut self, observer: Arc<dyn DeviceObserver>) {
+        self.observers.push(observer);
+    }
+
+    pub fn device(&self, label: &str) -> Option<DeviceHandle> {
+        self.devices
+            .get(label)
+            .map(|running| running.handle.clone())
+    }
+
+    /// Every device, sorted by label
+    pub fn devices(&self) -> impl Iterator<Item = &DeviceHandle> {
+        self.devices.values().map(|running| &running.handle)
+    }
+
+    /// Start, update, restart or stop devices so that the simulation matches
+    /// the given device settings. Changes to the schedule keep the session,
+    /// other changes restart the device.
+    pub async fn apply(&mut self, devices: HashMap<String, settings::Device>) {
+        // sorted so that the limit picks the same devices on every reload
+        let devices: BTreeMap<String, settings::Device> = devices
+            .into_iter()
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .filter(|(label, _)| self.shard.is_none_or(|shard| shard.contains(label)))
+            .take(self.limit)
+            .collect();
+
+        let removed: Vec<String> = self
+            .devices
+            .keys()
+            .filter(|label| !devices.contains_key(*label))
+            .cloned()
+            .collect();
+        for label in removed {
+            info!("{} removed from settings, stopping it", label);
+            self.stop_device(&label).await;
+        }
+
+        // relays first, so that relay devices pick them up as they start
+        for relay in devices.values().filter_map(|device| device.relay.as_ref()) {
+            if self.relays.contains_key(relay) {
+                continue;
+            }
+            match devices.get(relay) {
+                Some(device) => {
+                    let event_sender = self
+                        .event_log
+                        .get_device_sender(relay, &device.credentials.dev_eui);
+                    self.relays.insert(
+                        relay.clone(),
+                        Relay::new(self.instant, device.region.clone(), event_sender),
+                    );
+                }
+                None => error!("no device named {} to relay through", relay),
+            }
+        }
+
+        for (label, device) in devices {
+            if let Some(running) = self.devices.get_mut(&label) {
+                if !restart_required(&running.device, &device) {
+                    if running.device != device {
+                        info!("{} schedule changed", label);
+                        let _ = running.schedule.send((&device).into());
+                        running.device = device;
+                    }
+                    continue;
+                }
+                info!("{} settings changed, restarting it", label);
+                self.stop_device(&label).await;
+            }
+            self.spawn_device(label, device).await;
+        }
+    }
+
+    async fn spawn_device(&mut self, label: String, device: settings::Device) {
+        let packet_forwarder = if let Some(pf) = &device.packet_forwarder {
+            pf
+        } else {
+            DEFAULT_PF
+        };
+
+        let metrics_sender = self.metrics.get_server_sender(
+            match (&device.server, self.servers.get(packet_forwarder)) {
+                (Some(server), _) | (None, Some(server)) => server,
+                (None, None) => &self.default_server,
+            },
+        );
+
+        let event_sender = self
+            .event_log
+            .get_device_sender(&label, &device.credentials.dev_eui);
+
+        // end devices behind a relay reach it instead of a packet forwarder,
+        // and devices with a location are heard by every gateway in range
+        let builder = VirtualDevice::builder(&label);
+        let builder = match (
+            &device.relay,
+            self.packet_forwarders.get(packet_forwarder),
+            device.location,
+        ) {
+            (None, Some(udp_runtime), Some(location)) => {
+                let track = geolocation::Track::new(
+                    location,
+                    device.mobility.clone(),
+                    rng::device_rng(self.seed, &format!("{}-mobility", label)),
+                );
+                builder.transport(geolocation::GeoTransport::new(
+                    udp_runtime.clock(),
+                    track,
+                    self.gateways.clone(),
+                ))
+            }
+            (Some(relay), _, _) => match self.relays.get(relay) {
+                Some(relay) => builder.transport(relay.transport()),
+                None => {
+                    error!(
+                        "{} device could not be created: {}",
+                        label,
+                        Error::UnknownDevice(relay.to_string())
+                    );
+                    return;
+                }
+            },
+            (None, Some(udp_runtime), None) => builder.transport(udp_runtime.clone()),
+            (None, None, _) => {
+                error!(
+                    "{} device could not be created: {}",
+                    label,
+                    Error::InvalidPacketForwarder(packet_forwarder.to_string())
+                );
+                return;
+            }
+        };
+
+        let (schedule_sender, schedule) = watch::channel(Schedule::from(&device));
+        let (shutdown_sender, shutdown) = watch::channel(false);
+
+        // a single badly configured device shouldn't take the rest of the fleet down
+        let mut builder = builder
+            .time(self.instant)
+            .credentials(device.credentials.clone())
+            .metrics_sender(metrics_sender)
+            .event_sender(event_sender.clone())
+            .schedule(schedule)
+            .region(device.region.clone())
+            .rx_window(device.rx_window.clone())
+            .downlink_rules(&self.downlink_rules)
+            .uplink_limit(self.uplink_limit)
+            .negative_join(device.negative_join)
+            .data_rate(device.data_rate)
+            .battery(device.battery)
+            .uplink_snr_db(device.uplink_snr_db)
+            .uplink_quality(device.uplink_quality)
+            .lorawan_version(device.lorawan_version)
+            .dev_nonces(
+                self.dev_nonces
+                    .entry(device.credentials.dev_eui.clone())
+                    .or_default()
+                    .clone(),
+            )
+            .rx_timing_tolerance_us(self.rx_timing_tolerance_us)
+            .shutdown(shutdown);
+        if let Some(rate_limiter) = &self.rate_limiter {
+            builder = builder.rate_limiter(rate_limiter.clone());
+        }
+        if let Some((session, next_fcnt_down)) = self.resumes.remove(&device.credentials.dev_eui) {
+            builder = builder.resume(session, next_fcnt_down);
+        }
+        if let Some(payload) = &device.payload {
+            match payload::source(payload) {
+                Ok(source) => builder = builder.timed_payload(source),
+                Err(e) => {
+                    error!("{} device could not be created: {:?}", label, e);
+                    return;
+                }
+            }
+        }
+        for observer in &self.observers {
+            builder = builder.observer(observer.clone());
+        }
+        let relay = self.relays.get(&label);
+        if let Some(relay) = relay {
+            builder = builder.observer(relay.observer());
+        }
+        let lorawan_app = match builder.build().await {
+            Ok(lorawan_app) => lorawan_app,
+            Err(e) => {
+                error!("{} device could not be created: {:?}", label, e);
+                return;
+            }
+        };
+
+        let handle = DeviceHandle {
+            label: label.clone(),
+            control: lorawan_app.control(),
+            state: lorawan_app.state(),
+            events: self.events.clone(),
+        };
+        if let Some(relay) = relay {
+            relay.attach(handle.clone());
+        }
+        let device_rng = rng::device_rng(self.seed, &label);
+        let task_label = label.clone();
+        let id = self.next_id;
+        self.next_id += 1;
+        let finished = self.finished_sender.clone();
+        let health = self.metrics.health();
+        let task = tokio::spawn(async move {
+            let _running = health.device_running();
+            if let Err(e) = rng::scope(device_rng, lorawan_app.run()).await {
+                error!("{} device threw error: {:?}", task_label, e);
+                health.device_failed();
+                let _ = event_sender
+                    .send(event_log::Event::Error {
+                        message: e.to_string(),
+                    })
+                    .await;
+            }
+            let _ = finished.send(id);
+        });
+
+        self.devices.insert(
+            label,
+            Running {
+                id,
+                finished: false,
+                device,
+                handle,
+                schedule: schedule_sender,
+                shutdown: shutdown_sender,
+                task,
+            },
+        );
+    }
+
+    /// When the chaos controller next has a fault to inject or clear, if it
+    /// is enabled in the settings
+    pub fn chaos_deadline(&self) -> Option<Instant> {
+        self.chaos.as_ref().map(Chaos::deadline)
+    }
+
+    /// Inject and clear the faults which are due, recording each in the event
+    /// log. Call this once the chaos deadline has passed.
+    pub async fn chaos(&mut self) {
+        let chaos = match &mut self.chaos {
+            Some(chaos) => chaos,
+            None => return,
+        };
+        // sorted so that a seed picks the same targets on every run
+        let mut packet_forwarders: Vec<String> = self.packet_forwarders.keys().cloned().collect();
+        packet_forwarders.sort();
+        let devices: Vec<String> = self.devices.keys().cloned().collect();
+        let steps = chaos.step(&packet_forwarders, &devices);
+        for packet_forwarder in &packet_forwarders {
+            self.packet_forwarders[packet_forwarder].impair(chaos.impairments(packet_forwarder));
+        }
+
+        for step in steps {
+            let event = match step {
+                Step::Inject(fault) => {
+                    info!(
+                        "Chaos injecting {:?} into {} for {:?}",
+                        fault.kind, fault.target, fault.duration
+                    );
+                    if fault.kind == settings::FaultKind::DeviceReset {
+                        self.reset_device(&fault.target).await;
+                    }
+                    event_log::Event::FaultInjected {
+                        fault: fault.kind,
+                        target: fault.target,
+                        duration_ms: fault.duration.as_millis() as u64,
+                        loss: fault.loss,
+                        latency_ms: fault.latency.map(|latency| latency.as_millis() as u64),
+                    }
+                }
+                Step::Clear(fault) => {
+                    info!("Chaos cleared {:?} from {}", fault.kind, fault.target);
+                    event_log::Event::FaultCleared {
+                        fault: fault.kind,
+                        target: fault.target,
+                    }
+                }
+            };
+            if let Err(e) = self.chaos_log.send(event).await {
+                warn!("Unable to record chaos event: {:?}", e);
+            }
+        }
+    }
+
+    /// When the next handover from the settings is due, if any are left
+    pub fn handover_deadline(&self) -> Option<Instant> {
+        self.handovers
+            .last()
+            .map(|handover| self.instant + Duration::from_secs(handover.after_secs))
+    }
+
+    /// Carry out the handovers which are due. Call this once the handover
+    /// deadline has passed.
+    pub async fn handovers(&mut self) {
+        while let Some(handover) = self.handovers.pop() {
+            if self.instant + Duration::from_secs(handover.after_secs) > Instant::now() {
+                self.handovers.push(handover);
+                return;
+            }
+            let labels: Vec<String> = if handover.devices.is_empty() {
+                self.devices.keys().cloned().collect()
+            } else {
+                handover.devices.clone()
+            };
+            info!(
+                "Handing {} devices over to {}",
+                labels.len(),
+                handover.packet_forwarder
+            );
+            for label in labels {
+                if let Err(e) = self.hand_over(&label, &handover.packet_forwarder).await {
+                    warn!("Unable to hand {} over: {:?}", label, e);
+                }
+            }
+        }
+    }
+
+    /// Move a running device to another packet forwarder, keeping its
+    /// session. Settings reloads leave it there unless its settings change.
+    pub async fn hand_over(&self, label: &str, packet_forwarder: &str) -> Result {
+        let transport = self
+            .packet_forwarders
+            .get(packet_forwarder)
+            .ok_or_else(|| Error::InvalidPacketForwarder(packet_forwarder.to_string()))?;
+        let device = self
+            .device(label)
+            .ok_or_else(|| Error::UnknownDevice(label.to_string()))?;
+        device
+            .control(IntermediateEvent::Handover(virtual_device::Handover {
+                label: packet_forwarder.to_string(),
+                transport: Arc::new(transport.clone()),
+            }))
+            .await
+    }
+
+    /// Power cycle a device: its task is aborted and it starts over, losing
+    /// its session
+    pub async fn reset_device(&mut self, label: &str) {
+        if let Some(running) = self.devices.remove(label) {
+            running.task.abort();
+            self.spawn_device(label.to_string(), running.device).await;
+        }
+    }
+
+    /// Wait for the next running device to stop by itself, returning its label
+    pub async fn device_stopped(&mut self) -> String {
+        while let Some(id) = self.finished.recv().await {
+            // devices which were stopped or restarted are no longer listed
+            if let Some((label, running)) = self
+                .devices
+                .iter_mut()
+                .find(|(_, running)| running.id == id)
+            {
+                running.finished = true;
+                return label.clone();
+            }
+        }
+        // self holds a sender, so the channel never closes
+        std::future::pending().await
+    }
+
+    /// A checkpoint of every running device, their sessions and the
+    /// DevNonce counters
+    pub fn checkpoint(&self) -> checkpoint::Checkpoint {
+        let mut checkpoint = checkpoint::Checkpoint {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            dev_nonces: self
+                .dev_nonces
+                .iter()
+                .map(|(dev_eui, counter)| (dev_eui.clone(), counter.load(Ordering::Relaxed)))
+                .filter(|(_, dev_nonce)| *dev_nonce > 0)
+                .collect(),
+            ..Default::default()
+        };
+        checkpoint.set_devices(
+            self.devices
+                .iter()
+                .map(|(label, running)| (label.clone(), running.handle.state()))
+                .collect(),
+        );
+        checkpoint
+    }
+
+    /// Carry on counting DevNonces from a checkpoint, so that devices which
+    /// join again don't reuse one, and carry on with the sessions of devices
+    /// which were joined. Call before applying the devices.
+    pub fn restore(&mut self, checkpoint: &checkpoint::Checkpoint) {
+        for (dev_eui, dev_nonce) in &checkpoint.dev_nonces {
+            self.dev_nonces
+                .insert(dev_eui.clone(), Arc::new(AtomicU32::new(*dev_nonce)));
+        }
+        for (label, session) in &checkpoint.sessions {
+            if let Some(state) = checkpoint.devices.get(label) {
+                self.resumes.insert(
+                    state.dev_eui.clone(),
+                    (session.clone(), state.next_fcnt_down),
+                );
+            }
+        }
+    }
+
+    /// Whether every device has stopped by itself
+    pub fn all_stopped(&self) -> bool {
+        self.devices.values().all(|running| running.finished)
+    }
+
+    /// Stop a device, giving it SHUTDOWN_GRACE to finish its in-flight exchange
+    pub async fn stop_device(&mut self, label: &str) {
+        if let Some(mut running) = self.devices.remove(label) {
+            let _ = running.shutdown.send(true);
+            let deadline = Instant::now() + SHUTDOWN_GRACE;
+            if timeout_at(deadline, &mut running.task).await.is_err() {
+                warn!("{} did not stop in time, aborting it", label);
+                running.task.abort();
+            }
+        }
+    }
+
+    /// Stop every device, giving them SHUTDOWN_GRACE to finish in-flight
+    /// exchanges, and return their final states
+    pub async fn stop(self) -> BTreeMap<String, DeviceState> {
+        for running in self.devices.values() {
+            let _ = running.shutdown.send(true);
+        }
+        let deadline = Instant::now() + SHUTDOWN_GRACE;
+        let mut device_states = BTreeMap::new();
+        let mut timed_out = false;
+        for (label, running) in self.devices {
+            if !timed_out && timeout_at(deadline, running.task).await.is_err() {
+                warn!("Timed out waiting for devices to stop");
+                timed_out = true;
+            }
+            device_states.insert(label, running.handle.state());
+        }
+        device_states
+    }
+}
+
+fn event_stream(events: broadcast::Receiver<Value>) -> impl Stream<Item = Value> + Unpin {
+    BroadcastStream::new(events).filter_map(|event| match event {
+        Ok(event) => Some(event),
+        Err(BroadcastStreamRecvError::Lagged(n)) => {
+            warn!("Event stream lagged, {} events dropped", n);
+            None
+        }
+    })
+}
+
+/// Whether a settings change can only be applied by restarting the device,
+/// which loses its session
+fn restart_required(running: &settings::Device, device: &settings::Device) -> bool {
+    running.credentials != device.credentials
+        || running.region != device.region
+        || running.server != device.server
+        || running.packet_forwarder != device.packet_forwarder
+        || running.rx_window != device.rx_window
+        || running.negative_join != device.negative_join
+        || running.data_rate != device.data_rate
+        || running.relay != device.relay
+        || running.location != device.location
+        || running.mobility != device.mobility
+        || running.battery != device.battery
+        || running.uplink_snr_db != device.uplink_snr_db
+        || running.uplink_quality != device.uplink_quality
+        || running.lorawan_version != device.lorawan_version
+        || running.payload != device.payload
+}
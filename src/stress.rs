@@ -0,0 +1,793 @@
+use crate::*;
+use rand::Rng;
+use serde::Serialize;
+use serde_json::Value;
+use settings::NegativeJoin;
+use std::sync::{Arc, Mutex};
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{interval, sleep_until},
+};
+use virtual_device::{DeviceObserver, Direction, Frame};
+
+/// How often devices due to start in a storm are started
+const START_TICK: Duration = Duration::from_millis(100);
+/// How often a scenario checks whether every device is done
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// FPending in a downlink's FCtrl
+const F_PENDING: u8 = 0x10;
+/// The MHDR and MIC around a PHYPayload's MACPayload
+const PHY_OVERHEAD: usize = 5;
+
+pub struct JoinStormOptions {
+    /// Only storm with the first devices, sorted by label
+    pub devices: Option<usize>,
+    /// Devices start at random within this long of the first
+    pub window: Duration,
+    /// Share of the devices, from 0 to 1, which join again with the DevNonce
+    /// of their first join
+    pub replayed_dev_nonces: f64,
+    /// Give up on devices which haven't joined this long after the start
+    pub timeout: Duration,
+    /// Seed the start times so that a storm can be repeated
+    pub seed: Option<u64>,
+}
+
+/// Join requests, accepts and failures in one second of a storm
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct JoinSecond {
+    pub second: u64,
+    pub requests: usize,
+    pub accepts: usize,
+    pub failures: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JoinStormReport {
+    pub devices: usize,
+    pub joined: usize,
+    /// From the start of the storm until the last device joined, if they all did
+    pub all_joined_ms: Option<u64>,
+    /// From a device's first join request until its join accept, over the
+    /// devices which joined
+    pub join_latency_p50_ms: Option<u64>,
+    pub join_latency_p95_ms: Option<u64>,
+    pub join_latency_max_ms: Option<u64>,
+    /// Replayed DevNonces the network server rightly ignored
+    pub replays_rejected: usize,
+    /// Failures by cause
+    pub failures: BTreeMap<String, usize>,
+    pub seconds: Vec<JoinSecond>,
+}
+
+impl JoinStormReport {
+    pub fn peak_accepts_per_sec(&self) -> usize {
+        self.seconds
+            .iter()
+            .map(|second| second.accepts)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+pub struct DownlinkFloodOptions {
+    /// Only flood the first devices, sorted by label
+    pub devices: Option<usize>,
+    /// Downlinks the application queues for each device
+    pub downlinks: usize,
+    /// Bytes at the start of each downlink's payload numbering it in the
+    /// device's queue, big endian from 0, or 0 if downlinks aren't numbered
+    pub sequence_bytes: usize,
+    /// Seconds between the devices' uplinks, each opening RX windows for the
+    /// network server to send the next downlink in
+    pub secs_between_transmits: u64,
+    /// Give up on devices which haven't had every downlink this long after
+    /// the start
+    pub timeout: Duration,
+}
+
+/// How the downlinks queued for one device arrived
+#[derive(Serialize, Debug, Default)]
+pub struct FloodDevice {
+    pub received: usize,
+    /// Downlinks which arrived with FPending set
+    pub f_pending: usize,
+    /// Most downlinks in a row each announced by FPending on the one before
+    pub longest_chain: usize,
+    /// Downlinks without FPending which more downlinks followed anyway
+    pub f_pending_missing: usize,
+    /// The last downlink had FPending set, though nothing followed
+    pub f_pending_left_set: bool,
+    /// FCntDowns skipped, downlinks the network server sent which never
+    /// arrived
+    pub fcnt_gaps: u32,
+    /// Numbered downlinks which arrived after one numbered later
+    pub out_of_order: usize,
+    /// Downlinks not received, by their numbers if they are numbered
+    pub missing: usize,
+    /// Numbered downlinks received more than once
+    pub duplicates: usize,
+    /// Downlinks whose MACPayload was over the limit of the data rate they
+    /// were sent at
+    pub over_limit: usize,
+}
+
+impl FloodDevice {
+    fn failed(&self) -> bool {
+        self.missing > 0
+            || self.out_of_order > 0
+            || self.duplicates > 0
+            || self.over_limit > 0
+            || self.f_pending_missing > 0
+            || self.f_pending_left_set
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct DownlinkFloodReport {
+    /// Downlinks queued for each device
+    pub downlinks: usize,
+    pub devices: BTreeMap<String, FloodDevice>,
+}
+
+impl DownlinkFloodReport {
+    /// Devices which missed downlinks, had them out of order, over the
+    /// payload limit or with FPending set wrongly
+    pub fn failed(&self) -> usize {
+        self.devices
+            .values()
+            .filter(|device| device.failed())
+            .count()
+    }
+}
+
+/// A downlink as the device accepted it
+struct Received {
+    fcnt: u32,
+    f_pending: bool,
+    sequence: Option<u64>,
+    mac_payload: usize,
+    limit: Option<usize>,
+}
+
+/// Collects the downlinks every device accepts during a flood
+struct FloodObserver {
+    regions: HashMap<String, settings::Region>,
+    sequence_bytes: usize,
+    received: Mutex<HashMap<String, Vec<Received>>>,
+}
+
+impl DeviceObserver for FloodObserver {
+    fn on_frame(&self, device: &str, frame: &Frame) {
+        // join accepts have no FCntDown
+        let fcnt = match (&frame.direction, frame.fcnt) {
+            (Direction::Downlink, Some(fcnt)) => fcnt,
+            _ => return,
+        };
+        let sequence = frame
+            .payload
+            .get(..self.sequence_bytes)
+            .filter(|bytes| !bytes.is_empty())
+            .map(|bytes| {
+                bytes
+                    .iter()
+                    .fold(0, |sequence, byte| (sequence << 8) | u64::from(*byte))
+            });
+        let limit = match (self.regions.get(device), frame.datr) {
+            (Some(region), Some(datr)) => region.max_downlink_mac_payload(datr),
+            _ => None,
+        };
+        let received = Received {
+            fcnt,
+            f_pending: frame
+                .phy_payload
+                .get(5)
+                .is_some_and(|fctrl| fctrl & F_PENDING != 0),
+            sequence,
+            mac_payload: frame.phy_payload.len().saturating_sub(PHY_OVERHEAD),
+            limit,
+        };
+        self.received
+            .lock()
+            .expect("flood lock")
+            .entry(device.to_string())
+            .or_default()
+            .push(received);
+    }
+}
+
+impl FloodObserver {
+    fn devices_done(&self, downlinks: usize) -> usize {
+        self.received
+            .lock()
+            .expect("flood lock")
+            .values()
+            .filter(|received| received.len() >= downlinks)
+            .count()
+    }
+}
+
+/// Run the devices with a short time between uplinks while the application
+/// side queues downlinks for them, and follow how the downlinks arrive until
+/// every device has had them all or the timeout passes. Numbered downlinks
+/// are checked for order, drops and duplicates. The downlinks are best all
+/// queued before the first is sent, or FPending can't be judged.
+pub async fn downlink_flood(
+    settings: &settings::Settings,
+    flood: DownlinkFloodOptions,
+    options: simulation::Options,
+) -> Result<DownlinkFloodReport> {
+    let mut labels: Vec<&String> = settings.device.keys().collect();
+    labels.sort();
+    labels.truncate(flood.devices.unwrap_or(usize::MAX));
+    let count = labels.len();
+    let devices: HashMap<String, settings::Device> = labels
+        .into_iter()
+        .map(|label| {
+            let mut device = settings.device[label].clone();
+            device.secs_between_transmits = flood.secs_between_transmits;
+            (label.clone(), device)
+        })
+        .collect();
+    let observer = Arc::new(FloodObserver {
+        regions: devices
+            .iter()
+            .map(|(label, device)| (label.clone(), device.region.clone()))
+            .collect(),
+        sequence_bytes: flood.sequence_bytes,
+        received: Mutex::default(),
+    });
+
+    let mut simulation = Simulation::new(settings, options)?;
+    simulation.observe(observer.clone());
+    info!(
+        "Waiting for {} downlinks to each of {} devices, queue them with the application",
+        flood.downlinks, count
+    );
+    simulation.apply(devices).await;
+    let start = Instant::now();
+    let mut check = interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = check.tick() => {
+                if observer.devices_done(flood.downlinks) == count {
+                    info!("Every device has had its downlinks");
+                    break;
+                }
+            }
+            _ = sleep_until(start + flood.timeout) => {
+                warn!(
+                    "Downlink flood timed out with {} devices still waiting",
+                    count - observer.devices_done(flood.downlinks)
+                );
+                break;
+            }
+        }
+    }
+    let states = simulation.stop().await;
+
+    let mut received = std::mem::take(&mut *observer.received.lock().expect("flood lock"));
+    let devices = states
+        .into_keys()
+        .map(|label| {
+            let received = received.remove(&label).unwrap_or_default();
+            let device = flooded(&received, flood.downlinks, flood.sequence_bytes > 0);
+            (label, device)
+        })
+        .collect();
+    Ok(DownlinkFloodReport {
+        downlinks: flood.downlinks,
+        devices,
+    })
+}
+
+fn flooded(received: &[Received], downlinks: usize, numbered: bool) -> FloodDevice {
+    let mut device = FloodDevice {
+        received: received.len(),
+        f_pending: received
+            .iter()
+            .filter(|downlink| downlink.f_pending)
+            .count(),
+        f_pending_left_set: received.last().is_some_and(|downlink| downlink.f_pending),
+        over_limit: received
+            .iter()
+            .filter(|downlink| {
+                downlink
+                    .limit
+                    .is_some_and(|limit| downlink.mac_payload > limit)
+            })
+            .count(),
+        ..Default::default()
+    };
+    let mut chain = received.len().min(1);
+    device.longest_chain = chain;
+    for pair in received.windows(2) {
+        let (previous, next) = (&pair[0], &pair[1]);
+        if previous.f_pending {
+            chain += 1;
+        } else {
+            device.f_pending_missing += 1;
+            chain = 1;
+        }
+        device.longest_chain = device.longest_chain.max(chain);
+        if next.fcnt > previous.fcnt {
+            device.fcnt_gaps += next.fcnt - previous.fcnt - 1;
+        }
+        if let (Some(previous), Some(next)) = (previous.sequence, next.sequence) {
+            if next < previous {
+                device.out_of_order += 1;
+            }
+        }
+    }
+    if numbered {
+        let mut seen: BTreeMap<u64, usize> = BTreeMap::new();
+        for sequence in received.iter().filter_map(|downlink| downlink.sequence) {
+            *seen.entry(sequence).or_default() += 1;
+        }
+        device.missing = (0..downlinks as u64)
+            .filter(|sequence| !seen.contains_key(sequence))
+            .count();
+        device.duplicates = seen.values().map(|count| count - 1).sum();
+    } else {
+        device.missing = downlinks.saturating_sub(received.len());
+    }
+    device
+}
+
+pub struct AdrConvergenceOptions {
+    /// Only run the first devices, sorted by label
+    pub devices: Option<usize>,
+    /// SNR the devices' uplinks are received with, good enough for SF7
+    pub uplink_snr_db: f32,
+    pub secs_between_transmits: u64,
+    /// Give up on devices which haven't converged this long after the start
+    pub timeout: Duration,
+}
+
+/// How ADR took one device from the slowest data rate to the fastest
+#[derive(Serialize, Debug, Default)]
+pub struct AdrDevice {
+    /// The data rate ADR is to bring the device to, SF7 at 125 kHz
+    pub target: Option<u8>,
+    /// Uplinks and seconds from the first uplink of the session until the
+    /// first at the target
+    pub converged_uplinks: Option<usize>,
+    pub converged_secs: Option<u64>,
+    /// The data rates uplinks were sent at, each once in turn
+    pub data_rates: Vec<u8>,
+    pub link_adr_reqs: usize,
+    /// NbTrans the last LinkADRReq to set it left the device with
+    pub nb_trans: Option<u8>,
+    /// Channel masks and NbTrans which don't make sense for a device with a
+    /// good link
+    pub problems: Vec<String>,
+}
+
+impl AdrDevice {
+    fn failed(&self) -> bool {
+        self.converged_uplinks.is_none() || !self.problems.is_empty()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct AdrConvergenceReport {
+    pub devices: BTreeMap<String, AdrDevice>,
+}
+
+impl AdrConvergenceReport {
+    /// Devices which didn't converge, or were sent nonsense on the way
+    pub fn failed(&self) -> usize {
+        self.devices
+            .values()
+            .filter(|device| device.failed())
+            .count()
+    }
+}
+
+/// The fastest data rate at 125 kHz, which ADR should bring a device with a
+/// good link to
+fn adr_target(region: &settings::Region) -> Option<u8> {
+    region.data_rate("SF7BW125")
+}
+
+fn at_target(data_rate: Option<u8>, target: Option<u8>) -> bool {
+    matches!((data_rate, target), (Some(data_rate), Some(target)) if data_rate >= target)
+}
+
+/// What doesn't make sense in a LinkADRReq's channel mask
+fn ch_mask_problem(region: &settings::Region, ch_mask: u16, ch_mask_cntl: u8) -> Option<String> {
+    match (region, ch_mask_cntl) {
+        (settings::Region::EU868, 0) if ch_mask & 0b111 != 0b111 => {
+            Some(format!("ChMask {:04X} disables a default channel", ch_mask))
+        }
+        (settings::Region::EU868, 0 | 6) | (settings::Region::US915, 0..=3 | 5 | 6) => None,
+        (settings::Region::US915, 7) if ch_mask == 0 => {
+            Some("ChMaskCntl 7 with ChMask 0000 disables every channel".to_string())
+        }
+        (settings::Region::US915, 7) => None,
+        (_, ch_mask_cntl) => Some(format!("ChMaskCntl {} is RFU", ch_mask_cntl)),
+    }
+}
+
+/// When since the start each device sent its uplinks, and at which data rate
+type UplinkDataRates = HashMap<String, Vec<(Duration, Option<u8>)>>;
+
+/// The data rate of each uplink the devices send, since the start
+struct AdrObserver {
+    start: Instant,
+    regions: HashMap<String, settings::Region>,
+    uplinks: Mutex<UplinkDataRates>,
+}
+
+impl DeviceObserver for AdrObserver {
+    fn on_frame(&self, device: &str, frame: &Frame) {
+        // join requests have no FCnt
+        if !matches!(frame.direction, Direction::Uplink) || frame.fcnt.is_none() {
+            return;
+        }
+        let data_rate = match (self.regions.get(device), frame.datr) {
+            (Some(region), Some(datr)) => region.data_rate(datr),
+            _ => None,
+        };
+        self.uplinks
+            .lock()
+            .expect("adr lock")
+            .entry(device.to_string())
+            .or_default()
+            .push((self.start.elapsed(), data_rate));
+    }
+}
+
+impl AdrObserver {
+    fn devices_converged(&self) -> usize {
+        self.uplinks
+            .lock()
+            .expect("adr lock")
+            .iter()
+            .filter(|(label, uplinks)| {
+                let target = self.regions.get(*label).and_then(adr_target);
+                uplinks
+                    .iter()
+                    .any(|(_, data_rate)| at_target(*data_rate, target))
+            })
+            .count()
+    }
+}
+
+/// Run the devices from the slowest data rate with a link good enough for
+/// the fastest, and follow ADR until the network server has moved every
+/// device to SF7 or the timeout passes. The LinkADRReqs on the way are
+/// checked for channel masks and NbTrans which make no sense for such a link.
+pub async fn adr_convergence(
+    settings: &settings::Settings,
+    adr: AdrConvergenceOptions,
+    options: simulation::Options,
+) -> Result<AdrConvergenceReport> {
+    let mut labels: Vec<&String> = settings.device.keys().collect();
+    labels.sort();
+    labels.truncate(adr.devices.unwrap_or(usize::MAX));
+    let count = labels.len();
+    let devices: HashMap<String, settings::Device> = labels
+        .into_iter()
+        .map(|label| {
+            let mut device = settings.device[label].clone();
+            device.data_rate = Some(0);
+            device.uplink_snr_db = adr.uplink_snr_db;
+            device.secs_between_transmits = adr.secs_between_transmits;
+            (label.clone(), device)
+        })
+        .collect();
+    let regions: HashMap<String, settings::Region> = devices
+        .iter()
+        .map(|(label, device)| (label.clone(), device.region.clone()))
+        .collect();
+    let observer = Arc::new(AdrObserver {
+        start: Instant::now(),
+        regions: regions.clone(),
+        uplinks: Mutex::default(),
+    });
+
+    let mut simulation = Simulation::new(settings, options)?;
+    simulation.observe(observer.clone());
+    let mut events = simulation.subscribe();
+    info!("Starting {} devices at DR0 for ADR to converge", count);
+    simulation.apply(devices).await;
+    let start = Instant::now();
+    let mut link_adr_reqs: HashMap<String, Vec<Value>> = HashMap::new();
+    let mut check = interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = check.tick() => {
+                if observer.devices_converged() == count {
+                    info!("Every device is at SF7");
+                    break;
+                }
+            }
+            event = events.recv() => match event {
+                Ok(event) if event["event"] == "link_adr_req" => {
+                    let device = event["device"].as_str().unwrap_or_default().to_string();
+                    link_adr_reqs.entry(device).or_default().push(event);
+                }
+                Ok(_) => (),
+                Err(RecvError::Lagged(n)) => {
+                    warn!("ADR convergence lagged, {} events dropped", n)
+                }
+                // the simulation holds the sender until it is stopped
+                Err(RecvError::Closed) => unreachable!(),
+            },
+            _ = sleep_until(start + adr.timeout) => {
+                warn!(
+                    "ADR convergence timed out with {} devices short of SF7",
+                    count - observer.devices_converged()
+                );
+                break;
+            }
+        }
+    }
+    let states = simulation.stop().await;
+
+    let mut uplinks = std::mem::take(&mut *observer.uplinks.lock().expect("adr lock"));
+    let devices = states
+        .into_keys()
+        .map(|label| {
+            let region = &regions[&label];
+            let uplinks = uplinks.remove(&label).unwrap_or_default();
+            let requests = link_adr_reqs.remove(&label).unwrap_or_default();
+            let device = converged(region, &uplinks, &requests);
+            (label, device)
+        })
+        .collect();
+    Ok(AdrConvergenceReport { devices })
+}
+
+fn converged(
+    region: &settings::Region,
+    uplinks: &[(Duration, Option<u8>)],
+    link_adr_reqs: &[Value],
+) -> AdrDevice {
+    let target = adr_target(region);
+    let mut device = AdrDevice {
+        target,
+        link_adr_reqs: link_adr_reqs.len(),
+        ..Default::default()
+    };
+    for data_rate in uplinks.iter().filter_map(|(_, data_rate)| *data_rate) {
+        if device.data_rates.last() != Some(&data_rate) {
+            device.data_rates.push(data_rate);
+        }
+    }
+    if let (Some(first), Some(at_target)) = (
+        uplinks.first(),
+        uplinks
+            .iter()
+            .position(|(_, data_rate)| at_target(*data_rate, target)),
+    ) {
+        device.converged_uplinks = Some(at_target + 1);
+        device.converged_secs = Some((uplinks[at_target].0 - first.0).as_secs());
+    }
+    for request in link_adr_reqs {
+        let ch_mask = request["ch_mask"]
+            .as_str()
+            .and_then(|ch_mask| u16::from_str_radix(ch_mask, 16).ok())
+            .unwrap_or_default();
+        let ch_mask_cntl = request["ch_mask_cntl"].as_u64().unwrap_or_default() as u8;
+        if let Some(problem) = ch_mask_problem(region, ch_mask, ch_mask_cntl) {
+            device.problems.push(problem);
+        }
+        if let Some(nb_trans) = request["nb_trans"].as_u64() {
+            device.nb_trans = Some(nb_trans as u8);
+        }
+    }
+    // every transmission gets through on a good link, so repeats only
+    // take airtime
+    if let Some(nb_trans) = device.nb_trans.filter(|nb_trans| *nb_trans > 1) {
+        device.problems.push(format!(
+            "NbTrans {} on a link which needs no repeats",
+            nb_trans
+        ));
+    }
+    device.problems.dedup();
+    device
+}
+
+/// How a device in the storm has fared so far
+#[derive(Default)]
+struct Progress {
+    first_request_us: Option<u64>,
+    joined_us: Option<u64>,
+    replays: bool,
+    /// the replayed join has been accepted or ignored
+    replay_done: bool,
+    /// the join_fail which follows a rejected replay isn't a failure
+    replay_rejected: bool,
+}
+
+impl Progress {
+    fn done(&self) -> bool {
+        self.joined_us.is_some() && (!self.replays || self.replay_done)
+    }
+}
+
+/// Start the devices at random within a short window, so they all try to
+/// join at once, and follow their joins until every device has joined or the
+/// timeout passes. Their credentials must be registered with the network
+/// server.
+pub async fn join_storm(
+    settings: &settings::Settings,
+    storm: JoinStormOptions,
+    options: simulation::Options,
+) -> Result<JoinStormReport> {
+    let mut labels: Vec<&String> = settings.device.keys().collect();
+    labels.sort();
+    labels.truncate(storm.devices.unwrap_or(usize::MAX));
+    let count = labels.len();
+    let window_ms = storm.window.as_millis() as u64;
+
+    // replaying devices are spread evenly through the fleet
+    let mut starts: Vec<(Duration, String, settings::Device)> = labels
+        .into_iter()
+        .enumerate()
+        .map(|(i, label)| {
+            let mut device = settings.device[label].clone();
+            let replays = ((i + 1) as f64 * storm.replayed_dev_nonces).floor()
+                > (i as f64 * storm.replayed_dev_nonces).floor();
+            if replays {
+                device.negative_join = Some(NegativeJoin::ReplayedDevNonce);
+            }
+            let offset = rng::device_rng(storm.seed, label).gen_range(0..=window_ms);
+            (Duration::from_millis(offset), label.clone(), device)
+        })
+        .collect();
+    starts.sort_by_key(|(offset, ..)| *offset);
+    let mut progress: HashMap<String, Progress> = starts
+        .iter()
+        .map(|(_, label, device)| {
+            let progress = Progress {
+                replays: device.negative_join == Some(NegativeJoin::ReplayedDevNonce),
+                ..Default::default()
+            };
+            (label.clone(), progress)
+        })
+        .collect();
+    info!(
+        "Starting a join storm of {} devices within {:?}",
+        count, storm.window
+    );
+
+    let mut simulation = Simulation::new(settings, options)?;
+    let mut events = simulation.subscribe();
+    let start = Instant::now();
+    let start_us = simulation.instant().elapsed().as_micros() as u64;
+    let mut seconds: Vec<JoinSecond> = Vec::new();
+    let mut failures: BTreeMap<String, usize> = BTreeMap::new();
+    let mut replays_rejected = 0;
+    let mut running = HashMap::new();
+    let mut starts = starts.into_iter().peekable();
+    let mut start_timer = interval(START_TICK);
+    let mut remaining = count;
+
+    while remaining > 0 {
+        tokio::select! {
+            _ = start_timer.tick(), if starts.peek().is_some() => {
+                let due = start.elapsed();
+                let mut started = false;
+                while let Some((_, label, device)) =
+                    starts.next_if(|(offset, ..)| *offset <= due)
+                {
+                    running.insert(label, device);
+                    started = true;
+                }
+                if started {
+                    simulation.apply(running.clone()).await;
+                }
+            }
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("Join storm lagged, {} events dropped", n);
+                        continue;
+                    }
+                    // the simulation holds the sender until it is stopped
+                    Err(RecvError::Closed) => unreachable!(),
+                };
+                let device = event["device"]
+                    .as_str()
+                    .and_then(|device| progress.get_mut(device));
+                let device = match device {
+                    Some(device) => device,
+                    None => continue,
+                };
+                let was_done = device.done();
+                let elapsed_us = event["elapsed_us"]
+                    .as_u64()
+                    .unwrap_or_default()
+                    .saturating_sub(start_us);
+                let second = (elapsed_us / 1_000_000) as usize;
+                if seconds.len() <= second {
+                    seconds.extend((seconds.len()..=second).map(|second| JoinSecond {
+                        second: second as u64,
+                        ..Default::default()
+                    }));
+                }
+                let failure = match event["event"].as_str() {
+                    Some("join_request") => {
+                        device.first_request_us.get_or_insert(elapsed_us);
+                        seconds[second].requests += 1;
+                        None
+                    }
+                    Some("join_success") => {
+                        device.joined_us.get_or_insert(elapsed_us);
+                        seconds[second].accepts += 1;
+                        None
+                    }
+                    Some("join_rejected") => {
+                        device.replay_done = true;
+                        device.replay_rejected = true;
+                        replays_rejected += 1;
+                        None
+                    }
+                    Some("join_fail") if device.replay_rejected => {
+                        device.replay_rejected = false;
+                        None
+                    }
+                    Some("join_fail") => Some("no join accept".to_string()),
+                    Some("negative_join_accepted") => {
+                        device.replay_done = true;
+                        Some("replayed DevNonce accepted".to_string())
+                    }
+                    Some("rx_timing_violation") if device.joined_us.is_none() => {
+                        Some("join accept outside the RX windows".to_string())
+                    }
+                    Some("error") => Some(format!(
+                        "error: {}",
+                        event["message"].as_str().unwrap_or_default()
+                    )),
+                    _ => None,
+                };
+                if let Some(failure) = failure {
+                    seconds[second].failures += 1;
+                    *failures.entry(failure).or_default() += 1;
+                }
+                if !was_done && device.done() {
+                    remaining -= 1;
+                }
+            }
+            _ = sleep_until(start + storm.timeout) => {
+                warn!("Join storm timed out with {} devices not done", remaining);
+                break;
+            }
+        }
+    }
+    simulation.stop().await;
+
+    let mut latencies_ms: Vec<u64> = progress
+        .values()
+        .filter_map(|device| Some((device.joined_us? - device.first_request_us?) / 1000))
+        .collect();
+    latencies_ms.sort_unstable();
+    let percentile = |share: f64| {
+        let last = latencies_ms.len().checked_sub(1)?;
+        Some(latencies_ms[(last as f64 * share).round() as usize])
+    };
+    let joined = latencies_ms.len();
+    Ok(JoinStormReport {
+        devices: count,
+        joined,
+        all_joined_ms: (joined == count)
+            .then(|| {
+                progress
+                    .values()
+                    .filter_map(|device| device.joined_us)
+                    .max()
+            })
+            .flatten()
+            .map(|us| us / 1000),
+        join_latency_p50_ms: percentile(0.5),
+        join_latency_p95_ms: percentile(0.95),
+        join_latency_max_ms: latencies_ms.last().copied(),
+        replays_rejected,
+        failures,
+        seconds,
+    })
+}
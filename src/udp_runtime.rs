@@ -0,0 +1,1224 @@
+use super::*;
+use error::{Error, Result};
+use gateway_clock::GatewayClock;
+#[cfg(target_os = "linux")]
+use nix::sys::socket::{recvmmsg, sendmmsg, ControlMessage, MsgFlags, MultiHeaders};
+use rand::{rngs::StdRng, Rng};
+use semtech_udp::{
+    client_runtime::TxMessage, parser::Parser, pull_data, pull_resp, push_data, Identifier,
+    MacAddress, ParseError, SerializablePacket, Up,
+};
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+#[cfg(target_os = "linux")]
+use std::{
+    io::{IoSlice, IoSliceMut},
+    os::fd::AsRawFd,
+};
+use tokio::{
+    io::Interest,
+    net::UdpSocket,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, watch,
+    },
+    time::{interval_at, sleep, Duration, Instant},
+};
+use virtual_device::Delivery;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How often a stat is sent, as the Semtech packet forwarder does by default
+const STAT_INTERVAL: Duration = Duration::from_secs(30);
+/// How often a hostname is resolved again while connected, so a network
+/// server moved by DNS failover or load balancing is followed
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a network server has to ack something before the window counts
+/// as missed for failover, the keepalive interval of the Semtech packet
+/// forwarder's PULL_DATA
+const ACK_WINDOW: Duration = Duration::from_secs(10);
+/// Most rxpk in one PUSH_DATA from the Semtech packet forwarder, which
+/// fetches at most this many packets from the concentrator at a time
+const MAX_RXPK_PER_PUSH: usize = 8;
+/// Largest datagram sent or received, sized as the Semtech packet
+/// forwarder's TX buffer for a full PUSH_DATA and a stat
+const DATAGRAM_SIZE: usize = 540 * MAX_RXPK_PER_PUSH + 30 + 200;
+/// Most datagrams sent or received in one syscall
+const MAX_BATCH: usize = 64;
+/// Where a GWMP frame says which of them it is
+const IDENTIFIER_INDEX: usize = 3;
+/// The GPS epoch, 1980-01-06, in milliseconds since the Unix epoch
+const GPS_EPOCH_MS: u64 = 315_964_800_000;
+/// How far GPS time runs ahead of UTC, by the leap seconds since its epoch
+const GPS_LEAP_MS: u64 = 18_000;
+
+/// Talks GWMP to the network server for one packet forwarder. Devices
+/// subscribe and publish through channels owned here, so when the socket
+/// fails the connection can be made again (re-resolving the host) without
+/// the devices noticing anything beyond the outage itself.
+///
+/// Impairments set through a handle degrade the link to the network server:
+/// packets may be dropped or uplinks delayed, and while offline the runtime
+/// disconnects altogether, as a gateway losing its backhaul would.
+pub struct Runtime {
+    label: String,
+    mac: [u8; 8],
+    /// the network server in use, which failing over swaps with the backup
+    host: String,
+    failover: Option<Failover>,
+    /// ack windows missed and connections failed in a row by the server in
+    /// use, for failing over
+    missed_acks: u32,
+    mirror_host: Option<String>,
+    /// copies of uplinks for the mirror, once running
+    mirror: Option<mpsc::Sender<TxMessage>>,
+    /// 0 to send from any free port
+    local_port: u16,
+    clock: GatewayClock,
+    concentrator: Arc<Concentrator>,
+    metrics_sender: metrics::Sender,
+    /// shared rather than cloned for every device listening, most of which
+    /// drop it
+    downlink_sender: broadcast::Sender<Arc<semtech_udp::Packet>>,
+    uplink_sender: mpsc::Sender<TxMessage>,
+    uplink_receiver: mpsc::Receiver<TxMessage>,
+    ack_sender: mpsc::Sender<TxMessage>,
+    ack_receiver: mpsc::Receiver<TxMessage>,
+    counters: Arc<Counters>,
+    impairments_sender: Arc<watch::Sender<Impairments>>,
+    impairments: watch::Receiver<Impairments>,
+    /// draws which packets a lossy link drops, seeded for reproducible runs
+    loss_rng: StdRng,
+}
+
+/// The network servers a packet forwarder sends to
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    pub host: String,
+    pub failover: Option<Failover>,
+    /// Sent a copy of every uplink, its downlinks dropped
+    pub mirror: Option<String>,
+}
+
+/// The network server a packet forwarder switches to when the one in use
+/// stops acking, and back again should that one stop too
+#[derive(Clone, Debug)]
+pub struct Failover {
+    pub host: String,
+    /// ACK_WINDOWs in a row without an ack that trigger the switch, each
+    /// failed attempt to connect counting as one
+    pub missed_acks: u32,
+}
+
+/// Ways the link to the network server is degraded, none by default
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Impairments {
+    /// Share of packets dropped, both ways
+    pub loss: f64,
+    /// Extra delay before uplinks are sent on
+    pub latency: Duration,
+    pub offline: bool,
+}
+
+impl Impairments {
+    /// Whether to drop the packet at hand
+    fn drop_packet(&self, rng: &mut StdRng) -> bool {
+        self.offline || (self.loss > 0.0 && rng.gen::<f64>() < self.loss)
+    }
+}
+
+impl Runtime {
+    pub fn new(
+        label: String,
+        mac: [u8; 8],
+        upstream: Upstream,
+        local_port: u16,
+        clock: GatewayClock,
+        concentrator: Concentrator,
+        metrics_sender: metrics::Sender,
+    ) -> Runtime {
+        let (downlink_sender, _) = broadcast::channel(1024);
+        let (uplink_sender, uplink_receiver) = mpsc::channel(1024);
+        let (ack_sender, ack_receiver) = mpsc::channel(1024);
+        let (impairments_sender, impairments) = watch::channel(Impairments::default());
+        Runtime {
+            label,
+            mac,
+            host: upstream.host,
+            failover: upstream.failover,
+            missed_acks: 0,
+            mirror_host: upstream.mirror,
+            mirror: None,
+            local_port,
+            clock,
+            concentrator: Arc::new(concentrator),
+            metrics_sender,
+            downlink_sender,
+            uplink_sender,
+            uplink_receiver,
+            ack_sender,
+            ack_receiver,
+            counters: Arc::default(),
+            impairments_sender: Arc::new(impairments_sender),
+            impairments,
+            loss_rng: rng::device_rng(None, ""),
+        }
+    }
+
+    /// Derive the packets a lossy link drops from the simulation seed, so
+    /// that a seeded run drops the same ones again
+    pub fn seed(mut self, seed: Option<u64>) -> Runtime {
+        self.loss_rng = rng::device_rng(seed, &format!("{}-impairments", self.label));
+        self
+    }
+
+    /// Handle for devices to talk through this packet forwarder. It stays
+    /// usable after the runtime has been spawned.
+    pub fn handle(&self) -> Handle {
+        Handle {
+            clock: self.clock,
+            concentrator: self.concentrator.clone(),
+            downlink_sender: self.downlink_sender.clone(),
+            uplink_sender: self.uplink_sender.clone(),
+            ack_sender: self.ack_sender.clone(),
+            counters: self.counters.clone(),
+            impairments: self.impairments_sender.clone(),
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        if let Some(host) = self.mirror_host.clone() {
+            let (sender, uplinks) = mpsc::channel(1024);
+            tokio::spawn(mirror(self.label.clone(), self.mac, host, uplinks));
+            self.mirror = Some(sender);
+        }
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match self.connect_and_run(&mut backoff).await {
+                // taken offline on purpose, so reconnect without backing off
+                Ok(()) => {
+                    info!("Packet forwarder {} offline", self.label);
+                    self.metrics_sender
+                        .send(metrics::Message::Connected(false))
+                        .await?;
+                    self.drop_uplinks_while_offline().await;
+                    info!("Packet forwarder {} back online", self.label);
+                    continue;
+                }
+                // switched to the other server, which is tried straight away
+                Err(Error::FailedOver(from)) => {
+                    warn!(
+                        "Packet forwarder {} failing over from {} to {}",
+                        self.label, from, self.host
+                    );
+                    self.metrics_sender
+                        .send(metrics::Message::Connected(false))
+                        .await?;
+                    self.metrics_sender.send(metrics::Message::Failover).await?;
+                    continue;
+                }
+                // the host resolves elsewhere now, which is connected to straight away
+                Err(Error::HostMoved(host, from)) => {
+                    info!(
+                        "Packet forwarder {} following {} away from {}",
+                        self.label, host, from
+                    );
+                    self.metrics_sender
+                        .send(metrics::Message::Connected(false))
+                        .await?;
+                    continue;
+                }
+                Err(e) => warn!(
+                    "Packet forwarder {} lost connection to {}: {:?}. Reconnecting in {:?}",
+                    self.label, self.host, e, backoff
+                ),
+            }
+            self.metrics_sender
+                .send(metrics::Message::Connected(false))
+                .await?;
+            self.metrics_sender
+                .send(metrics::Message::UdpReconnect)
+                .await?;
+            self.drop_uplinks_for(backoff).await;
+            backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+        }
+    }
+
+    /// Hand the mirror a copy of a PUSH_DATA as it goes to the network
+    /// server, dropping it should the mirror fall behind
+    fn copy_to_mirror(&self, uplink: &TxMessage) {
+        if let (Some(mirror), semtech_udp::Packet::Up(Up::PushData(packet))) =
+            (&self.mirror, uplink)
+        {
+            if mirror.try_send(packet.clone().into()).is_err() {
+                debug!("Packet forwarder {} mirror can't keep up", self.label);
+            }
+        }
+    }
+
+    /// Connect and forward packets until the connection fails, or until the
+    /// runtime is taken offline, which returns Ok
+    async fn connect_and_run(&mut self, backoff: &mut Duration) -> Result<()> {
+        if self.impairments.borrow().offline {
+            return Ok(());
+        }
+        // the host is resolved again on every connection attempt, and while
+        // connected every RESOLVE_INTERVAL
+        let (connection, server) =
+            match connect(&self.label, self.mac, &self.host, self.local_port).await {
+                Ok(connected) => connected,
+                // a server that can't be resolved or reached is as gone as one
+                // that has stopped acking
+                Err(e) => {
+                    self.missed_acks += 1;
+                    self.fail_over()?;
+                    return Err(e);
+                }
+            };
+        let moved = moved(self.host.clone(), server);
+        tokio::pin!(moved);
+        *backoff = MIN_BACKOFF;
+        // only connected once the network server answers, which it does to
+        // the PULL_DATA keepalives sent from the start
+        let mut acked = false;
+
+        let mut keepalive_timer = interval_at(Instant::now(), ACK_WINDOW);
+        let mut stat_timer = interval_at(Instant::now() + STAT_INTERVAL, STAT_INTERVAL);
+        let mut ack_timer = interval_at(Instant::now() + ACK_WINDOW, ACK_WINDOW);
+        let mut acked_in_window = false;
+
+        loop {
+            tokio::select! {
+                _ = keepalive_timer.tick() => {
+                    connection.send(pull_data::Packet::new(0).into()).await?
+                }
+                _ = &mut moved => {
+                    return Err(Error::HostMoved(self.host.clone(), server));
+                }
+                // we hold a sender ourselves so the impairments never close
+                _ = self.impairments.changed() => {
+                    if self.impairments.borrow().offline {
+                        return Ok(());
+                    }
+                }
+                // we hold a sender ourselves so the uplink channel never closes
+                Some(uplink) = self.uplink_receiver.recv() => {
+                    // uplinks from devices sending in the same tick share a syscall
+                    let mut uplinks = vec![uplink];
+                    while uplinks.len() < MAX_BATCH {
+                        match self.uplink_receiver.try_recv() {
+                            Ok(uplink) => uplinks.push(uplink),
+                            Err(_) => break,
+                        }
+                    }
+                    let mut batch = Vec::with_capacity(uplinks.len());
+                    for uplink in uplinks {
+                        let impairments = *self.impairments.borrow();
+                        if impairments.drop_packet(&mut self.loss_rng) {
+                            debug!("Packet forwarder {} dropping uplink", self.label);
+                            continue;
+                        }
+                        self.counters.forwarded(&uplink);
+                        self.copy_to_mirror(&uplink);
+                        if impairments.latency > Duration::ZERO {
+                            let connection = connection.clone();
+                            tokio::spawn(async move {
+                                sleep(impairments.latency).await;
+                                let _ = connection.send(uplink).await;
+                            });
+                        } else {
+                            batch.push(uplink)
+                        }
+                    }
+                    connection.send_batch(batch).await?
+                }
+                // acks aren't delayed, the concentrator reports the TX at once
+                Some(ack) = self.ack_receiver.recv() => {
+                    if self.impairments.borrow().drop_packet(&mut self.loss_rng) {
+                        debug!("Packet forwarder {} dropping TX_ACK", self.label);
+                    } else {
+                        connection.send(ack).await?
+                    }
+                }
+                _ = ack_timer.tick(), if self.failover.is_some() => {
+                    self.missed_acks = if acked_in_window { 0 } else { self.missed_acks + 1 };
+                    acked_in_window = false;
+                    self.fail_over()?;
+                }
+                _ = stat_timer.tick() => {
+                    let stat: TxMessage =
+                        self.counters.stat(self.mac, self.concentrator.location)?.into();
+                    // like any other PUSH_DATA, the stat is lost to a lossy link
+                    if self.impairments.borrow().drop_packet(&mut self.loss_rng) {
+                        debug!("Packet forwarder {} dropping stat", self.label);
+                    } else {
+                        self.copy_to_mirror(&stat);
+                        connection.send(stat).await?
+                    }
+                }
+                datagrams = connection.recv_batch() => for datagram in datagrams? {
+                    let downlink = parse_downlink(&datagram);
+                    // eg: an empty payload, which semtech_udp parses but no device could receive
+                    let malformed = match &downlink {
+                        Ok(semtech_udp::Packet::Down(semtech_udp::Down::PullResp(pull_resp))) => {
+                            malformed_reason(pull_resp)
+                        }
+                        _ => None,
+                    };
+                    match (downlink, malformed) {
+                        // eg: bad base64 or mangled JSON, which semtech_udp can't parse
+                        (Err((Some(Identifier::PullResp), e)), _) => {
+                            warn!(
+                                "Packet forwarder {} dropping malformed downlink: {}",
+                                self.label, e
+                            );
+                            self.metrics_sender
+                                .send(metrics::Message::MalformedDownlink)
+                                .await?;
+                        }
+                        (Err((_, e)), _) => {
+                            debug!(
+                                "Packet forwarder {} dropping unparsable frame: {}",
+                                self.label, e
+                            )
+                        }
+                        (Ok(_), Some(reason)) => {
+                            warn!(
+                                "Packet forwarder {} dropping malformed downlink: {}",
+                                self.label, reason
+                            );
+                            self.metrics_sender
+                                .send(metrics::Message::MalformedDownlink)
+                                .await?;
+                        }
+                        // as a concentrator would, which aborts the TX
+                        (
+                            Ok(semtech_udp::Packet::Down(semtech_udp::Down::PullResp(pull_resp))),
+                            None,
+                        ) if !self.concentrator.can_transmit(pull_resp.data.txpk.rfch) =>
+                        {
+                            warn!(
+                                "Packet forwarder {} dropping downlink on rfch {}, \
+                                 which can't transmit",
+                                self.label,
+                                pull_resp.data.txpk.rfch
+                            );
+                            self.metrics_sender
+                                .send(metrics::Message::RfChainRejected)
+                                .await?;
+                        }
+                        (Ok(_), None)
+                            if self.impairments.borrow().drop_packet(&mut self.loss_rng) =>
+                        {
+                            debug!("Packet forwarder {} dropping downlink", self.label)
+                        }
+                        // no subscribers only means no devices are listening right now
+                        (Ok(downlink), None) => {
+                            self.counters.received(&downlink);
+                            let ack = matches!(
+                                downlink,
+                                semtech_udp::Packet::Down(
+                                    semtech_udp::Down::PushAck(_) | semtech_udp::Down::PullAck(_)
+                                )
+                            );
+                            acked_in_window |= ack;
+                            if ack && !acked {
+                                acked = true;
+                                info!("Packet forwarder {} acked by {}", self.label, self.host);
+                                self.metrics_sender
+                                    .send(metrics::Message::Connected(true))
+                                    .await?;
+                            }
+                            let _ = self.downlink_sender.send(Arc::new(downlink));
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// Switch to the other server once the one in use has missed enough acks
+    /// in a row, returning FailedOver so that it's connected to straight away
+    fn fail_over(&mut self) -> Result<()> {
+        match self.failover.as_mut() {
+            Some(failover) if self.missed_acks >= failover.missed_acks => {
+                std::mem::swap(&mut self.host, &mut failover.host);
+                self.missed_acks = 0;
+                Err(Error::FailedOver(failover.host.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Discard uplinks until the runtime is no longer offline
+    async fn drop_uplinks_while_offline(&mut self) {
+        while self.impairments.borrow().offline {
+            tokio::select! {
+                _ = self.impairments.changed() => (),
+                _ = self.uplink_receiver.recv() => (),
+                _ = self.ack_receiver.recv() => (),
+            }
+        }
+    }
+
+    /// Discard uplinks while waiting out the backoff so that devices don't
+    /// fill the queue during an outage
+    async fn drop_uplinks_for(&mut self, duration: Duration) {
+        let wait = sleep(duration);
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                _ = &mut wait => return,
+                _ = self.uplink_receiver.recv() => (),
+                _ = self.ack_receiver.recv() => (),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    clock: GatewayClock,
+    concentrator: Arc<Concentrator>,
+    downlink_sender: broadcast::Sender<Arc<semtech_udp::Packet>>,
+    uplink_sender: mpsc::Sender<TxMessage>,
+    ack_sender: mpsc::Sender<TxMessage>,
+    counters: Arc<Counters>,
+    impairments: Arc<watch::Sender<Impairments>>,
+}
+
+impl Handle {
+    pub fn clock(&self) -> GatewayClock {
+        self.clock
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<semtech_udp::Packet>> {
+        self.downlink_sender.subscribe()
+    }
+
+    pub fn publish_to(&self) -> mpsc::Sender<TxMessage> {
+        self.uplink_sender.clone()
+    }
+
+    fn send_uplink(&self, rxpk: push_data::RxPkV1) -> bool {
+        // heard by the concentrator and passed its CRC, even if the queue
+        // to the network server is full
+        self.counters.rxnb.fetch_add(1, Ordering::Relaxed);
+        let packet = push_data::Packet::from_rxpk(self.concentrator.report(rxpk));
+        self.uplink_sender.try_send(packet.into()).is_ok()
+    }
+
+    /// Degrade the link to the network server, replacing any earlier
+    /// impairments
+    pub fn impair(&self, impairments: Impairments) {
+        // the runtime holds a receiver so this can't fail
+        let _ = self.impairments.send(impairments);
+    }
+}
+
+/// Devices reach the network server through a packet forwarder by Semtech UDP
+impl virtual_device::VirtualTransport for Handle {
+    fn clock(&self) -> GatewayClock {
+        self.clock
+    }
+
+    fn uplink(&self, rxpk: push_data::RxPkV1) -> bool {
+        self.send_uplink(self.concentrator.heard(rxpk))
+    }
+
+    /// Judged by the SNR the antenna gain gives, unlike the default
+    fn uplink_at_reduced_power(&self, rxpk: push_data::RxPkV1, _reduction_db: u8) -> Delivery {
+        let rxpk = self.concentrator.heard(rxpk);
+        if !geolocation::demodulates(f64::from(rxpk.lsnr), &geolocation::datr_name(&rxpk.datr)) {
+            Delivery::Unheard
+        } else if self.send_uplink(rxpk) {
+            Delivery::Delivered
+        } else {
+            Delivery::Full
+        }
+    }
+
+    fn downlinks(&self) -> mpsc::Receiver<Box<pull_resp::Packet>> {
+        let mut receiver = self.subscribe();
+        let (sender, downlinks) = mpsc::channel(100);
+        tokio::spawn(async move {
+            loop {
+                let packet = match receiver.recv().await {
+                    Ok(packet) => packet,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("UdpRx lagged, {} packets dropped", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                // only downlinks are copied, for the device to own, the acks
+                // of every other device's uplinks are passed over
+                if let semtech_udp::Packet::Down(semtech_udp::Down::PullResp(pull_resp)) = &*packet
+                {
+                    // the device has stopped so there's nobody left to deliver to
+                    if sender.send(pull_resp.clone()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        downlinks
+    }
+
+    fn ack(&self, downlink: Box<pull_resp::Packet>) {
+        self.counters.txnb.fetch_add(1, Ordering::Relaxed);
+        let ack = downlink.into_ack_for_gateway(semtech_udp::MacAddress::new(&[0; 8]));
+        let sender = self.ack_sender.clone();
+        // the radio isn't in an async context so this is spawned off
+        tokio::spawn(async move { sender.send(ack.into()).await });
+    }
+}
+
+/// What the packet forwarder has seen since its last stat, reported in the
+/// stat as the Semtech packet forwarder does
+#[derive(Default)]
+struct Counters {
+    /// radio packets received
+    rxnb: AtomicU32,
+    /// radio packets forwarded to the network server
+    rxfw: AtomicU32,
+    /// PUSH_DATA datagrams sent, and those acknowledged
+    push_data: AtomicU32,
+    push_ack: AtomicU32,
+    /// downlinks received from the network server
+    dwnb: AtomicU32,
+    /// packets emitted
+    txnb: AtomicU32,
+}
+
+impl Counters {
+    fn forwarded(&self, message: &TxMessage) {
+        if let semtech_udp::Packet::Up(Up::PushData(packet)) = message {
+            let rxpk = packet.data.rxpk.as_ref().map_or(0, Vec::len);
+            self.rxfw.fetch_add(rxpk as u32, Ordering::Relaxed);
+            self.push_data.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn received(&self, packet: &semtech_udp::Packet) {
+        match packet {
+            semtech_udp::Packet::Down(semtech_udp::Down::PushAck(_)) => {
+                self.push_ack.fetch_add(1, Ordering::Relaxed);
+            }
+            semtech_udp::Packet::Down(semtech_udp::Down::PullResp(_)) => {
+                self.dwnb.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => (),
+        }
+    }
+
+    /// A stat of what's been seen since the last, which starts the counts
+    /// afresh. The stat is itself a PUSH_DATA, counted towards the next.
+    /// semtech_udp keeps the fields of a stat private, so it is read from
+    /// the JSON a packet forwarder would send.
+    fn stat(
+        &self,
+        mac: [u8; 8],
+        location: Option<settings::Location>,
+    ) -> Result<push_data::Packet> {
+        let take = |counter: &AtomicU32| counter.swap(0, Ordering::Relaxed);
+        let rxnb = take(&self.rxnb);
+        let push_data = take(&self.push_data);
+        let push_ack = take(&self.push_ack);
+        let ackr = if push_data == 0 {
+            0.0
+        } else {
+            100.0 * f64::from(push_ack.min(push_data)) / f64::from(push_data)
+        };
+        self.push_data.fetch_add(1, Ordering::Relaxed);
+        let time = humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .replace('T', " ")
+            .replace('Z', " GMT");
+        let stat = serde_json::json!({
+            "time": time,
+            "lati": location.map(|location| location.latitude),
+            "long": location.map(|location| location.longitude),
+            // unsigned in a stat, so a gateway below sea level reports 0
+            "alti": location.map(|location| location.altitude_m.round().max(0.0) as u64),
+            // packets with a bad CRC are never heard, so all are ok
+            "rxnb": rxnb,
+            "rxok": rxnb,
+            "rxfw": take(&self.rxfw),
+            "ackr": ackr,
+            "dwnb": take(&self.dwnb),
+            "txnb": take(&self.txnb),
+        });
+        Ok(push_data::Packet {
+            random_token: rand::random(),
+            gateway_mac: semtech_udp::MacAddress::new(&mac),
+            data: push_data::Data {
+                rxpk: None,
+                stat: Some(serde_json::from_value(stat)?),
+            },
+        })
+    }
+}
+
+/// The packet forwarder's concentrator: the RF chains it hears and sends on,
+/// the clock it stamps uplinks with and where it stands
+#[derive(Clone, Debug)]
+pub struct Concentrator {
+    pub rf_chains: Vec<settings::RfChain>,
+    pub time: settings::TimeField,
+    pub tmms: settings::TimeField,
+    pub location: Option<settings::Location>,
+}
+
+impl From<&settings::PacketForwarder> for Concentrator {
+    fn from(packet_forwarder: &settings::PacketForwarder) -> Concentrator {
+        Concentrator {
+            rf_chains: packet_forwarder.rf_chain.clone(),
+            time: packet_forwarder.time,
+            tmms: packet_forwarder.tmms,
+            location: packet_forwarder.location,
+        }
+    }
+}
+
+impl Concentrator {
+    /// The uplink as the concentrator hears it: on the RF chain nearest its
+    /// frequency, with that chain's antenna gain
+    fn heard(&self, mut rxpk: push_data::RxPkV1) -> push_data::RxPkV1 {
+        let nearest = self.rf_chains.iter().enumerate().min_by(|(_, a), (_, b)| {
+            (a.freq_mhz - rxpk.freq)
+                .abs()
+                .total_cmp(&(b.freq_mhz - rxpk.freq).abs())
+        });
+        if let Some((rfch, rf_chain)) = nearest {
+            rxpk.rfch = rfch as u64;
+            rxpk.rssi += rf_chain.antenna_gain_dbi.round() as i32;
+            rxpk.lsnr += rf_chain.antenna_gain_dbi as f32;
+        }
+        rxpk
+    }
+
+    /// The rxpk a heard uplink is forwarded in, stamped with the times by the
+    /// gateway's clocks. With tmms that's the second version of the rxpk,
+    /// which gives the signal per antenna, the RF chain's here.
+    fn report(&self, mut rxpk: push_data::RxPkV1) -> push_data::RxPk {
+        let arrival = rxpk.time.take();
+        let arrived_at = arrival
+            .as_deref()
+            .and_then(|arrival| humantime::parse_rfc3339(arrival).ok())
+            .unwrap_or_else(SystemTime::now);
+        rxpk.time = match (self.time, arrival) {
+            (time, _) if !time.enabled => None,
+            // a time of arrival worked out from the device's distance is kept
+            (time, Some(arrival)) if time.error_ms == 0 => Some(arrival),
+            (time, _) => {
+                let stamped = shift(arrived_at, time.error_ms);
+                Some(humantime::format_rfc3339_nanos(stamped).to_string())
+            }
+        };
+        if !self.tmms.enabled {
+            return push_data::RxPk::V1(rxpk);
+        }
+        push_data::RxPk::V2(push_data::RxPkV2 {
+            aesk: 0,
+            brd: 0,
+            codr: rxpk.codr,
+            data: rxpk.data,
+            datr: rxpk.datr,
+            freq: rxpk.freq,
+            jver: 2,
+            modu: match rxpk.modu {
+                semtech_udp::Modulation::LORA => "LORA",
+                semtech_udp::Modulation::FSK => "FSK",
+            }
+            .to_string(),
+            rsig: vec![push_data::RSig {
+                ant: rxpk.rfch as usize,
+                chan: rxpk.chan,
+                rssic: rxpk.rssi,
+                rssis: rxpk.rssis,
+                lsnr: rxpk.lsnr,
+                etime: None,
+                foff: None,
+                ftstat: None,
+                ftver: None,
+                ftdelta: None,
+            }],
+            size: rxpk.size,
+            stat: rxpk.stat,
+            tmst: rxpk.tmst,
+            delayed: None,
+            tmms: Some(gps_time_ms(shift(arrived_at, self.tmms.error_ms))),
+            time: rxpk.time,
+        })
+    }
+
+    /// Whether a downlink may be sent on this RF chain. Without any chains
+    /// configured, downlinks are sent whichever chain they name.
+    fn can_transmit(&self, rfch: u64) -> bool {
+        if self.rf_chains.is_empty() {
+            return true;
+        }
+        usize::try_from(rfch)
+            .ok()
+            .and_then(|rfch| self.rf_chains.get(rfch))
+            .is_some_and(|rf_chain| rf_chain.tx_enable)
+    }
+}
+
+/// Connect to the first of the host's addresses that can be reached,
+/// IPv6 or IPv4 as the resolver orders them, each from a socket of its
+/// own family, so a host with both is still reached over IPv4 from a
+/// machine without an IPv6 route
+async fn connect(
+    label: &str,
+    mac: [u8; 8],
+    host: &str,
+    local_port: u16,
+) -> Result<(Connection, SocketAddr)> {
+    let mut last_error = Error::UnresolvedHost(host.to_string());
+    for server in tokio::net::lookup_host(host).await? {
+        let outbound = match server {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, local_port)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, local_port)),
+        };
+        info!(
+            "Creating packet forwarder {} connecting to {} ({}) from {}",
+            label, host, server, outbound
+        );
+        match Connection::new(mac, outbound, server).await {
+            Ok(connection) => return Ok((connection, server)),
+            Err(e) => {
+                debug!(
+                    "Packet forwarder {} unable to reach {}: {:?}",
+                    label, server, e
+                );
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Send the uplinks copied for the mirror on to it, from a socket of its
+/// own on any free port, reconnecting with backoff as the packet forwarder
+/// does until the packet forwarder stops. Downlinks from the mirror are
+/// dropped, so only the primary's reach devices.
+async fn mirror(label: String, mac: [u8; 8], host: String, mut uplinks: mpsc::Receiver<TxMessage>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match mirror_connection(&label, mac, &host, &mut uplinks, &mut backoff).await {
+            Ok(()) => return,
+            Err(e) => warn!(
+                "Packet forwarder {} lost connection to mirror {}: {:?}. Reconnecting in {:?}",
+                label, host, e, backoff
+            ),
+        }
+        // uplinks copied during the outage are lost to the mirror
+        let wait = sleep(backoff);
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                _ = &mut wait => break,
+                uplink = uplinks.recv() => if uplink.is_none() {
+                    return;
+                },
+            }
+        }
+        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+/// Forward copies to the mirror until the connection fails, or until the
+/// packet forwarder is gone, which returns Ok
+async fn mirror_connection(
+    label: &str,
+    mac: [u8; 8],
+    host: &str,
+    uplinks: &mut mpsc::Receiver<TxMessage>,
+    backoff: &mut Duration,
+) -> Result<()> {
+    let (connection, _) = connect(label, mac, host, 0).await?;
+    *backoff = MIN_BACKOFF;
+    let mut keepalive_timer = interval_at(Instant::now(), ACK_WINDOW);
+    loop {
+        tokio::select! {
+            _ = keepalive_timer.tick() => {
+                connection.send(pull_data::Packet::new(0).into()).await?
+            }
+            uplink = uplinks.recv() => match uplink {
+                Some(uplink) => connection.send(uplink).await?,
+                None => return Ok(()),
+            },
+            datagrams = connection.recv_batch() => {
+                for datagram in datagrams? {
+                    if let Ok(semtech_udp::Packet::Down(semtech_udp::Down::PullResp(_))) =
+                        parse_downlink(&datagram)
+                    {
+                        debug!("Packet forwarder {} dropping downlink from mirror {}", label, host)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A socket connected to a network server. The semtech_udp client runtime
+/// drops frames it can't parse without a word, and its tasks carry on past
+/// a failed socket, so the socket is driven here instead.
+#[derive(Clone)]
+struct Connection {
+    mac: [u8; 8],
+    socket: Arc<UdpSocket>,
+}
+
+impl Connection {
+    async fn new(mac: [u8; 8], outbound: SocketAddr, server: SocketAddr) -> Result<Connection> {
+        let socket = UdpSocket::bind(outbound).await?;
+        // only frames from the server are received once connected
+        socket.connect(server).await?;
+        Ok(Connection {
+            mac,
+            socket: Arc::new(socket),
+        })
+    }
+
+    /// Send an uplink frame from this gateway, with a random token of its own
+    async fn send(&self, packet: TxMessage) -> Result<()> {
+        self.send_batch(vec![packet]).await
+    }
+
+    /// Send uplink frames from this gateway in as few syscalls as the
+    /// platform allows, in order
+    async fn send_batch(&self, packets: Vec<TxMessage>) -> Result<()> {
+        let datagrams = packets
+            .into_iter()
+            .map(|packet| self.serialize(packet))
+            .collect::<Result<Vec<_>>>()?;
+        let mut sent = 0;
+        while sent < datagrams.len() {
+            self.socket.writable().await?;
+            match self.socket.try_io(Interest::WRITABLE, || {
+                send_some(&self.socket, &datagrams[sent..])
+            }) {
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive the frames waiting, at least one, in as few syscalls as the
+    /// platform allows
+    async fn recv_batch(&self) -> Result<Vec<Vec<u8>>> {
+        loop {
+            self.socket.readable().await?;
+            match self
+                .socket
+                .try_io(Interest::READABLE, || recv_some(&self.socket))
+            {
+                Ok(datagrams) => return Ok(datagrams),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn serialize(&self, mut packet: TxMessage) -> Result<Vec<u8>> {
+        if let semtech_udp::Packet::Up(up) = &mut packet {
+            up.set_gateway_mac(MacAddress::new(&self.mac));
+            match up {
+                Up::PushData(push_data) => push_data.random_token = rand::random(),
+                Up::PullData(pull_data) => pull_data.random_token = rand::random(),
+                Up::TxAck(_) => (),
+            }
+        }
+        let mut buf = vec![0; DATAGRAM_SIZE];
+        let n = packet.serialize(&mut buf)? as usize;
+        buf.truncate(n);
+        Ok(buf)
+    }
+}
+
+/// Send as many of the datagrams as the socket takes in one sendmmsg
+#[cfg(target_os = "linux")]
+fn send_some(socket: &UdpSocket, datagrams: &[Vec<u8>]) -> io::Result<usize> {
+    let slices: Vec<[IoSlice; 1]> = datagrams
+        .iter()
+        .map(|datagram| [IoSlice::new(datagram)])
+        .collect();
+    // connected, so no addresses are needed
+    let mut headers = MultiHeaders::<()>::preallocate(slices.len(), None);
+    let addresses = vec![None; slices.len()];
+    let cmsgs: [ControlMessage; 0] = [];
+    let sent = sendmmsg(
+        socket.as_raw_fd(),
+        &mut headers,
+        &slices,
+        addresses,
+        cmsgs,
+        MsgFlags::empty(),
+    )?;
+    Ok(sent.count())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_some(socket: &UdpSocket, datagrams: &[Vec<u8>]) -> io::Result<usize> {
+    socket.try_send(&datagrams[0]).map(|_| 1)
+}
+
+/// Receive the datagrams waiting, up to a batch, in one recvmmsg
+#[cfg(target_os = "linux")]
+fn recv_some(socket: &UdpSocket) -> io::Result<Vec<Vec<u8>>> {
+    let mut datagrams = vec![vec![0; DATAGRAM_SIZE]; MAX_BATCH];
+    let mut headers = MultiHeaders::<()>::preallocate(MAX_BATCH, None);
+    let lengths: Vec<usize> = {
+        let mut slices: Vec<[IoSliceMut; 1]> = datagrams
+            .iter_mut()
+            .map(|datagram| [IoSliceMut::new(datagram)])
+            .collect();
+        recvmmsg(
+            socket.as_raw_fd(),
+            &mut headers,
+            &mut slices,
+            MsgFlags::MSG_DONTWAIT,
+            None,
+        )?
+        .map(|received| received.bytes)
+        .collect()
+    };
+    datagrams.truncate(lengths.len());
+    for (datagram, length) in datagrams.iter_mut().zip(lengths) {
+        datagram.truncate(length)
+    }
+    Ok(datagrams)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_some(socket: &UdpSocket) -> io::Result<Vec<Vec<u8>>> {
+    let mut datagram = vec![0; DATAGRAM_SIZE];
+    let n = socket.try_recv(&mut datagram)?;
+    datagram.truncate(n);
+    Ok(vec![datagram])
+}
+
+/// A downlink frame from a network server, or why it couldn't be parsed
+/// along with the kind of frame it claims to be. semtech_udp panics on an
+/// uplink frame too short for its MAC, so only downlink frames are parsed.
+fn parse_downlink(
+    datagram: &[u8],
+) -> std::result::Result<semtech_udp::Packet, (Option<Identifier>, ParseError)> {
+    let identifier = datagram
+        .get(IDENTIFIER_INDEX)
+        .and_then(|&identifier| Identifier::try_from(identifier).ok());
+    match identifier {
+        Some(Identifier::PushAck | Identifier::PullResp | Identifier::PullAck) => {
+            match semtech_udp::Packet::parse(datagram) {
+                Ok(packet @ semtech_udp::Packet::Down(_)) => Ok(packet),
+                Ok(semtech_udp::Packet::Up(_)) => Err((identifier, ParseError::InvalidIdentifier)),
+                Err(e) => Err((identifier, e)),
+            }
+        }
+        _ => Err((identifier, ParseError::InvalidIdentifier)),
+    }
+}
+
+/// Resolves once the host no longer resolves to the address connected to.
+/// Never for an IP address, nor while the host can't be resolved at all,
+/// which leaves the connection as it is.
+async fn moved(host: String, server: SocketAddr) {
+    if host.parse::<SocketAddr>().is_ok() {
+        return std::future::pending().await;
+    }
+    loop {
+        sleep(RESOLVE_INTERVAL).await;
+        match tokio::net::lookup_host(host.as_str()).await {
+            Ok(addresses) => {
+                let addresses: Vec<SocketAddr> = addresses.collect();
+                if !addresses.is_empty() && !addresses.contains(&server) {
+                    info!(
+                        "{} now resolves to {:?} rather than {}",
+                        host, addresses, server
+                    );
+                    return;
+                }
+            }
+            Err(e) => debug!("unable to resolve {} again: {:?}", host, e),
+        }
+    }
+}
+
+/// Milliseconds since the GPS epoch of a UTC time
+fn gps_time_ms(time: SystemTime) -> u64 {
+    let unix_ms = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    (unix_ms + GPS_LEAP_MS).saturating_sub(GPS_EPOCH_MS)
+}
+
+/// A time moved by a clock error, positive when ahead
+fn shift(time: SystemTime, error_ms: i64) -> SystemTime {
+    let error = Duration::from_millis(error_ms.unsigned_abs());
+    if error_ms >= 0 {
+        time + error
+    } else {
+        time - error
+    }
+}
+
+/// Sanity check a decoded downlink before handing it to any device
+fn malformed_reason(pull_resp: &semtech_udp::pull_resp::Packet) -> Option<&'static str> {
+    let txpk = &pull_resp.data.txpk;
+    if txpk.data.is_empty() {
+        Some("empty payload")
+    } else if txpk.size as usize != txpk.data.len() {
+        Some("size does not match payload length")
+    } else if !txpk.freq.is_finite() || txpk.freq <= 0.0 {
+        Some("invalid frequency")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn connected_pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        (a, b)
+    }
+
+    async fn send_all(socket: &UdpSocket, datagrams: &[Vec<u8>]) {
+        let mut sent = 0;
+        while sent < datagrams.len() {
+            socket.writable().await.unwrap();
+            if let Ok(n) =
+                socket.try_io(Interest::WRITABLE, || send_some(socket, &datagrams[sent..]))
+            {
+                sent += n;
+            }
+        }
+    }
+
+    async fn recv_count(socket: &UdpSocket, count: usize) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+        while received.len() < count {
+            socket.readable().await.unwrap();
+            if let Ok(datagrams) = socket.try_io(Interest::READABLE, || recv_some(socket)) {
+                assert!(datagrams.len() <= MAX_BATCH);
+                received.extend(datagrams);
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn batches_keep_datagrams_whole_and_in_order() {
+        let (a, b) = connected_pair().await;
+        let datagrams: Vec<Vec<u8>> = (0..MAX_BATCH + 6).map(|n| vec![n as u8; 12 + n]).collect();
+        send_all(&a, &datagrams).await;
+        assert_eq!(recv_count(&b, datagrams.len()).await, datagrams);
+    }
+
+    #[tokio::test]
+    async fn largest_datagram() {
+        let (a, b) = connected_pair().await;
+        let datagrams = vec![vec![0x5A; DATAGRAM_SIZE], vec![]];
+        send_all(&a, &datagrams).await;
+        assert_eq!(recv_count(&b, 2).await, datagrams);
+    }
+
+    #[test]
+    fn gps_time() {
+        let at = |unix_secs| SystemTime::UNIX_EPOCH + Duration::from_secs(unix_secs);
+        // 2020-01-01T00:00:00Z, 18 leap seconds after the GPS epoch
+        assert_eq!(gps_time_ms(at(1_577_836_800)), 1_261_872_018_000);
+        assert_eq!(gps_time_ms(at(315_964_800)), GPS_LEAP_MS);
+        assert_eq!(gps_time_ms(at(0)), 0);
+    }
+
+    #[test]
+    fn clock_error_shift() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        assert_eq!(shift(time, 250), time + Duration::from_millis(250));
+        assert_eq!(shift(time, -250), time - Duration::from_millis(250));
+        assert_eq!(shift(time, 0), time);
+    }
+
+    #[test]
+    fn stat_counts_since_the_last() {
+        let rxpk = push_data::RxPk::V1(push_data::RxPkV1 {
+            chan: 0,
+            codr: semtech_udp::CodingRate::_4_5,
+            size: 1,
+            data: vec![0],
+            datr: semtech_udp::DataRate::new(
+                semtech_udp::SpreadingFactor::SF7,
+                semtech_udp::Bandwidth::BW125,
+            ),
+            freq: 902.3,
+            lsnr: 5.5,
+            modu: semtech_udp::Modulation::LORA,
+            rfch: 0,
+            rssi: -112,
+            rssis: None,
+            stat: push_data::CRC::OK,
+            tmst: 0,
+            time: None,
+        });
+        let uplink = push_data::Packet {
+            random_token: 0,
+            gateway_mac: MacAddress::new(&[0; 8]),
+            data: push_data::Data {
+                rxpk: Some(vec![rxpk.clone(), rxpk]),
+                stat: None,
+            },
+        };
+        let counters = Counters::default();
+        counters.rxnb.fetch_add(3, Ordering::Relaxed);
+        counters.forwarded(&uplink.clone().into());
+        counters.forwarded(&uplink.into());
+        counters.received(&semtech_udp::Packet::Down(semtech_udp::Down::PushAck(
+            semtech_udp::push_ack::Packet { random_token: 0 },
+        )));
+        counters.txnb.fetch_add(1, Ordering::Relaxed);
+
+        let stat = |counters: &Counters| {
+            let packet = counters.stat([0; 8], None).unwrap();
+            serde_json::to_value(packet.data.stat.unwrap()).unwrap()
+        };
+        let first = stat(&counters);
+        assert_eq!(first["rxnb"], 3);
+        assert_eq!(first["rxok"], 3);
+        assert_eq!(first["rxfw"], 4);
+        assert_eq!(first["ackr"], 50.0);
+        assert_eq!(first["dwnb"], 0);
+        assert_eq!(first["txnb"], 1);
+
+        // the first stat is the only PUSH_DATA since, and it went unacked
+        let second = stat(&counters);
+        assert_eq!(second["rxnb"], 0);
+        assert_eq!(second["rxfw"], 0);
+        assert_eq!(second["ackr"], 0.0);
+        assert_eq!(second["txnb"], 0);
+    }
+
+    #[tokio::test]
+    async fn nothing_waiting_would_block() {
+        let (_a, b) = connected_pair().await;
+        assert_eq!(recv_some(&b).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+}
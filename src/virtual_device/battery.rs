@@ -0,0 +1,85 @@
+use super::*;
+
+/// Supply voltage the current drawn is worked out at
+const SUPPLY_V: f64 = 3.3;
+/// Share of the power drawn which the power amplifier puts out
+const PA_EFFICIENCY: f64 = 0.25;
+/// Current drawn by the rest of the radio while transmitting
+const BASE_CURRENT_MA: f64 = 20.0;
+
+/// A battery drained by transmitting, drawing a current which follows from
+/// the TX power for as long as each uplink is on air. Receiving and sleeping
+/// are taken to draw nothing.
+pub struct Battery {
+    capacity_mah: f64,
+    remaining_mah: f64,
+}
+
+impl Battery {
+    pub fn new(settings: &settings::Battery) -> Battery {
+        Battery {
+            capacity_mah: settings.capacity_mah,
+            remaining_mah: settings.capacity_mah * settings.level_percent / 100.0,
+        }
+    }
+
+    pub fn drain(&mut self, airtime: Duration, power_dbm: i8) {
+        let output_mw = 10f64.powf(f64::from(power_dbm) / 10.0);
+        let current_ma = BASE_CURRENT_MA + output_mw / PA_EFFICIENCY / SUPPLY_V;
+        let used_mah = current_ma * airtime.as_secs_f64() / 3600.0;
+        self.remaining_mah = (self.remaining_mah - used_mah).max(0.0);
+    }
+
+    pub fn percent(&self) -> f64 {
+        self.remaining_mah / self.capacity_mah * 100.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining_mah == 0.0
+    }
+
+    /// The level as reported in DevStatusAns, 1 when empty to 254 when full
+    pub fn dev_status(&self) -> u8 {
+        1 + (self.remaining_mah / self.capacity_mah * 253.0).round() as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn battery(level_percent: f64) -> Battery {
+        Battery::new(&settings::Battery {
+            capacity_mah: 1000.0,
+            level_percent,
+        })
+    }
+
+    #[test]
+    fn dev_status() {
+        assert_eq!(battery(100.0).dev_status(), 254);
+        assert_eq!(battery(50.0).dev_status(), 128);
+        assert_eq!(battery(0.0).dev_status(), 1);
+        // 255 is for devices which can't measure their battery
+        assert_eq!(battery(0.1).dev_status(), 1);
+        assert_eq!(battery(99.9).dev_status(), 254);
+    }
+
+    #[test]
+    fn drain() {
+        let mut battery = battery(10.0);
+        // at 14 dBm the amplifier puts out 25 mW, or draws 30 mA on top of the
+        // rest of the radio's 20 mA, for an hour on air
+        battery.drain(Duration::from_secs(3600), 14);
+        assert!(
+            (battery.percent() - 4.955).abs() < 0.001,
+            "{}",
+            battery.percent()
+        );
+        assert!(!battery.is_empty());
+        battery.drain(Duration::from_secs(3600), 14);
+        assert!(battery.is_empty());
+        assert_eq!(battery.percent(), 0.0);
+        assert_eq!(battery.dev_status(), 1);
+    }
+}
@@ -0,0 +1,436 @@
+use super::*;
+use lorawan::{keys::AES128, parser::DevAddr};
+use lorawan_device::{region, JoinMode};
+use std::sync::{atomic::AtomicU32, Arc};
+use tokio::sync::mpsc;
+
+/// Produces the fport and FRMPayload of each scheduled uplink. It is called
+/// from within the device's task, so rng::random draws from the device's rng.
+pub type PayloadSource = Box<dyn FnMut() -> (u8, Vec<u8>) + Send>;
+
+/// A PayloadSource which also says how long to wait before sending each
+/// payload, or None to wait the schedule's secs_between_transmits
+pub type TimedPayloadSource = Box<dyn FnMut() -> (Option<Duration>, u8, Vec<u8>) + Send>;
+
+/// Builds a VirtualDevice. Credentials, a transport and an event sender are
+/// required, everything else has the same defaults as the device settings.
+/// Without a metrics sender the device records no Prometheus metrics.
+///
+/// ```no_run
+/// # async fn example(
+/// #     transport: &virtual_lorawan_device::udp_runtime::Handle,
+/// #     metrics: &virtual_lorawan_device::metrics::Metrics,
+/// #     event_log: &virtual_lorawan_device::event_log::EventLog,
+/// # ) -> virtual_lorawan_device::Result<()> {
+/// use virtual_lorawan_device::{settings::Region, virtual_device::VirtualDevice, Credentials};
+///
+/// let credentials = Credentials {
+///     app_eui: "0000000000000000".to_string(),
+///     app_key: "2B7E151628AED2A6ABF7158809CF4F3C".to_string(),
+///     dev_eui: "3ED43BEF1857EF4B".to_string(),
+/// };
+/// let device = VirtualDevice::builder("one")
+///     .event_sender(event_log.get_device_sender("one", &credentials.dev_eui))
+///     .credentials(credentials)
+///     .region(Region::EU868)
+///     .interval(std::time::Duration::from_secs(30))
+///     .payload(|| (1, vec![0x01, 0x02]))
+///     .transport(transport.clone())
+///     .metrics_sender(metrics.get_server_sender("default"))
+///     .build()
+///     .await?;
+/// tokio::spawn(device.run());
+/// # Ok(())
+/// # }
+/// ```
+pub struct Builder {
+    label: String,
+    time: Option<Instant>,
+    credentials: Option<Credentials>,
+    region: settings::Region,
+    rx_window: settings::RxWindow,
+    schedule: Schedule,
+    schedule_receiver: Option<watch::Receiver<Schedule>>,
+    payload: TimedPayloadSource,
+    transport: Option<Arc<dyn VirtualTransport>>,
+    metrics_sender: Option<metrics::Sender>,
+    event_sender: Option<event_log::Sender>,
+    observers: Vec<Arc<dyn DeviceObserver>>,
+    downlink_rules: Vec<settings::DownlinkRule>,
+    uplink_limit: Option<u32>,
+    negative_join: Option<settings::NegativeJoin>,
+    data_rate: Option<u8>,
+    rx_timing_tolerance_us: u32,
+    battery: Option<settings::Battery>,
+    uplink_snr_db: f32,
+    uplink_quality: Option<settings::UplinkQuality>,
+    lorawan_version: settings::LorawanVersion,
+    dev_nonces: Option<Arc<AtomicU32>>,
+    resume: Option<(Session, Option<u32>)>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    shutdown: Option<watch::Receiver<bool>>,
+}
+
+impl Builder {
+    pub(super) fn new(label: &str) -> Builder {
+        Builder {
+            label: label.to_string(),
+            time: None,
+            credentials: None,
+            region: settings::Region::US915,
+            rx_window: settings::RxWindow::default(),
+            schedule: Schedule {
+                rejoin_frames: 0xFFFF,
+                rejoin_secs: None,
+                reboot_secs: None,
+                secs_between_transmits: 0,
+                adr_ack_limit: 64,
+                adr_ack_delay: 32,
+            },
+            schedule_receiver: None,
+            payload: Box::new(|| {
+                let (fport, data) = random_payload();
+                (None, fport, data)
+            }),
+            transport: None,
+            metrics_sender: None,
+            event_sender: None,
+            observers: Vec::new(),
+            downlink_rules: Vec::new(),
+            uplink_limit: None,
+            negative_join: None,
+            data_rate: None,
+            rx_timing_tolerance_us: 20,
+            battery: None,
+            uplink_snr_db: 5.5,
+            uplink_quality: None,
+            lorawan_version: settings::LorawanVersion::default(),
+            dev_nonces: None,
+            resume: None,
+            rate_limiter: None,
+            shutdown: None,
+        }
+    }
+
+    /// When time started for the device's timers, now by default
+    pub fn time(mut self, time: Instant) -> Builder {
+        self.time = Some(time);
+        self
+    }
+
+    pub fn credentials(mut self, credentials: Credentials) -> Builder {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn region(mut self, region: settings::Region) -> Builder {
+        self.region = region;
+        self
+    }
+
+    pub fn rx_window(mut self, rx_window: settings::RxWindow) -> Builder {
+        self.rx_window = rx_window;
+        self
+    }
+
+    /// Time between the end of one exchange and the next scheduled uplink
+    pub fn interval(mut self, interval: Duration) -> Builder {
+        self.schedule.secs_between_transmits = interval.as_secs();
+        self
+    }
+
+    /// Rejoin once the uplink frame counter passes this
+    pub fn rejoin_frames(mut self, rejoin_frames: u32) -> Builder {
+        self.schedule.rejoin_frames = rejoin_frames;
+        self
+    }
+
+    /// Rejoin once a session is this old
+    pub fn rejoin_interval(mut self, rejoin_interval: Duration) -> Builder {
+        self.schedule.rejoin_secs = Some(rejoin_interval.as_secs());
+        self
+    }
+
+    /// Reset the device, dropping its session, once it has been up this long
+    pub fn reboot_interval(mut self, reboot_interval: Duration) -> Builder {
+        self.schedule.reboot_secs = Some(reboot_interval.as_secs());
+        self
+    }
+
+    /// Uplinks without a downlink after which ADR backs off, and between
+    /// each step after that, 64 and 32 by default as in the spec
+    pub fn adr_backoff(mut self, adr_ack_limit: u32, adr_ack_delay: u32) -> Builder {
+        self.schedule.adr_ack_limit = adr_ack_limit;
+        self.schedule.adr_ack_delay = adr_ack_delay;
+        self
+    }
+
+    /// Follow a schedule which may change while the device runs, in place of
+    /// the interval and rejoin settings
+    pub fn schedule(mut self, schedule: watch::Receiver<Schedule>) -> Builder {
+        self.schedule_receiver = Some(schedule);
+        self
+    }
+
+    /// What to send in scheduled uplinks, four random bytes on a random port
+    /// by default
+    pub fn payload(
+        mut self,
+        mut payload: impl FnMut() -> (u8, Vec<u8>) + Send + 'static,
+    ) -> Builder {
+        self.payload = Box::new(move || {
+            let (fport, data) = payload();
+            (None, fport, data)
+        });
+        self
+    }
+
+    /// What to send in scheduled uplinks and when, in place of payload
+    pub fn timed_payload(
+        mut self,
+        payload: impl FnMut() -> (Option<Duration>, u8, Vec<u8>) + Send + 'static,
+    ) -> Builder {
+        self.payload = Box::new(payload);
+        self
+    }
+
+    /// How the device reaches the network server, usually a packet
+    /// forwarder's udp_runtime::Handle
+    pub fn transport(mut self, transport: impl VirtualTransport + 'static) -> Builder {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    pub fn metrics_sender(mut self, metrics_sender: metrics::Sender) -> Builder {
+        self.metrics_sender = Some(metrics_sender);
+        self
+    }
+
+    pub fn event_sender(mut self, event_sender: event_log::Sender) -> Builder {
+        self.event_sender = Some(event_sender);
+        self
+    }
+
+    /// Tell this observer about the device's lifecycle, may be given more
+    /// than once
+    pub fn observer(mut self, observer: Arc<dyn DeviceObserver>) -> Builder {
+        self.observers.push(observer);
+        self
+    }
+
+    pub fn downlink_rules(mut self, downlink_rules: &[settings::DownlinkRule]) -> Builder {
+        self.downlink_rules = downlink_rules.to_vec();
+        self
+    }
+
+    /// Stop once this many scheduled uplinks have been sent
+    pub fn uplink_limit(mut self, uplink_limit: Option<u32>) -> Builder {
+        self.uplink_limit = uplink_limit;
+        self
+    }
+
+    pub fn negative_join(mut self, negative_join: Option<settings::NegativeJoin>) -> Builder {
+        self.negative_join = negative_join;
+        self
+    }
+
+    /// Data rate index every session starts at, the stack's default if None
+    pub fn data_rate(mut self, data_rate: Option<u8>) -> Builder {
+        self.data_rate = data_rate;
+        self
+    }
+
+    pub fn rx_timing_tolerance_us(mut self, rx_timing_tolerance_us: u32) -> Builder {
+        self.rx_timing_tolerance_us = rx_timing_tolerance_us;
+        self
+    }
+
+    /// Run on a battery which uplinks drain, stopping the device once it's
+    /// empty. Without one the device reports its battery level as unknown.
+    pub fn battery(mut self, battery: Option<settings::Battery>) -> Builder {
+        self.battery = battery;
+        self
+    }
+
+    /// SNR gateways receive uplinks with at full power, 5.5 dB by default.
+    /// Ignored by transports which work it out themselves.
+    pub fn uplink_snr_db(mut self, uplink_snr_db: f32) -> Builder {
+        self.uplink_snr_db = uplink_snr_db;
+        self
+    }
+
+    /// RSSI and SNR gateways receive uplinks with at full power, fixed or
+    /// drawn for each uplink, in place of those uplink_snr_db gives. Ignored
+    /// by transports which work them out themselves.
+    pub fn uplink_quality(mut self, uplink_quality: Option<settings::UplinkQuality>) -> Builder {
+        self.uplink_quality = uplink_quality;
+        self
+    }
+
+    /// LoRaWAN version the device follows, 1.0.3 by default
+    pub fn lorawan_version(mut self, lorawan_version: settings::LorawanVersion) -> Builder {
+        self.lorawan_version = lorawan_version;
+        self
+    }
+
+    /// Counter a 1.0.4 device takes its DevNonces from, to carry them on
+    /// from an earlier run of the device. A fresh one starts at 0.
+    pub fn dev_nonces(mut self, dev_nonces: Arc<AtomicU32>) -> Builder {
+        self.dev_nonces = Some(dev_nonces);
+        self
+    }
+
+    /// Carry on with a session from an earlier run instead of joining, the
+    /// stack taking it up as an ABP session. Its FCntUp starts over at 0,
+    /// since the stack has no way of setting it; FCntDown carries on from
+    /// next_fcnt_down. The device joins as usual once it rejoins or reboots.
+    pub fn resume(mut self, session: Session, next_fcnt_down: Option<u32>) -> Builder {
+        self.resume = Some((session, next_fcnt_down));
+        self
+    }
+
+    /// Have scheduled uplinks wait their turn with the rest of the fleet
+    pub fn rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Builder {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Stop the device, once any exchange in flight completes, when this
+    /// turns true. Without it the device runs until its uplink limit.
+    pub fn shutdown(mut self, shutdown: watch::Receiver<bool>) -> Builder {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    pub async fn build(self) -> Result<VirtualDevice> {
+        let credentials = self
+            .credentials
+            .ok_or(Error::IncompleteDevice("credentials"))?;
+        let transport = self.transport.ok_or(Error::IncompleteDevice("transport"))?;
+        let metrics_sender = self
+            .metrics_sender
+            .unwrap_or_else(metrics::Sender::disabled);
+        let event_sender = self
+            .event_sender
+            .ok_or(Error::IncompleteDevice("event_sender"))?;
+        let rules = rules::Rules::new(&self.label, &self.downlink_rules)?;
+
+        let (sender, receiver) = mpsc::channel(100);
+        let radio = UdpRadio::new(
+            self.time.unwrap_or_else(Instant::now),
+            transport.clone(),
+            self.rx_window,
+            self.uplink_snr_db,
+            self.uplink_quality,
+            sender.clone(),
+        )
+        .await;
+        let region: region::Configuration = match self.region {
+            settings::Region::US915 => region::US915::subband(2).into(),
+            settings::Region::EU868 => region::EU868::default().into(),
+        };
+
+        let app_key = credentials.appkey_cloned_into_buf()?;
+        let mut deveui = credentials.deveui_cloned_into_buf()?;
+        let mut appkey = app_key;
+        match self.negative_join {
+            Some(settings::NegativeJoin::WrongAppKey) => {
+                appkey.iter_mut().for_each(|byte| *byte ^= 0xFF)
+            }
+            Some(settings::NegativeJoin::Unprovisioned) => {
+                deveui.iter_mut().for_each(|byte| *byte ^= 0xFF)
+            }
+            Some(settings::NegativeJoin::ReplayedDevNonce) | None => (),
+        }
+
+        let appeui = credentials.appeui_cloned_into_buf()?;
+        let join_mode = match &self.resume {
+            Some((session, _)) => {
+                let (dev_addr, nwk_skey, app_skey) = session.keys()?;
+                JoinMode::ABP {
+                    newskey: AES128(nwk_skey),
+                    appskey: AES128(app_skey),
+                    devaddr: DevAddr::new(dev_addr).expect("four byte DevAddr"),
+                }
+            }
+            None => JoinMode::OTAA {
+                deveui,
+                appeui,
+                appkey,
+            },
+        };
+        let mut device: Device<udp_radio::UdpRadio, LorawanCrypto, 512> =
+            Device::new(region, join_mode, radio, rng::stack_random);
+        // an ABP stack has no credentials, which later joins need
+        if self.resume.is_some() {
+            *device.get_credentials() =
+                Some(lorawan_device::Credentials::new(appeui, deveui, appkey));
+        }
+
+        // the senders are kept by the device when not given, so that neither
+        // channel closes while it runs
+        let (schedule_sender, schedule) = match self.schedule_receiver {
+            Some(schedule) => (None, schedule),
+            None => {
+                let (sender, receiver) = watch::channel(self.schedule);
+                (Some(sender), receiver)
+            }
+        };
+        let (shutdown_sender, shutdown) = match self.shutdown {
+            Some(shutdown) => (None, shutdown),
+            None => {
+                let (sender, receiver) = watch::channel(false);
+                (Some(sender), receiver)
+            }
+        };
+
+        let (state_sender, state_receiver) = watch::channel(DeviceState {
+            dev_eui: credentials.dev_eui.clone(),
+            joined: false,
+            fcnt_up: None,
+            next_fcnt_down: None,
+            rule_violations: 0,
+            paused: false,
+            session: None,
+        });
+
+        Ok(VirtualDevice {
+            label: self.label,
+            device,
+            clock: transport.clock(),
+            receiver,
+            sender,
+            metrics_sender,
+            event_sender,
+            observers: self.observers,
+            schedule,
+            payload: self.payload,
+            rules,
+            uplink_limit: self.uplink_limit,
+            negative_join: self.negative_join,
+            data_rate: self.data_rate,
+            app_key,
+            rx_timing_tolerance_us: self.rx_timing_tolerance_us,
+            region: self.region,
+            lorawan_version: self.lorawan_version,
+            dev_nonces: self.dev_nonces.unwrap_or_default(),
+            resume: self.resume,
+            rate_limiter: self.rate_limiter,
+            battery: self.battery.as_ref().map(Battery::new),
+            shutdown,
+            _senders: (schedule_sender, shutdown_sender),
+            state_sender,
+            state_receiver,
+        })
+    }
+}
+
+/// Four random bytes on a random application port
+fn random_payload() -> (u8, Vec<u8>) {
+    let mut fport = rng::random();
+    while fport == 0 {
+        fport = rng::random();
+    }
+    let data = vec![rng::random(), rng::random(), rng::random(), rng::random()];
+    (fport, data)
+}
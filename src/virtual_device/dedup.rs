@@ -0,0 +1,98 @@
+use semtech_udp::{pull_resp, StringOrNum};
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
+};
+
+/// Number of accepted downlinks remembered when looking for duplicates
+const DEDUP_WINDOW: usize = 16;
+
+/// Remembers the downlinks a device has accepted so that the same frame sent
+/// again by the network server can be recognized and dropped
+#[derive(Debug, Default)]
+pub struct Dedup {
+    seen: VecDeque<u64>,
+}
+
+impl Dedup {
+    /// Key a downlink on its tmst, frequency and payload
+    pub fn key(frame: &pull_resp::Packet) -> u64 {
+        let txpk = &frame.data.txpk;
+        let mut hasher = DefaultHasher::new();
+        match &txpk.tmst {
+            StringOrNum::N(n) => n.hash(&mut hasher),
+            StringOrNum::S(s) => s.hash(&mut hasher),
+        }
+        txpk.freq.to_bits().hash(&mut hasher);
+        txpk.data.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn insert(&mut self, key: u64) {
+        if self.seen.len() == DEDUP_WINDOW {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(key);
+    }
+
+    pub fn contains(&self, key: u64) -> bool {
+        self.seen.contains(&key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use semtech_udp::{Bandwidth, CodingRate, DataRate, Modulation, SpreadingFactor};
+
+    fn downlink(tmst: u32, freq: f64, data: &[u8]) -> pull_resp::Packet {
+        pull_resp::Packet {
+            random_token: 0,
+            data: pull_resp::Data {
+                txpk: pull_resp::TxPk {
+                    imme: false,
+                    tmst: StringOrNum::N(tmst),
+                    tmms: None,
+                    freq,
+                    rfch: 0,
+                    powe: 14,
+                    modu: Modulation::LORA,
+                    datr: DataRate::new(SpreadingFactor::SF7, Bandwidth::BW125),
+                    codr: CodingRate::_4_5,
+                    fdev: None,
+                    ipol: true,
+                    prea: None,
+                    size: data.len() as u64,
+                    data: data.to_vec(),
+                    ncrc: None,
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn key() {
+        let key = Dedup::key(&downlink(1_000, 868.1, &[0x60, 1, 2]));
+        let mut resent = downlink(1_000, 868.1, &[0x60, 1, 2]);
+        resent.random_token = 0xABCD;
+        resent.data.txpk.powe = 20;
+        assert_eq!(Dedup::key(&resent), key);
+        assert_ne!(Dedup::key(&downlink(2_000, 868.1, &[0x60, 1, 2])), key);
+        assert_ne!(Dedup::key(&downlink(1_000, 868.3, &[0x60, 1, 2])), key);
+        assert_ne!(Dedup::key(&downlink(1_000, 868.1, &[0x60, 1, 3])), key);
+    }
+
+    #[test]
+    fn window() {
+        let mut dedup = Dedup::default();
+        for key in 0..DEDUP_WINDOW as u64 {
+            dedup.insert(key);
+        }
+        assert!(dedup.contains(0));
+        dedup.insert(DEDUP_WINDOW as u64);
+        // the oldest is forgotten once the window is full
+        assert!(!dedup.contains(0));
+        assert!(dedup.contains(1));
+        assert!(dedup.contains(DEDUP_WINDOW as u64));
+    }
+}
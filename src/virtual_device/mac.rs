@@ -0,0 +1,250 @@
+use crate::settings;
+use lorawan::{
+    creator::DataPayloadCreator,
+    keys::AES128,
+    maccommands::SerializableMacCommand,
+    parser::{parse, DataHeader, DataPayload, FCtrl, FRMPayload, MHDRAble, MType, PhyPayload},
+};
+
+/// CIDs of the MAC commands the device looks at. It answers DevStatusReq
+/// itself, which the LoRaWAN stack leaves unanswered, and follows the
+/// DataRate, TXPower and NbTrans of LinkADRReq.
+pub const LINK_ADR: u8 = 0x03;
+pub const DEV_STATUS: u8 = 0x06;
+
+/// FOptsLen is a 4 bit field
+pub const MAX_FOPTS: usize = 15;
+
+/// Payload lengths and names of the commands a network server may send, by
+/// CID
+const DOWNLINK_COMMANDS: [(u8, usize, &str); 10] = [
+    (0x02, 2, "LinkCheckAns"),
+    (LINK_ADR, 4, "LinkADRReq"),
+    (0x04, 1, "DutyCycleReq"),
+    (0x05, 4, "RXParamSetupReq"),
+    (DEV_STATUS, 0, "DevStatusReq"),
+    (0x07, 5, "NewChannelReq"),
+    (0x08, 1, "RXTimingSetupReq"),
+    (0x09, 1, "TxParamSetupReq"),
+    (0x0A, 4, "DlChannelReq"),
+    (0x0D, 5, "DeviceTimeAns"),
+];
+
+/// The FOpts of a data downlink's PHYPayload, which are sent in the clear.
+/// Network servers put MAC commands there alongside or in place of
+/// application data, those sent on FPort 0 instead aren't looked at.
+pub fn fopts(phy_payload: &[u8]) -> &[u8] {
+    // MHDR, then DevAddr ahead of FCtrl and FCnt
+    let len = phy_payload
+        .get(5)
+        .map_or(0, |fctrl| usize::from(fctrl & 0x0F));
+    phy_payload.get(8..8 + len).unwrap_or_default()
+}
+
+/// The CID and payload of each command, up to the first one not known,
+/// whose length can't be told
+pub fn commands(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut commands = Vec::new();
+    while let Some((&cid, rest)) = data.split_first() {
+        let len = match DOWNLINK_COMMANDS.iter().find(|(known, ..)| *known == cid) {
+            Some((_, len, _)) if *len <= rest.len() => *len,
+            _ => break,
+        };
+        commands.push((cid, &rest[..len]));
+        data = &rest[len..];
+    }
+    commands
+}
+
+/// The commands named with their payloads in hex, eg:
+/// `LinkADRReq(5307FF01) DevStatusReq()`, and whatever follows the last one
+/// known left as it is
+pub fn describe(data: &[u8]) -> String {
+    let commands = commands(data);
+    let parsed: usize = commands.iter().map(|(_, payload)| 1 + payload.len()).sum();
+    let mut described: Vec<String> = commands
+        .iter()
+        .map(|(cid, payload)| {
+            let name = DOWNLINK_COMMANDS
+                .iter()
+                .find(|(known, ..)| known == cid)
+                .map_or("", |(.., name)| name);
+            format!("{}({})", name, hex::encode_upper(payload))
+        })
+        .collect();
+    if parsed < data.len() {
+        described.push(format!("unknown({})", hex::encode_upper(&data[parsed..])));
+    }
+    described.join(" ")
+}
+
+/// How far below its maximum a LinkADRReq has the device transmit, in dB,
+/// or None if it keeps the power as it is or asks for more steps than the
+/// region has
+pub fn tx_power_reduction_db(payload: &[u8], region: &settings::Region) -> Option<u8> {
+    let tx_power = payload.first()? & 0x0F;
+    let max = match region {
+        settings::Region::EU868 => 7,
+        settings::Region::US915 => 14,
+    };
+    // each step is 2 dB down from the maximum, 15 leaves it unchanged
+    (tx_power <= max).then(|| 2 * tx_power)
+}
+
+/// DataRate of a LinkADRReq, or None if it is to stay as it is
+pub fn data_rate(payload: &[u8]) -> Option<u8> {
+    let data_rate = payload.first()? >> 4;
+    (data_rate != 0x0F).then_some(data_rate)
+}
+
+/// ChMask and ChMaskCntl of a LinkADRReq, which the stack's channel plan
+/// doesn't take
+pub fn ch_mask(payload: &[u8]) -> (u16, u8) {
+    let ch_mask = match payload.get(1..3) {
+        Some(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]),
+        None => 0,
+    };
+    let ch_mask_cntl = payload
+        .get(3)
+        .map_or(0, |redundancy| (redundancy >> 4) & 0x07);
+    (ch_mask, ch_mask_cntl)
+}
+
+/// NbTrans of a LinkADRReq, the number of times each uplink is to be
+/// transmitted, or None if it is to stay as it is
+pub fn nb_trans(payload: &[u8]) -> Option<u8> {
+    let nb_trans = payload.get(3)? & 0x0F;
+    (nb_trans != 0).then_some(nb_trans)
+}
+
+/// An answer to a MAC command, waiting to go out with an uplink
+#[derive(Clone, Debug)]
+pub struct Answer {
+    cid: u8,
+    payload: Vec<u8>,
+}
+
+impl Answer {
+    /// Its length on air, CID included
+    pub fn size(&self) -> usize {
+        1 + self.payload.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.cid];
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+impl SerializableMacCommand for Answer {
+    fn payload_bytes(&self) -> &[u8] {
+        &self.payload
+    }
+
+    fn cid(&self) -> u8 {
+        self.cid
+    }
+
+    fn payload_len(&self) -> usize {
+        self.payload.len()
+    }
+}
+
+/// A DevStatusAns, with the battery level from 1 to 254 or 255 if unknown,
+/// and the SNR of the DevStatusReq
+pub fn dev_status_ans(battery: u8, margin: i8) -> Answer {
+    Answer {
+        cid: DEV_STATUS,
+        payload: vec![battery, margin.clamp(-32, 31) as u8 & 0x3F],
+    }
+}
+
+/// How many of the answers, from the first, fit in FOpts together
+pub fn fitting_fopts(answers: &[Answer]) -> usize {
+    let mut len = 0;
+    answers
+        .iter()
+        .take_while(|answer| {
+            len += answer.size();
+            len <= MAX_FOPTS
+        })
+        .count()
+}
+
+/// Answers to put in the FOpts of the next data uplink, or in its FRMPayload
+/// if it is on FPort 0, which the stack can't, with the session keys and
+/// FCnt to make the frame again with
+pub struct Piggyback {
+    pub answers: Vec<Answer>,
+    pub nwk_skey: [u8; 16],
+    pub app_skey: [u8; 16],
+    pub fcnt: u32,
+}
+
+impl Piggyback {
+    /// The frame made again with the answers, which are handed back if it
+    /// can't be
+    pub fn apply(self, phy_payload: &[u8]) -> Result<Vec<u8>, Vec<Answer>> {
+        piggyback(
+            phy_payload,
+            &self.answers,
+            &AES128(self.nwk_skey),
+            &AES128(self.app_skey),
+            self.fcnt,
+        )
+        .ok_or(self.answers)
+    }
+}
+
+/// The data uplink the stack built, made again with the answers in its
+/// FOpts and signed anew. The FRMPayload is encrypted the same whatever the
+/// FOpts, and the FCnt is the full one the frame was sent with. An uplink on
+/// FPort 0 has the answers as its FRMPayload instead, encrypted with the
+/// NwkSKey. None if the frame has neither application data nor FPort 0.
+pub fn piggyback(
+    phy_payload: &[u8],
+    answers: &[Answer],
+    nwk_skey: &AES128,
+    app_skey: &AES128,
+    fcnt: u32,
+) -> Option<Vec<u8>> {
+    let uplink = match parse(phy_payload.to_vec()) {
+        Ok(PhyPayload::Data(DataPayload::Encrypted(uplink))) => uplink,
+        _ => return None,
+    };
+    let fport = uplink.f_port()?;
+    let confirmed = uplink.mhdr().mtype() == MType::ConfirmedDataUp;
+    let mut dev_addr = [0; 4];
+    dev_addr.copy_from_slice(uplink.fhdr().dev_addr().as_ref());
+    // ADR, ADRACKReq and ACK as the stack set them, FOptsLen is filled in
+    let fctrl = phy_payload.get(5)? & 0xF0;
+    let payload = match fport {
+        0 => Vec::new(),
+        _ => match uplink
+            .decrypt(Some(nwk_skey), Some(app_skey), fcnt)
+            .ok()?
+            .frm_payload()
+        {
+            Ok(FRMPayload::Data(data)) => data.to_vec(),
+            _ => return None,
+        },
+    };
+
+    let mut creator = DataPayloadCreator::new();
+    creator
+        .set_confirmed(confirmed)
+        .set_uplink(true)
+        .set_dev_addr(&dev_addr)
+        .set_fcnt(fcnt)
+        .set_fctrl(&FCtrl::new(fctrl, true))
+        .set_f_port(fport);
+    let commands: Vec<&dyn SerializableMacCommand> = answers
+        .iter()
+        .map(|answer| answer as &dyn SerializableMacCommand)
+        .collect();
+    creator
+        .build(&payload, &commands, nwk_skey, app_skey)
+        .ok()
+        .map(|frame| frame.to_vec())
+}
@@ -1,108 +1,598 @@
 use super::*;
 
-use lorawan::default_crypto::DefaultFactory as LorawanCrypto;
+use battery::Battery;
+pub use builder::{Builder, PayloadSource, TimedPayloadSource};
+use dedup::Dedup;
+use latency::Latency;
+use lorawan::{
+    default_crypto::DefaultFactory as LorawanCrypto,
+    keys::AES128,
+    parser::{
+        DataHeader, DataPayload, DecryptedJoinAcceptPayload, FRMPayload, JoinAcceptPayload,
+        PhyPayload,
+    },
+};
 use lorawan_device::{
-    radio, region, Device, Event as LorawanEvent, JoinMode, Response as LorawanResponse,
+    radio, region::DR, Device, Event as LorawanEvent, Response as LorawanResponse,
 };
+pub use observer::{DeviceObserver, Direction, Frame};
+use rate_limit::RateLimiter;
 use semtech_udp::StringOrNum;
-use tokio::time::{sleep, Duration};
-use udp_radio::UdpRadio;
-pub(crate) use udp_radio::{IntermediateEvent, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use std::sync::{atomic::AtomicU32, Arc};
+use tokio::{
+    sync::watch,
+    time::{sleep, Duration},
+};
+pub use transport::{Delivery, Loopback, VirtualTransport};
+pub use udp_radio::{Error as RadioError, Handover, IntermediateEvent, Receiver, Sender, TxParams};
+use udp_radio::{UdpRadio, DOWNLINK_SNR, RX_BUFFER_SIZE};
+mod battery;
+mod builder;
+mod dedup;
+mod mac;
+mod observer;
+mod rules;
+mod rx_timing;
+mod transport;
 mod udp_radio;
 
+/// Bytes of MHDR, FHDR without FOpts, FPort and MIC around an uplink's data
+const FRAME_OVERHEAD: usize = 13;
+/// How far FCntDown may skip ahead before a 1.0.3 device drops the frame.
+/// LoRaWAN 1.0.4 did away with it.
+const MAX_FCNT_GAP: u32 = 16384;
+
 pub struct VirtualDevice {
     label: String,
     device: Device<UdpRadio, LorawanCrypto, 512>,
-    time: Instant,
+    clock: gateway_clock::GatewayClock,
     receiver: Receiver<IntermediateEvent>,
     sender: Sender<IntermediateEvent>,
     metrics_sender: metrics::Sender,
-    rejoin_frames: u32,
-    secs_between_transmits: u64,
+    event_sender: event_log::Sender,
+    observers: Vec<Arc<dyn DeviceObserver>>,
+    schedule: watch::Receiver<Schedule>,
+    payload: TimedPayloadSource,
+    rules: rules::Rules,
+    uplink_limit: Option<u32>,
+    negative_join: Option<settings::NegativeJoin>,
+    data_rate: Option<u8>,
+    /// the configured AppKey, which the stack doesn't use with WrongAppKey
+    app_key: [u8; 16],
+    rx_timing_tolerance_us: u32,
+    region: settings::Region,
+    lorawan_version: settings::LorawanVersion,
+    dev_nonces: Arc<AtomicU32>,
+    /// a session from an earlier run and its next FCntDown, carried on in
+    /// place of the first join
+    resume: Option<(Session, Option<u32>)>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    battery: Option<Battery>,
+    shutdown: watch::Receiver<bool>,
+    /// schedule and shutdown senders made by the builder, when none were given
+    _senders: (Option<watch::Sender<Schedule>>, Option<watch::Sender<bool>>),
+    state_sender: watch::Sender<DeviceState>,
+    state_receiver: watch::Receiver<DeviceState>,
+}
+
+/// Device settings which can be changed while the device runs without
+/// disturbing its session
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Schedule {
+    pub rejoin_frames: u32,
+    pub rejoin_secs: Option<u64>,
+    pub reboot_secs: Option<u64>,
+    pub secs_between_transmits: u64,
+    /// Uplinks without a downlink before ADR backs off, and between steps
+    pub adr_ack_limit: u32,
+    pub adr_ack_delay: u32,
+}
+
+impl From<&settings::Device> for Schedule {
+    fn from(device: &settings::Device) -> Schedule {
+        Schedule {
+            rejoin_frames: device.rejoin_frames,
+            rejoin_secs: device.rejoin_secs,
+            reboot_secs: device.reboot_secs,
+            secs_between_transmits: device.secs_between_transmits,
+            adr_ack_limit: device.adr_ack_limit,
+            adr_ack_delay: device.adr_ack_delay,
+        }
+    }
+}
+
+/// Snapshot of a device's session, kept up to date while it runs so that it
+/// can be flushed to disk on shutdown and checkpointed
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DeviceState {
+    pub dev_eui: String,
+    pub joined: bool,
+    pub fcnt_up: Option<u32>,
+    pub next_fcnt_down: Option<u32>,
+    pub rule_violations: u32,
+    /// Whether scheduled uplinks are held from the console
+    #[serde(default)]
+    pub paused: bool,
+    /// Kept out of state.json, which isn't meant to hold keys, and
+    /// checkpointed on its own
+    #[serde(skip)]
+    pub session: Option<Session>,
+}
+
+/// The DevAddr and keys of a joined device's session, in hex as network
+/// servers show them, so a checkpoint can carry the session on to the next run
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Session {
+    pub dev_addr: String,
+    pub nwk_skey: String,
+    pub app_skey: String,
+    pub rx_delay_secs: u8,
+}
+
+impl Session {
+    fn new(dev_addr: [u8; 4], nwk_skey: &[u8; 16], app_skey: &[u8; 16], rx_delay_secs: u8) -> Self {
+        Session {
+            dev_addr: hex::encode_upper(dev_addr),
+            nwk_skey: hex::encode_upper(nwk_skey),
+            app_skey: hex::encode_upper(app_skey),
+            rx_delay_secs,
+        }
+    }
+
+    /// The DevAddr, least significant byte first as it is sent, NwkSKey and
+    /// AppSKey
+    pub fn keys(&self) -> Result<([u8; 4], [u8; 16], [u8; 16])> {
+        let mut dev_addr = [0; 4];
+        hex::decode_to_slice(&self.dev_addr, &mut dev_addr)?;
+        dev_addr.reverse();
+        let mut nwk_skey = [0; 16];
+        hex::decode_to_slice(&self.nwk_skey, &mut nwk_skey)?;
+        let mut app_skey = [0; 16];
+        hex::decode_to_slice(&self.app_skey, &mut app_skey)?;
+        Ok((dev_addr, nwk_skey, app_skey))
+    }
 }
 
 impl VirtualDevice {
-    #[allow(clippy::too_many_arguments)]
-    pub async fn new(
-        label: String,
-        time: Instant,
-        udp_runtime: &semtech_udp::client_runtime::UdpRuntime,
-        credentials: Credentials,
-        metrics_sender: metrics::Sender,
-        rejoin_frames: u32,
-        secs_between_transmits: u64,
-        region: settings::Region,
-    ) -> Result<VirtualDevice> {
-        let (radio, receiver, sender) = UdpRadio::new(time, udp_runtime).await;
-        let region: region::Configuration = match region {
-            settings::Region::US915 => region::US915::subband(2).into(),
-            settings::Region::EU868 => region::EU868::default().into(),
-        };
+    pub fn builder(label: &str) -> Builder {
+        Builder::new(label)
+    }
 
-        let device: Device<udp_radio::UdpRadio, LorawanCrypto, 512> = Device::new(
-            region,
-            JoinMode::OTAA {
-                deveui: credentials.deveui_cloned_into_buf()?,
-                appeui: credentials.appeui_cloned_into_buf()?,
-                appkey: credentials.appkey_cloned_into_buf()?,
-            },
-            radio,
-            rand::random::<u32>,
-        );
+    pub fn state(&self) -> watch::Receiver<DeviceState> {
+        self.state_receiver.clone()
+    }
 
-        Ok(VirtualDevice {
-            label,
-            device,
-            time,
-            receiver,
-            sender,
-            metrics_sender,
-            rejoin_frames,
-            secs_between_transmits,
-        })
+    /// Sender for injecting events, eg: from the console
+    pub fn control(&self) -> Sender<IntermediateEvent> {
+        self.sender.clone()
     }
 
-    pub async fn run(mut self) -> Result<()> {
+    /// Run the device until it is shut down, reaches its uplink limit or
+    /// fails, telling the observers if it does
+    pub async fn run(self) -> Result<()> {
+        let label = self.label.clone();
+        let observers = self.observers.clone();
+        let result = match self.lorawan_version {
+            settings::LorawanVersion::V1_0_3 => self.run_loop().await,
+            settings::LorawanVersion::V1_0_4 => {
+                rng::count_dev_nonces(self.dev_nonces.clone(), self.run_loop()).await
+            }
+        };
+        if let Err(e) = &result {
+            for observer in &observers {
+                observer.on_error(&label, e);
+            }
+        }
+        result
+    }
+
+    /// How far off its RX delays a downlink may be, which 1.0.4 holds to the
+    /// spec's 20 μs however loosely the simulation is configured
+    fn rx_timing_tolerance(&self) -> u32 {
+        match self.lorawan_version {
+            settings::LorawanVersion::V1_0_3 => self.rx_timing_tolerance_us,
+            settings::LorawanVersion::V1_0_4 => self.rx_timing_tolerance_us.min(20),
+        }
+    }
+
+    async fn run_loop(mut self) -> Result<()> {
+        // lets RUST_LOG pick out single devices, eg: RUST_LOG=warn,device::one=trace
+        let log_target = format!("device::{}", self.label);
+        // routine per-frame messages, which VDEVICE_LOG_STEADY_STATE=false hides
+        let steady_target = format!("{}::steady", log_target);
+
         // stagger the starts slightly
-        let random = rand::random::<u64>() % 1000;
+        let random = rng::random::<u64>() % 1000;
         sleep(Duration::from_millis(random)).await;
 
         // Kickstart activity by trying to join
-        self.sender
-            .send(IntermediateEvent::NewSession)
-            .await
-            .unwrap();
+        self.sender.send(IntermediateEvent::NewSession).await?;
 
         let mut time_remaining = None;
+        // FCntDown we expect on the next downlink; a fresh session starts at 0
+        let mut next_fcnt_down: Option<u32> = None;
+        // set when the frame being processed was an immediate (unscheduled) downlink
+        let mut unscheduled = false;
+        let mut dedup = Dedup::default();
+        // dedup key of the frame most recently handed to the LoRaWAN stack
+        let mut last_rx_key = None;
+        let rx_timing_tolerance = self.rx_timing_tolerance();
         let mut lorawan = self.device;
+        if let Some(dr) = self.data_rate {
+            lorawan.set_datarate(dr_from_index(dr));
+        }
         let mut metrics_sender = self.metrics_sender;
+        let event_sender = self.event_sender;
+        // once stopping, no new uplinks or joins are started and the device
+        // exits as soon as nothing is in flight
+        let mut stopping = false;
+        let mut in_flight = false;
+        // whether the schedule already has an uplink waiting to be sent, so that
+        // exchanges started from the console don't start a second schedule
+        let mut uplink_scheduled = false;
+        // while paused, the scheduled uplink which comes due is held until
+        // resumed, so the schedule carries on from it
+        let mut paused = false;
+        let mut held: Option<(Vec<u8>, u8, bool)> = None;
+        let mut uplinks = 0;
+        let dev_eui = self.state_receiver.borrow().dev_eui.clone();
+        // the frame most recently handed to the stack
+        let mut last_rx = None;
+        // RX1 delay of the current session, from its join accept
+        let mut rx_delay_secs = 1;
+        // when the current session was joined, for rejoin_secs, and when the
+        // device last started, for reboot_secs
+        let mut joined_at = Instant::now();
+        let mut booted_at = Instant::now();
+        // when the join request and confirmed uplink awaiting an answer were
+        // last sent, for their latencies
+        let mut join_sent_at: Option<Instant> = None;
+        let mut confirmed_sent_at = None;
+        let mut rule_violations = 0;
+        // downlinks in a row with FPending set, which the network server
+        // is draining its queue with
+        let mut queue_depth = 0;
+        // answers to MAC commands, sent with the next uplink, and the
+        // session keys to add them to its FOpts with
+        let mut mac_answers: Vec<mac::Answer> = Vec::new();
+        let mut session_keys = None;
+        // the current session as checkpointed, and one to resume in place of
+        // the first join
+        let mut session = None;
+        let mut resume = self.resume.take();
+        // NbTrans from the network server, and the data, fport, FCnt and
+        // confirmed flag of the last uplink to be repeated with how many
+        // times it has been sent
+        let mut nb_trans = 1;
+        let mut repeated_uplink: Option<(Vec<u8>, u8, u32, bool)> = None;
+        // FPort and data of the last uplink handed to the stack, for observers
+        let mut last_sent: Option<(u8, Vec<u8>)> = None;
+        let mut transmissions = 0;
+        // uplinks since the last downlink, which ADR backs off after
+        let mut adr_ack_cnt: u32 = 0;
+        // whether the join in flight is one the network server should reject
+        let mut negative_attempt = matches!(
+            self.negative_join,
+            Some(settings::NegativeJoin::WrongAppKey | settings::NegativeJoin::Unprovisioned)
+        );
+        // ReplayedDevNonce joins legitimately first, then replays that DevNonce
+        let mut replay_pending =
+            self.negative_join == Some(settings::NegativeJoin::ReplayedDevNonce);
         loop {
-            let event = self
-                .receiver
-                .recv()
-                .await
-                .expect("Channel unexpectedly closed");
+            let event = tokio::select! {
+                event = self.receiver.recv() => event.ok_or(Error::DeviceChannelClosed)?,
+                _ = self.shutdown.changed(), if !stopping => {
+                    stopping = true;
+                    if !in_flight {
+                        info!(target: &log_target, "stopped");
+                        return Ok(());
+                    }
+                    info!(target: &log_target, "stopping once in-flight exchange completes");
+                    continue;
+                }
+            };
+            let scheduled = matches!(event, IntermediateEvent::SendPacket(..)) && !paused;
+            if scheduled {
+                uplink_scheduled = false;
+            }
+            // FCntDown of a downlink a 1.0.3 device drops for being too far ahead
+            let over_gap = match &event {
+                IntermediateEvent::UdpRx(frame) | IntermediateEvent::RadioEvent(frame, _)
+                    if self.lorawan_version == settings::LorawanVersion::V1_0_3 =>
+                {
+                    over_fcnt_gap(&frame.data.txpk.data, &session_keys, next_fcnt_down)
+                }
+                _ => None,
+            };
             let response = {
                 match event {
-                    IntermediateEvent::NewSession => {
-                        lorawan.handle_event(LorawanEvent::NewSessionRequest)
+                    IntermediateEvent::NewSession
+                    | IntermediateEvent::SendPacket(..)
+                    | IntermediateEvent::ManualPacket(..)
+                    | IntermediateEvent::Retransmit
+                    | IntermediateEvent::SendNow
+                        if stopping =>
+                    {
+                        Ok(LorawanResponse::NoUpdate)
                     }
+                    IntermediateEvent::Pause => {
+                        if !paused {
+                            info!(target: &log_target, "paused");
+                        }
+                        paused = true;
+                        Ok(LorawanResponse::NoUpdate)
+                    }
+                    IntermediateEvent::Resume => {
+                        if paused {
+                            info!(target: &log_target, "resumed");
+                        }
+                        paused = false;
+                        if let Some((data, fport, confirmed)) = held.take() {
+                            self.sender
+                                .send(IntermediateEvent::SendPacket(data, fport, confirmed))
+                                .await?;
+                        }
+                        Ok(LorawanResponse::NoUpdate)
+                    }
+                    IntermediateEvent::SendPacket(data, fport, confirmed) if paused => {
+                        debug!(target: &log_target, "holding scheduled uplink while paused");
+                        held = Some((data, fport, confirmed));
+                        Ok(LorawanResponse::NoUpdate)
+                    }
+                    // the held uplink goes out of turn and the schedule starts
+                    // again after it, otherwise the next payload is drawn and
+                    // sent alongside the schedule
+                    IntermediateEvent::SendNow => {
+                        let (data, fport, confirmed) = match held.take() {
+                            Some(uplink) => {
+                                uplink_scheduled = false;
+                                uplink
+                            }
+                            None => {
+                                let (_, fport, data) = (self.payload)();
+                                (data, fport, true)
+                            }
+                        };
+                        self.sender
+                            .send(IntermediateEvent::ManualPacket(data, fport, confirmed))
+                            .await?;
+                        Ok(LorawanResponse::NoUpdate)
+                    }
+                    IntermediateEvent::NewSession => match resume.take() {
+                        // the stack was built with the session's keys, so
+                        // it is ready to send without joining
+                        Some((resumed, fcnt_down)) => {
+                            info!(
+                                target: &log_target,
+                                "resuming session of {} from FCntUp 0", resumed.dev_addr
+                            );
+                            session_keys = resumed.keys().ok().map(|(_, nwk, app)| (nwk, app));
+                            rx_delay_secs = resumed.rx_delay_secs;
+                            next_fcnt_down = fcnt_down;
+                            session = Some(resumed);
+                            Ok(LorawanResponse::ReadyToSend)
+                        }
+                        None => {
+                            rng::start_join();
+                            lorawan.handle_event(LorawanEvent::NewSessionRequest)
+                        }
+                    },
+                    IntermediateEvent::Handover(handover) => {
+                        info!(target: &log_target, "handed over to {}", handover.label);
+                        self.clock = handover.transport.clock();
+                        lorawan.get_radio().hand_over(handover.transport);
+                        event_sender
+                            .send(event_log::Event::Handover {
+                                packet_forwarder: handover.label,
+                            })
+                            .await?;
+                        Ok(LorawanResponse::NoUpdate)
+                    }
+                    IntermediateEvent::Retransmit => match &repeated_uplink {
+                        Some((data, fport, fcnt, confirmed)) => {
+                            transmissions += 1;
+                            info!(
+                                target: &log_target,
+                                "retransmitting fcnt = {}, transmission {} of {}",
+                                fcnt,
+                                transmissions,
+                                nb_trans
+                            );
+                            event_sender
+                                .send(event_log::Event::Retransmission {
+                                    fcnt: *fcnt,
+                                    transmission: transmissions,
+                                })
+                                .await?;
+                            if *confirmed {
+                                confirmed_sent_at = Some(Instant::now());
+                            }
+                            lorawan.get_radio().repeat_next();
+                            lorawan.send(data, *fport, *confirmed)
+                        }
+                        None => Ok(LorawanResponse::NoUpdate),
+                    },
                     IntermediateEvent::Timeout(id) => {
                         if lorawan.get_radio().most_recent_timeout(id) {
+                            event_sender.send(event_log::Event::Timeout).await?;
                             lorawan.handle_event(LorawanEvent::TimeoutFired)
                         } else {
                             Ok(LorawanResponse::NoUpdate)
                         }
                     }
-                    IntermediateEvent::SendPacket(data, fport, confirmed) => {
-                        // this will only be None if there is no session
-                        if let Some(fcnt_up) = lorawan.get_fcnt_up() {
-                            info!(
-                                "{:8} sending packet fcnt = {} on fport {}",
-                                self.label, fcnt_up, fport
+                    IntermediateEvent::SendPacket(data, fport, confirmed)
+                    | IntermediateEvent::ManualPacket(data, fport, confirmed) => {
+                        // a compliant device keeps to the region's dwell time, so uplinks
+                        // too long for the current data rate are never sent
+                        if let Some(airtime) = over_dwell_time(
+                            lorawan.get_datarate(),
+                            &self.region,
+                            uplink_size(&data, fport, scheduled, &mac_answers, &session_keys),
+                        ) {
+                            warn!(
+                                target: &log_target,
+                                "dropping uplink of {} bytes on fport {}, {:?} on air is over the dwell time limit",
+                                data.len(),
+                                fport,
+                                airtime
                             );
+                            metrics_sender
+                                .send(metrics::Message::DwellTimeViolation)
+                                .await?;
+                            event_sender
+                                .send(event_log::Event::DwellTimeViolation {
+                                    fport,
+                                    size: data.len(),
+                                    airtime_ms: airtime.as_millis() as u64,
+                                })
+                                .await?;
+                            Ok(LorawanResponse::ReadyToSend)
+                        } else {
+                            // recorded once the frame carrying the answers is made
+                            let mut piggybacked = None;
+                            // this will only be None if there is no session
+                            if let Some(fcnt_up) = lorawan.get_fcnt_up() {
+                                info!(
+                                    target: &steady_target,
+                                    "sending packet fcnt = {} on fport {}",
+                                    fcnt_up,
+                                    fport
+                                );
+                                for observer in &self.observers {
+                                    observer.on_uplink_sent(&self.label, fcnt_up, fport, confirmed);
+                                }
+                                confirmed_sent_at = confirmed.then(Instant::now);
+                                event_sender
+                                    .send(event_log::Event::Uplink {
+                                        fcnt: fcnt_up,
+                                        fport,
+                                        confirmed,
+                                    })
+                                    .await?;
+                                // unconfirmed uplinks are repeated NbTrans times, confirmed
+                                // ones until acked
+                                repeated_uplink = Some((data.clone(), fport, fcnt_up, confirmed));
+                                transmissions = 1;
+                                let mac_only = scheduled && fport == 0;
+                                let fitting =
+                                    carried_answers(&data, fport, scheduled, &mac_answers);
+                                if fitting > 0 && (mac_only || session_keys.is_some()) {
+                                    let answers: Vec<mac::Answer> =
+                                        mac_answers.drain(..fitting).collect();
+                                    let commands: Vec<u8> =
+                                        answers.iter().flat_map(mac::Answer::to_bytes).collect();
+                                    debug!(
+                                        target: &log_target,
+                                        "MAC command answers {}",
+                                        hex::encode_upper(&commands)
+                                    );
+                                    let event = if mac_only {
+                                        event_log::Event::MacOnlyUplink {
+                                            fcnt: fcnt_up,
+                                            frm_payload: hex::encode_upper(&commands),
+                                        }
+                                    } else {
+                                        event_log::Event::Piggybacked {
+                                            fcnt: fcnt_up,
+                                            fopts: hex::encode_upper(&commands),
+                                        }
+                                    };
+                                    // without the session keys the stack sends the answers
+                                    // on FPort 0 as they are
+                                    match session_keys {
+                                        Some((nwk_skey, app_skey)) => {
+                                            lorawan.get_radio().piggyback_next(mac::Piggyback {
+                                                answers,
+                                                nwk_skey,
+                                                app_skey,
+                                                fcnt: fcnt_up,
+                                            });
+                                            piggybacked = Some(event);
+                                        }
+                                        None => event_sender.send(event).await?,
+                                    }
+                                }
+                            }
+                            let response = lorawan.send(&data, fport, confirmed);
+                            if let Some(event) = piggybacked {
+                                // answers which didn't go out wait at the front of the
+                                // queue for the next uplink
+                                match lorawan.get_radio().take_unsent_answers() {
+                                    Some(answers) => {
+                                        mac_answers.splice(..0, answers);
+                                    }
+                                    None => event_sender.send(event).await?,
+                                }
+                            }
+                            last_sent = Some((fport, data));
+                            response
                         }
-                        lorawan.send(&data, fport, confirmed)
+                    }
+                    // drop anything we've already accepted, whether it's just arrived or is
+                    // about to be delivered into the RX window
+                    IntermediateEvent::UdpRx(frame) | IntermediateEvent::RadioEvent(frame, _)
+                        if dedup.contains(Dedup::key(&frame)) =>
+                    {
+                        warn!(target: &log_target, "dropping duplicate downlink");
+                        metrics_sender
+                            .send(metrics::Message::DuplicateDownlink)
+                            .await?;
+                        event_sender
+                            .send(event_log::Event::DuplicateDownlink)
+                            .await?;
+                        Ok(LorawanResponse::NoUpdate)
+                    }
+                    // a malformed downlink must not take the device down, so drop it here
+                    IntermediateEvent::UdpRx(frame)
+                        if frame.data.txpk.data.len() > RX_BUFFER_SIZE =>
+                    {
+                        let size = frame.data.txpk.data.len();
+                        warn!(target: &log_target, "dropping oversized downlink of {} bytes", size);
+                        metrics_sender
+                            .send(metrics::Message::OversizedDownlink)
+                            .await?;
+                        event_sender
+                            .send(event_log::Event::OversizedDownlink { size })
+                            .await?;
+                        Ok(LorawanResponse::NoUpdate)
+                    }
+                    // the stack would accept the frame, advance FCntDown and ACK it, so
+                    // a 1.0.3 device drops it before the stack sees it
+                    IntermediateEvent::UdpRx(_) | IntermediateEvent::RadioEvent(..)
+                        if over_gap.is_some() =>
+                    {
+                        let expected = next_fcnt_down.unwrap_or_default();
+                        let received = over_gap.unwrap_or_default();
+                        warn!(
+                            target: &log_target,
+                            "dropping downlink, FCntDown {} is more than MAX_FCNT_GAP past {}",
+                            received,
+                            expected
+                        );
+                        metrics_sender
+                            .send(metrics::Message::FCntDownRejected)
+                            .await?;
+                        event_sender
+                            .send(event_log::Event::FCntDownRejected { expected, received })
+                            .await?;
+                        Ok(LorawanResponse::NoUpdate)
+                    }
+                    // immediate downlinks (eg: Class C) are handed to the device right away
+                    IntermediateEvent::UdpRx(frame) if is_immediate(&frame) => {
+                        info!(target: &log_target, "immediate downlink, delivering unscheduled");
+                        time_remaining = None;
+                        unscheduled = true;
+                        last_rx_key = Some(Dedup::key(&frame));
+                        last_rx = Some(Rx {
+                            arrival_tmst: self.clock.tmst(),
+                            freq: frame.data.txpk.freq,
+                            datr: geolocation::datr_name(&frame.data.txpk.datr),
+                            tmst: None,
+                            data: frame.data.txpk.data.clone(),
+                        });
+                        lorawan
+                            .handle_event(LorawanEvent::RadioEvent(radio::Event::PhyEvent(frame)))
                     }
                     // UdpRx processes the raw UDP frame and delays it if necessary
                     IntermediateEvent::UdpRx(frame) => {
@@ -111,36 +601,78 @@ impl VirtualDevice {
                             // we will hold the frame until the RxWindow begins
                             StringOrNum::N(n) => {
                                 let scheduled_time = *n;
-                                let time = self.time.elapsed().as_micros() as u32;
-                                if scheduled_time > time {
-                                    let delay = scheduled_time - time;
+                                let time = self.clock.tmst();
+                                let offset =
+                                    gateway_clock::GatewayClock::offset(time, scheduled_time);
+                                if offset > 0 {
+                                    let delay = self.clock.to_real(offset as u32);
                                     tokio::spawn(async move {
-                                        sleep(Duration::from_micros(delay as u64 + 50_000)).await;
-                                        self_sender
+                                        sleep(delay + Duration::from_micros(50_000)).await;
+                                        let _ = self_sender
                                             .send(IntermediateEvent::RadioEvent(frame, time as u64))
-                                            .await
-                                            .unwrap();
+                                            .await;
                                     });
                                 } else {
-                                    let time_since_scheduled_time = time - scheduled_time;
+                                    let time_since_scheduled_time = offset.unsigned_abs();
                                     warn!(
-                                        "{:8} UDP packet received after tx time by {} μs",
-                                        self.label, time_since_scheduled_time
+                                        target: &log_target,
+                                        "UDP packet received after tx time by {} μs",
+                                        time_since_scheduled_time
                                     );
+                                    metrics_sender
+                                        .send(metrics::Message::MissedRxWindow)
+                                        .await?;
+                                    event_sender
+                                        .send(event_log::Event::MissedRxWindow {
+                                            late_by_us: time_since_scheduled_time,
+                                        })
+                                        .await?;
                                 }
                             }
                             StringOrNum::S(s) => {
-                                warn!("{:8} Unexpected! UDP packet sent with {:?}", self.label, s);
+                                warn!(
+                                    target: &log_target,
+                                    "Unexpected! UDP packet sent with {:?}",
+                                    s
+                                );
                             }
                         }
                         Ok(LorawanResponse::NoUpdate)
                     }
                     // at this level, the RadioEvent is being delivered in the appopriate window
                     IntermediateEvent::RadioEvent(frame, time_received) => {
+                        // the stack can't open an accept made with the configured key
+                        if negative_attempt
+                            && self.negative_join == Some(settings::NegativeJoin::WrongAppKey)
+                            && decrypt_join_accept(&frame.data.txpk.data, &self.app_key).is_some()
+                        {
+                            warn!(target: &log_target, "join with the wrong AppKey was accepted");
+                            metrics_sender
+                                .send(metrics::Message::NegativeJoinAccepted)
+                                .await?;
+                            event_sender
+                                .send(event_log::Event::NegativeJoinAccepted {
+                                    mode: settings::NegativeJoin::WrongAppKey,
+                                })
+                                .await?;
+                        }
+                        unscheduled = false;
+                        last_rx_key = Some(Dedup::key(&frame));
+                        last_rx = Some(Rx {
+                            arrival_tmst: time_received as u32,
+                            freq: frame.data.txpk.freq,
+                            datr: geolocation::datr_name(&frame.data.txpk.datr),
+                            tmst: match frame.data.txpk.tmst {
+                                StringOrNum::N(tmst) => Some(tmst),
+                                StringOrNum::S(_) => None,
+                            },
+                            data: frame.data.txpk.data.clone(),
+                        });
                         time_remaining = match frame.data.txpk.tmst {
-                            semtech_udp::StringOrNum::N(tmst) => {
-                                Some(tmst as i64 - time_received as i64)
-                            }
+                            semtech_udp::StringOrNum::N(tmst) => Some(
+                                gateway_clock::GatewayClock::offset(time_received as u32, tmst)
+                                    as i64,
+                            ),
                             semtech_udp::StringOrNum::S(_) => None,
                         };
                         lorawan
@@ -148,73 +680,573 @@ impl VirtualDevice {
                     }
                 }
             };
-            //lorawan = new_state;
+            // errors raised by our own radio mean this device can't continue
+            if let Some(e) = lorawan.get_radio().take_error() {
+                return Err(e.into());
+            }
+            for _ in 0..lorawan.get_radio().take_unheard() {
+                warn!(target: &log_target, "uplink too weak for any gateway to hear");
+                metrics_sender.send(metrics::Message::UplinkUnheard).await?;
+                event_sender.send(event_log::Event::UplinkUnheard).await?;
+            }
+            if let (Some(battery), Some((airtime, power_dbm))) =
+                (&mut self.battery, lorawan.get_radio().take_transmission())
+            {
+                battery.drain(airtime, power_dbm);
+                metrics_sender
+                    .send(metrics::Message::BatteryLevel(
+                        self.label.clone(),
+                        battery.percent(),
+                    ))
+                    .await?;
+                if battery.is_empty() && !stopping {
+                    warn!(target: &log_target, "battery empty, stopping");
+                    stopping = true;
+                }
+            }
+            // every device sees every downlink, so only remember the frames which were for us
+            let rx_key = last_rx_key.take();
+            let rx = last_rx.take();
+            if let (
+                Some(key),
+                Ok(LorawanResponse::JoinSuccess | LorawanResponse::DownlinkReceived(_)),
+            ) = (rx_key, &response)
+            {
+                dedup.insert(key);
+            }
+            let rf_mismatch = lorawan.get_radio().take_rf_mismatch();
+            if let (
+                Some(mismatch),
+                Ok(LorawanResponse::JoinSuccess | LorawanResponse::DownlinkReceived(_)),
+            ) = (rf_mismatch, &response)
+            {
+                warn!(
+                    target: &log_target,
+                    "downlink sent on {} MHz {} but RX window is {} MHz {}",
+                    mismatch.freq,
+                    mismatch.datr.to_string(),
+                    mismatch.expected_freq,
+                    mismatch.expected_datr.to_string()
+                );
+                metrics_sender.send(metrics::Message::RfMismatch).await?;
+                event_sender
+                    .send(event_log::Event::RfMismatch {
+                        expected_freq: mismatch.expected_freq,
+                        freq: mismatch.freq,
+                        expected_datr: mismatch.expected_datr.to_string(),
+                        datr: mismatch.datr.to_string(),
+                    })
+                    .await?;
+            }
+            let tx_params = lorawan.get_radio().last_tx_params().cloned();
+            if let (
+                Some(tx_params),
+                Ok(LorawanResponse::JoinSuccess | LorawanResponse::DownlinkReceived(_)),
+            ) = (&tx_params, &response)
+            {
+                for reason in tx_params.problems(&self.region) {
+                    warn!(target: &log_target, "downlink TX parameters: {}", reason);
+                    metrics_sender
+                        .send(metrics::Message::TxParamViolation)
+                        .await?;
+                    event_sender
+                        .send(event_log::Event::TxParamViolation {
+                            reason,
+                            tx: tx_params.clone(),
+                        })
+                        .await?;
+                }
+            }
+            if let (
+                Some(rx),
+                Ok(
+                    response
+                    @ (LorawanResponse::JoinSuccess | LorawanResponse::DownlinkReceived(_)),
+                ),
+            ) = (&rx, &response)
+            {
+                let join_accept = matches!(response, LorawanResponse::JoinSuccess);
+                if join_accept {
+                    let decrypted = decrypt_join_accept(&rx.data, &self.app_key);
+                    rx_delay_secs = decrypted
+                        .as_ref()
+                        .map_or(1, |join_accept| join_accept.rx_delay());
+                    session_keys = derive_session_keys(
+                        lorawan.get_radio().last_uplink(),
+                        &rx.data,
+                        &self.app_key,
+                    );
+                    if let (Some(join_accept), Some((nwk_skey, app_skey))) =
+                        (&decrypted, &session_keys)
+                    {
+                        // sent least significant byte first
+                        let mut dev_addr = [0; 4];
+                        dev_addr.copy_from_slice(join_accept.dev_addr().as_ref());
+                        dev_addr.reverse();
+                        for observer in &self.observers {
+                            observer.on_session_keys(&self.label, dev_addr, nwk_skey, app_skey);
+                        }
+                        session = Some(Session::new(dev_addr, nwk_skey, app_skey, rx_delay_secs));
+                    }
+                }
+                if let (Some(tmst), Some(tx_tmst)) = (rx.tmst, lorawan.get_radio().last_tx_tmst()) {
+                    let offset_us = gateway_clock::GatewayClock::offset(tx_tmst, tmst) as i64;
+                    if let Some(problem) =
+                        rx_timing::check(offset_us, join_accept, rx_delay_secs, rx_timing_tolerance)
+                    {
+                        warn!(target: &log_target, "RX timing not conformant: {}", problem);
+                        metrics_sender
+                            .send(metrics::Message::RxTimingViolation)
+                            .await?;
+                        event_sender
+                            .send(event_log::Event::RxTimingViolation {
+                                offset_us,
+                                reason: problem,
+                            })
+                            .await?;
+                    }
+                }
+            }
+            if let Ok(response) = &response {
+                match response {
+                    LorawanResponse::UplinkSending(_) | LorawanResponse::JoinRequestSending => {
+                        in_flight = true
+                    }
+                    LorawanResponse::JoinSuccess
+                    | LorawanResponse::ReadyToSend
+                    | LorawanResponse::DownlinkReceived(_)
+                    | LorawanResponse::NoAck
+                    | LorawanResponse::NoJoinAccept
+                    | LorawanResponse::SessionExpired => in_flight = false,
+                    _ => (),
+                }
+            }
             let (send_uplink, confirmed) = {
                 let (mut send_uplink, mut confirmed) = (false, true);
                 match response {
                     Ok(response) => match response {
                         LorawanResponse::TimeoutRequest(ms) => {
-                            lorawan.get_radio().timer(ms).await;
-                            debug!("{:8} TimeoutRequest: {:?}", self.label, ms)
+                            if let Some(late_by) = lorawan.get_radio().timer(ms).await {
+                                warn!(
+                                    target: &log_target,
+                                    "timer requested {} ms in the past",
+                                    late_by
+                                );
+                                metrics_sender.send(metrics::Message::LateTimer).await?;
+                            }
+                            debug!(target: &log_target, "TimeoutRequest: {:?}", ms)
                         }
                         LorawanResponse::JoinSuccess => {
                             send_uplink = true;
+                            next_fcnt_down = Some(0);
+                            joined_at = Instant::now();
+                            queue_depth = 0;
+                            mac_answers.clear();
+                            confirmed_sent_at = None;
+                            if let Some(sent_at) = join_sent_at.take() {
+                                metrics_sender
+                                    .send(metrics::Message::Latency(
+                                        Latency::Join,
+                                        sent_at.elapsed(),
+                                    ))
+                                    .await?;
+                            }
+                            // a new session starts out at full power and the configured
+                            // data rate, sending each uplink once
+                            lorawan.get_radio().set_tx_power_reduction(0);
+                            if let Some(dr) = self.data_rate {
+                                lorawan.set_datarate(dr_from_index(dr));
+                            }
+                            nb_trans = 1;
+                            repeated_uplink = None;
+                            adr_ack_cnt = 0;
+                            if let Some(rx) = &rx {
+                                let frame = Frame {
+                                    direction: Direction::Downlink,
+                                    phy_payload: &rx.data,
+                                    payload: &[],
+                                    fcnt: None,
+                                    fport: None,
+                                    freq: rx.freq,
+                                    datr: Some(rx.datr.as_str()),
+                                    tmst: rx.tmst,
+                                };
+                                for observer in &self.observers {
+                                    observer.on_frame(&self.label, &frame);
+                                }
+                            }
                             if let Some(time_remaining) = time_remaining.take() {
+                                for observer in &self.observers {
+                                    observer.on_join(&self.label, time_remaining);
+                                }
                                 metrics_sender
                                     .send(metrics::Message::JoinSuccess(time_remaining))
                                     .await?;
+                                event_sender
+                                    .send(event_log::Event::JoinSuccess {
+                                        time_remaining_us: time_remaining,
+                                    })
+                                    .await?;
 
                                 if let Some(session) = lorawan.get_session_keys() {
                                     info!(
-                                        "{:8} join success, time remaining: {:4} ms, {:?}",
-                                        self.label,
+                                        target: &log_target,
+                                        "join success, time remaining: {:4} ms, {:?}",
                                         time_remaining / 1000,
                                         session
                                     )
                                 }
                             }
+                            if let (true, Some(mode)) = (negative_attempt, self.negative_join) {
+                                warn!(target: &log_target, "{:?} join was accepted", mode);
+                                metrics_sender
+                                    .send(metrics::Message::NegativeJoinAccepted)
+                                    .await?;
+                                event_sender
+                                    .send(event_log::Event::NegativeJoinAccepted { mode })
+                                    .await?;
+                            }
+                            if replay_pending {
+                                info!(target: &log_target, "joining again with the same DevNonce");
+                                replay_pending = false;
+                                negative_attempt = true;
+                                send_uplink = false;
+                                rng::replay_join();
+                                self.sender.send(IntermediateEvent::NewSession).await?;
+                            } else if self.negative_join
+                                == Some(settings::NegativeJoin::ReplayedDevNonce)
+                            {
+                                negative_attempt = false;
+                            }
+                        }
+                        // the spec has repetitions follow right after RX2
+                        LorawanResponse::ReadyToSend
+                            if matches!(repeated_uplink, Some((.., false)))
+                                && transmissions < nb_trans =>
+                        {
+                            self.sender.send(IntermediateEvent::Retransmit).await?;
                         }
                         LorawanResponse::ReadyToSend => {
                             send_uplink = true;
-                            debug!("{:8} ready to send", self.label)
+                            debug!(target: &log_target, "ready to send")
                         }
                         LorawanResponse::DownlinkReceived(fcnt_down) => {
                             send_uplink = true;
-                            if let Some(time_remaining) = time_remaining.take() {
+                            if let Some(expected) = next_fcnt_down {
+                                if fcnt_down != expected {
+                                    warn!(
+                                        target: &log_target,
+                                        "FCntDown discontinuity, expected {} received {}",
+                                        expected,
+                                        fcnt_down
+                                    );
+                                    metrics_sender
+                                        .send(if fcnt_down > expected {
+                                            metrics::Message::FCntDownGap(fcnt_down - expected)
+                                        } else {
+                                            metrics::Message::FCntDownRepeat
+                                        })
+                                        .await?;
+                                    event_sender
+                                        .send(event_log::Event::FCntDownDiscontinuity {
+                                            expected,
+                                            received: fcnt_down,
+                                        })
+                                        .await?;
+                                }
+                            }
+                            next_fcnt_down = Some(fcnt_down.wrapping_add(1));
+                            adr_ack_cnt = 0;
+                            let downlink = lorawan.take_data_downlink();
+                            let fport = downlink.as_ref().and_then(|downlink| downlink.f_port());
+                            let f_pending = downlink
+                                .as_ref()
+                                .is_some_and(|downlink| downlink.fhdr().fctrl().f_pending());
+                            queue_depth = if f_pending { queue_depth + 1 } else { 0 };
+                            let ack = downlink
+                                .as_ref()
+                                .is_some_and(|downlink| downlink.fhdr().fctrl().ack());
+                            if let Some(sent_at) = confirmed_sent_at.filter(|_| ack) {
+                                confirmed_sent_at = None;
+                                metrics_sender
+                                    .send(metrics::Message::Latency(
+                                        Latency::AckRoundTrip,
+                                        sent_at.elapsed(),
+                                    ))
+                                    .await?;
+                            }
+                            metrics_sender
+                                .send(metrics::Message::DownlinkQueueDepth(
+                                    self.label.clone(),
+                                    queue_depth,
+                                ))
+                                .await?;
+                            let payload = match downlink.as_ref().map(|d| d.frm_payload()) {
+                                Some(Ok(FRMPayload::Data(data))) => data.to_vec(),
+                                _ => Vec::new(),
+                            };
+                            for observer in &self.observers {
+                                observer.on_downlink(&self.label, fcnt_down, fport, &payload);
+                            }
+                            if let Some(rx) = &rx {
+                                let frame = Frame {
+                                    direction: Direction::Downlink,
+                                    phy_payload: &rx.data,
+                                    payload: &payload,
+                                    fcnt: Some(fcnt_down),
+                                    fport,
+                                    freq: rx.freq,
+                                    datr: Some(rx.datr.as_str()),
+                                    tmst: rx.tmst,
+                                };
+                                for observer in &self.observers {
+                                    observer.on_frame(&self.label, &frame);
+                                }
+                            }
+                            let fopts = rx.as_ref().map_or(&[][..], |rx| mac::fopts(&rx.data));
+                            for (cid, command) in mac::commands(fopts) {
+                                if cid == mac::LINK_ADR {
+                                    let data_rate = mac::data_rate(command)
+                                        .filter(|dr| self.region.datr(*dr).is_some());
+                                    let (ch_mask, ch_mask_cntl) = mac::ch_mask(command);
+                                    event_sender
+                                        .send(event_log::Event::LinkAdrReq {
+                                            data_rate,
+                                            ch_mask: format!("{:04X}", ch_mask),
+                                            ch_mask_cntl,
+                                            nb_trans: mac::nb_trans(command),
+                                        })
+                                        .await?;
+                                    if let Some(dr) = data_rate {
+                                        info!(target: &log_target, "data rate DR{}", dr);
+                                        lorawan.set_datarate(dr_from_index(dr));
+                                    }
+                                    if let Some(reduction_db) =
+                                        mac::tx_power_reduction_db(command, &self.region)
+                                    {
+                                        info!(
+                                            target: &log_target,
+                                            "TX power {} dB below maximum",
+                                            reduction_db
+                                        );
+                                        lorawan.get_radio().set_tx_power_reduction(reduction_db);
+                                        event_sender
+                                            .send(event_log::Event::TxPower { reduction_db })
+                                            .await?;
+                                    }
+                                    if let Some(n) = mac::nb_trans(command) {
+                                        info!(target: &log_target, "NbTrans {}", n);
+                                        nb_trans = n;
+                                    }
+                                } else if cid == mac::DEV_STATUS {
+                                    let battery =
+                                        self.battery.as_ref().map_or(255, Battery::dev_status);
+                                    mac_answers.push(mac::dev_status_ans(battery, DOWNLINK_SNR));
+                                    event_sender
+                                        .send(event_log::Event::DevStatusAns {
+                                            battery,
+                                            margin: DOWNLINK_SNR,
+                                        })
+                                        .await?;
+                                }
+                            }
+                            let contents = format!(
+                                "fport = {}, payload = {}, mac = [{}]",
+                                fport.map_or_else(|| "none".to_string(), |fport| fport.to_string()),
+                                hex::encode_upper(&payload),
+                                mac::describe(
+                                    rx.as_ref().map_or(&[][..], |rx| mac::fopts(&rx.data))
+                                )
+                            );
+                            if let Some(rx) = rx {
+                                let latency_us =
+                                    lorawan.get_radio().last_tx_tmst().and_then(|tx_tmst| {
+                                        u32::try_from(gateway_clock::GatewayClock::offset(
+                                            tx_tmst,
+                                            rx.arrival_tmst,
+                                        ))
+                                        .ok()
+                                    });
+                                let violations = self.rules.check(&rules::Downlink {
+                                    fport,
+                                    payload: &payload,
+                                    latency_us,
+                                    freq: rx.freq,
+                                });
+                                for violation in violations {
+                                    warn!(
+                                        target: &log_target,
+                                        "downlink rule broken: {}",
+                                        violation
+                                    );
+                                    rule_violations += 1;
+                                    metrics_sender
+                                        .send(metrics::Message::DownlinkRuleViolation)
+                                        .await?;
+                                    event_sender
+                                        .send(event_log::Event::DownlinkRuleViolation {
+                                            fcnt: fcnt_down,
+                                            reason: violation,
+                                        })
+                                        .await?;
+                                }
+                            }
+                            let time_remaining = time_remaining.take();
+                            if let Some(time_remaining) = time_remaining {
                                 metrics_sender
                                     .send(metrics::Message::DataSuccess(time_remaining))
                                     .await?;
                                 info!(
-                                    "{:8} downlink received with fcnt = {}, time remaining: {:4} ms",
-                                    self.label,
+                                    target: &steady_target,
+                                    "downlink received with fcnt = {}, time remaining: {:4} ms, {}",
+                                    fcnt_down,
+                                    time_remaining / 1000,
+                                    contents
+                                )
+                            } else if unscheduled {
+                                info!(
+                                    target: &steady_target,
+                                    "unscheduled downlink received with fcnt = {}, {}",
                                     fcnt_down,
-                                    time_remaining / 1000
+                                    contents
                                 )
                             }
+                            if time_remaining.is_some() || unscheduled {
+                                event_sender
+                                    .send(event_log::Event::Downlink {
+                                        fcnt: fcnt_down,
+                                        fport,
+                                        time_remaining_us: time_remaining,
+                                        unscheduled,
+                                        f_pending,
+                                        tx: tx_params.clone(),
+                                    })
+                                    .await?;
+                            }
+                        }
+                        // sent again after the spec's ACK_TIMEOUT of 1 to 3 s,
+                        // until it has been sent NbTrans times
+                        LorawanResponse::NoAck
+                            if matches!(repeated_uplink, Some((.., true)))
+                                && transmissions < nb_trans =>
+                        {
+                            let delay = Duration::from_millis(1000 + rng::random::<u64>() % 2000);
+                            debug!(target: &log_target, "no ACK, retransmitting in {:?}", delay);
+                            let sender = self.sender.clone();
+                            tokio::spawn(async move {
+                                sleep(delay).await;
+                                let _ = sender.send(IntermediateEvent::Retransmit).await;
+                            });
                         }
                         LorawanResponse::NoAck => {
                             metrics_sender.send(metrics::Message::DataFail).await?;
+                            event_sender.send(event_log::Event::NoAck).await?;
                             send_uplink = true;
                             confirmed = false;
-                            warn!("{:8} RxWindow expired, expected ACK to confirmed uplink not received", self.label)
+                            warn!(
+                                target: &log_target,
+                                "RxWindow expired, expected ACK to confirmed uplink not received"
+                            )
                         }
                         LorawanResponse::NoJoinAccept => {
+                            if let (true, Some(mode)) = (negative_attempt, self.negative_join) {
+                                info!(target: &log_target, "{:?} join was rejected", mode);
+                                event_sender
+                                    .send(event_log::Event::JoinRejected { mode })
+                                    .await?;
+                                if mode == settings::NegativeJoin::ReplayedDevNonce {
+                                    negative_attempt = false;
+                                }
+                            }
                             metrics_sender.send(metrics::Message::JoinFail).await?;
+                            event_sender.send(event_log::Event::JoinFail).await?;
                             self.sender.send(IntermediateEvent::NewSession).await?;
-                            warn!("{:8} No Join Accept Received", self.label)
+                            warn!(target: &log_target, "No Join Accept Received")
                         }
                         LorawanResponse::SessionExpired => {
+                            event_sender.send(event_log::Event::SessionExpired).await?;
                             self.sender.send(IntermediateEvent::NewSession).await?;
-                            debug!("{:8} SessionExpired. Created new Session", self.label)
+                            debug!(target: &log_target, "SessionExpired. Created new Session")
                         }
                         LorawanResponse::NoUpdate => {
-                            debug!("{:8} NoUpdate", self.label)
+                            debug!(target: &log_target, "NoUpdate")
                         }
                         LorawanResponse::UplinkSending(fcnt_up) => {
-                            info!("{:8} Uplink with FCnt {}", self.label, fcnt_up)
+                            info!(target: &steady_target, "Uplink with FCnt {}", fcnt_up);
+                            let radio = lorawan.get_radio();
+                            let (fport, payload) = match &last_sent {
+                                Some((fport, data)) => (Some(*fport), &data[..]),
+                                None => (None, &[][..]),
+                            };
+                            let frame = Frame {
+                                direction: Direction::Uplink,
+                                phy_payload: radio.last_uplink(),
+                                payload,
+                                fcnt: Some(fcnt_up),
+                                fport,
+                                freq: radio.last_tx_freq().unwrap_or_default(),
+                                datr: radio.last_tx_datr_name(),
+                                tmst: radio.last_tx_tmst(),
+                            };
+                            for observer in &self.observers {
+                                observer.on_frame(&self.label, &frame);
+                            }
+                            adr_ack_cnt += 1;
+                            let schedule = *self.schedule.borrow();
+                            let past_limit = adr_ack_cnt.saturating_sub(schedule.adr_ack_limit);
+                            // heard nothing back for too long, so step towards a link
+                            // more likely to get through: full power, then slower data rates
+                            if past_limit > 0
+                                && past_limit.is_multiple_of(schedule.adr_ack_delay.max(1))
+                            {
+                                let radio = lorawan.get_radio();
+                                let mut data_rate = radio
+                                    .last_tx_datr_name()
+                                    .and_then(|datr| self.region.data_rate(datr));
+                                let stepped = if radio.tx_power_reduction_db() > 0 {
+                                    radio.set_tx_power_reduction(0);
+                                    true
+                                } else if let Some(dr) = data_rate.and_then(|dr| dr.checked_sub(1))
+                                {
+                                    lorawan.set_datarate(dr_from_index(dr));
+                                    data_rate = Some(dr);
+                                    true
+                                } else {
+                                    false
+                                };
+                                if stepped {
+                                    info!(
+                                        target: &log_target,
+                                        "no downlink in {} uplinks, backing off",
+                                        adr_ack_cnt
+                                    );
+                                    event_sender
+                                        .send(event_log::Event::AdrBackoff {
+                                            uplinks: adr_ack_cnt,
+                                            data_rate,
+                                        })
+                                        .await?;
+                                }
+                            }
                         }
                         LorawanResponse::JoinRequestSending => {
-                            info!("{:8} Join Request Sending", self.label)
+                            let radio = lorawan.get_radio();
+                            let frame = Frame {
+                                direction: Direction::Uplink,
+                                phy_payload: radio.last_uplink(),
+                                payload: &[],
+                                fcnt: None,
+                                fport: None,
+                                freq: radio.last_tx_freq().unwrap_or_default(),
+                                datr: radio.last_tx_datr_name(),
+                                tmst: radio.last_tx_tmst(),
+                            };
+                            for observer in &self.observers {
+                                observer.on_frame(&self.label, &frame);
+                            }
+                            join_sent_at = Some(Instant::now());
+                            event_sender.send(event_log::Event::JoinRequest).await?;
+                            info!(target: &log_target, "Join Request Sending")
                         }
                     },
                     // silent errors since we receive radio frames for other devices
@@ -222,33 +1254,114 @@ impl VirtualDevice {
                 }
                 (send_uplink, confirmed)
             };
-            if send_uplink {
+            let fcnt_up = lorawan.get_fcnt_up();
+            let joined = lorawan.get_session_keys().is_some();
+            let _ = self.state_sender.send(DeviceState {
+                dev_eui: dev_eui.clone(),
+                joined,
+                fcnt_up,
+                next_fcnt_down,
+                rule_violations,
+                paused,
+                session: session.clone().filter(|_| joined),
+            });
+            if send_uplink && !stopping && Some(uplinks) == self.uplink_limit {
+                info!(target: &log_target, "sent {} uplinks, stopping", uplinks);
+                stopping = true;
+            }
+            if stopping && !in_flight {
+                info!(target: &log_target, "stopped");
+                return Ok(());
+            }
+            if send_uplink && !uplink_scheduled {
                 if let Some(fcnt_up) = lorawan.get_fcnt_up() {
-                    if fcnt_up > self.rejoin_frames {
+                    let schedule = *self.schedule.borrow();
+                    let session_age = joined_at.elapsed();
+                    let uptime = booted_at.elapsed();
+                    if schedule
+                        .reboot_secs
+                        .is_some_and(|secs| uptime >= Duration::from_secs(secs))
+                    {
+                        // nothing survives the reset but what a device keeps in
+                        // flash, the DevNonce counter of 1.0.4
+                        info!(
+                            target: &log_target,
+                            "rebooting after {} s, dropping session and {} MAC answers",
+                            uptime.as_secs(),
+                            mac_answers.len()
+                        );
+                        event_sender
+                            .send(event_log::Event::Reboot {
+                                uptime_secs: uptime.as_secs(),
+                                mac_answers_dropped: mac_answers.len(),
+                            })
+                            .await?;
+                        booted_at = Instant::now();
+                        mac_answers.clear();
+                        session_keys = None;
+                        session = None;
+                        repeated_uplink = None;
+                        next_fcnt_down = None;
+                        nb_trans = 1;
+                        adr_ack_cnt = 0;
+                        lorawan.get_radio().set_tx_power_reduction(0);
+                        if let Some(dr) = self.data_rate {
+                            lorawan.set_datarate(dr_from_index(dr));
+                        }
+                        self.sender.send(IntermediateEvent::NewSession).await?;
+                    } else if fcnt_up > schedule.rejoin_frames
+                        || schedule
+                            .rejoin_secs
+                            .is_some_and(|secs| session_age >= Duration::from_secs(secs))
+                    {
+                        info!(
+                            target: &log_target,
+                            "rotating session after {} uplinks and {} s",
+                            fcnt_up,
+                            session_age.as_secs()
+                        );
+                        event_sender
+                            .send(event_log::Event::SessionRotated {
+                                fcnt_up,
+                                session_age_secs: session_age.as_secs(),
+                            })
+                            .await?;
                         self.sender.send(IntermediateEvent::NewSession).await?;
                     } else {
-                        let mut fport = rand::random();
-                        while fport == 0 {
-                            fport = rand::random();
-                        }
+                        // drawn here since the spawned task doesn't carry the device's rng.
+                        // MAC command answers go in the uplink's FOpts, or in a MAC-only
+                        // uplink on FPort 0 in place of application data when there are
+                        // more than FOpts holds or the frame can't carry them.
+                        let (delay, fport, data) = (self.payload)();
+                        let (fport, data) = if !mac_answers.is_empty()
+                            && (mac::fitting_fopts(&mac_answers) < mac_answers.len()
+                                || session_keys.is_none()
+                                || fport == 0
+                                || data.is_empty())
+                        {
+                            (
+                                0,
+                                mac_answers.iter().flat_map(mac::Answer::to_bytes).collect(),
+                            )
+                        } else {
+                            (fport, data)
+                        };
 
                         let sender = self.sender.clone();
-                        let duration = Duration::from_secs(self.secs_between_transmits);
+                        let duration = delay.unwrap_or_else(|| {
+                            Duration::from_secs(schedule.secs_between_transmits)
+                        });
+                        let rate_limiter = self.rate_limiter.clone();
+                        uplink_scheduled = true;
+                        uplinks += 1;
                         tokio::spawn(async move {
                             sleep(duration).await;
-                            sender
-                                .send(IntermediateEvent::SendPacket(
-                                    vec![
-                                        rand::random(),
-                                        rand::random(),
-                                        rand::random(),
-                                        rand::random(),
-                                    ],
-                                    fport,
-                                    confirmed,
-                                ))
-                                .await
-                                .unwrap();
+                            if let Some(rate_limiter) = rate_limiter {
+                                rate_limiter.acquire().await;
+                            }
+                            let _ = sender
+                                .send(IntermediateEvent::SendPacket(data, fport, confirmed))
+                                .await;
                         });
                     }
                 }
@@ -256,3 +1369,201 @@ impl VirtualDevice {
         }
     }
 }
+
+/// How long an uplink of this many bytes of PHYPayload would be on air at
+/// the stack's data rate, if that's over the region's dwell time limit
+fn over_dwell_time(data_rate: DR, region: &settings::Region, size: usize) -> Option<Duration> {
+    let airtime = udp_radio::uplink_airtime(region.datr(data_rate as u8)?, size)?;
+    (airtime > region.dwell_time_limit()?).then_some(airtime)
+}
+
+/// How many of the MAC command answers waiting, from the first, an uplink
+/// carries. A scheduled uplink on FPort 0 carries every answer in its
+/// FRMPayload, others as many as fit in FOpts if they have data to go with.
+fn carried_answers(data: &[u8], fport: u8, scheduled: bool, answers: &[mac::Answer]) -> usize {
+    if scheduled && fport == 0 {
+        answers.len()
+    } else if fport != 0 && !data.is_empty() {
+        mac::fitting_fopts(answers)
+    } else {
+        0
+    }
+}
+
+/// Bytes of PHYPayload an uplink is sent as, with the answers it carries.
+/// Without the session keys the frame can't be made again, so it is sent
+/// as the stack built it.
+fn uplink_size(
+    data: &[u8],
+    fport: u8,
+    scheduled: bool,
+    answers: &[mac::Answer],
+    session_keys: &Option<([u8; 16], [u8; 16])>,
+) -> usize {
+    let carried = &answers[..carried_answers(data, fport, scheduled, answers)];
+    let answers: usize = carried.iter().map(mac::Answer::size).sum();
+    match session_keys {
+        // the answers take the place of the data in the FRMPayload
+        Some(_) if scheduled && fport == 0 => FRAME_OVERHEAD + answers,
+        Some(_) => FRAME_OVERHEAD + answers + data.len(),
+        None => FRAME_OVERHEAD + data.len(),
+    }
+}
+
+/// FCntDown of a downlink to this session MAX_FCNT_GAP or more past the one
+/// expected. Only its low 16 bits are sent, so it is taken to be the count
+/// nearest the one expected that the MIC checks out with.
+fn over_fcnt_gap(
+    data: &[u8],
+    session_keys: &Option<([u8; 16], [u8; 16])>,
+    expected: Option<u32>,
+) -> Option<u32> {
+    let ((nwk_skey, _), expected) = (session_keys.as_ref()?, expected?);
+    let downlink = match lorawan::parser::parse(data.to_vec()) {
+        Ok(PhyPayload::Data(DataPayload::Encrypted(downlink))) if !downlink.is_uplink() => downlink,
+        _ => return None,
+    };
+    let ahead = downlink.fhdr().fcnt().wrapping_sub(expected as u16) as i16;
+    let fcnt = expected.wrapping_add(i32::from(ahead) as u32);
+    (i32::from(ahead) >= MAX_FCNT_GAP as i32 && downlink.validate_mic(&AES128(*nwk_skey), fcnt))
+        .then_some(fcnt)
+}
+
+/// The stack's data rate for a regional index, up to the highest either
+/// region uses
+fn dr_from_index(index: u8) -> DR {
+    match index {
+        0 => DR::_0,
+        1 => DR::_1,
+        2 => DR::_2,
+        3 => DR::_3,
+        4 => DR::_4,
+        5 => DR::_5,
+        _ => DR::_6,
+    }
+}
+
+/// Whether the network server asked for this frame to be sent immediately
+/// rather than at a given tmst
+fn is_immediate(frame: &semtech_udp::pull_resp::Packet) -> bool {
+    frame.data.txpk.imme || matches!(&frame.data.txpk.tmst, StringOrNum::S(s) if s == "immediate")
+}
+
+/// Where and when a frame handed to the stack arrived, kept until the stack
+/// says whether it was for this device
+struct Rx {
+    arrival_tmst: u32,
+    freq: f64,
+    datr: heapless::String<16>,
+    /// None for immediate downlinks
+    tmst: Option<u32>,
+    data: Vec<u8>,
+}
+
+/// The NwkSKey and AppSKey of the session started by a join accept made with
+/// this AppKey, in answer to the join request
+fn derive_session_keys(
+    join_request: &[u8],
+    join_accept: &[u8],
+    app_key: &[u8; 16],
+) -> Option<([u8; 16], [u8; 16])> {
+    let join_accept = decrypt_join_accept(join_accept, app_key)?;
+    let key = AES128(*app_key);
+    match lorawan::parser::parse(join_request.to_vec()) {
+        Ok(PhyPayload::JoinRequest(join_request)) => {
+            let dev_nonce = join_request.dev_nonce();
+            Some((
+                join_accept.derive_newskey(&dev_nonce, &key).0,
+                join_accept.derive_appskey(&dev_nonce, &key).0,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Open a downlink if it is a join accept made with this AppKey
+fn decrypt_join_accept(
+    data: &[u8],
+    app_key: &[u8; 16],
+) -> Option<DecryptedJoinAcceptPayload<Vec<u8>, LorawanCrypto>> {
+    let key = AES128(*app_key);
+    match lorawan::parser::parse(data.to_vec()) {
+        Ok(PhyPayload::JoinAccept(JoinAcceptPayload::Encrypted(join_accept))) => {
+            let join_accept = join_accept.decrypt(&key);
+            join_accept.validate_mic(&key).then_some(join_accept)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lorawan::{creator::DataPayloadCreator, parser::FCtrl};
+
+    const NWK_SKEY: [u8; 16] = [0x2B; 16];
+    const APP_SKEY: [u8; 16] = [0x7E; 16];
+
+    fn downlink(fcnt: u32) -> Vec<u8> {
+        let mut creator = DataPayloadCreator::new();
+        creator
+            .set_confirmed(false)
+            .set_uplink(false)
+            .set_dev_addr(&[0x04, 0x03, 0x02, 0x01])
+            .set_fcnt(fcnt)
+            .set_fctrl(&FCtrl::new(0, false));
+        creator
+            .build(&[], &[], &AES128(NWK_SKEY), &AES128(APP_SKEY))
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn fcnt_gap() {
+        let keys = Some((NWK_SKEY, APP_SKEY));
+        assert_eq!(over_fcnt_gap(&downlink(10), &keys, Some(10)), None);
+        assert_eq!(
+            over_fcnt_gap(&downlink(9 + MAX_FCNT_GAP), &keys, Some(10)),
+            None
+        );
+        assert_eq!(
+            over_fcnt_gap(&downlink(10 + MAX_FCNT_GAP), &keys, Some(10)),
+            Some(10 + MAX_FCNT_GAP)
+        );
+        // half the counter space or more ahead reads as behind
+        assert_eq!(over_fcnt_gap(&downlink(10 + 0x8000), &keys, Some(10)), None);
+    }
+
+    #[test]
+    fn fcnt_gap_across_the_low_16_bits_wrapping() {
+        let keys = Some((NWK_SKEY, APP_SKEY));
+        let expected = 0xFFF0;
+        assert_eq!(
+            over_fcnt_gap(&downlink(expected + MAX_FCNT_GAP), &keys, Some(expected)),
+            Some(expected + MAX_FCNT_GAP)
+        );
+        assert_eq!(
+            over_fcnt_gap(&downlink(expected + 0x20), &keys, Some(expected)),
+            None
+        );
+        assert_eq!(
+            over_fcnt_gap(&downlink(expected - 1), &keys, Some(expected)),
+            None
+        );
+    }
+
+    #[test]
+    fn fcnt_gap_needs_the_session() {
+        let downlink = downlink(10 + MAX_FCNT_GAP);
+        assert_eq!(over_fcnt_gap(&downlink, &None, Some(10)), None);
+        assert_eq!(
+            over_fcnt_gap(&downlink, &Some((NWK_SKEY, APP_SKEY)), None),
+            None
+        );
+        // a MIC that doesn't check out is some other device's frame
+        assert_eq!(
+            over_fcnt_gap(&downlink, &Some(([0; 16], APP_SKEY)), Some(10)),
+            None
+        );
+    }
+}
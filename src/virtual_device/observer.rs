@@ -0,0 +1,67 @@
+use super::*;
+
+/// Callbacks on a device's lifecycle, for library users who'd rather react
+/// to devices directly than follow the event log. Observers are registered
+/// per device through the builder, or for the whole fleet on a Simulation.
+/// Every callback does nothing by default. They are called from the device's
+/// task, so they should return quickly.
+pub trait DeviceObserver: Send + Sync {
+    /// The device joined, with the time left in the RX window when the join
+    /// accept arrived
+    fn on_join(&self, _device: &str, _time_remaining_us: i64) {}
+
+    /// An uplink is about to be handed to the radio
+    fn on_uplink_sent(&self, _device: &str, _fcnt: u32, _fport: u8, _confirmed: bool) {}
+
+    /// The device accepted a downlink
+    fn on_downlink(&self, _device: &str, _fcnt: u32, _fport: Option<u8>, _payload: &[u8]) {}
+
+    /// A frame went over the air, either way. Uplinks are told of once they
+    /// have been handed to the radio, downlinks once the device accepted them.
+    fn on_frame(&self, _device: &str, _frame: &Frame) {}
+
+    /// The device derived its session keys from a join accept. The DevAddr
+    /// is most significant byte first, as it's usually written.
+    fn on_session_keys(
+        &self,
+        _device: &str,
+        _dev_addr: [u8; 4],
+        _nwk_skey: &[u8; 16],
+        _app_skey: &[u8; 16],
+    ) {
+    }
+
+    /// The device stopped on an error
+    fn on_error(&self, _device: &str, _error: &Error) {}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Uplink,
+    Downlink,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Uplink => "uplink",
+            Direction::Downlink => "downlink",
+        }
+    }
+}
+
+/// A frame as it went over the air, with what the device made of it
+#[derive(Debug)]
+pub struct Frame<'a> {
+    pub direction: Direction,
+    pub phy_payload: &'a [u8],
+    /// The FRMPayload in the clear, empty for joins and frames without one
+    pub payload: &'a [u8],
+    /// None for joins
+    pub fcnt: Option<u32>,
+    pub fport: Option<u8>,
+    pub freq: f64,
+    pub datr: Option<&'a str>,
+    /// None for immediate downlinks
+    pub tmst: Option<u32>,
+}
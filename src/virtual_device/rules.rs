@@ -0,0 +1,67 @@
+use crate::{settings::DownlinkRule, Result};
+use regex::Regex;
+
+/// What the device knows about a downlink its LoRaWAN stack accepted
+pub struct Downlink<'a> {
+    pub fport: Option<u8>,
+    pub payload: &'a [u8],
+    /// gateway time from the end of the uplink to the downlink arriving
+    pub latency_us: Option<u32>,
+    pub freq: f64,
+}
+
+/// The downlink rules which apply to one device, with payload patterns compiled
+#[derive(Debug, Default)]
+pub struct Rules {
+    rules: Vec<(DownlinkRule, Option<Regex>)>,
+}
+
+impl Rules {
+    pub fn new(label: &str, rules: &[DownlinkRule]) -> Result<Rules> {
+        let mut compiled = Vec::new();
+        for rule in rules {
+            if rule.device.as_deref().is_none_or(|device| device == label) {
+                let payload = rule.payload.as_deref().map(Regex::new).transpose()?;
+                compiled.push((rule.clone(), payload));
+            }
+        }
+        Ok(Rules { rules: compiled })
+    }
+
+    /// Describe every way the downlink breaks a rule covering its fport
+    pub fn check(&self, downlink: &Downlink) -> Vec<String> {
+        let mut violations = Vec::new();
+        for (rule, payload) in &self.rules {
+            if rule.fport.is_some() && rule.fport != downlink.fport {
+                continue;
+            }
+            if let Some(payload) = payload {
+                let hex = hex::encode_upper(downlink.payload);
+                if !payload.is_match(&hex) {
+                    violations.push(format!("payload {} does not match {}", hex, payload));
+                }
+            }
+            if let Some(max_latency_ms) = rule.max_latency_ms {
+                match downlink.latency_us {
+                    Some(latency_us) if latency_us <= max_latency_ms.saturating_mul(1000) => (),
+                    Some(latency_us) => violations.push(format!(
+                        "latency of {} ms exceeds {} ms",
+                        latency_us / 1000,
+                        max_latency_ms
+                    )),
+                    None => violations.push("latency unknown".to_string()),
+                }
+            }
+            if let Some(freq) = rule.freq {
+                // same tolerance as the RX window check
+                if (downlink.freq - freq).abs() > 0.0001 {
+                    violations.push(format!(
+                        "sent on {} MHz instead of {} MHz",
+                        downlink.freq, freq
+                    ));
+                }
+            }
+        }
+        violations
+    }
+}
@@ -0,0 +1,77 @@
+/// RECEIVE_DELAY1 when a join accept's RxDelay is 0, and the gap from RX1 to RX2
+const SECOND_US: i64 = 1_000_000;
+const JOIN_ACCEPT_DELAY1_US: i64 = 5_000_000;
+
+/// Check the time from the end of an uplink to the tmst its answer was
+/// scheduled for against the RX1 and RX2 delays the spec mandates, describing
+/// the problem if it fits neither. The delays are the same in every region.
+pub fn check(
+    offset_us: i64,
+    join_accept: bool,
+    rx_delay_secs: u8,
+    tolerance_us: u32,
+) -> Option<String> {
+    let rx1 = if join_accept {
+        JOIN_ACCEPT_DELAY1_US
+    } else {
+        // only the low 4 bits of RxDelay are used, and 0 means 1 second
+        i64::from((rx_delay_secs & 0x0F).max(1)) * SECOND_US
+    };
+    let rx2 = rx1 + SECOND_US;
+    let tolerance = i64::from(tolerance_us);
+    if (offset_us - rx1).abs() <= tolerance || (offset_us - rx2).abs() <= tolerance {
+        None
+    } else {
+        Some(format!(
+            "{} scheduled {} μs after the uplink, RX1 is at {} μs and RX2 at {} μs",
+            if join_accept {
+                "join accept"
+            } else {
+                "downlink"
+            },
+            offset_us,
+            rx1,
+            rx2
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_accept_windows() {
+        assert_eq!(check(5_000_000, true, 1, 20), None);
+        assert_eq!(check(6_000_020, true, 1, 20), None);
+        // a join accept is late by RX1 of data, whatever the RxDelay
+        assert!(check(1_000_000, true, 1, 20).is_some());
+        assert!(check(5_000_021, true, 1, 20).is_some());
+    }
+
+    #[test]
+    fn data_windows_follow_rx_delay() {
+        assert_eq!(check(1_000_000, false, 1, 20), None);
+        assert_eq!(check(2_000_000, false, 1, 20), None);
+        assert_eq!(check(5_000_000, false, 5, 20), None);
+        assert_eq!(check(6_000_000, false, 5, 20), None);
+        assert!(check(1_000_000, false, 5, 20).is_some());
+        assert!(check(1_500_000, false, 1, 20).is_some());
+    }
+
+    #[test]
+    fn rx_delay_zero_and_rfu_bits() {
+        // 0 means a second, and the high bits are RFU
+        assert_eq!(check(1_000_000, false, 0, 20), None);
+        assert_eq!(check(2_000_000, false, 0x12, 20), None);
+    }
+
+    #[test]
+    fn problem_names_the_windows() {
+        assert_eq!(
+            check(1_500_000, false, 1, 20).unwrap(),
+            "downlink scheduled 1500000 μs after the uplink, RX1 is at 1000000 μs and RX2 at \
+             2000000 μs"
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{gateway_clock::GatewayClock, geolocation};
+use semtech_udp::{pull_resp, push_data};
+use tokio::sync::mpsc;
+
+/// What became of an uplink handed to a transport
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    Delivered,
+    /// Too weak for any gateway to demodulate
+    Unheard,
+    /// The transport can't take it right now
+    Full,
+}
+
+/// How a device's radio reaches the network server. Frames are described as
+/// Semtech rxpk and txpk, which carry the RF metadata any gateway backend
+/// deals in, so a backend such as Basics Station or MQTT only translates them
+/// to its own protocol while the radio state machine stays the same.
+pub trait VirtualTransport: Send + Sync {
+    /// Clock the transport's gateway stamps frames with
+    fn clock(&self) -> GatewayClock;
+
+    /// Hand an uplink over without waiting. Returns false if the transport
+    /// can't take it right now.
+    fn uplink(&self, rxpk: push_data::RxPkV1) -> bool;
+
+    /// Hand an uplink over which was sent this many dB below the device's
+    /// maximum TX power. Transports which model propagation take the power
+    /// into account, the rest go by the SNR already in the rxpk and drop
+    /// the uplink if it's below the demodulation floor of its spreading
+    /// factor.
+    fn uplink_at_reduced_power(&self, rxpk: push_data::RxPkV1, _reduction_db: u8) -> Delivery {
+        if !geolocation::demodulates(f64::from(rxpk.lsnr), &geolocation::datr_name(&rxpk.datr)) {
+            Delivery::Unheard
+        } else if self.uplink(rxpk) {
+            Delivery::Delivered
+        } else {
+            Delivery::Full
+        }
+    }
+
+    /// Downlinks from the network server, for every device on the transport.
+    /// Each call gets its own receiver.
+    fn downlinks(&self) -> mpsc::Receiver<Box<pull_resp::Packet>>;
+
+    /// Confirm that a downlink was transmitted
+    fn ack(&self, downlink: Box<pull_resp::Packet>);
+}
+
+/// A transport which connects devices straight to the caller rather than to
+/// a network server, for tests. Uplinks come out of the receiver returned
+/// alongside it and downlinks are put in with `downlink`.
+#[derive(Clone)]
+pub struct Loopback {
+    clock: GatewayClock,
+    uplinks: mpsc::Sender<push_data::RxPkV1>,
+    downlinks: tokio::sync::broadcast::Sender<pull_resp::TxPk>,
+}
+
+impl Loopback {
+    pub fn new(clock: GatewayClock) -> (Loopback, mpsc::Receiver<push_data::RxPkV1>) {
+        let (uplinks, receiver) = mpsc::channel(1024);
+        let (downlinks, _) = tokio::sync::broadcast::channel(1024);
+        (
+            Loopback {
+                clock,
+                uplinks,
+                downlinks,
+            },
+            receiver,
+        )
+    }
+
+    /// Deliver a downlink to every device on the transport
+    pub fn downlink(&self, txpk: pull_resp::TxPk) {
+        // no receivers only means no devices are listening right now
+        let _ = self.downlinks.send(txpk);
+    }
+}
+
+impl VirtualTransport for Loopback {
+    fn clock(&self) -> GatewayClock {
+        self.clock
+    }
+
+    fn uplink(&self, rxpk: push_data::RxPkV1) -> bool {
+        self.uplinks.try_send(rxpk).is_ok()
+    }
+
+    fn downlinks(&self) -> mpsc::Receiver<Box<pull_resp::Packet>> {
+        let mut downlinks = self.downlinks.subscribe();
+        let (sender, receiver) = mpsc::channel(100);
+        tokio::spawn(async move {
+            while let Ok(txpk) = downlinks.recv().await {
+                let packet = pull_resp::Packet {
+                    random_token: rand::random(),
+                    data: pull_resp::Data { txpk },
+                };
+                if sender.send(Box::new(packet)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+
+    fn ack(&self, _downlink: Box<pull_resp::Packet>) {}
+}
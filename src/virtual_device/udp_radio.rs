@@ -1,10 +1,27 @@
-use log::info;
+use super::{
+    mac::{Answer, Piggyback},
+    Delivery, VirtualTransport,
+};
+use crate::{
+    gateway_clock::GatewayClock,
+    settings::{self, RxWindow},
+};
+use log::{info, warn};
 use lorawan_device::{radio, Timings};
-use semtech_udp::client_runtime;
-use semtech_udp::{push_data, Bandwidth, CodingRate, DataRate, SpreadingFactor};
-use std::time::{Duration, Instant};
-pub use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::time::sleep;
+use semtech_udp::{pull_resp, Bandwidth, CodingRate, DataRate, SpreadingFactor};
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+pub use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::{
+    task::JoinHandle,
+    time::{sleep, Instant},
+};
 
 #[derive(Debug)]
 // I need some intermediate event because of Lifetimes
@@ -15,92 +32,370 @@ pub enum IntermediateEvent {
     NewSession,
     Timeout(usize),
     SendPacket(Vec<u8>, u8, bool),
+    /// An uplink requested from the console rather than by the device's schedule
+    ManualPacket(Vec<u8>, u8, bool),
+    /// Move to another transport, keeping the session
+    Handover(Handover),
+    /// Send the last confirmed uplink again, as it wasn't acknowledged
+    Retransmit,
+    /// Hold scheduled uplinks until resumed
+    Pause,
+    Resume,
+    /// Send the next scheduled payload straight away
+    SendNow,
+}
+
+/// The transport a device moves to and the label it's known by
+#[derive(Clone)]
+pub struct Handover {
+    pub label: String,
+    pub transport: Arc<dyn VirtualTransport>,
+}
+
+impl std::fmt::Debug for Handover {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Handover").field(&self.label).finish()
+    }
 }
 
 #[derive(Debug)]
 pub enum Response {}
 
-#[derive(Debug)]
+/// Largest frame the radio will accept from a downlink
+pub const RX_BUFFER_SIZE: usize = 512;
+/// SNR every downlink is received with
+pub const DOWNLINK_SNR: i8 = 5;
+/// What gateways report of an uplink with the default SNR, sent at the
+/// device's maximum TX power
+const UPLINK_RSSI: i32 = -112;
+const UPLINK_SNR: f32 = 5.5;
+
+/// The radio the LoRaWAN stack drives. It follows the Semtech UDP model of
+/// frames, rxpk up and txpk down, over whichever transport it is given.
 pub struct UdpRadio {
-    udp_sender: Sender<client_runtime::TxMessage>,
+    transport: Arc<dyn VirtualTransport>,
+    /// task handing the transport's downlinks to the device
+    downlinks: JoinHandle<()>,
     lorawan_sender: Sender<IntermediateEvent>,
     time: Instant,
+    clock: GatewayClock,
     settings: Settings,
+    rx_window: RxWindow,
+    // spreading factor of the last uplink, which the RX windows follow
+    tx_spreading_factor: &'static str,
     timeout_id: usize,
     window_start: u32,
-    rx_buffer: [u8; 512],
+    rx_buffer: [u8; RX_BUFFER_SIZE],
     pos: usize,
+    error: Option<Error>,
+    /// error raised by an uplink handed to the transport once it was on air
+    late_error: Arc<Mutex<Option<Error>>>,
+    /// uplinks no gateway could hear, since this was last taken
+    unheard: Arc<AtomicU32>,
+    /// SNR gateways receive uplinks with at full power, and the RSSI and
+    /// SNR to report instead if given
+    uplink_snr_db: f32,
+    uplink_quality: Option<settings::UplinkQuality>,
+    rf_mismatch: Option<RfMismatch>,
+    tx_params: Option<TxParams>,
+    last_tx_tmst: Option<u32>,
+    /// time on air and TX power of the last uplink, until it's taken
+    transmission: Option<(Duration, i8)>,
+    /// how far below its maximum the network server has the device transmit
+    tx_power_reduction_db: u8,
+    /// frequency and data rate the last uplink was sent at
+    last_tx_freq: Option<f64>,
+    last_tx_datr_name: Option<&'static str>,
+    /// PHYPayload of the last uplink, and whether the next is to repeat it
+    last_uplink: Vec<u8>,
+    repeat: bool,
+    /// MAC command answers to add to the FOpts of the next uplink, and
+    /// those the last uplink couldn't be made again with
+    piggyback: Option<Piggyback>,
+    unsent_answers: Option<Vec<Answer>>,
+}
+
+/// RF parameters of a downlink which didn't match the RX window the device
+/// was listening on
+#[derive(Debug)]
+pub struct RfMismatch {
+    pub expected_freq: f64,
+    pub freq: f64,
+    pub expected_datr: DataRate,
+    pub datr: DataRate,
+}
+
+/// TX parameters the network server gave a downlink in its txpk
+#[derive(Clone, Debug, Serialize)]
+pub struct TxParams {
+    pub freq: f64,
+    pub powe: u64,
+    pub ipol: bool,
+    /// as written in the txpk, eg: "4/5"
+    pub codr: serde_json::Value,
+    pub rfch: u64,
+}
+
+impl TxParams {
+    fn of(txpk: &pull_resp::TxPk) -> TxParams {
+        TxParams {
+            freq: txpk.freq,
+            powe: txpk.powe,
+            ipol: txpk.ipol,
+            codr: serde_json::to_value(&txpk.codr).unwrap_or_default(),
+            rfch: txpk.rfch,
+        }
+    }
+
+    /// What about these parameters would keep the downlink from reaching a
+    /// device, or break the region's limits
+    pub fn problems(&self, region: &settings::Region) -> Vec<String> {
+        let mut problems = Vec::new();
+        let max_power_dbm = region.max_downlink_power_dbm(self.freq);
+        if self.powe > max_power_dbm {
+            problems.push(format!(
+                "powe {} dBm is over the {} dBm allowed on {} MHz",
+                self.powe, max_power_dbm, self.freq
+            ));
+        }
+        if !self.ipol {
+            problems.push("ipol is false, devices only hear inverted polarity".to_string());
+        }
+        if self.codr != "4/5" {
+            problems.push(format!("codr is {}, LoRaWAN downlinks use 4/5", self.codr));
+        }
+        // gateways have two radio chains
+        if self.rfch > 1 {
+            problems.push(format!("rfch {} is not a radio chain", self.rfch));
+        }
+        problems
+    }
 }
 
 impl UdpRadio {
+    /// Create a radio talking through the given transport, which hands
+    /// downlinks and timeouts to the device through lorawan_sender
     pub async fn new(
         time: Instant,
-        udp_runtime: &semtech_udp::client_runtime::UdpRuntime,
-    ) -> (
-        UdpRadio,
-        tokio::sync::mpsc::Receiver<IntermediateEvent>,
-        tokio::sync::mpsc::Sender<IntermediateEvent>,
-    ) {
-        let (mut udp_receiver, udp_sender) = (udp_runtime.subscribe(), udp_runtime.publish_to());
-
-        let (lorawan_sender, lorawan_receiver) = mpsc::channel(100);
-        let udp_lorawan_sender = lorawan_sender.clone();
-
-        // this task receives downlinks and sends them to the lorawan layer as if a PHY radio
-        // received the frame
-        tokio::spawn(async move {
-            loop {
-                let event = udp_receiver.recv().await.unwrap();
-                if let semtech_udp::Packet::Down(semtech_udp::Down::PullResp(pull_resp)) = event {
-                    udp_lorawan_sender
-                        .send(IntermediateEvent::UdpRx(pull_resp))
-                        .await
-                        .unwrap();
-                }
-            }
-        });
-
-        (
-            UdpRadio {
-                time,
-                settings: Settings::default(),
-                udp_sender,
-                timeout_id: 0,
-                lorawan_sender: lorawan_sender.clone(),
-                window_start: 0,
-                rx_buffer: [0; 512],
-                pos: 0,
-            },
-            lorawan_receiver,
+        transport: Arc<dyn VirtualTransport>,
+        rx_window: RxWindow,
+        uplink_snr_db: f32,
+        uplink_quality: Option<settings::UplinkQuality>,
+        lorawan_sender: Sender<IntermediateEvent>,
+    ) -> UdpRadio {
+        UdpRadio {
+            time,
+            clock: transport.clock(),
+            settings: Settings::default(),
+            rx_window,
+            tx_spreading_factor: "SF7",
+            downlinks: forward_downlinks(transport.as_ref(), lorawan_sender.clone()),
+            transport,
+            timeout_id: 0,
             lorawan_sender,
-        )
+            window_start: 0,
+            rx_buffer: [0; RX_BUFFER_SIZE],
+            pos: 0,
+            error: None,
+            late_error: Arc::new(Mutex::new(None)),
+            unheard: Arc::new(AtomicU32::new(0)),
+            uplink_snr_db,
+            uplink_quality,
+            rf_mismatch: None,
+            tx_params: None,
+            last_tx_tmst: None,
+            transmission: None,
+            tx_power_reduction_db: 0,
+            last_tx_freq: None,
+            last_tx_datr_name: None,
+            last_uplink: Vec::new(),
+            repeat: false,
+            piggyback: None,
+            unsent_answers: None,
+        }
     }
 
-    pub async fn timer(&mut self, future_time: u32) {
-        let timeout_id = rand::random::<usize>();
+    /// Schedule a Timeout event for the given time. If the time has already
+    /// passed, the Timeout is dispatched immediately and the number of ms it
+    /// is late by is returned.
+    pub async fn timer(&mut self, future_time: u32) -> Option<u32> {
+        let timeout_id = crate::rng::random::<usize>();
         self.timeout_id = timeout_id;
         // units are in millis here because
         // the lorawan device stack operates in millis
         let elapsed = self.time.elapsed().as_millis() as u32;
-        // only kick out the packet if its on time
-        if future_time > elapsed {
+        let sender = self.lorawan_sender.clone();
+        if future_time >= elapsed {
             let delay = future_time - elapsed;
-            let sender = self.lorawan_sender.clone();
-
             tokio::spawn(async move {
                 sleep(Duration::from_millis(delay as u64)).await;
-                sender
-                    .send(IntermediateEvent::Timeout(timeout_id))
-                    .await
-                    .unwrap()
+                // the device may have stopped while we slept
+                let _ = sender.send(IntermediateEvent::Timeout(timeout_id)).await;
             });
             self.window_start = delay;
+            None
+        } else {
+            // the event loop fell behind, so fire right away
+            tokio::spawn(async move {
+                let _ = sender.send(IntermediateEvent::Timeout(timeout_id)).await;
+            });
+            self.window_start = 0;
+            Some(elapsed - future_time)
         }
     }
 
     pub fn most_recent_timeout(&mut self, timeout_id: usize) -> bool {
         self.timeout_id == timeout_id
     }
+
+    /// Take the error raised while handling the last radio event, if any.
+    /// The LoRaWAN stack swallows PHY errors into its own error type, so the
+    /// radio holds onto them for the device to inspect.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error
+            .take()
+            .or_else(|| self.late_error.lock().expect("late error lock").take())
+    }
+
+    /// Take the RF mismatch found on the last received frame, if any. Every
+    /// device sees every downlink, so this is only meaningful once the
+    /// LoRaWAN stack has accepted the frame.
+    pub fn take_rf_mismatch(&mut self) -> Option<RfMismatch> {
+        self.rf_mismatch.take()
+    }
+
+    /// TX parameters of the last received frame, with the same caveat as
+    /// take_rf_mismatch
+    pub fn last_tx_params(&self) -> Option<&TxParams> {
+        self.tx_params.as_ref()
+    }
+
+    /// Gateway time at which the most recent uplink was received in full
+    pub fn last_tx_tmst(&self) -> Option<u32> {
+        self.last_tx_tmst
+    }
+
+    /// Take the time on air and TX power in dBm of the uplink sent since
+    /// this was last called, if any
+    pub fn take_transmission(&mut self) -> Option<(Duration, i8)> {
+        self.transmission.take()
+    }
+
+    /// Take the number of uplinks too weak for any gateway to hear since
+    /// this was last called
+    pub fn take_unheard(&mut self) -> u32 {
+        self.unheard.swap(0, Ordering::Relaxed)
+    }
+
+    /// Frequency the most recent uplink was sent on, in MHz
+    pub fn last_tx_freq(&self) -> Option<f64> {
+        self.last_tx_freq
+    }
+
+    /// Data rate the most recent uplink was sent at, as written in an rxpk,
+    /// eg: "SF7BW125"
+    pub fn last_tx_datr_name(&self) -> Option<&'static str> {
+        self.last_tx_datr_name
+    }
+
+    pub fn tx_power_reduction_db(&self) -> u8 {
+        self.tx_power_reduction_db
+    }
+
+    /// Transmit this many dB below the maximum TX power from the next uplink
+    /// on, as the network server asks with LinkADRReq
+    pub fn set_tx_power_reduction(&mut self, reduction_db: u8) {
+        self.tx_power_reduction_db = reduction_db;
+    }
+
+    /// Put the last uplink on air again in place of the next frame the stack
+    /// sends. The stack can't resend a frame with the same FCnt itself, so
+    /// the frame it builds is discarded, skipping that FCnt.
+    pub fn repeat_next(&mut self) {
+        self.repeat = !self.last_uplink.is_empty();
+    }
+
+    /// Add MAC command answers to the FOpts of the next frame the stack
+    /// sends, making it again with them
+    pub fn piggyback_next(&mut self, piggyback: Piggyback) {
+        self.piggyback = Some(piggyback);
+    }
+
+    /// The answers given to piggyback_next which the frame sent since
+    /// doesn't carry, either because it couldn't be made again with them
+    /// or because the stack sent nothing new
+    pub fn take_unsent_answers(&mut self) -> Option<Vec<Answer>> {
+        self.unsent_answers
+            .take()
+            .or_else(|| self.piggyback.take().map(|piggyback| piggyback.answers))
+    }
+
+    /// PHYPayload of the most recent uplink
+    pub fn last_uplink(&self) -> &[u8] {
+        &self.last_uplink
+    }
+
+    /// Talk through another transport from here on. Downlinks still on
+    /// their way through the old one are lost, as is any exchange in flight
+    /// since the new gateway keeps its own time.
+    pub fn hand_over(&mut self, transport: Arc<dyn VirtualTransport>) {
+        self.downlinks.abort();
+        self.downlinks = forward_downlinks(transport.as_ref(), self.lorawan_sender.clone());
+        self.clock = transport.clock();
+        self.transport = transport;
+        self.last_tx_tmst = None;
+    }
+
+    fn fail(&mut self, error: Error) -> LoraError<Self> {
+        self.error = Some(error);
+        LoraError::PhyError(error)
+    }
+}
+
+/// Receive the transport's downlinks and hand them to the lorawan layer as if a
+/// PHY radio received the frame
+fn forward_downlinks(
+    transport: &dyn VirtualTransport,
+    lorawan_sender: Sender<IntermediateEvent>,
+) -> JoinHandle<()> {
+    let mut downlinks = transport.downlinks();
+    tokio::spawn(async move {
+        while let Some(pull_resp) = downlinks.recv().await {
+            // the device has stopped so there's nobody left to deliver to
+            if lorawan_sender
+                .send(IntermediateEvent::UdpRx(pull_resp))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    })
+}
+
+/// Time on air of an uplink of this many bytes of PHYPayload at this datr,
+/// eg: "SF7BW125"
+pub fn uplink_airtime(datr: &str, len: usize) -> Option<Duration> {
+    Modulation::from_datr(datr).map(|modulation| modulation.airtime(len))
+}
+
+/// The measurement for one uplink, drawn from the device's rng. A normal
+/// distribution is drawn by the Box-Muller transform.
+fn draw(measurement: &settings::Measurement) -> f32 {
+    match *measurement {
+        settings::Measurement::Fixed { value } => value,
+        settings::Measurement::Uniform { min, max } => {
+            min + (max - min) * crate::rng::random::<f32>()
+        }
+        settings::Measurement::Normal { mean, std_dev } => {
+            let (u1, u2) = (
+                1.0 - crate::rng::random::<f64>(),
+                crate::rng::random::<f64>(),
+            );
+            let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+            mean + std_dev * z as f32
+        }
+    }
 }
 
 use lorawan_device::radio::{
@@ -127,38 +422,79 @@ impl radio::PhyRxTx for UdpRadio {
         use semtech_udp::push_data::*;
         match event {
             radio::Event::TxRequest(tx_config, buffer) => {
-                let size = buffer.len() as u64;
-                let tmst = self.time.elapsed().as_micros() as u32;
-                info!("Transmit tmst: {}", tmst);
+                if !std::mem::take(&mut self.repeat) {
+                    self.last_uplink = match self.piggyback.take().map(|p| p.apply(buffer)) {
+                        Some(Ok(frame)) => frame,
+                        Some(Err(answers)) => {
+                            warn!("unable to add FOpts, sending uplink without them");
+                            self.unsent_answers = Some(answers);
+                            buffer.to_vec()
+                        }
+                        None => buffer.to_vec(),
+                    };
+                }
+                let data = self.last_uplink.clone();
+                let size = data.len() as u64;
+                let reduction_db = self.tx_power_reduction_db;
+                let power_dbm = tx_config.pw.saturating_sub(reduction_db as i8);
                 let settings = Settings::from(tx_config);
-                let mut data = Vec::new();
-                data.extend_from_slice(buffer);
+                let airtime = settings.airtime(data.len());
+                self.transmission = Some((airtime, power_dbm));
+                // like a concentrator, the gateway stamps the uplink once the
+                // whole frame has been received
+                let tmst = self.clock.tmst().wrapping_add(airtime.as_micros() as u32);
+                info!("Transmit tmst: {}, airtime {:?}", tmst, airtime);
+                self.last_tx_tmst = Some(tmst);
+                self.tx_spreading_factor = settings.get_spreading_factor_name();
+                self.last_tx_freq = Some(settings.get_freq());
+                self.last_tx_datr_name = Some(settings.get_datr_name());
+                let quality = self.uplink_quality.unwrap_or(settings::UplinkQuality {
+                    rssi_dbm: None,
+                    snr_db: None,
+                });
+                let snr_db = quality.snr_db.as_ref().map_or(self.uplink_snr_db, draw);
+                let lsnr = snr_db - f32::from(reduction_db);
+                let rssi = match &quality.rssi_dbm {
+                    Some(rssi_dbm) => (draw(rssi_dbm) - f32::from(reduction_db)).round() as i32,
+                    None => UPLINK_RSSI + (lsnr - UPLINK_SNR).round() as i32,
+                };
                 let rxpk = RxPkV1 {
                     chan: 0,
                     codr: settings.get_codr(),
                     data,
                     datr: settings.get_datr(),
                     freq: settings.get_freq(),
-                    lsnr: 5.5,
+                    lsnr,
                     modu: semtech_udp::Modulation::LORA,
                     rfch: 0,
-                    rssi: -112,
+                    rssi,
                     rssis: None,
                     size,
                     stat: semtech_udp::push_data::CRC::OK,
                     tmst,
                     time: None,
                 };
-                let packet = push_data::Packet::from_rxpk(RxPk::V1(rxpk));
-
-                if let Err(e) = self.udp_sender.try_send(packet.into()) {
-                    panic!("UdpTx Queue Overflow! {}", e)
-                }
+                // and only then is it forwarded, reaching the network server
+                // when it would from a real gateway
+                let (transport, late_error) = (self.transport.clone(), self.late_error.clone());
+                let unheard = self.unheard.clone();
+                tokio::spawn(async move {
+                    sleep(airtime).await;
+                    match transport.uplink_at_reduced_power(rxpk, reduction_db) {
+                        Delivery::Delivered => (),
+                        Delivery::Unheard => {
+                            unheard.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Delivery::Full => {
+                            *late_error.lock().expect("late error lock") = Some(Error::TxQueueFull)
+                        }
+                    }
+                });
 
                 // units are in millis here because
                 // the lorawan device stack operates in millis
                 Ok(radio::Response::TxDone(
-                    self.time.elapsed().as_millis() as u32
+                    (self.time.elapsed() + airtime).as_millis() as u32,
                 ))
             }
             radio::Event::RxRequest(config) => {
@@ -167,33 +503,92 @@ impl radio::PhyRxTx for UdpRadio {
             }
             radio::Event::CancelRx => Ok(radio::Response::Idle),
             radio::Event::PhyEvent(packet) => {
-                self.pos = packet.data.txpk.data.len();
-                for (i, el) in packet.data.txpk.data.iter().enumerate() {
-                    self.rx_buffer[i] = *el;
+                let len = packet.data.txpk.data.len();
+                if len > self.rx_buffer.len() {
+                    return Err(self.fail(Error::RxBufferOverflow(len)));
                 }
-                let ack = packet
-                    .into_ack_for_gateway(semtech_udp::MacAddress::new(&[0, 0, 0, 0, 0, 0, 0, 0]));
-
-                let sender = self.udp_sender.clone();
-                // we are not in an async context so we must spawn this off
-                tokio::task::spawn(async move { sender.send(ack.into()).await });
-                Ok(LoraResponse::RxDone(RxQuality::new(-120, 5)))
+                self.pos = len;
+                self.rf_mismatch = self
+                    .settings
+                    .check_downlink(packet.data.txpk.freq, &packet.data.txpk.datr);
+                self.tx_params = Some(TxParams::of(&packet.data.txpk));
+                self.rx_buffer[..len].copy_from_slice(&packet.data.txpk.data);
+                self.transport.ack(packet);
+                Ok(LoraResponse::RxDone(RxQuality::new(-120, DOWNLINK_SNR)))
             }
         }
     }
 }
 
+impl UdpRadio {
+    fn rx_window_timing(&self) -> &crate::settings::RxWindowTiming {
+        // config lowercases keys, so match them case insensitively
+        self.rx_window
+            .spreading_factor
+            .iter()
+            .find(|(sf, _)| sf.eq_ignore_ascii_case(self.tx_spreading_factor))
+            .map(|(_, timing)| timing)
+            .unwrap_or(&self.rx_window.timing)
+    }
+}
+
 impl Timings for UdpRadio {
     fn get_rx_window_offset_ms(&self) -> i32 {
-        20
+        self.rx_window_timing().offset_ms
     }
     fn get_rx_window_duration_ms(&self) -> u32 {
-        100
+        self.rx_window_timing().duration_ms
     }
 }
 
-#[derive(Debug)]
-pub enum Error {}
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+pub enum Error {
+    #[error("transport tx queue full")]
+    TxQueueFull,
+    #[error("received frame of {0} bytes overflows rx buffer")]
+    RxBufferOverflow(usize),
+}
+
+/// LoRa parameters of an uplink, with the coding rate as 1 for 4/5 up to 4
+/// for 4/8
+#[derive(Debug, Clone, Copy)]
+struct Modulation {
+    spreading_factor: i64,
+    bandwidth_hz: f64,
+    coding_rate: i64,
+}
+
+impl Modulation {
+    /// The LoRa parameters of an uplink at this datr, eg: "SF7BW125", with
+    /// the coding rate of 4/5 uplinks are sent at
+    fn from_datr(datr: &str) -> Option<Modulation> {
+        let (spreading_factor, bandwidth_khz) = datr.strip_prefix("SF")?.split_once("BW")?;
+        Some(Modulation {
+            spreading_factor: spreading_factor.parse().ok()?,
+            bandwidth_hz: bandwidth_khz.parse::<f64>().ok()? * 1000.0,
+            coding_rate: 1,
+        })
+    }
+
+    /// Time on air of a frame with this many bytes of PHYPayload, per the
+    /// Semtech LoRa modem designer's guide, with an 8 symbol preamble, an
+    /// explicit header and a CRC as uplinks use
+    fn airtime(&self, len: usize) -> Duration {
+        let Modulation {
+            spreading_factor,
+            bandwidth_hz,
+            coding_rate,
+        } = *self;
+        let symbol = f64::from(1u32 << spreading_factor) / bandwidth_hz;
+        // low data rate optimisation is on once symbols reach 16 ms
+        let low_data_rate = i64::from(symbol >= 0.016);
+        let bits = 8 * len as i64 - 4 * spreading_factor + 28 + 16;
+        let per_block = 4 * (spreading_factor - 2 * low_data_rate);
+        let blocks = (bits + per_block - 1).div_euclid(per_block).max(0);
+        let payload_symbols = 8 + blocks * (coding_rate + 4);
+        Duration::from_secs_f64((8.0 + 4.25 + payload_symbols as f64) * symbol)
+    }
+}
 
 #[derive(Debug)]
 struct Settings {
@@ -240,6 +635,42 @@ impl Settings {
         )
     }
 
+    /// The datr, named once here rather than formatted for every uplink
+    fn get_datr_name(&self) -> &'static str {
+        use radio::{Bandwidth as BW, SpreadingFactor as SF};
+        match (&self.rfconfig.spreading_factor, &self.rfconfig.bandwidth) {
+            (SF::_7, BW::_125KHz) => "SF7BW125",
+            (SF::_7, BW::_250KHz) => "SF7BW250",
+            (SF::_7, BW::_500KHz) => "SF7BW500",
+            (SF::_8, BW::_125KHz) => "SF8BW125",
+            (SF::_8, BW::_250KHz) => "SF8BW250",
+            (SF::_8, BW::_500KHz) => "SF8BW500",
+            (SF::_9, BW::_125KHz) => "SF9BW125",
+            (SF::_9, BW::_250KHz) => "SF9BW250",
+            (SF::_9, BW::_500KHz) => "SF9BW500",
+            (SF::_10, BW::_125KHz) => "SF10BW125",
+            (SF::_10, BW::_250KHz) => "SF10BW250",
+            (SF::_10, BW::_500KHz) => "SF10BW500",
+            (SF::_11, BW::_125KHz) => "SF11BW125",
+            (SF::_11, BW::_250KHz) => "SF11BW250",
+            (SF::_11, BW::_500KHz) => "SF11BW500",
+            (SF::_12, BW::_125KHz) => "SF12BW125",
+            (SF::_12, BW::_250KHz) => "SF12BW250",
+            (SF::_12, BW::_500KHz) => "SF12BW500",
+        }
+    }
+
+    fn get_spreading_factor_name(&self) -> &'static str {
+        match self.rfconfig.spreading_factor {
+            radio::SpreadingFactor::_7 => "SF7",
+            radio::SpreadingFactor::_8 => "SF8",
+            radio::SpreadingFactor::_9 => "SF9",
+            radio::SpreadingFactor::_10 => "SF10",
+            radio::SpreadingFactor::_11 => "SF11",
+            radio::SpreadingFactor::_12 => "SF12",
+        }
+    }
+
     fn get_codr(&self) -> CodingRate {
         match self.rfconfig.coding_rate {
             radio::CodingRate::_4_5 => CodingRate::_4_5,
@@ -249,7 +680,86 @@ impl Settings {
         }
     }
 
+    /// Time on air of a frame with this many bytes of PHYPayload
+    fn airtime(&self, len: usize) -> Duration {
+        self.modulation().airtime(len)
+    }
+
+    /// The LoRa parameters of the uplink, as times on air are worked out
+    fn modulation(&self) -> Modulation {
+        let spreading_factor = match self.rfconfig.spreading_factor {
+            radio::SpreadingFactor::_7 => 7,
+            radio::SpreadingFactor::_8 => 8,
+            radio::SpreadingFactor::_9 => 9,
+            radio::SpreadingFactor::_10 => 10,
+            radio::SpreadingFactor::_11 => 11,
+            radio::SpreadingFactor::_12 => 12,
+        };
+        let bandwidth_hz = match self.rfconfig.bandwidth {
+            radio::Bandwidth::_125KHz => 125_000.0,
+            radio::Bandwidth::_250KHz => 250_000.0,
+            radio::Bandwidth::_500KHz => 500_000.0,
+        };
+        let coding_rate = match self.rfconfig.coding_rate {
+            radio::CodingRate::_4_5 => 1,
+            radio::CodingRate::_4_6 => 2,
+            radio::CodingRate::_4_7 => 3,
+            radio::CodingRate::_4_8 => 4,
+        };
+        Modulation {
+            spreading_factor,
+            bandwidth_hz,
+            coding_rate,
+        }
+    }
+
     fn get_freq(&self) -> f64 {
         self.rfconfig.frequency as f64 / 1_000_000.0
     }
+
+    fn check_downlink(&self, freq: f64, datr: &DataRate) -> Option<RfMismatch> {
+        let expected_freq = self.get_freq();
+        let expected_datr = self.get_datr();
+        // freq is in MHz, so this allows for 100 Hz of rounding
+        if (freq - expected_freq).abs() > 0.0001 || *datr != expected_datr {
+            Some(RfMismatch {
+                expected_freq,
+                freq,
+                expected_datr,
+                datr: datr.clone(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn airtime_us(datr: &str, len: usize) -> u64 {
+        let airtime = Modulation::from_datr(datr).unwrap().airtime(len);
+        (airtime.as_secs_f64() * 1_000_000.0).round() as u64
+    }
+
+    #[test]
+    fn airtime() {
+        // an empty data uplink, and a join request
+        assert_eq!(airtime_us("SF7BW125", 13), 46_336);
+        assert_eq!(airtime_us("SF10BW125", 23), 370_688);
+        // low data rate optimisation on at SF11 and SF12 on 125 kHz only
+        assert_eq!(airtime_us("SF12BW125", 13), 1_155_072);
+        assert_eq!(airtime_us("SF11BW125", 51), 1_314_816);
+        assert_eq!(airtime_us("SF12BW500", 13), 288_768);
+    }
+
+    #[test]
+    fn modulation_from_datr() {
+        let modulation = Modulation::from_datr("SF9BW500").unwrap();
+        assert_eq!(modulation.spreading_factor, 9);
+        assert_eq!(modulation.bandwidth_hz, 500_000.0);
+        assert!(Modulation::from_datr("50000").is_none());
+        assert!(Modulation::from_datr("SF7").is_none());
+    }
 }